@@ -0,0 +1,219 @@
+use crate::event::{AbDiff, AbSummary, Latency, VariantSummary};
+use crate::offset::OffsetMap;
+use crate::protocol;
+use log::warn;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+// Turns of one variant still waiting for their counterpart
+const MAX_UNMATCHED: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Variant {
+    A,
+    B,
+}
+
+impl Variant {
+    pub fn tag(self) -> &'static str {
+        match self {
+            Variant::A => "a",
+            Variant::B => "b",
+        }
+    }
+
+    fn other(self) -> Self {
+        match self {
+            Variant::A => Variant::B,
+            Variant::B => Variant::A,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Turn {
+    span: Option<(f64, f64)>,
+    text: String,
+}
+
+#[derive(Debug, Default)]
+struct Tally {
+    turns: usize,
+    chars: usize,
+    latencies_ms: Vec<u64>,
+    stopped_at: HashMap<String, Instant>,
+    unmatched: VecDeque<Turn>,
+}
+
+// Pairs up the final transcripts of both variants and keeps the numbers for the summary
+#[derive(Debug)]
+pub struct Comparison {
+    models: [String; 2],
+    tallies: [Tally; 2],
+}
+
+impl Comparison {
+    pub fn new(model_a: &str, model_b: &str) -> Self {
+        Self { models: [model_a.into(), model_b.into()], tallies: Default::default() }
+    }
+
+    // Offsets are expected on the source timeline already. Returns the diff once a turn has its counterpart.
+    pub fn observe(&mut self, variant: Variant, event: &Value, received_at: Instant) -> Option<AbDiff> {
+        let item_id = event["item_id"].as_str()?;
+        let tally = &mut self.tallies[variant as usize];
+
+        match protocol::event_type(event) {
+            "input_audio_buffer.speech_stopped" | "input_audio_buffer.committed" => {
+                tally.stopped_at.entry(item_id.into()).or_insert(received_at);
+                return None
+            }
+            "conversation.item.input_audio_transcription.completed" => {}
+            _ => return None,
+        }
+
+        let text = event["transcript"].as_str().unwrap_or_default().to_string();
+
+        tally.turns += 1;
+        tally.chars += text.chars().count();
+
+        if let Some(stopped_at) = tally.stopped_at.remove(item_id) {
+            tally.latencies_ms.push(received_at.saturating_duration_since(stopped_at).as_millis() as u64);
+        }
+
+        let turn = Turn { span: event["audio_start_ms"].as_f64().zip(event["audio_end_ms"].as_f64()), text };
+        let others = &mut self.tallies[variant.other() as usize].unmatched;
+
+        // segmentation differs between models, so turns pair up by overlapping audio, or in order without offsets
+        let position = match turn.span {
+            Some((start_ms, end_ms)) => others
+                .iter()
+                .position(|other| other.span.is_some_and(|(other_start, other_end)| start_ms.max(other_start) < end_ms.min(other_end))),
+            None => (!others.is_empty()).then_some(0),
+        };
+
+        let Some(other) = position.and_then(|position| others.remove(position)) else {
+            let unmatched = &mut self.tallies[variant as usize].unmatched;
+
+            if unmatched.len() == MAX_UNMATCHED {
+                unmatched.pop_front();
+            }
+
+            unmatched.push_back(turn);
+            return None
+        };
+
+        let (a, b) = match variant {
+            Variant::A => (turn, other),
+            Variant::B => (other, turn),
+        };
+
+        let distance = edit_distance(&a.text, &b.text);
+        let longest = a.text.chars().count().max(b.text.chars().count());
+
+        Some(AbDiff {
+            start_ms: a.span.map(|(start_ms, _)| start_ms.round() as u64),
+            end_ms: a.span.map(|(_, end_ms)| end_ms.round() as u64),
+            a: a.text,
+            b: b.text,
+            edit_distance: distance,
+            similarity: if longest == 0 { 1.0 } else { 1.0 - distance as f64 / longest as f64 },
+        })
+    }
+
+    pub fn summary(&self, dropped_bytes: [u64; 2]) -> AbSummary {
+        let variants = [Variant::A, Variant::B]
+            .into_iter()
+            .map(|variant| {
+                let tally = &self.tallies[variant as usize];
+                let mut latencies_ms = tally.latencies_ms.clone();
+                latencies_ms.sort_unstable();
+
+                VariantSummary {
+                    variant: variant.tag().into(),
+                    model: self.models[variant as usize].clone(),
+                    turns: tally.turns,
+                    chars: tally.chars,
+                    latency_ms: (!latencies_ms.is_empty()).then(|| Latency {
+                        p50: percentile(&latencies_ms, 50),
+                        p90: percentile(&latencies_ms, 90),
+                        p99: percentile(&latencies_ms, 99),
+                    }),
+                    dropped_bytes: dropped_bytes[variant as usize],
+                }
+            })
+            .collect();
+
+        AbSummary { variants }
+    }
+}
+
+// Copy of the outgoing stream for the B session. It never blocks the A session: when B falls behind,
+// audio is dropped and recorded as a gap so B's offsets stay on the shared timeline.
+pub struct Fanout {
+    events_tx: mpsc::Sender<Value>,
+    gaps: Mutex<OffsetMap>,
+    dropped_bytes: Mutex<u64>,
+}
+
+impl Fanout {
+    pub fn new(events_tx: mpsc::Sender<Value>, sample_rate: u32) -> Self {
+        Self { events_tx, gaps: Mutex::new(OffsetMap::new(sample_rate)), dropped_bytes: Mutex::new(0) }
+    }
+
+    pub fn audio(&self, event: &Value, bytes: usize) {
+        match self.events_tx.try_send(event.clone()) {
+            Ok(()) => self.gaps.lock().unwrap().sent(bytes),
+            Err(TrySendError::Full(_)) => {
+                let mut dropped_bytes = self.dropped_bytes.lock().unwrap();
+
+                if *dropped_bytes == 0 {
+                    warn!("The B session falls behind, dropping its audio until it catches up");
+                }
+
+                *dropped_bytes += bytes as u64;
+                self.gaps.lock().unwrap().skipped(bytes);
+            }
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+
+    // Commits and clears are never dropped, they are rare and change what B transcribes
+    pub async fn control(&self, event: &Value) {
+        let _ = self.events_tx.send(event.clone()).await;
+    }
+
+    // Maps an offset on B's server timeline back onto the timeline both sessions were fed
+    pub fn correct_event(&self, event: &mut Value) -> bool {
+        self.gaps.lock().unwrap().correct_event(event)
+    }
+
+    pub fn dropped_bytes(&self) -> u64 {
+        *self.dropped_bytes.lock().unwrap()
+    }
+}
+
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+
+        for (j, &b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+// Nearest rank on sorted values
+fn percentile(sorted: &[u64], p: usize) -> u64 {
+    sorted[((sorted.len() - 1) * p).div_ceil(100)]
+}
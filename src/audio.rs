@@ -0,0 +1,333 @@
+use crate::offset::OffsetMap;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const FRAME_MS: u32 = 10;
+pub const SILENCE_DBFS: f32 = -50.0;
+
+// Calibration puts the silence level this far above the loudest ambient frames it saw
+const CALIBRATION_MARGIN_DB: f32 = 6.0;
+// Digital silence would otherwise be -inf
+const FLOOR_DBFS: f32 = -120.0;
+
+pub fn rms_dbfs(pcm: &[u8]) -> f32 {
+    let samples = pcm.len() / 2;
+
+    if samples == 0 {
+        return f32::NEG_INFINITY
+    }
+
+    let sum: f64 = pcm
+        .chunks_exact(2)
+        .map(|sample| i16::from_le_bytes([sample[0], sample[1]]) as f64)
+        .map(|sample| sample * sample)
+        .sum();
+
+    let rms = (sum / samples as f64).sqrt() / i16::MAX as f64;
+
+    20.0 * rms.log10() as f32
+}
+
+// Compresses silences longer than `threshold_ms` down to `bridge_ms`, recording what was skipped
+pub struct SilenceTrimmer {
+    frame_bytes: usize,
+    bridge_frames: usize,
+    threshold_frames: usize,
+    // silence kept from a trimmed gap, sent ahead of the next voiced frame
+    preroll_bytes: usize,
+    silence_dbfs: f32,
+    pending: Vec<u8>,
+    held: Vec<u8>,
+    silent_frames: usize,
+    offsets: Arc<Mutex<OffsetMap>>,
+}
+
+impl SilenceTrimmer {
+    pub fn new(sample_rate: u32, silence_dbfs: f32, bridge_ms: u32, threshold_ms: u32, preroll_ms: u32, offsets: Arc<Mutex<OffsetMap>>) -> Self {
+        let bridge_frames = (bridge_ms / FRAME_MS) as usize;
+        let frame_bytes = (sample_rate * FRAME_MS / 1000 * 2) as usize;
+
+        Self {
+            frame_bytes,
+            bridge_frames,
+            threshold_frames: ((threshold_ms / FRAME_MS) as usize).max(bridge_frames),
+            preroll_bytes: (preroll_ms / FRAME_MS) as usize * frame_bytes,
+            silence_dbfs,
+            pending: Vec::new(),
+            held: Vec::new(),
+            silent_frames: 0,
+            offsets,
+        }
+    }
+
+    pub fn process(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.pending.extend_from_slice(chunk);
+
+        let whole = self.pending.len() / self.frame_bytes * self.frame_bytes;
+        let frames: Vec<u8> = self.pending.drain(..whole).collect();
+        let mut output = Vec::with_capacity(frames.len());
+        let mut offsets = self.offsets.lock().unwrap();
+
+        for frame in frames.chunks_exact(self.frame_bytes) {
+            if rms_dbfs(frame) > self.silence_dbfs {
+                self.silent_frames = 0;
+                offsets.sent(self.held.len() + frame.len());
+                output.append(&mut self.held);
+                output.extend_from_slice(frame);
+                continue
+            }
+
+            self.silent_frames += 1;
+
+            if self.silent_frames <= self.bridge_frames {
+                offsets.sent(frame.len());
+                output.extend_from_slice(frame);
+            } else if self.silent_frames <= self.threshold_frames {
+                self.held.extend_from_slice(frame);
+            } else {
+                // only the oldest audio is skipped, the gap is recorded before the preroll so it keeps its source offsets
+                self.held.extend_from_slice(frame);
+                let excess = self.held.len().saturating_sub(self.preroll_bytes);
+                if excess > 0 {
+                    offsets.skipped(excess);
+                    self.held.drain(..excess);
+                }
+            }
+        }
+
+        output
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut offsets = self.offsets.lock().unwrap();
+
+        // a preroll with no voice after it is part of the trimmed gap
+        if self.silent_frames > self.threshold_frames && !self.held.is_empty() {
+            offsets.skipped(self.held.len());
+            self.held.clear();
+        }
+
+        output.append(&mut self.held);
+        output.append(&mut self.pending);
+        offsets.sent(output.len());
+        output
+    }
+}
+
+// The rates audio comes in, anything else is a mistake
+pub const COMMON_RATES: &[u32] = &[8000, 11025, 16000, 22050, 24000, 32000, 44100, 48000];
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+const RATE_WARMUP: Duration = Duration::from_secs(2);
+const RATE_PERSISTENCE: Duration = Duration::from_secs(3);
+const RATE_TOLERANCE: f64 = 0.2;
+
+// Levels of ambient audio, framed the way SilenceTrimmer frames it
+#[derive(Debug, Clone, Serialize)]
+pub struct Calibration {
+    pub seconds: f64,
+    pub frames: usize,
+    pub p50_dbfs: f32,
+    pub p90_dbfs: f32,
+    pub p95_dbfs: f32,
+    pub p99_dbfs: f32,
+    pub peak_dbfs: f32,
+    // for --trim-silence-db, frames at or below it count as silence
+    pub recommended_silence_dbfs: f32,
+}
+
+pub fn calibrate(pcm: &[u8], sample_rate: u32) -> Option<Calibration> {
+    let frame_bytes = (sample_rate * FRAME_MS / 1000 * 2) as usize;
+    let mut levels: Vec<f32> = pcm.chunks_exact(frame_bytes).map(|frame| rms_dbfs(frame).max(FLOOR_DBFS)).collect();
+
+    if levels.is_empty() {
+        return None
+    }
+
+    levels.sort_unstable_by(f32::total_cmp);
+
+    let percentile = |p: usize| levels[((levels.len() - 1) * p).div_ceil(100)];
+    let round = |dbfs: f32| (dbfs * 10.0).round() / 10.0;
+
+    Some(Calibration {
+        seconds: (levels.len() as u32 * FRAME_MS) as f64 / 1000.0,
+        frames: levels.len(),
+        p50_dbfs: round(percentile(50)),
+        p90_dbfs: round(percentile(90)),
+        p95_dbfs: round(percentile(95)),
+        p99_dbfs: round(percentile(99)),
+        peak_dbfs: round(levels[levels.len() - 1]),
+        // the 95th percentile rather than the peak, a single click should not raise it
+        recommended_silence_dbfs: (percentile(95) + CALIBRATION_MARGIN_DB).clamp(FLOOR_DBFS, -10.0).ceil(),
+    })
+}
+
+// Compares the byte rate of a live source against the declared sample rate
+pub struct RateMonitor {
+    declared: u32,
+    started: Option<Instant>,
+    window: VecDeque<(Instant, usize)>,
+    mismatch_since: Option<Instant>,
+    reported: bool,
+}
+
+impl RateMonitor {
+    pub fn new(declared: u32) -> Self {
+        Self {
+            declared,
+            started: None,
+            window: VecDeque::new(),
+            mismatch_since: None,
+            reported: false,
+        }
+    }
+
+    pub fn declared(&self) -> u32 {
+        self.declared
+    }
+
+    // Returns the measured rate once it has deviated from the declared one for long enough, at most once
+    pub fn observe(&mut self, bytes: usize, now: Instant) -> Option<u32> {
+        let started = *self.started.get_or_insert(now);
+
+        // skip the initial burst of buffered audio most capture pipelines produce
+        if self.reported || now.duration_since(started) < RATE_WARMUP {
+            return None
+        }
+
+        self.window.push_back((now, bytes));
+        while self.window.front().is_some_and(|(at, _)| now.duration_since(*at) > RATE_WINDOW) {
+            self.window.pop_front();
+        }
+
+        let span = now.duration_since(self.window.front()?.0).as_secs_f64();
+
+        if span < 1.0 {
+            return None
+        }
+
+        // the newest chunk was read at `now`, the bytes of the oldest one arrived before the span began
+        let bytes: usize = self.window.iter().skip(1).map(|(_, bytes)| bytes).sum();
+        let measured = bytes as f64 / 2.0 / span;
+
+        if (measured - self.declared as f64).abs() <= self.declared as f64 * RATE_TOLERANCE {
+            self.mismatch_since = None;
+            return None
+        }
+
+        if now.duration_since(*self.mismatch_since.get_or_insert(now)) < RATE_PERSISTENCE {
+            return None
+        }
+
+        self.reported = true;
+        Some(measured.round() as u32)
+    }
+}
+
+pub fn likely_sample_rate(measured: u32) -> u32 {
+    *COMMON_RATES.iter().min_by_key(|rate| rate.abs_diff(measured)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1 kHz keeps frames at 10 samples: 2 frames of bridge, gaps over 5 frames trimmed, 3 frames of preroll
+    const RATE: u32 = 1000;
+
+    fn trimmer() -> (SilenceTrimmer, Arc<Mutex<OffsetMap>>) {
+        let offsets = Arc::new(Mutex::new(OffsetMap::new(RATE)));
+        (SilenceTrimmer::new(RATE, SILENCE_DBFS, 20, 50, 30, offsets.clone()), offsets)
+    }
+
+    // A frame per character, v voiced and . silent, every sample of it tagged with the frame's index.
+    // Silent frames stay far below SILENCE_DBFS.
+    fn frames(pattern: &str) -> Vec<u8> {
+        pattern
+            .chars()
+            .enumerate()
+            .flat_map(|(i, c)| std::iter::repeat_n(if c == 'v' { 10_000 + i as i16 } else { i as i16 }, 10))
+            .flat_map(i16::to_le_bytes)
+            .collect()
+    }
+
+    // The index of every frame sent, in order
+    fn indices(pcm: &[u8]) -> Vec<usize> {
+        pcm.chunks_exact(20).map(|frame| (i16::from_le_bytes([frame[0], frame[1]]) % 10_000) as usize).collect()
+    }
+
+    fn run(trimmer: &mut SilenceTrimmer, pcm: &[u8], chunk_bytes: usize) -> Vec<usize> {
+        let mut sent: Vec<u8> = pcm.chunks(chunk_bytes).flat_map(|chunk| trimmer.process(chunk)).collect();
+        sent.extend(trimmer.finish());
+        indices(&sent)
+    }
+
+    #[test]
+    fn sends_the_preroll_ahead_of_speech() {
+        let (mut trimmer, offsets) = trimmer();
+        let sent = run(&mut trimmer, &frames("vv..........vv"), 20);
+
+        // two frames of bridge, 4 to 8 skipped, 9 to 11 as preroll
+        assert_eq!(sent, [0, 1, 2, 3, 9, 10, 11, 12, 13]);
+
+        let offsets = offsets.lock().unwrap();
+        assert_eq!(offsets.sent_ms(), 90.0);
+        // the preroll keeps its place on the source timeline, the gap lies before it
+        assert_eq!(offsets.to_source_ms(39.0), 39.0);
+        assert_eq!(offsets.to_source_ms(40.0), 90.0);
+        assert_eq!(offsets.to_source_ms(70.0), 120.0);
+    }
+
+    #[test]
+    fn keeps_gaps_under_the_threshold() {
+        let (mut trimmer, offsets) = trimmer();
+        let sent = run(&mut trimmer, &frames("v.....v..v"), 20);
+
+        assert_eq!(sent, (0..10).collect::<Vec<_>>());
+        assert_eq!(offsets.lock().unwrap().to_source_ms(95.0), 95.0);
+    }
+
+    #[test]
+    fn flapping_never_sends_twice() {
+        let (mut trimmer, offsets) = trimmer();
+        let pattern = "v........v......v.v...........v.........";
+        let sent = run(&mut trimmer, &frames(pattern), 7);
+
+        assert!(sent.windows(2).all(|pair| pair[0] < pair[1]), "{sent:?}");
+        assert!(pattern.match_indices('v').all(|(i, _)| sent.contains(&i)));
+        // the three frames before each trimmed gap's end are the preroll
+        assert_eq!(sent, [0, 1, 2, 6, 7, 8, 9, 10, 11, 13, 14, 15, 16, 17, 18, 19, 20, 27, 28, 29, 30, 31, 32]);
+
+        let offsets = offsets.lock().unwrap();
+        assert_eq!(offsets.sent_ms(), sent.len() as f64 * 10.0);
+        // every frame sent maps back to where it was in the input
+        for (at, &index) in sent.iter().enumerate() {
+            assert_eq!(offsets.to_source_ms(at as f64 * 10.0), index as f64 * 10.0, "frame {index}");
+        }
+    }
+
+    #[test]
+    fn drops_a_preroll_nothing_follows() {
+        let (mut trimmer, offsets) = trimmer();
+        let sent = run(&mut trimmer, &frames("vv.........."), 20);
+
+        assert_eq!(sent, [0, 1, 2, 3]);
+        assert_eq!(offsets.lock().unwrap().sent_ms(), 40.0);
+    }
+
+    #[test]
+    fn flushes_held_silence_and_partial_frames_at_the_end() {
+        let (mut trimmer, offsets) = trimmer();
+        let mut pcm = frames("v....");
+        pcm.extend([1, 0, 1, 0]);
+
+        let mut sent: Vec<u8> = trimmer.process(&pcm);
+        sent.extend(trimmer.finish());
+
+        assert_eq!(sent.len(), pcm.len());
+        assert_eq!(indices(&sent), [0, 1, 2, 3, 4]);
+        assert_eq!(offsets.lock().unwrap().sent_ms(), 52.0);
+    }
+}
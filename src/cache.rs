@@ -0,0 +1,176 @@
+use crate::protocol;
+use crate::sink::Kind;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const FORMAT_VERSION: u64 = 2;
+const ENTRY_EXTENSION: &str = "ndjson";
+
+// Transcripts of earlier runs, keyed by the audio and every parameter that shapes the output
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(dir: &Path) -> Self {
+        Self { dir: dir.to_path_buf() }
+    }
+
+    pub fn key(files: &[PathBuf], params: &Value) -> io::Result<String> {
+        let mut hasher = Sha256::new();
+
+        hasher.update(params.to_string());
+
+        for path in files {
+            // streamed, fixtures can be far larger than memory allows
+            io::copy(&mut File::open(path)?, &mut hasher)?;
+        }
+
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    // None on a miss, and for entries written by another format version or under another key
+    pub fn load(&self, key: &str) -> io::Result<Option<Vec<(Kind, String)>>> {
+        let path = self.entry_path(key);
+
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let mut lines = BufReader::new(file).lines();
+
+        let Some(header) = lines.next().transpose()? else {
+            return Ok(None)
+        };
+
+        let header: Value = serde_json::from_str(&header).unwrap_or_default();
+
+        if header["asr_cache"] != FORMAT_VERSION || header["key"] != key {
+            return Ok(None)
+        }
+
+        // each line as [kind, line], subtitle cues only reach stdout as turns
+        let lines = lines.map(|line| Ok(serde_json::from_str(&line?)?)).collect::<io::Result<Vec<_>>>()?;
+
+        // hits count as uses, so gc drops what CI stopped asking for first
+        File::options().append(true).open(&path)?.set_modified(SystemTime::now())?;
+
+        Ok(Some(lines))
+    }
+
+    pub fn store(&self, key: &str, mut header: Value, lines: &[(Kind, String)]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        header["asr_cache"] = FORMAT_VERSION.into();
+        header["key"] = key.into();
+        header["created"] = humantime::format_rfc3339_seconds(SystemTime::now()).to_string().into();
+
+        // written aside and renamed, a concurrent reader never sees half an entry
+        let path = self.entry_path(key);
+        let partial = path.with_extension("partial");
+        let mut file = io::BufWriter::new(File::create(&partial)?);
+
+        writeln!(file, "{header}")?;
+
+        for line in lines {
+            writeln!(file, "{}", json!(line))?;
+        }
+
+        file.into_inner().map_err(io::IntoInnerError::into_error)?.sync_all()?;
+        fs::rename(partial, path)
+    }
+
+    // Removes the least recently used entries until the rest fits into max_bytes
+    pub fn gc(&self, max_bytes: u64) -> io::Result<Value> {
+        let mut entries = Vec::new();
+
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+
+            if path.extension().is_some_and(|extension| extension == ENTRY_EXTENSION) {
+                let metadata = entry.metadata()?;
+                entries.push((metadata.modified()?, metadata.len(), path));
+            }
+        }
+
+        // most recently used first
+        entries.sort_unstable_by_key(|(modified, _, _)| Reverse(*modified));
+
+        let (mut kept_bytes, mut removed, mut freed_bytes) = (0, 0, 0);
+
+        for (_, len, path) in entries {
+            if kept_bytes + len <= max_bytes {
+                kept_bytes += len;
+                continue
+            }
+
+            fs::remove_file(path)?;
+            removed += 1;
+            freed_bytes += len;
+        }
+
+        Ok(json!({ "removed": removed, "freed_bytes": freed_bytes, "kept_bytes": kept_bytes }))
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.{ENTRY_EXTENSION}"))
+    }
+}
+
+// Whether a run reported errors, in server events or in the error of a --format results line
+pub fn failed(lines: &[(Kind, String)]) -> bool {
+    lines.iter().any(|(_, line)| {
+        serde_json::from_str::<Value>(line).is_ok_and(|event| {
+            protocol::error_detail(&event).is_some() || protocol::failed_turn(&event).is_some() || event.get("error").is_some_and(|error| !error.is_null())
+        })
+    })
+}
+
+// Byte count with an optional binary K, M or G suffix, like 100M
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let trimmed = value.trim().trim_end_matches(['B', 'b']).trim_end_matches('i');
+
+    let (digits, multiplier) = match trimmed.char_indices().last() {
+        Some((at, 'K' | 'k')) => (&trimmed[..at], 1 << 10),
+        Some((at, 'M' | 'm')) => (&trimmed[..at], 1 << 20),
+        Some((at, 'G' | 'g')) => (&trimmed[..at], 1 << 30),
+        _ => (trimmed, 1),
+    };
+
+    digits
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|size| size.checked_mul(multiplier))
+        .ok_or_else(|| format!("`{value}` is not a size, expected a byte count like 512K, 100M or 2G"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[Value]) -> Vec<(Kind, String)> {
+        lines.iter().map(|line| (Kind::Turn, line.to_string())).collect()
+    }
+
+    #[test]
+    fn failed_runs() {
+        let completed = json!({ "type": "conversation.item.input_audio_transcription.completed", "item_id": "a", "transcript": "好" });
+        let result = json!({ "item_id": "a", "text": "好", "error": null });
+
+        assert!(!failed(&lines(&[completed.clone(), result.clone()])));
+        assert!(failed(&lines(&[completed.clone(), json!({ "type": "error", "error": { "code": "bad", "message": "no" } })])));
+        assert!(failed(&lines(&[json!({ "type": "conversation.item.input_audio_transcription.failed", "item_id": "b" })])));
+        assert!(failed(&lines(&[result, json!({ "item_id": "b", "text": null, "error": { "code": "bad" } })])));
+        // subtitle cues are not JSON
+        assert!(!failed(&[(Kind::Turn, "1\n00:00:00,000 --> 00:00:01,000\nerror\n".into())]));
+    }
+}
@@ -0,0 +1,156 @@
+use crate::client::SessionConfig;
+
+// Optional session settings that only some models take
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Feature {
+    Translation,
+    Itn,
+    Punctuation,
+}
+
+pub const FEATURES: [Feature; 3] = [Feature::Translation, Feature::Itn, Feature::Punctuation];
+
+impl Feature {
+    pub fn flag(self) -> &'static str {
+        match self {
+            Feature::Translation => "--translate-to",
+            Feature::Itn => "--itn",
+            Feature::Punctuation => "--punctuation",
+        }
+    }
+
+    pub fn requested(self, config: &SessionConfig) -> bool {
+        match self {
+            Feature::Translation => config.translate_to.is_some(),
+            Feature::Itn => config.itn.is_some(),
+            Feature::Punctuation => config.punctuation.is_some(),
+        }
+    }
+
+    // Turns the setting on, for a probe that sends nothing else optional
+    pub fn enable(self, config: &mut SessionConfig) {
+        match self {
+            Feature::Translation => config.translate_to = Some(if config.language.starts_with("en") { "zh" } else { "en" }.into()),
+            Feature::Itn => config.itn = Some(true),
+            Feature::Punctuation => config.punctuation = Some(true),
+        }
+    }
+}
+
+pub struct ModelCapabilities {
+    pub model: &'static str,
+    pub features: &'static [Feature],
+}
+
+// DashScope realtime models. One missing here is not checked, the server has the last word on it.
+pub const DASHSCOPE: &[ModelCapabilities] = &[
+    ModelCapabilities { model: "qwen3-asr-flash-realtime", features: &[Feature::Itn] },
+    ModelCapabilities { model: "qwen3-livetranslate-flash-realtime", features: &[Feature::Translation] },
+];
+
+// Gateways on other hosts may serve anything under these names, only DashScope itself is looked up
+fn table(host: &str) -> Option<&'static [ModelCapabilities]> {
+    (host.starts_with("dashscope") && host.ends_with(".aliyuncs.com")).then_some(DASHSCOPE)
+}
+
+// Dated snapshots like qwen3-asr-flash-realtime-2025-10-27 share the entry of their model, other models named after it do not
+pub fn lookup(host: &str, model: &str) -> Option<&'static ModelCapabilities> {
+    let snapshot = |date: &str| !date.is_empty() && date.split('-').all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()));
+
+    table(host)?.iter().find(|entry| model == entry.model || model.strip_prefix(entry.model).and_then(|rest| rest.strip_prefix('-')).is_some_and(snapshot))
+}
+
+// The first requested setting the table says the model does not take, as a message naming who does
+pub fn check(host: &str, model: &str, config: &SessionConfig) -> Result<(), String> {
+    let Some(entry) = lookup(host, model) else {
+        return Ok(())
+    };
+
+    let Some(feature) = FEATURES.into_iter().find(|feature| feature.requested(config) && !entry.features.contains(feature)) else {
+        return Ok(())
+    };
+
+    let supported: Vec<&str> = table(host).unwrap_or_default().iter().filter(|entry| entry.features.contains(&feature)).map(|entry| entry.model).collect();
+
+    Err(match supported.as_slice() {
+        [] => format!("model {model} does not support {}", feature.flag()),
+        supported => format!("model {model} does not support {}; supported models: {}", feature.flag(), supported.join(", ")),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "dashscope.aliyuncs.com";
+
+    fn config() -> SessionConfig {
+        SessionConfig {
+            sample_rate: 16000,
+            language: "zh".into(),
+            vad_threshold: 0.2,
+            vad_silence_ms: 800,
+            vad_prefix_padding_ms: None,
+            server_vad: true,
+            translate_to: None,
+            itn: None,
+            punctuation: None,
+        }
+    }
+
+    #[test]
+    fn dated_snapshots() {
+        assert_eq!(lookup(HOST, "qwen3-asr-flash-realtime").map(|entry| entry.model), Some("qwen3-asr-flash-realtime"));
+        assert_eq!(lookup(HOST, "qwen3-asr-flash-realtime-2025-10-27").map(|entry| entry.model), Some("qwen3-asr-flash-realtime"));
+        assert_eq!(lookup(HOST, "qwen3-asr-flash-realtime-20251027").map(|entry| entry.model), Some("qwen3-asr-flash-realtime"));
+        // a model of its own that happens to share the name
+        assert!(lookup(HOST, "qwen3-asr-flash-realtime-pro").is_none());
+        assert!(lookup(HOST, "qwen3-asr-flash-realtime-").is_none());
+        assert!(lookup(HOST, "qwen3-asr-flash-realtime2025").is_none());
+        assert!(lookup(HOST, "qwen3-asr-flash").is_none());
+    }
+
+    #[test]
+    fn dashscope_hosts_only() {
+        assert!(lookup("dashscope-intl.aliyuncs.com", "qwen3-asr-flash-realtime").is_some());
+        assert!(lookup("127.0.0.1", "qwen3-asr-flash-realtime").is_none());
+        assert!(lookup("gateway.example.com", "qwen3-asr-flash-realtime").is_none());
+        assert!(lookup("dashscope.aliyuncs.com.example.com", "qwen3-asr-flash-realtime").is_none());
+
+        // gateways are left to reject what they do not take
+        let config = SessionConfig { translate_to: Some("en".into()), ..config() };
+        assert_eq!(check("gateway.example.com", "qwen3-asr-flash-realtime", &config), Ok(()));
+    }
+
+    #[test]
+    fn names_the_models_that_do() {
+        let translate = SessionConfig { translate_to: Some("en".into()), ..config() };
+        let punctuation = SessionConfig { punctuation: Some(true), ..config() };
+        let itn = SessionConfig { itn: Some(false), ..config() };
+
+        assert_eq!(
+            check(HOST, "qwen3-asr-flash-realtime-2025-10-27", &translate),
+            Err("model qwen3-asr-flash-realtime-2025-10-27 does not support --translate-to; supported models: qwen3-livetranslate-flash-realtime".into()),
+        );
+        assert_eq!(check(HOST, "qwen3-asr-flash-realtime", &punctuation), Err("model qwen3-asr-flash-realtime does not support --punctuation".into()));
+        assert_eq!(check(HOST, "qwen3-asr-flash-realtime", &itn), Ok(()));
+        assert_eq!(check(HOST, "qwen3-livetranslate-flash-realtime", &translate), Ok(()));
+        // unknown models are not checked
+        assert_eq!(check(HOST, "qwen3-asr-next-realtime", &translate), Ok(()));
+        assert_eq!(check(HOST, "qwen3-asr-flash-realtime", &config()), Ok(()));
+    }
+
+    #[test]
+    fn enables_every_feature() {
+        for feature in FEATURES {
+            let mut config = config();
+            assert!(!feature.requested(&config));
+            feature.enable(&mut config);
+            assert!(feature.requested(&config));
+        }
+
+        let mut config = SessionConfig { language: "en".into(), ..config() };
+        Feature::Translation.enable(&mut config);
+        assert_eq!(config.translate_to.as_deref(), Some("zh"));
+    }
+}
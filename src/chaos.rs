@@ -0,0 +1,95 @@
+use log::warn;
+use std::io::{self, Read};
+use std::time::{Duration, Instant};
+
+// The --chaos-* options do nothing unless this is set to 1 as well
+pub const ENV: &str = "ASR_CHAOS";
+
+pub fn enabled() -> bool {
+    std::env::var(ENV).is_ok_and(|value| value == "1")
+}
+
+// Failures injected on purpose, to see the recovery paths run against a mock server
+#[derive(Debug, Clone, Default)]
+pub struct Chaos {
+    pub drop_connection_after: Option<Duration>,
+    pub delay_events: Option<Duration>,
+    pub corrupt_every_n: Option<u64>,
+    pub stall_stdin_after: Option<Duration>,
+}
+
+impl Chaos {
+    pub fn is_empty(&self) -> bool {
+        self.drop_connection_after.is_none() && self.delay_events.is_none() && self.corrupt_every_n.is_none() && self.stall_stdin_after.is_none()
+    }
+
+    // Without ASR_CHAOS=1 nothing fires, a stray option in a production config only warns
+    pub fn armed(self) -> Self {
+        match (self.is_empty(), enabled()) {
+            (true, _) | (false, true) => self,
+            (false, false) => {
+                warn!("Ignoring the --chaos-* options, {ENV}=1 is not set");
+                Self::default()
+            }
+        }
+    }
+
+    pub fn corruptor(&self) -> Option<Corruptor> {
+        self.corrupt_every_n.filter(|&every_n| every_n > 0).map(|every_n| Corruptor { every_n, frames: 0 })
+    }
+
+    // The error a connection reset by the network would have surfaced as
+    pub fn dropped_connection() -> io::Error {
+        io::Error::new(io::ErrorKind::ConnectionReset, "connection dropped by --chaos-drop-connection-after-s")
+    }
+}
+
+// Flips a byte in every Nth outgoing audio frame
+#[derive(Debug)]
+pub struct Corruptor {
+    every_n: u64,
+    frames: u64,
+}
+
+impl Corruptor {
+    pub fn apply(&mut self, audio_data: &mut [u8]) {
+        self.frames += 1;
+
+        if !self.frames.is_multiple_of(self.every_n) || audio_data.is_empty() {
+            return
+        }
+
+        let at = audio_data.len() / 2;
+        audio_data[at] ^= 0xff;
+        warn!("Corrupted audio frame {} at byte {at}", self.frames);
+    }
+}
+
+// Reads normally until the time is up, then blocks for good like a capture device that hung
+pub struct StallingReader<R> {
+    inner: R,
+    after: Duration,
+    started: Option<Instant>,
+}
+
+impl<R> StallingReader<R> {
+    pub fn new(inner: R, after: Duration) -> Self {
+        Self { inner, after, started: None }
+    }
+}
+
+impl<R: Read> Read for StallingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let started = *self.started.get_or_insert_with(Instant::now);
+
+        if started.elapsed() >= self.after {
+            warn!("Stalling the audio input, --chaos-stall-stdin-after-s");
+
+            loop {
+                std::thread::park();
+            }
+        }
+
+        self.inner.read(buf)
+    }
+}
@@ -0,0 +1,164 @@
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+// CRC-32 as in zlib and PNG
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+};
+
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+// One line per audio append as it goes on the wire, for proving where audio got corrupted
+pub struct ChecksumLog {
+    file: BufWriter<File>,
+    seq: u64,
+    offset_samples: u64,
+}
+
+impl ChecksumLog {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self { file: BufWriter::new(File::create(path)?), seq: 0, offset_samples: 0 })
+    }
+
+    pub fn append(&mut self, audio: &[u8]) -> io::Result<()> {
+        let entry = json!({
+            "seq": self.seq,
+            "offset_samples": self.offset_samples,
+            "bytes": audio.len(),
+            "crc32": format!("{:08x}", crc32(audio)),
+        });
+
+        self.seq += 1;
+        self.offset_samples += audio.len() as u64 / 2;
+
+        writeln!(self.file, "{entry}")?;
+        self.file.flush()
+    }
+}
+
+// Cuts the audio into the chunks the log names and reports the first one that differs
+pub fn verify(log: &Path, audio: &mut dyn Read) -> io::Result<Value> {
+    let (mut chunks, mut bytes) = (0, 0);
+    let mut chunk = Vec::new();
+
+    for line in BufReader::new(File::open(log)?).lines() {
+        let entry: Value = serde_json::from_str(&line?)?;
+
+        let (Some(len), Some(expected)) = (entry["bytes"].as_u64(), entry["crc32"].as_str()) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is not an audio checksum log", log.display())))
+        };
+
+        chunk.clear();
+        audio.take(len).read_to_end(&mut chunk)?;
+
+        let actual = (chunk.len() as u64 == len).then(|| format!("{:08x}", crc32(&chunk)));
+
+        if actual.as_deref() != Some(expected) {
+            return Ok(json!({
+                "ok": false,
+                "seq": entry["seq"],
+                "offset_samples": entry["offset_samples"],
+                "expected": expected,
+                // null once the audio ran out
+                "actual": actual,
+            }))
+        }
+
+        chunks += 1;
+        bytes += len;
+    }
+
+    Ok(json!({ "ok": true, "chunks": chunks, "bytes": bytes }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn log_of(name: &str, chunks: &[&[u8]]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("qasr-test-checksums-{name}-{}.jsonl", std::process::id()));
+        let mut log = ChecksumLog::create(&path).unwrap();
+
+        for chunk in chunks {
+            log.append(chunk).unwrap();
+        }
+
+        path
+    }
+
+    #[test]
+    fn matches_zlib() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414f_a339);
+    }
+
+    #[test]
+    fn logs_every_append_with_its_offset() {
+        let path = log_of("offsets", &[&[1; 320], &[2; 64]]);
+        let entries: Vec<Value> = fs::read_to_string(&path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(entries[0], json!({ "seq": 0, "offset_samples": 0, "bytes": 320, "crc32": format!("{:08x}", crc32(&[1; 320])) }));
+        assert_eq!((entries[1]["seq"].as_u64(), entries[1]["offset_samples"].as_u64()), (Some(1), Some(160)));
+    }
+
+    #[test]
+    fn verifies_the_audio_it_was_written_for() {
+        let path = log_of("ok", &[&[1; 320], &[2; 320], &[3; 100]]);
+        let audio: Vec<u8> = [[1; 320], [2; 320]].concat().into_iter().chain([3; 100]).collect();
+
+        assert_eq!(verify(&path, &mut &audio[..]).unwrap(), json!({ "ok": true, "chunks": 3, "bytes": 740 }));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reports_the_first_mismatch() {
+        let path = log_of("mismatch", &[&[1; 320], &[2; 320], &[3; 320]]);
+        let mut audio: Vec<u8> = [[1; 320], [2; 320], [3; 320]].concat();
+        // the second and third chunks both differ, the second is reported
+        audio[400] ^= 0x80;
+        audio[700] ^= 0x80;
+
+        let report = verify(&path, &mut &audio[..]).unwrap();
+        assert_eq!((report["ok"].as_bool(), report["seq"].as_u64(), report["offset_samples"].as_u64()), (Some(false), Some(1), Some(160)));
+        assert_eq!(report["expected"].as_str().unwrap(), format!("{:08x}", crc32(&[2; 320])));
+        assert_ne!(report["actual"], report["expected"]);
+
+        // audio that runs out has nothing to compare
+        let report = verify(&path, &mut &audio[..500]).unwrap();
+        assert_eq!((report["seq"].as_u64(), &report["actual"]), (Some(1), &Value::Null));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let path = std::env::temp_dir().join(format!("qasr-test-checksums-other-{}.jsonl", std::process::id()));
+        fs::write(&path, "{\"type\":\"session.created\"}\n").unwrap();
+
+        assert_eq!(verify(&path, &mut &[][..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+}
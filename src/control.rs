@@ -0,0 +1,356 @@
+use crate::client::{self, VadChange};
+use crate::results::TurnHistory;
+use serde::Deserialize;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Control {
+    Commit,
+    Clear,
+    SetVad(VadChange),
+    // ends the session so the schedule can disconnect once its last results are in
+    Finish,
+}
+
+// Turns a history command returns when it does not say
+const DEFAULT_HISTORY_TURNS: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Command {
+    Pause,
+    Resume,
+    Commit,
+    Clear,
+    SetVad(VadChange),
+    Export {
+        path: PathBuf,
+    },
+    History {
+        #[serde(default = "default_history_turns")]
+        last: usize,
+    },
+}
+
+fn default_history_turns() -> usize {
+    DEFAULT_HISTORY_TURNS
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Pause => "pause",
+            Command::Resume => "resume",
+            Command::Commit => "commit",
+            Command::Clear => "clear",
+            Command::SetVad(_) => "set_vad",
+            Command::Export { .. } => "export",
+            Command::History { .. } => "history",
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Command::SetVad(change) => client::validate_vad(change.threshold, change.silence_ms),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn apply_blocking(self, control_tx: &mpsc::Sender<Control>, paused: &AtomicBool) -> Result<(), String> {
+        let control = match self {
+            Command::Pause | Command::Resume => {
+                paused.store(self == Command::Pause, Ordering::Relaxed);
+                return Ok(())
+            }
+            Command::Commit => Control::Commit,
+            Command::Clear => Control::Clear,
+            Command::SetVad(change) => Control::SetVad(change),
+            Command::Export { .. } | Command::History { .. } => return Err("only available on --control-fd".into()),
+        };
+
+        control_tx.blocking_send(control).map_err(|_| "session is closed".to_string())
+    }
+}
+
+// Serves newline-delimited JSON commands from an inherited descriptor, answering on it when it is writable
+#[cfg(unix)]
+pub fn serve_fd(fd: i32, control_tx: mpsc::Sender<Control>, paused: &AtomicBool, force: bool, history: Option<&Mutex<TurnHistory>>) -> io::Result<()> {
+    use serde_json::json;
+    use std::fs::File;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::fd::BorrowedFd;
+
+    // a descriptor that was never inherited is refused before it is borrowed, the duplicate is this run's own to close
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(io::Error::last_os_error())
+    }
+
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?;
+    let file = File::from(fd);
+    let mut responses = file.try_clone()?;
+
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+
+        if line.trim().is_empty() {
+            continue
+        }
+
+        let response = match serde_json::from_str::<Command>(&line) {
+            Ok(command) => {
+                // --force lets values outside the documented ranges through to the server
+                let validated = if force { Ok(()) } else { command.validate() };
+
+                let applied = validated.and_then(|()| match &command {
+                    Command::Export { path } => export_history(history, path),
+                    Command::History { last } => with_history(history, |history| json!({ "turns": history.last(*last) })),
+                    command => command.clone().apply_blocking(&control_tx, paused).map(|()| json!({})),
+                });
+
+                match applied {
+                    Ok(mut response) => {
+                        response["ok"] = true.into();
+                        response["cmd"] = command.name().into();
+                        response
+                    }
+                    Err(err) => json!({ "ok": false, "cmd": command.name(), "error": err }),
+                }
+            }
+            Err(err) => json!({ "ok": false, "error": err.to_string() }),
+        };
+
+        // read-only descriptors such as plain pipes cannot carry responses
+        let _ = writeln!(responses, "{response}");
+    }
+
+    Ok(())
+}
+
+// Snapshotted under the lock, written after it, so the reader that adds turns is never held up by the disk
+#[cfg(unix)]
+fn export_history(history: Option<&Mutex<TurnHistory>>, path: &std::path::Path) -> Result<serde_json::Value, String> {
+    use crate::results;
+    use serde_json::json;
+
+    let (turns, evicted) = with_history(history, |history| (history.last(usize::MAX), history.evicted()))?;
+
+    results::export(&turns, path).map_err(|err| format!("failed to write {}: {err}", path.display()))?;
+    Ok(json!({ "path": path, "turns": turns.len(), "evicted": evicted }))
+}
+
+#[cfg(unix)]
+fn with_history<T>(history: Option<&Mutex<TurnHistory>>, f: impl FnOnce(&TurnHistory) -> T) -> Result<T, String> {
+    match history {
+        Some(history) => Ok(f(&history.lock().unwrap())),
+        None => Err("the turn history is off, --history-turns is 0".into()),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn serve_fd(_fd: i32, _control_tx: mpsc::Sender<Control>, _paused: &AtomicBool, _force: bool, _history: Option<&Mutex<TurnHistory>>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "--control-fd is only supported on Unix"))
+}
+
+// SIGUSR1 on Unix and Ctrl+Break on Windows force-commit the pending audio buffer
+#[cfg(unix)]
+pub async fn forward_commit_signal(control_tx: mpsc::Sender<Control>) -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut commit_signal = signal(SignalKind::user_defined1())?;
+
+    while commit_signal.recv().await.is_some() {
+        if control_tx.send(Control::Commit).await.is_err() {
+            break
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(windows)]
+pub async fn forward_commit_signal(control_tx: mpsc::Sender<Control>) -> io::Result<()> {
+    let mut commit_signal = tokio::signal::windows::ctrl_break()?;
+
+    while commit_signal.recv().await.is_some() {
+        if control_tx.send(Control::Commit).await.is_err() {
+            break
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Command {
+        serde_json::from_str(line).unwrap()
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse(r#"{"cmd":"pause"}"#), Command::Pause);
+        assert_eq!(parse(r#"{"cmd":"commit"}"#), Command::Commit);
+        assert_eq!(parse(r#"{"cmd":"set_vad","threshold":0.3,"silence_ms":500}"#), Command::SetVad(VadChange { threshold: Some(0.3), silence_ms: Some(500), prefix_padding_ms: None }));
+        assert_eq!(parse(r#"{"cmd":"export","path":"turns.json"}"#), Command::Export { path: "turns.json".into() });
+        assert_eq!(parse(r#"{"cmd":"history"}"#), Command::History { last: DEFAULT_HISTORY_TURNS });
+        assert_eq!(parse(r#"{"cmd":"history","last":3}"#).name(), "history");
+
+        for line in [r#"{"cmd":"reboot"}"#, r#"{"cmd":"export"}"#, r#"{"threshold":0.3}"#, "pause"] {
+            assert!(serde_json::from_str::<Command>(line).is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn validates_vad_changes_only() {
+        assert!(parse(r#"{"cmd":"set_vad","threshold":0.5}"#).validate().is_ok());
+        assert!(parse(r#"{"cmd":"set_vad","threshold":5}"#).validate().is_err());
+        assert!(parse(r#"{"cmd":"set_vad","silence_ms":0}"#).validate().is_err());
+        assert!(parse(r#"{"cmd":"history","last":100000}"#).validate().is_ok());
+    }
+
+    #[test]
+    fn applies_commands_to_the_session() {
+        let (control_tx, mut control_rx) = mpsc::channel(4);
+        let paused = AtomicBool::new(false);
+
+        Command::Pause.apply_blocking(&control_tx, &paused).unwrap();
+        assert!(paused.load(Ordering::Relaxed));
+        Command::Resume.apply_blocking(&control_tx, &paused).unwrap();
+        assert!(!paused.load(Ordering::Relaxed));
+
+        Command::Commit.apply_blocking(&control_tx, &paused).unwrap();
+        assert_eq!(control_rx.try_recv(), Ok(Control::Commit));
+
+        // the socket has no history to answer from
+        assert!(Command::History { last: 1 }.apply_blocking(&control_tx, &paused).is_err());
+
+        drop(control_rx);
+        assert_eq!(Command::Clear.apply_blocking(&control_tx, &paused), Err("session is closed".into()));
+    }
+
+    #[cfg(unix)]
+    mod fd {
+        use super::*;
+        use serde_json::{json, Value};
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::Shutdown;
+        use std::os::fd::{AsRawFd, FromRawFd};
+        use std::os::unix::net::UnixStream;
+        use std::time::Instant;
+
+        // The responses to lines written to one end of a socketpair the other end of which is served
+        fn serve(lines: &[&str], force: bool, history: Option<&Mutex<TurnHistory>>) -> (Vec<Value>, Vec<Control>) {
+            let (ours, theirs) = UnixStream::pair().unwrap();
+            let (control_tx, mut control_rx) = mpsc::channel(16);
+            let paused = AtomicBool::new(false);
+
+            for line in lines {
+                writeln!(&ours, "{line}").unwrap();
+            }
+            ours.shutdown(Shutdown::Write).unwrap();
+
+            serve_fd(theirs.as_raw_fd(), control_tx, &paused, force, history).unwrap();
+            drop(theirs);
+
+            let responses = BufReader::new(ours).lines().map(|line| serde_json::from_str(&line.unwrap()).unwrap()).collect();
+            let controls = std::iter::from_fn(|| control_rx.try_recv().ok()).collect();
+            (responses, controls)
+        }
+
+        fn history() -> Mutex<TurnHistory> {
+            let mut history = TurnHistory::new(10, vec![(f64::MAX, "stdin".into())]);
+
+            for (item_id, transcript) in [("a", "你好"), ("b", "世界")] {
+                let completed = json!({ "type": "conversation.item.input_audio_transcription.completed", "item_id": item_id, "transcript": transcript, "audio_start_ms": 0, "audio_end_ms": 500 });
+                history.observe(&completed, Instant::now());
+            }
+
+            Mutex::new(history)
+        }
+
+        #[test]
+        fn answers_every_line() {
+            let (responses, controls) = serve(&[r#"{"cmd":"commit"}"#, "", "not json", r#"{"cmd":"pause"}"#], false, None);
+
+            assert_eq!(responses[0], json!({ "ok": true, "cmd": "commit" }));
+            assert_eq!(responses[1]["ok"], false);
+            assert_eq!(responses[2], json!({ "ok": true, "cmd": "pause" }));
+            assert_eq!(responses.len(), 3);
+            assert_eq!(controls, [Control::Commit]);
+        }
+
+        #[test]
+        fn force_lets_out_of_range_values_through() {
+            let set_vad = r#"{"cmd":"set_vad","threshold":5}"#;
+
+            let (responses, controls) = serve(&[set_vad], false, None);
+            assert_eq!((responses[0]["ok"].as_bool(), responses[0]["cmd"].as_str()), (Some(false), Some("set_vad")));
+            assert!(responses[0]["error"].as_str().unwrap().contains("outside"));
+            assert!(controls.is_empty());
+
+            let (responses, controls) = serve(&[set_vad], true, None);
+            assert_eq!(responses[0]["ok"], true);
+            assert_eq!(controls, [Control::SetVad(VadChange { threshold: Some(5.0), ..VadChange::default() })]);
+        }
+
+        #[test]
+        fn history_and_export_need_the_turn_history() {
+            let (responses, _) = serve(&[r#"{"cmd":"history"}"#, r#"{"cmd":"export","path":"/nonexistent/turns.json"}"#], false, None);
+
+            for response in &responses {
+                assert_eq!(response["ok"], false);
+                assert!(response["error"].as_str().unwrap().contains("--history-turns is 0"));
+            }
+        }
+
+        #[test]
+        fn answers_from_the_turn_history() {
+            let path = std::env::temp_dir().join(format!("qasr-test-control-export-{}.json", std::process::id()));
+            let export = json!({ "cmd": "export", "path": path }).to_string();
+            let history = history();
+
+            let (responses, _) = serve(&[r#"{"cmd":"history","last":1}"#, &export], false, Some(&history));
+
+            assert_eq!(responses[0]["turns"].as_array().unwrap().len(), 1);
+            assert_eq!(responses[0]["turns"][0]["text"], "世界");
+            assert_eq!((responses[1]["ok"].as_bool(), responses[1]["turns"].as_u64(), responses[1]["evicted"].as_u64()), (Some(true), Some(2), Some(0)));
+
+            let exported: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(exported.as_array().map(Vec::len), Some(2));
+        }
+
+        #[test]
+        fn reads_commands_from_a_read_only_descriptor() {
+            let mut fds = [0; 2];
+            assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+            // SAFETY: both ends were just created and are owned here
+            let (read, mut write) = unsafe { (std::fs::File::from_raw_fd(fds[0]), std::fs::File::from_raw_fd(fds[1])) };
+
+            writeln!(write, r#"{{"cmd":"commit"}}"#).unwrap();
+            writeln!(write, r#"{{"cmd":"clear"}}"#).unwrap();
+            drop(write);
+
+            let (control_tx, mut control_rx) = mpsc::channel(4);
+            // the responses go nowhere, the commands still count
+            serve_fd(read.as_raw_fd(), control_tx, &AtomicBool::new(false), false, None).unwrap();
+
+            assert_eq!(control_rx.try_recv(), Ok(Control::Commit));
+            assert_eq!(control_rx.try_recv(), Ok(Control::Clear));
+        }
+
+        #[test]
+        fn refuses_a_descriptor_that_was_never_opened() {
+            let (control_tx, _control_rx) = mpsc::channel(1);
+            let err = serve_fd(1 << 20, control_tx, &AtomicBool::new(false), false, None).unwrap_err();
+
+            assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+        }
+    }
+}
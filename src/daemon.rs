@@ -0,0 +1,491 @@
+use crate::audio;
+use crate::client::{self, SessionConfig};
+use crate::error::{AsrError, Result};
+use crate::event::{ClientEvent, SessionError};
+use crate::limiter::Limiter;
+use crate::protocol::{self, Handshake};
+use crate::results::TurnResults;
+use crate::systemd::Notifier;
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, warn};
+use serde::Deserialize;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio_tungstenite::tungstenite::Message;
+use url::Url;
+
+// The header is one JSON line, anything longer is not a header
+const MAX_HEADER_BYTES: u64 = 4096;
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long the server gets to answer session.finish once a client stopped sending
+const FINISH_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    #[default]
+    Events,
+    Results,
+}
+
+// What a client sends before its audio, unset fields take the daemon's defaults
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Header {
+    pub language: Option<String>,
+    pub sample_rate: Option<u32>,
+    #[serde(default)]
+    pub format: Format,
+}
+
+// Everything a connection needs for its own upstream session
+pub struct Daemon {
+    pub url: Url,
+    pub api_key: String,
+    pub model: String,
+    pub session_config: SessionConfig,
+    pub limiter: Arc<Limiter>,
+    pub max_message_size: usize,
+}
+
+// unix:PATH, as given to --listen
+pub fn parse_listen(value: &str) -> std::result::Result<PathBuf, String> {
+    match value.strip_prefix("unix:") {
+        Some("") => Err("the unix: address has no path".into()),
+        Some(path) => Ok(PathBuf::from(path)),
+        None => Err(format!("`{value}` is not a unix:PATH address")),
+    }
+}
+
+impl Daemon {
+    // Serves until SIGTERM or Ctrl+C, on the socket systemd passed in if there is one, then waits for the sessions in flight, a second signal cuts them off
+    #[cfg(unix)]
+    pub async fn serve(self: Arc<Self>, path: &Path, notifier: &Notifier) -> io::Result<()> {
+        use crate::systemd;
+        use tokio::net::UnixListener;
+        use tokio::signal::unix::{signal, SignalKind};
+        use tokio::task::JoinSet;
+
+        // a socket-activated daemon listens on the socket systemd owns, the path is systemd's business then
+        let activated = systemd::listen_fd();
+
+        let listener = match activated {
+            Some(fd) => {
+                use std::os::fd::FromRawFd;
+
+                // SAFETY: systemd hands the descriptor over to this process, nothing else in it owns it
+                let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+                debug!("Listening on the socket passed in by systemd");
+                UnixListener::from_std(listener)?
+            }
+            None => {
+                // a socket nobody answers on was left by a daemon that died, a live one is not taken over
+                if path.exists() {
+                    match std::os::unix::net::UnixStream::connect(path) {
+                        Ok(_) => return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("another daemon is listening on {}", path.display()))),
+                        Err(_) => std::fs::remove_file(path)?,
+                    }
+                }
+
+                debug!("Listening on {}", path.display());
+                UnixListener::bind(path)?
+            }
+        };
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut sessions = JoinSet::new();
+
+        notifier.ready();
+
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => {
+                        let daemon = self.clone();
+                        sessions.spawn(async move { daemon.session(stream).await });
+                        notifier.status(&format!("{} session(s) in flight", sessions.len()));
+                    }
+                    Err(err) => warn!("Failed to accept a connection: {err}"),
+                },
+                Some(_) = sessions.join_next(), if !sessions.is_empty() => notifier.status(&format!("{} session(s) in flight", sessions.len())),
+                _ = terminate.recv() => break,
+                _ = tokio::signal::ctrl_c() => break,
+            }
+        }
+
+        notifier.stopping();
+        drop(listener);
+
+        if activated.is_none() {
+            let _ = std::fs::remove_file(path);
+        }
+
+        if !sessions.is_empty() {
+            warn!("Shutting down once {} session(s) in flight have finished", sessions.len());
+        }
+
+        tokio::select! {
+            _ = async { while sessions.join_next().await.is_some() {} } => {}
+            _ = terminate.recv() => warn!("Cutting off {} session(s)", sessions.len()),
+            _ = tokio::signal::ctrl_c() => warn!("Cutting off {} session(s)", sessions.len()),
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub async fn serve(self: Arc<Self>, _path: &Path, _notifier: &Notifier) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "asr daemon is only supported on Unix"))
+    }
+
+    // One connection: a header line, then raw PCM until the client shuts down its side
+    pub async fn session<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S) {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+
+        if let Err(err) = self.transcribe(&mut reader, &mut writer).await {
+            debug!("Session ended with an error: {err}");
+
+            let event = ClientEvent::SessionError(SessionError { code: err.exit_code(), message: err.to_string() });
+            let _ = writer.write_all(format!("{event}\n").as_bytes()).await;
+        }
+
+        let _ = writer.shutdown().await;
+    }
+
+    async fn transcribe<R, W>(&self, reader: &mut BufReader<R>, writer: &mut W) -> Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let header = read_header(reader).await?;
+
+        // a client that cannot be served right away is turned away, its audio would only pile up
+        let Ok(_session_permit) = self.limiter.try_acquire_session() else {
+            return Err(AsrError::Protocol { code: "too_many_sessions".into(), message: "all --max-concurrent-sessions are in use".into() })
+        };
+
+        let mut session_config = self.session_config.clone();
+        session_config.language = header.language.unwrap_or(session_config.language);
+        session_config.sample_rate = header.sample_rate.unwrap_or(session_config.sample_rate);
+
+        let optional_fields = session_config.optional_fields();
+        let ws_stream = client::connect(&self.url, &self.api_key, self.max_message_size).await?;
+        let (mut sink, mut stream) = ws_stream.split();
+
+        self.limiter.acquire_request().await;
+        sink.send(Message::Text(session_config.update_event().to_string().into())).await?;
+
+        let mut results = (header.format == Format::Results).then(|| TurnResults::new(TurnResults::sources(&[], session_config.sample_rate)));
+        let mut handshake = Handshake::default();
+        // 100 ms per append, like a live capture
+        let mut audio = vec![0; session_config.sample_rate as usize / 5];
+        let mut finish_deadline = None;
+
+        let ended: Result<()> = loop {
+            tokio::select! {
+                read = reader.read(&mut audio), if finish_deadline.is_none() => match read? {
+                    0 => {
+                        self.limiter.acquire_request().await;
+                        sink.send(Message::Text(client::finish_event().to_string().into())).await?;
+                        finish_deadline = Some(tokio::time::Instant::now() + FINISH_TIMEOUT);
+                    }
+                    n => {
+                        self.limiter.acquire_audio(n as f64 / (session_config.sample_rate as f64 * 2.0)).await;
+                        self.limiter.acquire_request().await;
+                        sink.send(Message::Text(client::audio_append_event(&audio[..n]).to_string().into())).await?;
+                    }
+                },
+                msg = stream.next() => {
+                    let Some(msg) = msg else {
+                        break Ok(())
+                    };
+
+                    let text = match msg {
+                        Err(_) if !handshake.is_ready() && handshake.has_errors() => break Ok(()),
+                        Err(err) => break Err(err.into()),
+                        Ok(Message::Text(text)) => text,
+                        Ok(Message::Close(_)) => break Ok(()),
+                        Ok(_) => continue,
+                    };
+
+                    let Ok(event) = serde_json::from_str::<serde_json::Value>(&text) else {
+                        continue
+                    };
+
+                    handshake.observe(&event, &self.model, &optional_fields)?;
+
+                    let line = match &mut results {
+                        Some(results) => results.observe(&event, Instant::now()).map(|result| result.to_string()),
+                        None => Some(text.to_string()),
+                    };
+
+                    // a client that went away mid-stream takes its upstream session with it
+                    if let Some(line) = line {
+                        writer.write_all(format!("{line}\n").as_bytes()).await?;
+                    }
+
+                    if protocol::event_type(&event) == "session.finished" {
+                        break Ok(())
+                    }
+                }
+                _ = tokio::time::sleep_until(finish_deadline.unwrap_or_else(tokio::time::Instant::now)), if finish_deadline.is_some() => {
+                    warn!("No session.finished within {}s, ending the session without the last results", FINISH_TIMEOUT.as_secs());
+                    break Ok(())
+                }
+            }
+        };
+
+        let _ = sink.close().await;
+        ended?;
+
+        if !handshake.is_ready() {
+            return Err(handshake.error(&self.model, self.url.host_str().unwrap_or_default()))
+        }
+
+        Ok(())
+    }
+}
+
+async fn read_header<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> Result<Header> {
+    let mut line = String::new();
+
+    tokio::time::timeout(HEADER_TIMEOUT, (&mut *reader).take(MAX_HEADER_BYTES).read_line(&mut line))
+        .await
+        .map_err(|_| AsrError::Timeout("waiting for the session header".into()))??;
+
+    if !line.ends_with('\n') {
+        return Err(AsrError::AudioInput(io::Error::new(io::ErrorKind::InvalidData, "expected a JSON header line before the audio")))
+    }
+
+    let header: Header = serde_json::from_str(&line)?;
+
+    // the audio buffer is sized from it
+    if let Some(rate) = header.sample_rate.filter(|rate| !audio::COMMON_RATES.contains(rate)) {
+        return Err(AsrError::Protocol { code: "invalid_header".into(), message: format!("{rate} Hz is not a supported sample rate") })
+    }
+
+    Ok(header)
+}
+
+// The client side of `asr connect`: sends the header and the input, copies the answers to the output.
+// Returns the exit code of a session error the daemon reported.
+#[cfg(unix)]
+pub fn connect(path: &Path, header: &serde_json::Value, mut input: Box<dyn io::Read + Send>, output: &mut dyn io::Write) -> io::Result<Option<i32>> {
+    use std::io::{BufRead, Write};
+    use std::net::Shutdown;
+    use std::os::unix::net::UnixStream;
+
+    let stream = UnixStream::connect(path)?;
+    let mut sender = stream.try_clone()?;
+    writeln!(sender, "{header}")?;
+
+    // a plain thread, like the transcription's reader, so stdin that never closes does not hold up the exit
+    std::thread::spawn(move || {
+        if let Err(err) = io::copy(&mut input, &mut sender) {
+            debug!("Stopped sending audio to the daemon: {err}");
+        }
+
+        let _ = sender.shutdown(Shutdown::Write);
+    });
+
+    let mut code = None;
+
+    for line in io::BufReader::new(stream).lines() {
+        let line = line?;
+
+        if let Ok(event) = serde_json::from_str::<serde_json::Value>(&line) {
+            if protocol::event_type(&event) == "client.session_error" {
+                code = event["code"].as_i64().map(|code| code as i32);
+            }
+        }
+
+        writeln!(output, "{line}")?;
+        output.flush()?;
+    }
+
+    Ok(code)
+}
+
+#[cfg(not(unix))]
+pub fn connect(_path: &Path, _header: &serde_json::Value, _input: Box<dyn io::Read + Send>, _output: &mut dyn io::Write) -> io::Result<Option<i32>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "asr connect is only supported on Unix"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+    use serde_json::{json, Value};
+    use tokio::net::TcpListener;
+
+    async fn header(input: &[u8]) -> Result<Header> {
+        read_header(&mut BufReader::new(input)).await
+    }
+
+    #[tokio::test]
+    async fn reads_the_header_line_and_leaves_the_audio() {
+        let mut reader = BufReader::new(&b"{\"language\":\"en\",\"format\":\"results\"}\n\x01\x02"[..]);
+        let header = read_header(&mut reader).await.unwrap();
+
+        assert_eq!((header.language.as_deref(), header.sample_rate, header.format), (Some("en"), None, Format::Results));
+
+        let mut audio = Vec::new();
+        reader.read_to_end(&mut audio).await.unwrap();
+        assert_eq!(audio, [1, 2]);
+
+        let header = self::header(b"{}\n").await.unwrap();
+        assert_eq!((header.language, header.sample_rate, header.format), (None, None, Format::Events));
+    }
+
+    #[tokio::test]
+    async fn rejects_what_is_not_a_header() {
+        assert!(matches!(header(b"").await, Err(AsrError::AudioInput(_))));
+        assert!(matches!(header(b"\x00\x01 raw audio").await, Err(AsrError::AudioInput(_))));
+        assert!(matches!(header(b"not json\n").await, Err(AsrError::Json(_))));
+        assert!(matches!(header(b"{\"format\":\"srt\"}\n").await, Err(AsrError::Json(_))));
+
+        // a line past MAX_HEADER_BYTES is cut off before its end
+        let long = format!("{{\"language\":\"{}\"}}\n", "x".repeat(MAX_HEADER_BYTES as usize));
+        assert!(matches!(header(long.as_bytes()).await, Err(AsrError::AudioInput(_))));
+    }
+
+    #[tokio::test]
+    async fn bounds_the_sample_rate() {
+        assert_eq!(header(b"{\"sample_rate\":8000}\n").await.unwrap().sample_rate, Some(8000));
+
+        for rate in ["0", "12345", "4294967295"] {
+            let line = format!("{{\"sample_rate\":{rate}}}\n");
+            assert!(matches!(header(line.as_bytes()).await, Err(AsrError::Protocol { code, .. }) if code == "invalid_header"));
+        }
+    }
+
+    #[test]
+    fn parses_listen_addresses() {
+        assert_eq!(parse_listen("unix:/run/asr.sock"), Ok(PathBuf::from("/run/asr.sock")));
+        assert!(parse_listen("unix:").is_err() && parse_listen("/run/asr.sock").is_err());
+    }
+
+    fn daemon(url: &str, max_sessions: Option<usize>) -> Daemon {
+        Daemon {
+            url: url.parse().unwrap(),
+            api_key: "test".into(),
+            model: "qwen3-asr-flash-realtime".into(),
+            session_config: SessionConfig {
+                sample_rate: 16000,
+                language: "zh".into(),
+                vad_threshold: 0.2,
+                vad_silence_ms: 800,
+                vad_prefix_padding_ms: None,
+                server_vad: true,
+                translate_to: None,
+                itn: None,
+                punctuation: None,
+            },
+            limiter: Arc::new(Limiter::new(max_sessions, None, None)),
+            max_message_size: 1 << 20,
+        }
+    }
+
+    // What a client sending input gets back, one JSON value a line
+    async fn session(daemon: &Daemon, input: &[u8]) -> Vec<Value> {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let (mut client_reader, mut client_writer) = tokio::io::split(client);
+
+        client_writer.write_all(input).await.unwrap();
+        client_writer.shutdown().await.unwrap();
+
+        daemon.session(server).await;
+
+        let mut output = String::new();
+        client_reader.read_to_string(&mut output).await.unwrap();
+        output.lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+
+    // An upstream that answers session.update, and one finished turn for all the audio once the client is done
+    async fn upstream() -> (String, tokio::task::JoinHandle<Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let (mut update, mut bytes) = (Value::Null, 0);
+            let send = |event: Value| Message::Text(event.to_string().into());
+
+            ws.send(send(json!({ "type": "session.created", "session": { "id": "sess" } }))).await.unwrap();
+
+            while let Some(Ok(Message::Text(text))) = ws.next().await {
+                let event: Value = serde_json::from_str(&text).unwrap();
+
+                match protocol::event_type(&event) {
+                    "session.update" => {
+                        update = event.clone();
+                        ws.send(send(json!({ "type": "session.updated", "session": event["session"] }))).await.unwrap();
+                    }
+                    "input_audio_buffer.append" => bytes += base64::engine::general_purpose::STANDARD.decode(event["audio"].as_str().unwrap()).unwrap().len(),
+                    "session.finish" => {
+                        let turn = json!({
+                            "type": "conversation.item.input_audio_transcription.completed",
+                            "item_id": "a",
+                            "transcript": format!("{bytes} bytes"),
+                            "audio_start_ms": 0,
+                            "audio_end_ms": 500,
+                        });
+                        ws.send(send(turn)).await.unwrap();
+                        ws.send(send(json!({ "type": "session.finished" }))).await.unwrap();
+                        break
+                    }
+                    _ => {}
+                }
+            }
+
+            update
+        });
+
+        (url, server)
+    }
+
+    #[tokio::test]
+    async fn streams_events_back_on_the_connection() {
+        let (url, server) = upstream().await;
+        let mut input = b"{\"language\":\"en\",\"sample_rate\":8000}\n".to_vec();
+        input.extend([0; 3000]);
+
+        let events = session(&daemon(&url, None), &input).await;
+        let types: Vec<_> = events.iter().map(protocol::event_type).collect();
+        assert_eq!(types, ["session.created", "session.updated", "conversation.item.input_audio_transcription.completed", "session.finished"]);
+        assert_eq!(events[2]["transcript"], "3000 bytes");
+
+        // the header's settings win over the daemon's
+        let update = server.await.unwrap();
+        assert_eq!((update["session"]["sample_rate"].as_u64(), update["session"]["input_audio_transcription"]["language"].as_str()), (Some(8000), Some("en")));
+    }
+
+    #[tokio::test]
+    async fn answers_with_results_when_asked() {
+        let (url, _server) = upstream().await;
+        let events = session(&daemon(&url, None), b"{\"format\":\"results\"}\n\x00\x00").await;
+
+        assert_eq!(events.len(), 1, "{events:?}");
+        assert_eq!((events[0]["turn"].as_u64(), events[0]["end_ms"].as_u64(), events[0]["text"].as_str()), (Some(0), Some(500), Some("2 bytes")));
+    }
+
+    #[tokio::test]
+    async fn turns_clients_away() {
+        // nothing listens there, none of these get as far as connecting
+        let daemon = daemon("ws://127.0.0.1:9", Some(1));
+
+        let events = session(&daemon, b"{\"sample_rate\":4294967295}\n").await;
+        assert_eq!((events[0]["type"].as_str(), events[0]["code"].as_i64()), (Some("client.session_error"), Some(5)));
+
+        let _permit = daemon.limiter.try_acquire_session().unwrap();
+        let events = session(&daemon, b"{}\n").await;
+        assert_eq!(events[0]["code"].as_i64(), Some(5));
+        assert!(events[0]["message"].as_str().unwrap().contains("--max-concurrent-sessions"), "{events:?}");
+    }
+}
@@ -0,0 +1,248 @@
+use crate::event::TurnDuplicate;
+use crate::keyword;
+use crate::protocol;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+
+// Share of the two turns' combined span they must have in common to be the same audio
+const MIN_OVERLAP: f64 = 0.8;
+
+// Earlier turns a replayed one is compared with, more than a replay window ever holds
+const MAX_RECENT: usize = 64;
+
+#[derive(Debug)]
+struct Fingerprint {
+    item_id: String,
+    start_ms: f64,
+    end_ms: f64,
+    tokens: Vec<String>,
+    // the session the turn came from, only turns of an earlier one can be heard again
+    generation: u32,
+}
+
+// Suppresses the turns a new session transcribes again from audio replayed to it, after the old session had already
+// finished them. Only turns within the replay window are checked, and only against those of earlier sessions, so a phrase
+// said twice is never taken for itself: its two turns sit at different offsets.
+#[derive(Debug)]
+pub struct TurnDedup {
+    min_similarity: f64,
+    generation: u32,
+    // the source-timeline span the current session was sent again
+    replay: Option<(f64, f64)>,
+    recent: VecDeque<Fingerprint>,
+    // offsets from the speech events, for transcripts that do not repeat them
+    spans: HashMap<String, (Option<f64>, Option<f64>)>,
+}
+
+impl TurnDedup {
+    pub fn new(min_similarity: f64) -> Self {
+        Self { min_similarity, generation: 0, replay: None, recent: VecDeque::new(), spans: HashMap::new() }
+    }
+
+    // A new session took over and is sent start_ms to end_ms of the source again
+    pub fn replayed(&mut self, start_ms: f64, end_ms: f64) {
+        self.generation += 1;
+        self.replay = (end_ms > start_ms).then_some((start_ms, end_ms));
+        self.spans.clear();
+    }
+
+    // Offsets are expected on the source timeline already. A duplicate is not remembered, the turn it repeats stands for it.
+    pub fn observe(&mut self, event: &Value) -> Option<TurnDuplicate> {
+        let item_id = event["item_id"].as_str()?;
+
+        match protocol::event_type(event) {
+            "input_audio_buffer.speech_started" => {
+                self.spans.entry(item_id.into()).or_default().0 = event["audio_start_ms"].as_f64();
+                return None
+            }
+            "input_audio_buffer.speech_stopped" => {
+                self.spans.entry(item_id.into()).or_default().1 = event["audio_end_ms"].as_f64();
+                return None
+            }
+            "conversation.item.input_audio_transcription.completed" => {}
+            _ => return None,
+        }
+
+        let span = self.spans.remove(item_id).unwrap_or_default();
+        let (Some(start_ms), Some(end_ms)) = (event["audio_start_ms"].as_f64().or(span.0), event["audio_end_ms"].as_f64().or(span.1)) else {
+            return None
+        };
+
+        let text = event["transcript"].as_str().unwrap_or_default();
+        let tokens = keyword::tokens(text);
+
+        if let Some((earlier, overlap, similarity)) = self.duplicate(start_ms, end_ms, &tokens) {
+            return Some(TurnDuplicate {
+                item_id: item_id.into(),
+                duplicate_of: earlier.item_id.clone(),
+                text: text.into(),
+                start_ms: start_ms.round() as u64,
+                end_ms: end_ms.round() as u64,
+                overlap: (overlap * 100.0).round() / 100.0,
+                similarity: (similarity * 100.0).round() / 100.0,
+            })
+        }
+
+        if self.recent.len() == MAX_RECENT {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(Fingerprint { item_id: item_id.into(), start_ms, end_ms, tokens, generation: self.generation });
+
+        None
+    }
+
+    // The earlier turn most like this one, with their overlap and text similarity
+    fn duplicate(&self, start_ms: f64, end_ms: f64, tokens: &[String]) -> Option<(&Fingerprint, f64, f64)> {
+        let (replay_start_ms, replay_end_ms) = self.replay?;
+
+        if end_ms <= replay_start_ms || start_ms >= replay_end_ms {
+            return None
+        }
+
+        self.recent
+            .iter()
+            .filter(|earlier| earlier.generation < self.generation)
+            .map(|earlier| (earlier, overlap((start_ms, end_ms), (earlier.start_ms, earlier.end_ms)), similarity(tokens, &earlier.tokens)))
+            .filter(|&(_, overlap, similarity)| overlap > MIN_OVERLAP && similarity >= self.min_similarity)
+            .max_by(|a, b| a.2.total_cmp(&b.2))
+    }
+}
+
+pub fn parse_similarity(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(similarity) if (0.0..=1.0).contains(&similarity) => Ok(similarity),
+        _ => Err(format!("`{value}` is not a similarity from 0 to 1")),
+    }
+}
+
+// Intersection over union of two spans. A span only partly covering the other, like a fragment cut at the replay
+// point next to the whole turn, stays well below MIN_OVERLAP.
+pub fn overlap(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let intersection = (a.1.min(b.1) - a.0.max(b.0)).max(0.0);
+    let union = a.1.max(b.1) - a.0.min(b.0);
+
+    match union > 0.0 {
+        true => intersection / union,
+        // two turns without length at the same instant
+        false => f64::from(a == b),
+    }
+}
+
+// 1 minus the token edit distance over the longer transcript, 1 for two empty ones. CJK characters are tokens of their own,
+// so a short confirmation like 好的 against 好 scores 0.5, not a near match.
+pub fn similarity(a: &[String], b: &[String]) -> f64 {
+    let longer = a.len().max(b.len());
+
+    if longer == 0 {
+        return 1.0
+    }
+
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, token) in a.iter().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+
+        for (j, other) in b.iter().enumerate() {
+            current[j + 1] = (previous[j] + usize::from(token != other)).min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+
+        previous = current;
+    }
+
+    1.0 - previous[b.len()] as f64 / longer as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn completed(item_id: &str, start_ms: f64, end_ms: f64, transcript: &str) -> Value {
+        json!({
+            "type": "conversation.item.input_audio_transcription.completed",
+            "item_id": item_id,
+            "audio_start_ms": start_ms,
+            "audio_end_ms": end_ms,
+            "transcript": transcript,
+        })
+    }
+
+    #[test]
+    fn overlaps() {
+        assert_eq!(overlap((1000.0, 2000.0), (1000.0, 2000.0)), 1.0);
+        assert_eq!(overlap((1000.0, 2000.0), (2000.0, 3000.0)), 0.0);
+        assert_eq!(overlap((1000.0, 2000.0), (3000.0, 4000.0)), 0.0);
+        // a fragment cut at the replay point next to the whole turn
+        assert!((overlap((1000.0, 3000.0), (2000.0, 3000.0)) - 0.5).abs() < 1e-9);
+        assert!((overlap((1000.0, 2000.0), (1500.0, 2500.0)) - 1.0 / 3.0).abs() < 1e-9);
+        assert_eq!(overlap((1000.0, 1000.0), (1000.0, 1000.0)), 1.0);
+        assert_eq!(overlap((1000.0, 1000.0), (2000.0, 2000.0)), 0.0);
+    }
+
+    #[test]
+    fn similarities() {
+        let similarity = |a: &str, b: &str| similarity(&keyword::tokens(a), &keyword::tokens(b));
+
+        assert_eq!(similarity("", ""), 1.0);
+        assert_eq!(similarity("今天天气不错", "今天天气不错。"), 1.0);
+        assert_eq!(similarity("Hello, World", "hello world"), 1.0);
+        assert_eq!(similarity("好的", "好"), 0.5);
+        assert_eq!(similarity("好的", ""), 0.0);
+        assert_eq!(similarity("turn on the light", "turn off the light"), 0.75);
+        assert!((similarity("今天天气不错", "今天天气真不错") - 6.0 / 7.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn drops_turns_heard_again() {
+        let mut dedup = TurnDedup::new(0.85);
+
+        assert!(dedup.observe(&completed("a", 1000.0, 2000.0, "好的")).is_none());
+        assert!(dedup.observe(&completed("b", 2500.0, 4000.0, "今天天气不错")).is_none());
+
+        dedup.replayed(1500.0, 4000.0);
+
+        let duplicate = dedup.observe(&completed("c", 2520.0, 4010.0, "今天天气不错。")).unwrap();
+        assert_eq!(duplicate.duplicate_of, "b");
+        assert_eq!(duplicate.similarity, 1.0);
+        assert!(duplicate.overlap > MIN_OVERLAP);
+
+        // a fragment of the first turn cut at the replay point overlaps it too little
+        assert!(dedup.observe(&completed("d", 1500.0, 2000.0, "的")).is_none());
+        // the same phrase said again later is new speech
+        assert!(dedup.observe(&completed("e", 4500.0, 5500.0, "好的")).is_none());
+    }
+
+    #[test]
+    fn keeps_short_confirmations_that_differ() {
+        let mut dedup = TurnDedup::new(0.85);
+
+        dedup.observe(&completed("a", 1000.0, 1500.0, "好的"));
+        dedup.replayed(0.0, 2000.0);
+
+        assert!(dedup.observe(&completed("b", 1000.0, 1500.0, "好")).is_none());
+    }
+
+    #[test]
+    fn takes_offsets_from_speech_events() {
+        let mut dedup = TurnDedup::new(0.85);
+
+        dedup.observe(&completed("a", 1000.0, 2000.0, "hello world"));
+        dedup.replayed(0.0, 3000.0);
+
+        dedup.observe(&json!({ "type": "input_audio_buffer.speech_started", "item_id": "b", "audio_start_ms": 1000 }));
+        dedup.observe(&json!({ "type": "input_audio_buffer.speech_stopped", "item_id": "b", "audio_end_ms": 2000 }));
+
+        let completed = json!({ "type": "conversation.item.input_audio_transcription.completed", "item_id": "b", "transcript": "Hello world." });
+        assert_eq!(dedup.observe(&completed).map(|duplicate| duplicate.duplicate_of), Some("a".into()));
+    }
+
+    #[test]
+    fn only_checks_the_replay_window() {
+        let mut dedup = TurnDedup::new(0.85);
+
+        dedup.observe(&completed("a", 1000.0, 2000.0, "hello world"));
+        dedup.replayed(3000.0, 5000.0);
+
+        assert!(dedup.observe(&completed("b", 1000.0, 2000.0, "hello world")).is_none());
+    }
+}
@@ -0,0 +1,212 @@
+use crate::event::QualitySummary;
+use crate::protocol;
+use crate::text;
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Characters per second a plausible turn stays within, a CJK character carries about a syllable
+const CJK_CPS: (f64, f64) = (1.0, 10.0);
+const OTHER_CPS: (f64, f64) = (2.0, 25.0);
+
+// Shorter turns have too coarse a duration to judge
+const MIN_TURN_MS: f64 = 500.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Warning {
+    LowDensity,
+    HighDensity,
+}
+
+impl Warning {
+    pub fn tag(self) -> &'static str {
+        match self {
+            Warning::LowDensity => "low_density",
+            Warning::HighDensity => "high_density",
+        }
+    }
+}
+
+// Flags final transcripts far too short or too long for the audio they cover, like garbage audio heard as two characters
+#[derive(Debug, Default)]
+pub struct DensityCheck {
+    // unset bounds follow the script of the transcript
+    min_cps: Option<f64>,
+    max_cps: Option<f64>,
+    turns: AtomicU64,
+    low_density: AtomicU64,
+    high_density: AtomicU64,
+}
+
+impl DensityCheck {
+    pub fn new(min_cps: Option<f64>, max_cps: Option<f64>) -> Self {
+        Self { min_cps, max_cps, ..Default::default() }
+    }
+
+    // Offsets are taken as the server reports them, trimmed silence would otherwise count as speech.
+    // Returns whether the event was tagged.
+    pub fn observe(&self, event: &mut Value) -> bool {
+        if protocol::event_type(event) != "conversation.item.input_audio_transcription.completed" {
+            return false
+        }
+
+        let (Some(start_ms), Some(end_ms)) = (event["audio_start_ms"].as_f64(), event["audio_end_ms"].as_f64()) else {
+            return false
+        };
+
+        self.turns.fetch_add(1, Ordering::Relaxed);
+
+        let Some(warning) = self.classify(event["transcript"].as_str().unwrap_or_default(), end_ms - start_ms) else {
+            return false
+        };
+
+        match warning {
+            Warning::LowDensity => &self.low_density,
+            Warning::HighDensity => &self.high_density,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+
+        event["quality_warning"] = warning.tag().into();
+        true
+    }
+
+    pub fn classify(&self, text: &str, duration_ms: f64) -> Option<Warning> {
+        if duration_ms < MIN_TURN_MS {
+            return None
+        }
+
+        // punctuation and spaces are not speech
+        let chars: Vec<char> = text.chars().filter(|c| c.is_alphanumeric()).collect();
+        let cjk = chars.iter().filter(|&&c| text::is_cjk(c)).count();
+        let (min_cps, max_cps) = if cjk * 2 >= chars.len() && cjk > 0 { CJK_CPS } else { OTHER_CPS };
+
+        let cps = chars.len() as f64 * 1000.0 / duration_ms;
+
+        if cps < self.min_cps.unwrap_or(min_cps) {
+            Some(Warning::LowDensity)
+        } else if cps > self.max_cps.unwrap_or(max_cps) {
+            Some(Warning::HighDensity)
+        } else {
+            None
+        }
+    }
+
+    // A turn an earlier run already checked, from --journal-resume
+    pub fn restore(&self, quality_warning: Option<&str>) {
+        self.turns.fetch_add(1, Ordering::Relaxed);
+
+        match quality_warning {
+            Some(tag) if tag == Warning::LowDensity.tag() => self.low_density.fetch_add(1, Ordering::Relaxed),
+            Some(tag) if tag == Warning::HighDensity.tag() => self.high_density.fetch_add(1, Ordering::Relaxed),
+            _ => 0,
+        };
+    }
+
+    // Only worth reporting when something was flagged
+    pub fn summary(&self) -> Option<QualitySummary> {
+        let (low_density, high_density) = (self.low_density.load(Ordering::Relaxed), self.high_density.load(Ordering::Relaxed));
+
+        (low_density + high_density > 0).then(|| QualitySummary { turns: self.turns.load(Ordering::Relaxed), low_density, high_density })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn completed(start_ms: f64, end_ms: f64, transcript: &str) -> Value {
+        json!({
+            "type": "conversation.item.input_audio_transcription.completed",
+            "item_id": "item",
+            "audio_start_ms": start_ms,
+            "audio_end_ms": end_ms,
+            "transcript": transcript,
+        })
+    }
+
+    #[test]
+    fn bounds_follow_the_script() {
+        let check = DensityCheck::default();
+
+        // CJK from 1 to 10 characters a second
+        assert_eq!(check.classify("你好", 2000.0), None);
+        assert_eq!(check.classify("好", 2000.0), Some(Warning::LowDensity));
+        assert_eq!(check.classify(&"好".repeat(20), 2000.0), None);
+        assert_eq!(check.classify(&"好".repeat(21), 2000.0), Some(Warning::HighDensity));
+        assert_eq!(check.classify("こんにちは", 1000.0), None);
+
+        // anything else from 2 to 25
+        assert_eq!(check.classify("hi", 2000.0), Some(Warning::LowDensity));
+        assert_eq!(check.classify("okay", 2000.0), None);
+        assert_eq!(check.classify(&"a".repeat(50), 2000.0), None);
+        assert_eq!(check.classify(&"a".repeat(51), 2000.0), Some(Warning::HighDensity));
+
+        // half CJK or more goes by the CJK bounds, punctuation and spaces do not count
+        assert_eq!(check.classify("ok 好的", 4000.0), None);
+        assert_eq!(check.classify("okay 好", 4000.0), Some(Warning::LowDensity));
+        assert_eq!(check.classify("。。。，，，!!!", 1000.0), Some(Warning::LowDensity));
+        assert_eq!(check.classify("", 1000.0), Some(Warning::LowDensity));
+    }
+
+    #[test]
+    fn skips_short_turns() {
+        let check = DensityCheck::default();
+
+        assert_eq!(check.classify("", MIN_TURN_MS - 1.0), None);
+        assert_eq!(check.classify(&"a".repeat(100), MIN_TURN_MS - 1.0), None);
+        assert_eq!(check.classify("", MIN_TURN_MS), Some(Warning::LowDensity));
+    }
+
+    #[test]
+    fn overrides_either_bound() {
+        let check = DensityCheck::new(Some(0.5), None);
+        assert_eq!(check.classify("好", 2000.0), None);
+        assert_eq!(check.classify("hi", 2000.0), None);
+        assert_eq!(check.classify(&"好".repeat(21), 2000.0), Some(Warning::HighDensity));
+
+        let check = DensityCheck::new(None, Some(5.0));
+        assert_eq!(check.classify(&"a".repeat(12), 2000.0), Some(Warning::HighDensity));
+        assert_eq!(check.classify("hi", 2000.0), Some(Warning::LowDensity));
+    }
+
+    #[test]
+    fn tags_and_counts_turns() {
+        let check = DensityCheck::default();
+        assert!(check.summary().is_none());
+
+        let mut fine = completed(1000.0, 3000.0, "今天天气不错");
+        assert!(!check.observe(&mut fine));
+        assert!(fine.get("quality_warning").is_none());
+        assert!(check.summary().is_none());
+
+        let mut low = completed(1000.0, 6000.0, "嗯");
+        assert!(check.observe(&mut low));
+        assert_eq!(low["quality_warning"], "low_density");
+
+        let mut high = completed(0.0, 1000.0, &"word ".repeat(10));
+        assert!(check.observe(&mut high));
+        assert_eq!(high["quality_warning"], "high_density");
+
+        // neither partial transcripts nor turns without offsets are judged or counted
+        assert!(!check.observe(&mut json!({ "type": "conversation.item.input_audio_transcription.text", "text": "嗯", "audio_start_ms": 0, "audio_end_ms": 5000 })));
+        assert!(!check.observe(&mut json!({ "type": "conversation.item.input_audio_transcription.completed", "transcript": "嗯" })));
+
+        let summary = check.summary().unwrap();
+        assert_eq!((summary.turns, summary.low_density, summary.high_density), (3, 1, 1));
+    }
+
+    #[test]
+    fn restores_earlier_turns() {
+        let check = DensityCheck::default();
+
+        check.restore(None);
+        assert!(check.summary().is_none());
+
+        check.restore(Some("high_density"));
+        check.restore(Some("something_else"));
+        check.observe(&mut completed(0.0, 5000.0, "嗯"));
+
+        let summary = check.summary().unwrap();
+        assert_eq!((summary.turns, summary.low_density, summary.high_density), (4, 1, 1));
+    }
+}
@@ -0,0 +1,74 @@
+use std::io;
+use thiserror::Error;
+use tokio_tungstenite::tungstenite;
+
+pub type Result<T, E = AsrError> = std::result::Result<T, E>;
+
+pub const EXIT_CODES: &[(i32, &str)] = &[
+    (0, "Success"),
+    (1, "Other errors"),
+    (2, "Invalid command-line usage"),
+    (3, "Authentication failed"),
+    (4, "Could not connect to the endpoint"),
+    (5, "Server reported a protocol error"),
+    (6, "Failed to read audio input"),
+    (7, "Timed out"),
+    (8, "Connection closed abnormally or failed midway"),
+    (9, "No speech started within --once-timeout-ms"),
+    (10, "The input ended before any audio, without --allow-empty"),
+];
+
+#[derive(Debug, Error)]
+pub enum AsrError {
+    #[error("authentication failed: {0}")]
+    Auth(String),
+    #[error("failed to connect: {source}")]
+    Connect {
+        #[source]
+        source: tungstenite::Error,
+    },
+    #[error("server error {code}: {message}")]
+    Protocol { code: String, message: String },
+    #[error("failed to read audio input: {0}")]
+    AudioInput(#[source] io::Error),
+    #[error("timed out: {0}")]
+    Timeout(String),
+    #[error("connection closed ({code}): {reason}")]
+    Closed { code: u16, reason: String },
+    #[error("no speech within {0} ms")]
+    NoSpeech(u64),
+    #[error("the input ended before any audio")]
+    NoInput,
+    #[error("websocket error: {0}")]
+    WebSocket(#[from] tungstenite::Error),
+    #[error("invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl AsrError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AsrError::Io(_) | AsrError::Json(_) => 1,
+            AsrError::Auth(_) => 3,
+            AsrError::Connect { .. } => 4,
+            AsrError::Protocol { .. } => 5,
+            AsrError::AudioInput(_) => 6,
+            AsrError::Timeout(_) => 7,
+            AsrError::Closed { .. } | AsrError::WebSocket(_) => 8,
+            AsrError::NoSpeech(_) => 9,
+            AsrError::NoInput => 10,
+        }
+    }
+
+    pub fn from_handshake(err: tungstenite::Error) -> Self {
+        match err {
+            tungstenite::Error::Http(response) if matches!(response.status().as_u16(), 401 | 403) => {
+                let body = response.body().as_deref().map(String::from_utf8_lossy).unwrap_or_default();
+                AsrError::Auth(format!("{} {}", response.status(), body.trim()).trim_end().to_string())
+            }
+            source => AsrError::Connect { source },
+        }
+    }
+}
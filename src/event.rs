@@ -0,0 +1,520 @@
+use schemars::{JsonSchema, Schema};
+use serde::Serialize;
+use std::fmt;
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Envelope<E> {
+    pub schema_version: u32,
+    /// The upstream session current when the event was emitted, once the server assigned one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_session_id: Option<String>,
+    #[serde(flatten)]
+    pub event: E,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(tag = "type")]
+pub enum ClientEvent {
+    #[serde(rename = "client.session_info")]
+    SessionInfo(SessionInfo),
+    #[serde(rename = "client.throttled")]
+    Throttled(Throttled),
+    #[serde(rename = "client.rate_mismatch")]
+    RateMismatch(RateMismatch),
+    #[serde(rename = "client.model_switched")]
+    ModelSwitched(ModelSwitched),
+    #[serde(rename = "client.spool_remaining")]
+    SpoolRemaining(SpoolRemaining),
+    #[serde(rename = "client.check")]
+    Check(Check),
+    #[serde(rename = "client.capabilities")]
+    Capabilities(Capabilities),
+    #[serde(rename = "client.protocol_warning")]
+    ProtocolWarning(ProtocolWarning),
+    #[serde(rename = "client.ab_diff")]
+    AbDiff(AbDiff),
+    #[serde(rename = "client.ab_summary")]
+    AbSummary(AbSummary),
+    #[serde(rename = "client.vad_updated")]
+    VadUpdated(VadInfo),
+    #[serde(rename = "client.tty_allocated")]
+    TtyAllocated(TtyAllocated),
+    #[serde(rename = "client.reference_report")]
+    ReferenceReport(ReferenceReport),
+    #[serde(rename = "client.quality_summary")]
+    QualitySummary(QualitySummary),
+    #[serde(rename = "client.server_lagging")]
+    ServerLagging(ServerLagging),
+    #[serde(rename = "client.session_error")]
+    SessionError(SessionError),
+    #[serde(rename = "client.schedule_muted")]
+    ScheduleMuted(ScheduleChange),
+    #[serde(rename = "client.schedule_unmuted")]
+    ScheduleUnmuted(ScheduleChange),
+    #[serde(rename = "client.server_sessions")]
+    ServerSessions(ServerSessionList),
+    // EOF before a single byte of audio, nothing was committed
+    #[serde(rename = "client.no_input")]
+    NoInput,
+    #[serde(rename = "client.keyword_triggered")]
+    KeywordTriggered(KeywordTriggered),
+    // left by a `mark` keyword action, where the phrase was said
+    #[serde(rename = "client.marker")]
+    Marker(Marker),
+    // a turn a new session transcribed again from replayed audio, left out of the output
+    #[serde(rename = "client.turn_duplicate")]
+    TurnDuplicate(TurnDuplicate),
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct SessionInfo {
+    pub session_id: String,
+    pub client_version: String,
+    pub model: String,
+    pub host: String,
+    pub sample_rate: u32,
+    pub language: String,
+    pub vad: VadInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub translate_to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub itn: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub punctuation: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct VadInfo {
+    pub threshold: f32,
+    pub silence_duration_ms: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefix_padding_ms: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Throttled {
+    pub retry_after_ms: u64,
+    pub attempt: u32,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct RateMismatch {
+    pub declared_rate: u32,
+    pub measured_rate: u32,
+    pub likely_rate: u32,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ModelSwitched {
+    pub from: String,
+    pub to: String,
+    pub language: String,
+    pub at_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct SpoolRemaining {
+    pub directory: String,
+    pub chunks: usize,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct TtyAllocated {
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ServerLagging {
+    /// Seconds of sent audio the server has not referred to yet
+    pub lag_s: f64,
+    pub sent_s: f64,
+    /// Furthest audio offset in any server event, absent when the provider reports none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acknowledged_s: Option<f64>,
+}
+
+// Emitted at the end when a run went through more than one upstream session
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ServerSessionList {
+    pub server_session_ids: Vec<String>,
+}
+
+// Audio stops or starts being forwarded as an --active-hours window closes or opens
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ScheduleChange {
+    /// Local time of the next change, absent when the schedule stays as it is all week
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub until: Option<String>,
+}
+
+// A --keyword-action phrase was said in a final transcript
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct KeywordTriggered {
+    /// The phrase of the rule that fired
+    pub rule: String,
+    pub action: String,
+    pub item_id: String,
+    /// The directory an export_last action wrote to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Marker {
+    pub rule: String,
+    pub item_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct TurnDuplicate {
+    pub item_id: String,
+    /// The item of the earlier turn it repeats
+    pub duplicate_of: String,
+    pub text: String,
+    pub start_ms: u64,
+    pub end_ms: u64,
+    /// Intersection over union of the two offset ranges
+    pub overlap: f64,
+    /// 1 minus the edit distance of the normalized transcripts over the longer one
+    pub similarity: f64,
+}
+
+// Why `asr daemon` ended a connection's session, the last line it gets
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct SessionError {
+    /// The exit code a transcription failing the same way would have
+    pub code: i32,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Check {
+    pub model: String,
+    pub host: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_session_id: Option<String>,
+    /// The session configuration as confirmed in session.updated
+    pub session: serde_json::Value,
+    pub connect_ms: u64,
+    /// From sending session.update to receiving session.updated
+    pub rtt_ms: u64,
+}
+
+// What --probe-capabilities found out, one session.update per optional setting
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Capabilities {
+    pub model: String,
+    pub host: String,
+    pub features: Vec<FeatureProbe>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct FeatureProbe {
+    pub flag: String,
+    /// The server confirmed a session.update with the setting, one that ignores unknown settings accepts them all
+    pub supported: bool,
+    /// What the built-in capability table says, absent for a model it does not list
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected: Option<bool>,
+    /// The server's reason for rejecting it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl ClientEvent {
+    pub fn line(&self, server_session_id: Option<String>) -> String {
+        let envelope = Envelope { schema_version: SCHEMA_VERSION, server_session_id, event: self };
+        serde_json::to_string(&envelope).unwrap_or_default()
+    }
+}
+
+impl fmt::Display for ClientEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.line(None))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ProtocolWarning {
+    /// invalid_utf8, message_too_large or invalid_json
+    pub kind: String,
+    pub message: String,
+    /// Start of the offending payload, when there was one to show
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preview: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct AbDiff {
+    /// Span of the A turn on the source timeline, absent without server offsets
+    pub start_ms: Option<u64>,
+    pub end_ms: Option<u64>,
+    pub a: String,
+    pub b: String,
+    /// Levenshtein distance in characters
+    pub edit_distance: usize,
+    /// 1 - edit_distance / length of the longer text
+    pub similarity: f64,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct AbSummary {
+    pub variants: Vec<VariantSummary>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct VariantSummary {
+    pub variant: String,
+    pub model: String,
+    pub turns: usize,
+    pub chars: usize,
+    /// From the end of speech to the final transcript
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<Latency>,
+    pub dropped_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ReferenceReport {
+    pub reference_tokens: usize,
+    pub hypothesis_tokens: usize,
+    pub substitutions: usize,
+    pub insertions: usize,
+    pub deletions: usize,
+    /// Substitutions, insertions and deletions per reference token
+    pub error_rate: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub turns: Option<Vec<TurnAlignment>>,
+}
+
+// How many final transcripts got a quality_warning, emitted at the end when any did
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct QualitySummary {
+    pub turns: u64,
+    pub low_density: u64,
+    pub high_density: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, JsonSchema)]
+pub struct TurnAlignment {
+    pub turn: usize,
+    pub reference: String,
+    pub hypothesis: String,
+    pub substitutions: usize,
+    pub insertions: usize,
+    pub deletions: usize,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Latency {
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+}
+
+pub fn schema() -> Schema {
+    let mut schema = schemars::schema_for!(Envelope<ClientEvent>);
+
+    schema.insert("title".into(), "asr client events".into());
+    schema.insert("x-schema-version".into(), SCHEMA_VERSION.into());
+
+    // server events are passed through as the provider sends them, only the normalized envelope is ours
+    if let Some(defs) = schema.ensure_object().entry("$defs").or_insert_with(|| serde_json::json!({})).as_object_mut() {
+        defs.insert("NormalizedServerEvent".into(), crate::normalize::schema());
+    }
+    schema
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    // The keywords the generated schema uses, enough to check events against it without a validator crate
+    fn validate(root: &Value, schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+        let Some(schema) = schema.as_object() else {
+            return match schema {
+                Value::Bool(true) => Ok(()),
+                _ => Err(format!("{path}: no schema allows it")),
+            }
+        };
+
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            let name = reference.strip_prefix("#/$defs/").ok_or_else(|| format!("{path}: unknown $ref {reference}"))?;
+            validate(root, &root["$defs"][name], value, path)?;
+        }
+
+        if let Some(types) = schema.get("type") {
+            let expected: Vec<&str> = match types {
+                Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+                types => types.as_str().into_iter().collect(),
+            };
+            let is = |kind: &str| match kind {
+                "null" => value.is_null(),
+                "boolean" => value.is_boolean(),
+                "integer" => value.is_i64() || value.is_u64(),
+                "number" => value.is_number(),
+                "string" => value.is_string(),
+                "array" => value.is_array(),
+                "object" => value.is_object(),
+                _ => false,
+            };
+
+            if !expected.iter().any(|kind| is(kind)) {
+                return Err(format!("{path}: {value} is not of type {types}"))
+            }
+        }
+
+        if schema.get("const").is_some_and(|constant| constant != value) {
+            return Err(format!("{path}: {value} is not {}", schema["const"]))
+        }
+
+        if schema.get("enum").and_then(Value::as_array).is_some_and(|values| !values.contains(value)) {
+            return Err(format!("{path}: {value} is not one of {}", schema["enum"]))
+        }
+
+        if let (Some(minimum), Some(number)) = (schema.get("minimum").and_then(Value::as_f64), value.as_f64()) {
+            if number < minimum {
+                return Err(format!("{path}: {number} is below {minimum}"))
+            }
+        }
+
+        if let Some(object) = value.as_object() {
+            for field in schema.get("required").and_then(Value::as_array).into_iter().flatten().filter_map(Value::as_str) {
+                if !object.contains_key(field) {
+                    return Err(format!("{path}: {field} is missing"))
+                }
+            }
+
+            for (field, property) in schema.get("properties").and_then(Value::as_object).into_iter().flatten() {
+                if let Some(value) = object.get(field) {
+                    validate(root, property, value, &format!("{path}.{field}"))?;
+                }
+            }
+        }
+
+        if let (Some(items), Some(values)) = (schema.get("items"), value.as_array()) {
+            for (i, value) in values.iter().enumerate() {
+                validate(root, items, value, &format!("{path}[{i}]"))?;
+            }
+        }
+
+        if let Some(any_of) = schema.get("anyOf").and_then(Value::as_array) {
+            if !any_of.iter().any(|schema| validate(root, schema, value, path).is_ok()) {
+                return Err(format!("{path}: {value} matches none of anyOf"))
+            }
+        }
+
+        if let Some(one_of) = schema.get("oneOf").and_then(Value::as_array) {
+            let matching = one_of.iter().filter(|schema| validate(root, schema, value, path).is_ok()).count();
+
+            if matching != 1 {
+                return Err(format!("{path}: {value} matches {matching} of oneOf"))
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check(line: &str) -> Result<(), String> {
+        let schema = serde_json::to_value(schema()).unwrap();
+        validate(&schema, &schema, &serde_json::from_str(line).unwrap(), "$")
+    }
+
+    fn samples() -> Vec<ClientEvent> {
+        let vad = VadInfo { threshold: 0.2, silence_duration_ms: 800, prefix_padding_ms: None };
+
+        vec![
+            ClientEvent::SessionInfo(SessionInfo {
+                session_id: "s".into(),
+                client_version: "1.0.0".into(),
+                model: "qwen3-asr-flash-realtime".into(),
+                host: "dashscope.aliyuncs.com".into(),
+                sample_rate: 16000,
+                language: "zh".into(),
+                vad: vad.clone(),
+                translate_to: Some("en".into()),
+                itn: None,
+                punctuation: Some(true),
+            }),
+            ClientEvent::Throttled(Throttled { retry_after_ms: 1000, attempt: 2 }),
+            ClientEvent::ServerLagging(ServerLagging { lag_s: 4.5, sent_s: 10.0, acknowledged_s: Some(5.5) }),
+            ClientEvent::ScheduleMuted(ScheduleChange { until: None }),
+            ClientEvent::NoInput,
+            ClientEvent::KeywordTriggered(KeywordTriggered { rule: "note that".into(), action: "export_last:60".into(), item_id: "item".into(), path: Some("/tmp/keyword".into()) }),
+            ClientEvent::Marker(Marker { rule: "mark".into(), item_id: "item".into(), start_ms: Some(0), end_ms: None }),
+            ClientEvent::Check(Check { model: "m".into(), host: "h".into(), server_session_id: None, session: json!({ "any": ["thing"] }), connect_ms: 10, rtt_ms: 20 }),
+            ClientEvent::Capabilities(Capabilities { model: "m".into(), host: "h".into(), features: vec![FeatureProbe { flag: "--itn".into(), supported: false, expected: Some(true), message: Some("no".into()) }] }),
+            ClientEvent::AbDiff(AbDiff { start_ms: None, end_ms: Some(1000), a: "a".into(), b: "b".into(), edit_distance: 1, similarity: 0.0 }),
+            ClientEvent::AbSummary(AbSummary { variants: vec![VariantSummary { variant: "a".into(), model: "m".into(), turns: 1, chars: 2, latency_ms: Some(Latency { p50: 1, p90: 2, p99: 3 }), dropped_bytes: 0 }] }),
+            ClientEvent::ReferenceReport(ReferenceReport {
+                reference_tokens: 2,
+                hypothesis_tokens: 2,
+                substitutions: 1,
+                insertions: 0,
+                deletions: 0,
+                error_rate: 0.5,
+                turns: Some(vec![TurnAlignment::default()]),
+            }),
+            ClientEvent::VadUpdated(vad),
+            ClientEvent::SessionError(SessionError { code: -1, message: "m".into() }),
+        ]
+    }
+
+    #[test]
+    fn events_match_the_schema() {
+        for event in samples() {
+            for server_session_id in [None, Some("sess".into())] {
+                let line = event.line(server_session_id);
+                assert_eq!(check(&line), Ok(()), "{line}");
+            }
+        }
+    }
+
+    #[test]
+    fn schema_rejects_what_events_never_look_like() {
+        let line = ClientEvent::Throttled(Throttled { retry_after_ms: 1000, attempt: 2 }).line(None);
+        let mut event: Value = serde_json::from_str(&line).unwrap();
+
+        event["type"] = "client.unknown".into();
+        assert!(check(&event.to_string()).is_err());
+
+        event["type"] = "client.throttled".into();
+        event.as_object_mut().unwrap().remove("attempt");
+        assert!(check(&event.to_string()).is_err());
+
+        event["attempt"] = "2".into();
+        assert!(check(&event.to_string()).is_err());
+
+        event["attempt"] = (-2).into();
+        assert!(check(&event.to_string()).is_err());
+
+        event["attempt"] = 2.into();
+        event["schema_version"] = Value::Null;
+        assert!(check(&event.to_string()).is_err());
+    }
+
+    #[test]
+    fn normalized_events_match_their_schema() {
+        let schema = serde_json::to_value(schema()).unwrap();
+        let normalized = &schema["$defs"]["NormalizedServerEvent"];
+
+        for event in [json!({ "type": "conversation.item.input_audio_transcription.completed", "transcript": "t" }), json!({ "type": "transcription_session.created" })] {
+            let event = crate::normalize::event(&event, true);
+            assert_eq!(validate(&schema, normalized, &event, "$"), Ok(()), "{event}");
+        }
+
+        assert!(validate(&schema, normalized, &json!({ "type": "transcript.final" }), "$").is_err());
+        assert!(validate(&schema, normalized, &json!({ "type": "x", "original_type": "x" }), "$").is_err());
+    }
+}
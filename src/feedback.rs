@@ -0,0 +1,141 @@
+use crate::protocol;
+use log::debug;
+use serde_json::Value;
+use std::f32::consts::PI;
+use std::sync::mpsc;
+
+const FADE_MS: u32 = 5;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Cue {
+    SpeechStopped,
+    Final,
+}
+
+impl Cue {
+    pub fn for_event(event: &Value) -> Option<Self> {
+        match protocol::event_type(event) {
+            "input_audio_buffer.speech_stopped" => Some(Cue::SpeechStopped),
+            "conversation.item.input_audio_transcription.completed" => Some(Cue::Final),
+            _ => None,
+        }
+    }
+
+    // (frequency in Hz, duration in ms) of each tone
+    fn tones(self) -> &'static [(f32, u32)] {
+        match self {
+            Cue::SpeechStopped => &[(880.0, 80)],
+            Cue::Final => &[(660.0, 70), (990.0, 90)],
+        }
+    }
+}
+
+pub fn synthesize(cue: Cue, sample_rate: u32, volume: f32) -> Vec<f32> {
+    let volume = volume.clamp(0.0, 1.0);
+    let fade = (sample_rate * FADE_MS / 1000).max(1) as usize;
+
+    cue.tones()
+        .iter()
+        .flat_map(|&(frequency, duration_ms)| {
+            let len = (sample_rate * duration_ms / 1000) as usize;
+
+            (0..len).map(move |i| {
+                // short ramps at both ends keep the tone from clicking
+                let envelope = (i.min(len - 1 - i) as f32 / fade as f32).min(1.0);
+                (2.0 * PI * frequency * i as f32 / sample_rate as f32).sin() * volume * envelope
+            })
+        })
+        .collect()
+}
+
+// Plays cues on a thread of its own, so a slow audio device never holds up the event loop
+pub struct Beeper {
+    cues: mpsc::Sender<Cue>,
+}
+
+impl Beeper {
+    // None when there is nothing to play on, feedback is best effort and never fails the session
+    pub fn start(volume: f32) -> Option<Self> {
+        let (cues, cue_rx) = mpsc::channel();
+        let (ready_tx, ready_rx) = mpsc::sync_channel(1);
+
+        std::thread::spawn(move || playback::run(volume, cue_rx, ready_tx));
+
+        match ready_rx.recv() {
+            Ok(Ok(())) => Some(Self { cues }),
+            Ok(Err(reason)) => {
+                debug!("Audio feedback disabled: {reason}");
+                None
+            }
+            Err(_) => None,
+        }
+    }
+
+    pub fn play(&self, cue: Cue) {
+        let _ = self.cues.send(cue);
+    }
+}
+
+#[cfg(feature = "capture")]
+mod playback {
+    use super::{synthesize, Cue};
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    use log::debug;
+    use std::collections::VecDeque;
+    use std::sync::{mpsc, Arc, Mutex};
+
+    // cpal streams are not Send, so the stream stays on this thread for as long as cues arrive
+    pub fn run(volume: f32, cues: mpsc::Receiver<Cue>, ready: mpsc::SyncSender<Result<(), String>>) {
+        let pending = Arc::new(Mutex::new(VecDeque::new()));
+
+        let (_stream, sample_rate, channels) = match open(pending.clone()) {
+            Ok(opened) => opened,
+            Err(reason) => {
+                let _ = ready.send(Err(reason));
+                return
+            }
+        };
+
+        let _ = ready.send(Ok(()));
+
+        for cue in cues {
+            let samples = synthesize(cue, sample_rate, volume);
+            pending.lock().unwrap().extend(samples.into_iter().flat_map(|sample| std::iter::repeat_n(sample, channels)));
+        }
+    }
+
+    fn open(pending: Arc<Mutex<VecDeque<f32>>>) -> Result<(cpal::Stream, u32, usize), String> {
+        let device = cpal::default_host().default_output_device().ok_or("no output device")?;
+        let config = device.default_output_config().map_err(|err| err.to_string())?.config();
+        let (sample_rate, channels) = (config.sample_rate, config.channels as usize);
+
+        let stream = device
+            .build_output_stream(
+                config,
+                move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                    let mut pending = pending.lock().unwrap();
+
+                    for sample in data {
+                        *sample = pending.pop_front().unwrap_or(0.0);
+                    }
+                },
+                |err| debug!("Audio feedback stream error: {err}"),
+                None,
+            )
+            .map_err(|err| err.to_string())?;
+
+        stream.play().map_err(|err| err.to_string())?;
+
+        Ok((stream, sample_rate, channels))
+    }
+}
+
+#[cfg(not(feature = "capture"))]
+mod playback {
+    use super::Cue;
+    use std::sync::mpsc;
+
+    pub fn run(_volume: f32, _cues: mpsc::Receiver<Cue>, ready: mpsc::SyncSender<Result<(), String>>) {
+        let _ = ready.send(Err("built without the capture feature".into()));
+    }
+}
@@ -0,0 +1,166 @@
+use std::f64::consts::PI;
+use std::io::{self, Write};
+
+const WAV_HEADER_BYTES: u32 = 44;
+
+// Known audio for fixtures and bug reports, always 16-bit mono
+#[derive(Debug, Clone, Copy)]
+pub enum Signal {
+    Sine { frequency: f64 },
+    Silence,
+    // same seed, same samples, on every platform
+    Noise { seed: u64 },
+    // linear sweep over the whole duration
+    Chirp { from: f64, to: f64 },
+}
+
+pub fn sample_count(sample_rate: u32, duration_s: f64) -> usize {
+    (duration_s * sample_rate as f64).round() as usize
+}
+
+pub fn synthesize(signal: Signal, sample_rate: u32, count: usize, amplitude: f64) -> Vec<i16> {
+    let peak = amplitude.clamp(0.0, 1.0) * i16::MAX as f64;
+    let rate = sample_rate as f64;
+
+    match signal {
+        Signal::Silence => vec![0; count],
+        Signal::Sine { frequency } => (0..count).map(|i| quantize(peak * (2.0 * PI * frequency * i as f64 / rate).sin())).collect(),
+        Signal::Chirp { from, to } => {
+            let duration_s = count as f64 / rate;
+
+            (0..count)
+                .map(|i| {
+                    let t = i as f64 / rate;
+                    // phase is the integral of the instantaneous frequency, so the sweep has no jumps
+                    let phase = 2.0 * PI * (from * t + (to - from) * t * t / (2.0 * duration_s));
+                    quantize(peak * phase.sin())
+                })
+                .collect()
+        }
+        Signal::Noise { seed } => {
+            let mut state = seed;
+
+            (0..count)
+                .map(|_| {
+                    let uniform = (splitmix64(&mut state) >> 11) as f64 / (1u64 << 53) as f64;
+                    quantize(peak * (2.0 * uniform - 1.0))
+                })
+                .collect()
+        }
+    }
+}
+
+pub fn write_raw(w: &mut dyn Write, samples: &[i16]) -> io::Result<()> {
+    let bytes: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+    w.write_all(&bytes)
+}
+
+pub fn write_wav(w: &mut dyn Write, sample_rate: u32, samples: &[i16]) -> io::Result<()> {
+    let data_bytes = u32::try_from(samples.len() * 2)
+        .ok()
+        .filter(|bytes| bytes.checked_add(WAV_HEADER_BYTES - 8).is_some())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "too many samples for a WAV file, write raw PCM instead"))?;
+
+    let mut header = Vec::with_capacity(WAV_HEADER_BYTES as usize);
+    header.extend(b"RIFF");
+    header.extend((WAV_HEADER_BYTES - 8 + data_bytes).to_le_bytes());
+    header.extend(b"WAVE");
+    header.extend(b"fmt ");
+    header.extend(16u32.to_le_bytes());
+    // PCM, mono
+    header.extend(1u16.to_le_bytes());
+    header.extend(1u16.to_le_bytes());
+    header.extend(sample_rate.to_le_bytes());
+    header.extend((sample_rate * 2).to_le_bytes());
+    // block align and bits per sample
+    header.extend(2u16.to_le_bytes());
+    header.extend(16u16.to_le_bytes());
+    header.extend(b"data");
+    header.extend(data_bytes.to_le_bytes());
+
+    w.write_all(&header)?;
+    write_raw(w, samples)
+}
+
+fn quantize(sample: f64) -> i16 {
+    sample.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16
+}
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rms(samples: &[i16]) -> f64 {
+        (samples.iter().map(|&sample| (sample as f64).powi(2)).sum::<f64>() / samples.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn exact_lengths() {
+        assert_eq!(sample_count(16000, 5.0), 80_000);
+        assert_eq!(sample_count(44_100, 0.5), 22_050);
+        assert_eq!(sample_count(16000, 1.0 / 3.0), 5333);
+        assert_eq!(sample_count(8000, 0.0), 0);
+
+        for signal in [Signal::Sine { frequency: 440.0 }, Signal::Silence, Signal::Noise { seed: 1 }, Signal::Chirp { from: 100.0, to: 4000.0 }] {
+            assert_eq!(synthesize(signal, 16000, 12_345, 0.5).len(), 12_345);
+        }
+
+        let mut raw = Vec::new();
+        write_raw(&mut raw, &synthesize(Signal::Silence, 16000, 100, 0.5)).unwrap();
+        assert_eq!(raw.len(), 200);
+    }
+
+    #[test]
+    fn levels() {
+        let peak = 0.5 * i16::MAX as f64;
+        let close = |rms: f64, expected: f64| (rms - expected).abs() < expected * 0.01;
+
+        assert!(close(rms(&synthesize(Signal::Sine { frequency: 440.0 }, 16000, 16000, 0.5)), peak / 2f64.sqrt()));
+        assert!(close(rms(&synthesize(Signal::Chirp { from: 100.0, to: 4000.0 }, 16000, 16000, 0.5)), peak / 2f64.sqrt()));
+        assert!(close(rms(&synthesize(Signal::Noise { seed: 7 }, 16000, 160_000, 0.5)), peak / 3f64.sqrt()));
+        assert_eq!(rms(&synthesize(Signal::Silence, 16000, 16000, 0.5)), 0.0);
+
+        // full scale stays within range
+        let loud = synthesize(Signal::Noise { seed: 7 }, 16000, 16000, 2.0);
+        assert!(loud.iter().any(|&sample| sample > 32_000) && loud.iter().all(|&sample| sample > i16::MIN));
+        assert_eq!(synthesize(Signal::Sine { frequency: 440.0 }, 16000, 100, -1.0), vec![0; 100]);
+    }
+
+    #[test]
+    fn seeded_noise() {
+        let noise = |seed| synthesize(Signal::Noise { seed }, 16000, 1000, 0.5);
+
+        assert_eq!(noise(42), noise(42));
+        assert_ne!(noise(42), noise(43));
+        // pinned, the first splitmix64 output for seed 0 is 0xe220a8397b1dcdaf; a change to the generator shows up here rather than in fixtures
+        assert_eq!(noise(0)[..4], [12_560, -2244, -15_517, 15_429]);
+    }
+
+    #[test]
+    fn wav_header() {
+        let mut wav = Vec::new();
+        write_wav(&mut wav, 16000, &[1, -1, 0x1234]).unwrap();
+
+        #[rustfmt::skip]
+        let header: [u8; 44] = [
+            b'R', b'I', b'F', b'F', 42, 0, 0, 0, b'W', b'A', b'V', b'E',
+            b'f', b'm', b't', b' ', 16, 0, 0, 0,
+            1, 0, 1, 0, 0x80, 0x3e, 0, 0, 0, 0x7d, 0, 0, 2, 0, 16, 0,
+            b'd', b'a', b't', b'a', 6, 0, 0, 0,
+        ];
+        assert_eq!(wav[..44], header);
+        assert_eq!(wav[44..], [1, 0, 0xff, 0xff, 0x34, 0x12]);
+
+        let layout = crate::input::probe(&mut io::Cursor::new(&wav), wav.len() as u64).unwrap();
+        assert_eq!((layout.data_start, layout.frames(), layout.sample_rate), (44, 3, Some(16000)));
+    }
+}
@@ -0,0 +1,559 @@
+use crate::audio::{self, RateMonitor, SilenceTrimmer};
+use crate::error::{AsrError, Result};
+use crate::event::{ClientEvent, RateMismatch};
+use crate::offset::OffsetMap;
+use crate::output::Printer;
+use crate::spool::Spool;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+pub struct AudioReader {
+    pub audio_tx: mpsc::Sender<Vec<u8>>,
+    pub trimmer: Option<SilenceTrimmer>,
+    pub rate_monitor: Option<RateMonitor>,
+    pub strict_input: bool,
+    pub paused: Arc<AtomicBool>,
+    // outside --active-hours, kept apart so a resume does not unmute
+    pub muted: Arc<AtomicBool>,
+    pub offsets: Arc<Mutex<OffsetMap>>,
+    pub printer: Arc<Printer>,
+    pub spool: Option<Arc<Spool>>,
+}
+
+impl AudioReader {
+    // The bytes read in total, paused and muted ones included
+    pub fn run(mut self, mut input: impl Read) -> Result<u64> {
+        let mut buffer = [0u8; 8192];
+        let mut total = 0u64;
+
+        loop {
+            let n = input.read(&mut buffer).map_err(AsrError::AudioInput)?;
+
+            if n == 0 {
+                break
+            }
+
+            total += n as u64;
+
+            self.check_rate(n)?;
+
+            // paused or muted audio is still drained, and accounted as a gap so later offsets stay aligned
+            if self.paused.load(Ordering::Relaxed) || self.muted.load(Ordering::Relaxed) {
+                self.offsets.lock().unwrap().skipped(n);
+                continue
+            }
+
+            let audio_data = match &mut self.trimmer {
+                Some(trimmer) => trimmer.process(&buffer[..n]),
+                None => {
+                    self.offsets.lock().unwrap().sent(n);
+                    buffer[..n].to_vec()
+                }
+            };
+
+            if !audio_data.is_empty() && !self.send(audio_data) {
+                return Ok(total)
+            }
+        }
+
+        if let Some(audio_data) = self.trimmer.as_mut().map(SilenceTrimmer::finish).filter(|audio_data| !audio_data.is_empty()) {
+            self.send(audio_data);
+        }
+
+        Ok(total)
+    }
+
+    fn send(&self, audio_data: Vec<u8>) -> bool {
+        match &self.spool {
+            Some(spool) => spool.send(&self.audio_tx, audio_data),
+            None => self.audio_tx.blocking_send(audio_data).is_ok(),
+        }
+    }
+
+    fn check_rate(&mut self, bytes: usize) -> Result<()> {
+        let Some(monitor) = &mut self.rate_monitor else {
+            return Ok(())
+        };
+
+        let Some(measured) = monitor.observe(bytes, Instant::now()) else {
+            return Ok(())
+        };
+
+        let declared = monitor.declared();
+        let likely = audio::likely_sample_rate(measured);
+        let message = format!(
+            "input arrives at ~{measured} Hz but --sample-rate is {declared}; pass --sample-rate {likely} or resample with ffmpeg -ar {declared}"
+        );
+
+        if self.strict_input {
+            return Err(AsrError::AudioInput(io::Error::new(io::ErrorKind::InvalidData, message)))
+        }
+
+        self.printer.print(ClientEvent::RateMismatch(RateMismatch {
+            declared_rate: declared,
+            measured_rate: measured,
+            likely_rate: likely,
+            message,
+        }));
+
+        Ok(())
+    }
+}
+
+// Frames converted at a time when a file is not 16-bit mono already
+const MIXDOWN_FRAMES: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    S16,
+    F32,
+}
+
+// Where a file keeps its samples and how. Raw files are 16-bit mono from the first byte, WAV files say otherwise in their header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Layout {
+    pub data_start: u64,
+    pub data_bytes: u64,
+    pub channels: u16,
+    pub encoding: Encoding,
+    // None for raw files, which are taken to be at --sample-rate
+    pub sample_rate: Option<u32>,
+}
+
+impl Layout {
+    fn raw(len: u64) -> Self {
+        Self { data_start: 0, data_bytes: len, channels: 1, encoding: Encoding::S16, sample_rate: None }
+    }
+
+    pub fn bytes_per_frame(&self) -> u64 {
+        let bytes_per_sample = match self.encoding {
+            Encoding::S16 => 2,
+            Encoding::F32 => 4,
+        };
+
+        self.channels as u64 * bytes_per_sample
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.data_bytes / self.bytes_per_frame()
+    }
+}
+
+// The layout of a file, a WAV file by its RIFF header. Only 16-bit PCM and 32-bit float WAV files are taken.
+pub fn probe(file: &mut (impl Read + Seek), len: u64) -> io::Result<Layout> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut riff = [0u8; 12];
+
+    if len < 12 || file.read_exact(&mut riff).is_err() || &riff[..4] != b"RIFF" || &riff[8..] != b"WAVE" {
+        file.seek(SeekFrom::Start(0))?;
+        return Ok(Layout::raw(len))
+    }
+
+    let mut format = None;
+    let mut at = 12;
+
+    loop {
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header).map_err(|_| invalid("a WAV file without a data chunk".into()))?;
+        let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as u64;
+        at += 8;
+
+        match &header[..4] {
+            b"fmt " => {
+                let mut fmt = vec![0u8; size.clamp(16, 40) as usize];
+                file.read_exact(&mut fmt).map_err(|_| invalid("a WAV file with a truncated fmt chunk".into()))?;
+                file.seek(SeekFrom::Start(at + size + (size & 1)))?;
+
+                let u16_at = |i: usize| u16::from_le_bytes([fmt[i], fmt[i + 1]]);
+                // WAVE_FORMAT_EXTENSIBLE names the actual format in its subformat
+                let tag = match u16_at(0) {
+                    0xfffe if size >= 26 => u16_at(24),
+                    tag => tag,
+                };
+                let channels = u16_at(2);
+                let sample_rate = u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]);
+
+                let encoding = match (tag, u16_at(14)) {
+                    (1, 16) => Encoding::S16,
+                    (3, 32) => Encoding::F32,
+                    (tag, bits) => return Err(invalid(format!("a {bits}-bit WAV file of format {tag}, only 16-bit PCM and 32-bit float are supported"))),
+                };
+
+                if channels == 0 {
+                    return Err(invalid("a WAV file without channels".into()))
+                }
+
+                format = Some((channels, encoding, sample_rate));
+            }
+            b"data" => {
+                let Some((channels, encoding, sample_rate)) = format else {
+                    return Err(invalid("a WAV file with its data before the fmt chunk".into()))
+                };
+
+                // streamed WAV files leave the size unset or too large, the data then runs to the end
+                let data_bytes = size.min(len.saturating_sub(at));
+
+                return Ok(Layout { data_start: at, data_bytes, channels, encoding, sample_rate: Some(sample_rate) })
+            }
+            _ => {
+                file.seek(SeekFrom::Start(at + size + (size & 1)))?;
+            }
+        }
+
+        at += size + (size & 1);
+    }
+}
+
+pub fn layout(path: &Path) -> io::Result<Layout> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    probe(&mut file, len)
+}
+
+// Opens every file up front so a typo fails before connecting, then reads them back to back as 16-bit mono,
+// returning the layout of each. The first `skip` frames are seeked past, files that end before that are not read at all.
+pub fn open_files(paths: &[PathBuf], mut skip: u64) -> Result<(Box<dyn Read + Send>, Vec<Layout>)> {
+    let mut input: Box<dyn Read + Send> = Box::new(io::empty());
+    let mut layouts = Vec::with_capacity(paths.len());
+
+    for path in paths {
+        let failed = |err: io::Error| AsrError::AudioInput(io::Error::new(err.kind(), format!("{}: {err}", path.display())));
+        let mut file = File::open(path).map_err(failed)?;
+        let len = file.metadata().map_err(failed)?.len();
+        let layout = probe(&mut file, len).map_err(failed)?;
+        let frames = layout.frames();
+        layouts.push(layout);
+
+        if skip >= frames {
+            skip -= frames;
+            continue
+        }
+
+        file.seek(SeekFrom::Start(layout.data_start + skip * layout.bytes_per_frame())).map_err(failed)?;
+        let data = file.take((frames - skip) * layout.bytes_per_frame());
+        skip = 0;
+
+        input = match (layout.channels, layout.encoding) {
+            (1, Encoding::S16) => Box::new(input.chain(data)),
+            _ => Box::new(input.chain(Mixdown { inner: data, layout, raw: Vec::new(), pending: Vec::new(), at: 0 })),
+        };
+    }
+
+    Ok((input, layouts))
+}
+
+// A usage error for a --seek at or past the end of files with the given layouts
+pub fn check_seek(seek: Duration, sample_rate: u32, layouts: &[Layout]) -> std::result::Result<(), String> {
+    let frames: u64 = layouts.iter().map(Layout::frames).sum();
+
+    match frames_at(seek, sample_rate) {
+        0 => Ok(()),
+        skip if skip < frames => Ok(()),
+        _ => Err(format!("--seek {:.3} s is past the end of the input, which is {:.3} s long", seek.as_secs_f64(), frames as f64 / sample_rate as f64)),
+    }
+}
+
+// A file's samples as 16-bit mono, channels averaged and floats scaled. A frame cut off at the end is dropped.
+struct Mixdown<R> {
+    inner: R,
+    layout: Layout,
+    raw: Vec<u8>,
+    pending: Vec<u8>,
+    at: usize,
+}
+
+impl<R: Read> Read for Mixdown<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.at == self.pending.len() {
+            let bytes_per_frame = self.layout.bytes_per_frame() as usize;
+            self.raw.resize(MIXDOWN_FRAMES * bytes_per_frame, 0);
+
+            let mut n = 0;
+
+            while n < self.raw.len() {
+                match self.inner.read(&mut self.raw[n..]) {
+                    Ok(0) => break,
+                    Ok(read) => n += read,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(err) => return Err(err),
+                }
+            }
+
+            let layout = self.layout;
+            self.pending = self.raw[..n - n % bytes_per_frame].chunks_exact(bytes_per_frame).flat_map(|frame| mix(frame, layout).to_le_bytes()).collect();
+            self.at = 0;
+        }
+
+        let n = buf.len().min(self.pending.len() - self.at);
+        buf[..n].copy_from_slice(&self.pending[self.at..self.at + n]);
+        self.at += n;
+
+        Ok(n)
+    }
+}
+
+fn mix(frame: &[u8], layout: Layout) -> i16 {
+    let sum: f64 = match layout.encoding {
+        Encoding::S16 => frame.chunks_exact(2).map(|sample| i16::from_le_bytes([sample[0], sample[1]]) as f64).sum(),
+        Encoding::F32 => frame.chunks_exact(4).map(|sample| f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]) as f64 * i16::MAX as f64).sum(),
+    };
+
+    (sum / layout.channels as f64).round().clamp(i16::MIN as f64, i16::MAX as f64) as i16
+}
+
+// HH:MM:SS, MM:SS or seconds, each with an optional fraction, or milliseconds with an ms suffix
+pub fn parse_position(value: &str) -> std::result::Result<Duration, String> {
+    let invalid = || format!("`{value}` is not a position like 00:12:30, 750, 750.5 or 1500ms");
+    let value = value.trim();
+
+    if let Some(ms) = value.strip_suffix("ms") {
+        return ms.trim().parse::<u64>().map(Duration::from_millis).map_err(|_| invalid())
+    }
+
+    let mut seconds = 0.0;
+    let parts: Vec<&str> = value.strip_suffix('s').unwrap_or(value).split(':').collect();
+
+    for (i, part) in parts.iter().enumerate() {
+        let last = i == parts.len() - 1;
+        let number: f64 = match last {
+            true => part.parse().map_err(|_| invalid())?,
+            false => part.parse::<u64>().map_err(|_| invalid())? as f64,
+        };
+
+        // minutes and seconds after a colon stay below 60
+        if !number.is_finite() || number < 0.0 || i > 0 && number >= 60.0 {
+            return Err(invalid())
+        }
+
+        seconds = seconds * 60.0 + number;
+    }
+
+    match parts.len() {
+        1..=3 => Duration::try_from_secs_f64(seconds).map_err(|_| invalid()),
+        _ => Err(invalid()),
+    }
+}
+
+// The sample at a position, rounded to the nearest one
+pub fn frames_at(position: Duration, sample_rate: u32) -> u64 {
+    ((position.as_nanos() * sample_rate as u128 + 500_000_000) / 1_000_000_000) as u64
+}
+
+// The byte offset of that sample in the 16-bit mono audio sent
+pub fn bytes_at(position: Duration, sample_rate: u32) -> u64 {
+    frames_at(position, sample_rate) * 2
+}
+
+#[cfg(unix)]
+pub fn stdin_is_file() -> bool {
+    use std::os::fd::AsFd;
+
+    io::stdin()
+        .as_fd()
+        .try_clone_to_owned()
+        .map(File::from)
+        .and_then(|file| file.metadata())
+        .is_ok_and(|metadata| metadata.is_file())
+}
+
+#[cfg(windows)]
+pub fn stdin_is_file() -> bool {
+    use std::os::windows::io::AsHandle;
+
+    io::stdin()
+        .as_handle()
+        .try_clone_to_owned()
+        .map(File::from)
+        .and_then(|file| file.metadata())
+        .is_ok_and(|metadata| metadata.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 16000;
+
+    // A WAV file with a LIST chunk of odd size ahead of the data, so the data starts at neither 44 nor an even offset
+    fn wav(tag: u16, channels: u16, bits: u16, data: &[u8]) -> Vec<u8> {
+        let block_align = channels * bits / 8;
+        let mut fmt = Vec::new();
+        fmt.extend(tag.to_le_bytes());
+        fmt.extend(channels.to_le_bytes());
+        fmt.extend(RATE.to_le_bytes());
+        fmt.extend((RATE * block_align as u32).to_le_bytes());
+        fmt.extend(block_align.to_le_bytes());
+        fmt.extend(bits.to_le_bytes());
+
+        let mut wav = b"RIFF\0\0\0\0WAVE".to_vec();
+        wav.extend(b"fmt ");
+        wav.extend((fmt.len() as u32).to_le_bytes());
+        wav.extend(fmt);
+        wav.extend(b"LIST");
+        wav.extend(5u32.to_le_bytes());
+        wav.extend(b"INFO\0\0");
+        wav.extend(b"data");
+        wav.extend((data.len() as u32).to_le_bytes());
+        wav.extend(data);
+        // a trailing chunk is not audio
+        wav.extend(b"id3 \x02\0\0\0ab");
+        wav
+    }
+
+    // Sample n of every channel is n, as 16-bit or float
+    fn ramp_s16(frames: usize, channels: usize) -> Vec<u8> {
+        (0..frames).flat_map(|n| std::iter::repeat_n(n as i16, channels)).flat_map(i16::to_le_bytes).collect()
+    }
+
+    fn ramp_f32(frames: usize, channels: usize) -> Vec<u8> {
+        (0..frames).flat_map(|n| std::iter::repeat_n(n as f32 / i16::MAX as f32, channels)).flat_map(f32::to_le_bytes).collect()
+    }
+
+    fn write(name: &str, contents: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("qasr-test-{}-{name}", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    // The samples sent for the files, seeked and cut like --seek and --duration do
+    fn sent(paths: &[PathBuf], seek: Duration, duration: Option<Duration>) -> Vec<i16> {
+        let (input, _) = open_files(paths, frames_at(seek, RATE)).unwrap();
+        let mut bytes = Vec::new();
+
+        match duration {
+            Some(duration) => input.take(bytes_at(duration, RATE)).read_to_end(&mut bytes),
+            None => { input }.read_to_end(&mut bytes),
+        }
+        .unwrap();
+
+        bytes.chunks_exact(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])).collect()
+    }
+
+    fn remove(paths: &[PathBuf]) {
+        paths.iter().for_each(|path| {
+            let _ = std::fs::remove_file(path);
+        });
+    }
+
+    #[test]
+    fn positions() {
+        assert_eq!(parse_position("00:12:30"), Ok(Duration::from_secs(750)));
+        assert_eq!(parse_position("12:30.5"), Ok(Duration::from_millis(750_500)));
+        assert_eq!(parse_position("750"), Ok(Duration::from_secs(750)));
+        assert_eq!(parse_position("2.5s"), Ok(Duration::from_millis(2500)));
+        assert_eq!(parse_position("1500ms"), Ok(Duration::from_millis(1500)));
+        assert!(parse_position("1:60").is_err());
+        assert!(parse_position("1:2:3:4").is_err());
+        assert!(parse_position("-1").is_err());
+        assert!(parse_position("1.5ms").is_err());
+    }
+
+    #[test]
+    fn rounds_to_the_nearest_sample() {
+        assert_eq!(frames_at(Duration::from_secs(1), RATE), 16000);
+        // 1/32000 s is half a sample at 16 kHz
+        assert_eq!(frames_at(Duration::from_nanos(31_250), RATE), 1);
+        assert_eq!(frames_at(Duration::from_nanos(31_249), RATE), 0);
+        assert_eq!(bytes_at(Duration::from_millis(1500), RATE), 48_000);
+        assert_eq!(bytes_at(Duration::from_secs(1), 44_100), 88_200);
+    }
+
+    #[test]
+    fn raw_files() {
+        let path = write("raw.pcm", &ramp_s16(32_000, 1));
+        let samples = sent(std::slice::from_ref(&path), Duration::from_millis(500), Some(Duration::from_millis(250)));
+        remove(&[path]);
+
+        assert_eq!(samples.len(), 4000);
+        assert_eq!((samples[0], samples[samples.len() - 1]), (8000, 11_999));
+    }
+
+    #[test]
+    fn wav_data_chunk() {
+        let path = write("mono.wav", &wav(1, 1, 16, &ramp_s16(32_000, 1)));
+        let layout = layout(&path).unwrap();
+        let samples = sent(std::slice::from_ref(&path), Duration::from_secs(1), None);
+        remove(&[path]);
+
+        assert_eq!(layout, Layout { data_start: 58, data_bytes: 64_000, channels: 1, encoding: Encoding::S16, sample_rate: Some(RATE) });
+        // neither the header nor the chunk after the data is audio
+        assert_eq!(samples.len(), 16_000);
+        assert_eq!((samples[0], samples[samples.len() - 1]), (16_000, 31_999));
+    }
+
+    #[test]
+    fn stereo_wav() {
+        let path = write("stereo.wav", &wav(1, 2, 16, &ramp_s16(32_000, 2)));
+        let samples = sent(std::slice::from_ref(&path), Duration::from_millis(1250), Some(Duration::from_millis(500)));
+        remove(&[path]);
+
+        assert_eq!(samples.len(), 8000);
+        assert_eq!((samples[0], samples[samples.len() - 1]), (20_000, 27_999));
+    }
+
+    #[test]
+    fn float_wav() {
+        let path = write("float.wav", &wav(3, 2, 32, &ramp_f32(20_000, 2)));
+        let layout = layout(&path).unwrap();
+        let samples = sent(std::slice::from_ref(&path), Duration::from_millis(250), None);
+        remove(&[path]);
+
+        assert_eq!((layout.encoding, layout.bytes_per_frame(), layout.frames()), (Encoding::F32, 8, 20_000));
+        assert_eq!(samples.len(), 16_000);
+        assert_eq!((samples[0], samples[1], samples[samples.len() - 1]), (4000, 4001, 19_999));
+    }
+
+    #[test]
+    fn mixes_channels_down() {
+        let frames: Vec<u8> = [(1000i16, 3000i16), (-32768, -32768), (32767, 32767)].into_iter().flat_map(|(l, r)| [l.to_le_bytes(), r.to_le_bytes()]).flatten().collect();
+        let path = write("mix.wav", &wav(1, 2, 16, &frames));
+        let samples = sent(std::slice::from_ref(&path), Duration::ZERO, None);
+        remove(&[path]);
+
+        assert_eq!(samples, [2000, -32768, 32767]);
+    }
+
+    #[test]
+    fn seeks_across_files() {
+        let paths = [write("a.wav", &wav(1, 2, 16, &ramp_s16(8000, 2))), write("b.pcm", &ramp_s16(16_000, 1))];
+        let layouts = open_files(&paths, 0).unwrap().1;
+
+        // 0.75 s in is 4000 samples into the second file
+        let samples = sent(&paths, Duration::from_millis(750), Some(Duration::from_millis(100)));
+        let whole = sent(&paths, Duration::ZERO, None);
+        remove(&paths);
+
+        assert_eq!(layouts.iter().map(Layout::frames).collect::<Vec<_>>(), [8000, 16_000]);
+        assert_eq!(samples.len(), 1600);
+        assert_eq!((samples[0], samples[samples.len() - 1]), (4000, 5599));
+        assert_eq!((whole.len(), whole[7999], whole[8000]), (24_000, 7999, 0));
+    }
+
+    #[test]
+    fn seeks_past_the_end() {
+        let layouts = [Layout::raw(32_000), Layout { data_start: 44, data_bytes: 64_000, channels: 2, encoding: Encoding::S16, sample_rate: Some(RATE) }];
+
+        assert_eq!(check_seek(Duration::ZERO, RATE, &layouts), Ok(()));
+        assert_eq!(check_seek(Duration::from_millis(1999), RATE, &layouts), Ok(()));
+        assert_eq!(check_seek(Duration::from_secs(2), RATE, &layouts), Err("--seek 2.000 s is past the end of the input, which is 2.000 s long".into()));
+        assert!(check_seek(Duration::from_secs(60), RATE, &layouts).is_err());
+        assert!(check_seek(Duration::from_secs(1), RATE, &[]).is_err());
+    }
+
+    #[test]
+    fn rejects_other_wav_formats() {
+        let path = write("s24.wav", &wav(1, 1, 24, &[0; 6]));
+        let err = layout(&path).unwrap_err();
+        let truncated = write("truncated.wav", &wav(1, 1, 16, &[])[..40]);
+        let missing = layout(&truncated).unwrap_err();
+        remove(&[path, truncated]);
+
+        assert_eq!(err.to_string(), "a 24-bit WAV file of format 1, only 16-bit PCM and 32-bit float are supported");
+        assert_eq!(missing.to_string(), "a WAV file without a data chunk");
+    }
+}
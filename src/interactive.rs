@@ -0,0 +1,112 @@
+use crate::control::{Command, Control};
+use crate::protocol;
+use base64::Engine;
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+#[cfg(unix)]
+const TTY_PATHS: (&str, &str) = ("/dev/tty", "/dev/tty");
+#[cfg(windows)]
+const TTY_PATHS: (&str, &str) = ("CONIN$", "CONOUT$");
+
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Push-to-talk dictation on the controlling terminal, leaving stdout to the event stream
+pub struct Terminal {
+    input: File,
+    output: Mutex<File>,
+    clipboard: bool,
+    pending: Mutex<usize>,
+    drained: Condvar,
+}
+
+impl Terminal {
+    pub fn open(clipboard: bool) -> io::Result<Self> {
+        let (input_path, output_path) = TTY_PATHS;
+
+        Ok(Self {
+            input: File::open(input_path)?,
+            output: Mutex::new(OpenOptions::new().write(true).open(output_path)?),
+            clipboard,
+            pending: Mutex::new(0),
+            drained: Condvar::new(),
+        })
+    }
+
+    // Enter toggles between capturing and committing, EOF (Ctrl+D) waits for outstanding transcripts
+    pub fn run(&self, control_tx: mpsc::Sender<Control>, paused: &AtomicBool) -> io::Result<()> {
+        let mut recording = false;
+
+        self.say("Press Enter to start dictating, Ctrl+D to quit")?;
+
+        for line in BufReader::new(&self.input).lines() {
+            line?;
+
+            if recording {
+                self.commit(&control_tx, paused);
+                self.say("Transcribing...")?;
+            } else {
+                let _ = Command::Resume.apply_blocking(&control_tx, paused);
+                self.say("Recording, press Enter to stop")?;
+            }
+
+            recording = !recording;
+        }
+
+        if recording {
+            self.commit(&control_tx, paused);
+        }
+
+        let pending = self.pending.lock().unwrap();
+        let _ = self.drained.wait_timeout_while(pending, DRAIN_TIMEOUT, |pending| *pending > 0);
+
+        Ok(())
+    }
+
+    pub fn observe(&self, event: &Value) {
+        let transcript = match protocol::event_type(event) {
+            "conversation.item.input_audio_transcription.completed" => event["transcript"].as_str().unwrap_or_default(),
+            "conversation.item.input_audio_transcription.failed" => "[transcription failed]",
+            _ => return,
+        };
+
+        let _ = self.say(transcript);
+
+        if self.clipboard {
+            let _ = self.copy(transcript);
+        }
+
+        let mut pending = self.pending.lock().unwrap();
+        *pending = pending.saturating_sub(1);
+        self.drained.notify_all();
+    }
+
+    fn commit(&self, control_tx: &mpsc::Sender<Control>, paused: &AtomicBool) {
+        let _ = Command::Pause.apply_blocking(control_tx, paused);
+
+        // counted before sending, the transcript may arrive before apply_blocking returns
+        *self.pending.lock().unwrap() += 1;
+
+        if Command::Commit.apply_blocking(control_tx, paused).is_err() {
+            *self.pending.lock().unwrap() -= 1;
+        }
+    }
+
+    fn say(&self, line: &str) -> io::Result<()> {
+        writeln!(self.output.lock().unwrap(), "{line}")
+    }
+
+    // OSC 52 lets the terminal emulator set the clipboard, which also works over SSH
+    fn copy(&self, text: &str) -> io::Result<()> {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(text);
+        let mut output = self.output.lock().unwrap();
+
+        write!(output, "\x1b]52;c;{encoded}\x07")?;
+        output.flush()
+    }
+}
@@ -0,0 +1,200 @@
+use crate::results::{TurnResult, TurnResults};
+use log::{error, warn};
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Instant;
+
+// Every finished turn as a --format results line, synced as it is written, so a killed run loses at most the turn it was writing
+pub struct Journal {
+    file: File,
+    // its own, like TurnHistory, whatever the output format is
+    results: TurnResults,
+}
+
+impl Journal {
+    // Starts a new journal, or with resume, continues one and returns the turns already in it
+    pub fn open(path: &Path, resume: bool, mut results: TurnResults) -> io::Result<(Self, Vec<TurnResult>)> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(!resume).open(path)?;
+
+        let turns = match resume {
+            true => recover(&mut file, path)?,
+            false => Vec::new(),
+        };
+
+        results.continue_after(&turns);
+        file.seek(SeekFrom::End(0))?;
+
+        Ok((Self { file, results }, turns))
+    }
+
+    pub fn observe(&mut self, event: &Value, received_at: Instant) {
+        let Some(result) = self.results.observe(event, received_at) else {
+            return
+        };
+
+        // one write per line, a torn one can only ever be the last
+        if let Err(err) = self.file.write_all(format!("{result}\n").as_bytes()).and_then(|()| self.file.sync_data()) {
+            error!("Failed to journal turn {}: {err}", result.turn);
+        }
+    }
+
+    pub fn next_session(&mut self) {
+        self.results.next_session();
+    }
+}
+
+// Reads back the journaled turns. Whatever follows the last complete line is a write the crash cut off and is truncated away.
+fn recover(file: &mut File, path: &Path) -> io::Result<Vec<TurnResult>> {
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+
+    let mut turns = Vec::new();
+    let mut valid_len = 0;
+    let mut offset = 0;
+
+    for line in contents.split_inclusive(|&byte| byte == b'\n') {
+        offset += line.len();
+
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue
+        }
+
+        match (line.ends_with(b"\n"), serde_json::from_slice::<TurnResult>(line)) {
+            (true, Ok(turn)) => {
+                turns.push(turn);
+                valid_len = offset;
+            }
+            (_, result) => warn!("Skipping a damaged line at byte {} of {}: {}", offset - line.len(), path.display(), result.err().map_or("no newline at the end".into(), |err| err.to_string())),
+        }
+    }
+
+    if valid_len < contents.len() {
+        warn!("Truncating {} from {} to {valid_len} bytes, past its last complete turn", path.display(), contents.len());
+        file.set_len(valid_len as u64)?;
+        file.sync_all()?;
+    }
+
+    Ok(turns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    fn path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("qasr-test-journal-{name}-{}", std::process::id()))
+    }
+
+    fn results() -> TurnResults {
+        TurnResults::new(vec![(f64::MAX, "stdin".into())])
+    }
+
+    fn completed(item_id: &str, transcript: &str) -> Value {
+        json!({
+            "type": "conversation.item.input_audio_transcription.completed",
+            "item_id": item_id,
+            "audio_start_ms": 0,
+            "audio_end_ms": 1000,
+            "transcript": transcript,
+        })
+    }
+
+    fn texts(turns: &[TurnResult]) -> Vec<&str> {
+        turns.iter().filter_map(|turn| turn.text.as_deref()).collect()
+    }
+
+    // Two turns journaled by an earlier run
+    fn journaled(path: &Path) -> usize {
+        let (mut journal, turns) = Journal::open(path, false, results()).unwrap();
+        assert!(turns.is_empty());
+
+        journal.observe(&completed("a", "first"), Instant::now());
+        journal.observe(&json!({ "type": "input_audio_buffer.speech_started", "item_id": "b" }), Instant::now());
+        journal.observe(&completed("b", "second"), Instant::now());
+
+        std::fs::metadata(path).unwrap().len() as usize
+    }
+
+    #[test]
+    fn resumes_where_the_last_run_stopped() {
+        let path = path("resume");
+        journaled(&path);
+
+        let (mut journal, turns) = Journal::open(&path, true, results()).unwrap();
+        assert_eq!(texts(&turns), ["first", "second"]);
+        assert_eq!(turns.iter().map(|turn| turn.turn).collect::<Vec<_>>(), [0, 1]);
+
+        // numbered on in a session of its own, after what is there
+        journal.observe(&completed("c", "third"), Instant::now());
+        drop(journal);
+
+        let (_, turns) = Journal::open(&path, true, results()).unwrap();
+        assert_eq!(texts(&turns), ["first", "second", "third"]);
+        assert_eq!((turns[2].turn, turns[2].session_index), (2, 1));
+
+        // without resume it starts over
+        let (_, turns) = Journal::open(&path, false, results()).unwrap();
+        assert!(turns.is_empty());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn truncates_a_torn_tail() {
+        let path = path("torn");
+
+        // half a line, and a whole one the crash cut off before its newline
+        for torn in [r#"{"turn":2,"start_ms":0,"tex"#, r#"{"turn":2,"start_ms":0,"end_ms":1000,"session_index":0,"source":"stdin"}"#] {
+            let len = journaled(&path);
+            OpenOptions::new().append(true).open(&path).unwrap().write_all(torn.as_bytes()).unwrap();
+
+            let (mut journal, turns) = Journal::open(&path, true, results()).unwrap();
+            assert_eq!(texts(&turns), ["first", "second"]);
+            assert_eq!(std::fs::metadata(&path).unwrap().len() as usize, len);
+
+            // the next turn starts on a line of its own
+            journal.observe(&completed("c", "third"), Instant::now());
+            drop(journal);
+
+            let contents = std::fs::read_to_string(&path).unwrap();
+            assert_eq!(contents.lines().count(), 3);
+            assert!(contents.lines().all(|line| serde_json::from_str::<TurnResult>(line).is_ok()), "{contents}");
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn skips_damaged_lines_before_the_last_turn() {
+        let path = path("damaged");
+        journaled(&path);
+
+        let mut contents = std::fs::read_to_string(&path).unwrap();
+        contents.insert_str(contents.find('\n').unwrap() + 1, "\n{\"not\": \"a turn\"}\n\u{0}\u{0}\u{0}\n");
+        std::fs::write(&path, &contents).unwrap();
+
+        let (_, turns) = Journal::open(&path, true, results()).unwrap();
+        assert_eq!(texts(&turns), ["first", "second"]);
+        // nothing after the last complete turn, so nothing to truncate
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), contents);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn resumes_an_empty_or_missing_journal() {
+        let path = path("missing");
+        let _ = std::fs::remove_file(&path);
+
+        let (_, turns) = Journal::open(&path, true, results()).unwrap();
+        assert!(turns.is_empty());
+        assert!(path.exists());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
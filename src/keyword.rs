@@ -0,0 +1,121 @@
+use crate::generate;
+use crate::results::{self, TurnResult};
+use crate::template;
+use crate::text::{self, Folding};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// What a spoken trigger phrase does
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    // the last N seconds of sent audio and the turns within them, to a directory of their own
+    ExportLast(u32),
+    Mark,
+    Mute,
+    Unmute,
+}
+
+// An action by name, and the argument after its colon if it takes one
+struct Registered {
+    name: &'static str,
+    parse: fn(Option<&str>) -> Result<Action, String>,
+}
+
+// Every action --keyword-action knows. A new one is a variant above and an entry here.
+const REGISTRY: &[Registered] = &[
+    Registered { name: "export_last", parse: |arg| arg.and_then(|seconds| seconds.parse().ok()).filter(|&seconds| seconds > 0).map(Action::ExportLast).ok_or_else(|| "export_last needs a number of seconds, like export_last:60".into()) },
+    Registered { name: "mark", parse: |arg| no_argument("mark", arg, Action::Mark) },
+    Registered { name: "mute", parse: |arg| no_argument("mute", arg, Action::Mute) },
+    Registered { name: "unmute", parse: |arg| no_argument("unmute", arg, Action::Unmute) },
+];
+
+fn no_argument(name: &str, arg: Option<&str>, action: Action) -> Result<Action, String> {
+    match arg {
+        None => Ok(action),
+        Some(_) => Err(format!("{name} takes no argument")),
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::ExportLast(seconds) => write!(f, "export_last:{seconds}"),
+            Action::Mark => f.write_str("mark"),
+            Action::Mute => f.write_str("mute"),
+            Action::Unmute => f.write_str("unmute"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    // the phrase as given, naming the rule in events
+    pub phrase: String,
+    pub action: Action,
+    tokens: Vec<String>,
+}
+
+// phrase=action[:argument], as given to --keyword-action
+pub fn parse_rule(value: &str) -> Result<Rule, String> {
+    let (phrase, action) = value.rsplit_once('=').ok_or_else(|| format!("`{value}` is not a phrase=action pair"))?;
+    let tokens = tokens(phrase);
+
+    if tokens.is_empty() {
+        return Err(format!("`{value}` has no phrase to listen for"))
+    }
+
+    let (name, arg) = match action.trim().split_once(':') {
+        Some((name, arg)) => (name, Some(arg.trim())),
+        None => (action.trim(), None),
+    };
+
+    let Some(registered) = REGISTRY.iter().find(|registered| registered.name == name) else {
+        let names: Vec<&str> = REGISTRY.iter().map(|registered| registered.name).collect();
+        return Err(format!("`{name}` is not a keyword action, expected one of {}", names.join(", ")))
+    };
+
+    Ok(Rule { phrase: phrase.trim().into(), action: (registered.parse)(arg)?, tokens })
+}
+
+impl Rule {
+    // The phrase as a run of whole tokens, so "note that" is not found in "denote thatch"
+    pub fn matches(&self, transcript: &[String]) -> bool {
+        transcript.windows(self.tokens.len()).any(|window| window == self.tokens)
+    }
+}
+
+// The longest export_last window, the audio kept has to cover it
+pub fn export_window(rules: &[Rule]) -> Option<u32> {
+    rules
+        .iter()
+        .filter_map(|rule| match rule.action {
+            Action::ExportLast(seconds) => Some(seconds),
+            _ => None,
+        })
+        .max()
+}
+
+// The directory name of an export without a --name-template, the local time like keyword-20250101T093000
+pub fn default_name() -> PathBuf {
+    format!("keyword-{}", jiff::Zoned::now().strftime("%Y%m%dT%H%M%S")).into()
+}
+
+// Writes audio.wav and turns.json to a new directory at path, or next to it with a suffix if it is taken
+pub fn export(path: &Path, sample_rate: u32, audio: &[u8], turns: &[TurnResult]) -> io::Result<PathBuf> {
+    let path = template::create_dir_unique(path)?;
+
+    let samples: Vec<i16> = audio.chunks_exact(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])).collect();
+    let mut wav = BufWriter::new(File::create(path.join("audio.wav"))?);
+    generate::write_wav(&mut wav, sample_rate, &samples)?;
+    wav.flush()?;
+
+    results::export(turns, &path.join("turns.json"))?;
+    Ok(path)
+}
+
+// Text as rules match it, accents or not
+pub fn tokens(text: &str) -> Vec<String> {
+    text::tokens(text, Folding::Accents)
+}
@@ -0,0 +1,39 @@
+pub mod ab;
+pub mod audio;
+pub mod cache;
+pub mod capability;
+pub mod chaos;
+pub mod checksum;
+pub mod client;
+pub mod control;
+pub mod daemon;
+pub mod dedup;
+pub mod density;
+pub mod error;
+pub mod event;
+pub mod feedback;
+pub mod generate;
+pub mod input;
+pub mod interactive;
+pub mod journal;
+pub mod keyword;
+pub mod limiter;
+pub mod man;
+pub mod normalize;
+pub mod offset;
+pub mod output;
+pub mod protocol;
+pub mod reference;
+pub mod results;
+pub mod retry;
+pub mod route;
+pub mod schedule;
+pub mod sink;
+pub mod spool;
+pub mod subtitle;
+pub mod systemd;
+pub mod template;
+pub mod text;
+pub mod tty;
+pub mod watchdog;
+pub mod words;
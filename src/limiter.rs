@@ -0,0 +1,219 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, SemaphorePermit, TryAcquireError};
+
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+pub struct Limiter {
+    sessions: Option<Semaphore>,
+    requests: Option<TokenBucket>,
+    audio: Option<TokenBucket>,
+    backoff: Mutex<Backoff>,
+}
+
+impl Limiter {
+    pub fn new(max_sessions: Option<usize>, request_rate: Option<f64>, audio_hours_per_hour: Option<f64>) -> Self {
+        Self {
+            sessions: max_sessions.map(Semaphore::new),
+            requests: request_rate.map(|rate| TokenBucket::new(rate, rate.max(1.0))),
+            // audio is accounted in seconds, with up to one minute of quota as burst
+            audio: audio_hours_per_hour.map(|rate| TokenBucket::new(rate, rate * 60.0)),
+            backoff: Mutex::new(Backoff::default()),
+        }
+    }
+
+    pub async fn acquire_session(&self) -> Option<SemaphorePermit<'_>> {
+        match &self.sessions {
+            Some(semaphore) => semaphore.acquire().await.ok(),
+            None => None,
+        }
+    }
+
+    // Fails when every session is taken, instead of waiting for one
+    pub fn try_acquire_session(&self) -> Result<Option<SemaphorePermit<'_>>, TryAcquireError> {
+        match &self.sessions {
+            Some(semaphore) => semaphore.try_acquire().map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn acquire_request(&self) {
+        self.wait_backoff().await;
+
+        if let Some(bucket) = &self.requests {
+            bucket.acquire(1.0).await;
+        }
+    }
+
+    pub async fn acquire_audio(&self, seconds: f64) {
+        if let Some(bucket) = &self.audio {
+            bucket.acquire(seconds).await;
+        }
+    }
+
+    pub fn throttle(&self) -> Duration {
+        self.backoff.lock().unwrap().next(Instant::now())
+    }
+
+    pub fn backoff_attempts(&self) -> u32 {
+        self.backoff.lock().unwrap().attempts
+    }
+
+    async fn wait_backoff(&self) {
+        loop {
+            let until = self.backoff.lock().unwrap().until;
+
+            match until {
+                Some(until) if until > Instant::now() => tokio::time::sleep_until(until.into()).await,
+                _ => break,
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Backoff {
+    attempts: u32,
+    delay: Duration,
+    until: Option<Instant>,
+}
+
+impl Backoff {
+    // Doubles from BACKOFF_BASE up to BACKOFF_MAX, starting over once a whole delay passed after the last one ended
+    fn next(&mut self, now: Instant) -> Duration {
+        if self.until.is_some_and(|until| now > until + self.delay) {
+            self.attempts = 0;
+        }
+
+        self.delay = BACKOFF_BASE.saturating_mul(1 << self.attempts.min(6)).min(BACKOFF_MAX);
+        self.attempts += 1;
+        self.until = Some(now + self.delay);
+        self.delay
+    }
+}
+
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            rate,
+            capacity,
+            state: Mutex::new((capacity, Instant::now())),
+        }
+    }
+
+    async fn acquire(&self, amount: f64) {
+        while let Some(wait) = self.take(amount, Instant::now()) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    // Takes amount if the bucket holds it by now, or says how long until it will
+    fn take(&self, amount: f64, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+
+        *tokens = (*tokens + now.saturating_duration_since(*last).as_secs_f64() * self.rate).min(self.capacity);
+        *last = now.max(*last);
+
+        // requests larger than the bucket go through once it is full, leaving it in debt
+        if *tokens >= amount.min(self.capacity) {
+            *tokens -= amount;
+            return None
+        }
+
+        Some(Duration::from_secs_f64((amount.min(self.capacity) - *tokens) / self.rate))
+    }
+}
+
+// A positive rate, zero or less would never refill a bucket
+pub fn parse_rate(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        _ => Err(format!("`{value}` is not a positive rate")),
+    }
+}
+
+// At least one session, none would leave every run waiting
+pub fn parse_sessions(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(sessions) if sessions > 0 => Ok(sessions),
+        _ => Err(format!("`{value}` is not a positive number of sessions")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(seconds: f64) -> Duration {
+        Duration::from_secs_f64(seconds)
+    }
+
+    #[test]
+    fn refills_at_the_rate_up_to_capacity() {
+        let bucket = TokenBucket::new(2.0, 4.0);
+        let start = bucket.state.lock().unwrap().1;
+
+        for _ in 0..4 {
+            assert_eq!(bucket.take(1.0, start), None);
+        }
+
+        // empty, one token is half a second away
+        assert_eq!(bucket.take(1.0, start), Some(secs(0.5)));
+        assert_eq!(bucket.take(1.0, start + secs(0.5)), None);
+
+        // a long pause refills no further than capacity
+        assert_eq!(bucket.take(4.0, start + secs(60.0)), None);
+        assert_eq!(bucket.take(1.0, start + secs(60.0)), Some(secs(0.5)));
+    }
+
+    #[test]
+    fn waits_for_what_is_missing() {
+        let bucket = TokenBucket::new(10.0, 10.0);
+        let start = bucket.state.lock().unwrap().1;
+
+        assert_eq!(bucket.take(7.0, start), None);
+        assert_eq!(bucket.take(5.0, start), Some(secs(0.2)));
+        // still missing half of it
+        assert_eq!(bucket.take(5.0, start + secs(0.1)), Some(secs(0.1)));
+    }
+
+    #[test]
+    fn oversized_requests_go_through_a_full_bucket() {
+        let bucket = TokenBucket::new(1.0, 2.0);
+        let start = bucket.state.lock().unwrap().1;
+
+        assert_eq!(bucket.take(5.0, start), None);
+        // three tokens in debt, then two for a full bucket again
+        assert_eq!(bucket.take(5.0, start), Some(secs(5.0)));
+        assert_eq!(bucket.take(5.0, start + secs(5.0)), None);
+    }
+
+    #[test]
+    fn backs_off_exponentially_and_starts_over() {
+        let mut backoff = Backoff::default();
+        let start = Instant::now();
+
+        let delays: Vec<_> = (0..8).map(|_| backoff.next(start).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60]);
+
+        // within a delay of the last one ending it stays at the cap, a quiet delay after that starts over
+        assert_eq!(backoff.next(start + secs(119.0)), secs(60.0));
+        assert_eq!(backoff.next(start + secs(240.0)), secs(1.0));
+    }
+
+    #[test]
+    fn rejects_limits_that_never_let_anything_through() {
+        assert_eq!(parse_rate("0.5"), Ok(0.5));
+        assert!(["0", "-1", "inf", "NaN", "fast"].into_iter().all(|value| parse_rate(value).is_err()));
+        assert_eq!(parse_sessions("3"), Ok(3));
+        assert!(parse_sessions("0").is_err() && parse_sessions("-1").is_err());
+    }
+}
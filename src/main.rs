@@ -1,12 +1,59 @@
-use anyhow::Result;
-use base64::Engine;
-use clap::{CommandFactory, Parser};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
-use serde_json::json;
-use std::io::{self, IsTerminal, Read};
-use log::error;
+use jiff::tz::TimeZone;
+use qwen_asr::ab::{Comparison, Fanout, Variant};
+use qwen_asr::audio::{self, RateMonitor, SilenceTrimmer};
+use qwen_asr::cache::{self, Cache};
+use qwen_asr::capability;
+use qwen_asr::chaos::{Chaos, StallingReader};
+use qwen_asr::checksum::{self, ChecksumLog};
+use qwen_asr::client::{self, SessionConfig};
+use qwen_asr::control::{self, Control};
+use qwen_asr::daemon::{self, Daemon};
+use qwen_asr::dedup::{self, TurnDedup};
+use qwen_asr::density::DensityCheck;
+use qwen_asr::error::{AsrError, Result};
+use qwen_asr::event::{self, Check, ClientEvent, Capabilities, FeatureProbe, KeywordTriggered, Marker, ModelSwitched, ProtocolWarning, ScheduleChange, ServerSessionList, SessionInfo, SpoolRemaining, Throttled, TtyAllocated, VadInfo};
+use qwen_asr::feedback::{Beeper, Cue};
+use qwen_asr::generate::{self, Signal};
+use qwen_asr::input::{self, AudioReader};
+use qwen_asr::interactive::Terminal;
+use qwen_asr::journal::Journal;
+use qwen_asr::keyword::{self, Action, Rule};
+use qwen_asr::limiter::{self, Limiter};
+use qwen_asr::man;
+use qwen_asr::offset::OffsetMap;
+use qwen_asr::normalize;
+use qwen_asr::output::{FinishGuard, Printer};
+use qwen_asr::protocol::{self, Handshake, ServerSessions};
+use qwen_asr::reference;
+use qwen_asr::results::{TurnHistory, TurnResult, TurnResults};
+use qwen_asr::retry::{AudioHistory, TurnRetry, TurnSpans};
+use qwen_asr::route::Router;
+use qwen_asr::schedule::{self, Days, Schedule, Window};
+use qwen_asr::spool::{self, Spool};
+use qwen_asr::subtitle::{self, CueWriter, SubtitleFormat, Subtitles, Track};
+use qwen_asr::systemd::{self, Notifier};
+use qwen_asr::template::{self, Fields, Template};
+use qwen_asr::tty::TtyOut;
+use qwen_asr::watchdog::AckWatchdog;
+use serde_json::{json, Value};
+use std::io::{self, IsTerminal, Read, Write};
+use std::ops::ControlFlow;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use log::{debug, error, warn};
 use tokio::sync::{mpsc, oneshot};
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tokio::task::JoinSet;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::{self, Message};
+use url::Url;
 use uuid::Uuid;
 
 #[derive(Parser, Debug)]
@@ -29,144 +76,2637 @@ Usage examples (ffmpeg -> stdin):
 
 Environment:
   - Set DASHSCOPE_API_KEY via env or use --api-key
-"#
+"#,
+    args_conflicts_with_subcommands = true
 )]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    transcribe: Args,
+}
+
+#[derive(clap::Args, Debug)]
 struct Args {
+    /// DashScope API key
     #[arg(long, env = "DASHSCOPE_API_KEY")]
-    api_key: String,
-    #[arg(long, short, default_value = "qwen3-asr-flash-realtime")]
+    api_key: Option<String>,
+    /// ASR model to use
+    #[arg(long, short, env = "ASR_MODEL", default_value = "qwen3-asr-flash-realtime")]
     model: String,
-    #[arg(long, default_value = "wss://dashscope.aliyuncs.com/api-ws/v1/realtime")]
-    base_url: String,
-    #[arg(long, short, default_value_t = 16000)]
+    /// WebSocket endpoint
+    #[arg(long, env = "ASR_BASE_URL", default_value = "wss://dashscope.aliyuncs.com/api-ws/v1/realtime", value_parser = client::parse_base_url)]
+    base_url: Url,
+    /// Extra query parameter for the endpoint, as key=value (repeatable)
+    #[arg(long = "query", env = "ASR_QUERY", value_name = "KEY=VALUE", value_delimiter = ',', value_parser = client::parse_key_value)]
+    query: Vec<(String, String)>,
+    /// Audio sample rate in Hz
+    #[arg(long, short, env = "ASR_SAMPLE_RATE", default_value_t = 16000)]
     sample_rate: u32,
-    #[arg(long, short, default_value = "zh")]
+    /// Recognition language code
+    #[arg(long, short, env = "ASR_LANGUAGE", default_value = "zh")]
     language: String,
-    #[arg(long, default_value_t = 0.2)]
+    /// Switch models by the language the server detects, as comma-separated language=model pairs
+    #[arg(long, env = "ASR_LANGUAGE_ROUTE", value_name = "LANG=MODEL", value_delimiter = ',', value_parser = client::parse_key_value)]
+    language_route: Vec<(String, String)>,
+    /// Also transcribe with this model on a second session, tagging every event with its variant
+    #[arg(long, env = "ASR_AB_MODEL", value_name = "MODEL", conflicts_with_all = ["language_route", "format", "interactive", "turn_retries"])]
+    ab_model: Option<String>,
+    /// Emit a client.ab_diff event for every turn both models transcribed
+    #[arg(long, env = "ASR_AB_DIFF", requires = "ab_model")]
+    ab_diff: bool,
+    /// Minimum seconds between two model switches
+    #[arg(long, env = "ASR_ROUTE_MIN_INTERVAL_S", default_value_t = 30)]
+    route_min_interval_s: u64,
+    /// Transcript similarity from 0 to 1 at which a turn transcribed again after a model switch is dropped as a duplicate
+    #[arg(long, env = "ASR_DEDUP_SIMILARITY", default_value_t = 0.85, value_parser = dedup::parse_similarity)]
+    dedup_similarity: f64,
+    /// Voice activity detection threshold
+    #[arg(long, env = "ASR_VAD_THRESHOLD", default_value_t = 0.2)]
     vad_threshold: f32,
-    #[arg(long, default_value_t = 800)]
+    /// Silence duration in milliseconds for VAD
+    #[arg(long, env = "ASR_VAD_SILENCE_MS", default_value_t = 800)]
     vad_silence_ms: u32,
-    #[arg(short, long)]
+    /// Audio in milliseconds kept before detected speech, server default when unset
+    #[arg(long, env = "ASR_VAD_PREFIX_PADDING_MS")]
+    vad_prefix_padding_ms: Option<u32>,
+    /// Send VAD values outside the documented ranges instead of rejecting them
+    #[arg(long, env = "ASR_FORCE")]
+    force: bool,
+    /// Keep the session open after stdin reaches EOF
+    #[arg(short, long, env = "ASR_KEEP")]
     keep: bool,
+    /// Exit with 0 instead of 10 when the input ends before any audio
+    #[arg(long, env = "ASR_ALLOW_EMPTY")]
+    allow_empty: bool,
+    /// Tag the results with short_input when the whole input is shorter than this many milliseconds
+    #[arg(long, env = "ASR_MIN_AUDIO_MS", default_value_t = 100)]
+    min_audio_ms: u64,
+    /// Start this far into the files, as HH:MM:SS, seconds or 1500ms; offsets still count from the start of the files
+    #[arg(long, env = "ASR_SEEK", value_name = "POSITION", value_parser = input::parse_position, requires = "files")]
+    seek: Option<Duration>,
+    /// Stop after this much of the files, in the same forms as --seek
+    #[arg(long, env = "ASR_DURATION", value_name = "POSITION", value_parser = input::parse_position, requires = "files")]
+    duration: Option<Duration>,
+    /// Transcribe a single utterance, print it and exit
+    #[arg(long, env = "ASR_ONCE", conflicts_with_all = ["keep", "interactive", "ab_model", "turn_retries", "cache"])]
+    once: bool,
+    /// With --once, how long to wait for speech to start before exiting with code 9 and no output
+    #[arg(long, env = "ASR_ONCE_TIMEOUT_MS", default_value_t = 10000, requires = "once")]
+    once_timeout_ms: u64,
+    /// Only forward audio within this local time window, like 08:00-19:00 (repeatable)
+    #[arg(long, env = "ASR_ACTIVE_HOURS", value_name = "HH:MM-HH:MM", value_delimiter = ',', value_parser = schedule::parse_window)]
+    active_hours: Vec<Window>,
+    /// Days the --active-hours apply on, like mon-fri or sat,sun
+    #[arg(long, env = "ASR_ACTIVE_DAYS", requires = "active_hours", value_parser = schedule::parse_days)]
+    active_days: Option<Days>,
+    /// Time zone of --active-hours, like Asia/Shanghai, the system's by default
+    #[arg(long, env = "ASR_TIMEZONE", requires = "active_hours", value_parser = schedule::parse_timezone)]
+    timezone: Option<TimeZone>,
+    /// Close the session outside --active-hours and open a new one when the window opens again
+    #[arg(long, env = "ASR_SCHEDULE_DISCONNECT", requires = "active_hours", conflicts_with_all = ["once", "ab_model"])]
+    schedule_disconnect: bool,
+    /// Maximum concurrent upstream sessions
+    #[arg(long, env = "ASR_MAX_CONCURRENT_SESSIONS", value_parser = limiter::parse_sessions)]
+    max_concurrent_sessions: Option<usize>,
+    /// Audio quota, sent audio hours per hour
+    #[arg(long, env = "ASR_MAX_AUDIO_HOURS_PER_HOUR", value_parser = limiter::parse_rate)]
+    max_audio_hours_per_hour: Option<f64>,
+    /// Maximum outgoing messages per second
+    #[arg(long, env = "ASR_REQUEST_RATE", value_parser = limiter::parse_rate)]
+    request_rate: Option<f64>,
+    /// Do not emit the client.session_info event
+    #[arg(long, env = "ASR_NO_SESSION_INFO")]
+    no_session_info: bool,
+    /// Keep the server-assigned session ids in this file, one per line, for support tickets
+    #[arg(long, env = "ASR_SESSION_ID_FILE", value_name = "PATH")]
+    session_id_file: Option<PathBuf>,
+    /// Also translate the transcripts into this language
+    #[arg(long, env = "ASR_TRANSLATE_TO")]
+    translate_to: Option<String>,
+    /// Only emit translations, dropping the transcription events
+    #[arg(long, env = "ASR_TRANSLATE_ONLY", requires = "translate_to")]
+    translate_only: bool,
+    /// Inverse text normalization, server default when unset
+    #[arg(long, env = "ASR_ITN")]
+    itn: Option<Toggle>,
+    /// Punctuation prediction, server default when unset
+    #[arg(long, env = "ASR_PUNCTUATION")]
+    punctuation: Option<Toggle>,
+    /// Compress long silences before upload, keeping output offsets on the source timeline
+    #[arg(long, env = "ASR_TRIM_SILENCE")]
+    trim_silence: bool,
+    /// Minimum silence length in milliseconds that gets compressed
+    #[arg(long, env = "ASR_TRIM_THRESHOLD_MS", default_value_t = 2000, requires = "trim_silence")]
+    trim_threshold_ms: u32,
+    /// Level in dBFS at or below which a frame counts as silence, `asr calibrate` recommends one
+    #[arg(long, env = "ASR_TRIM_SILENCE_DB", default_value_t = audio::SILENCE_DBFS, allow_negative_numbers = true, requires = "trim_silence")]
+    trim_silence_db: f32,
+    /// Milliseconds of a trimmed silence still sent ahead of the speech that ends it
+    #[arg(long, env = "ASR_PREROLL_MS", default_value_t = 300, requires = "trim_silence")]
+    preroll_ms: u32,
+    /// Output protocol events as they arrive, one combined line per finished turn, or SRT or karaoke ASS subtitles
+    #[arg(long, env = "ASR_FORMAT", value_enum, default_value_t = Format::Events)]
+    format: Format,
+    /// Rename server event types onto a canonical set shared by all providers, keeping the original as original_type
+    #[arg(long, env = "ASR_NORMALIZE_EVENTS")]
+    normalize_events: bool,
+    /// Write --format srt or ass tracks to files instead of stdout, as comma-separated track=path pairs
+    #[arg(long, env = "ASR_SUBTITLE_OUT", value_name = "TRACK=PATH", value_delimiter = ',', value_parser = subtitle::parse_track, conflicts_with = "cache")]
+    subtitle_out: Vec<(Track, PathBuf)>,
+    /// Translation cue for a turn whose translation never arrived
+    #[arg(long, env = "ASR_SUBTITLE_FALLBACK", value_enum, default_value_t = SubtitleFallback::Mark)]
+    subtitle_fallback: SubtitleFallback,
+    /// Prefix each output line with its RFC3339 receive time and a tab
+    #[arg(long, env = "ASR_TIMESTAMPS")]
+    timestamps: bool,
+    /// Escape every non-ASCII character in the JSON output as \uXXXX
+    #[arg(long, env = "ASR_ASCII_JSON")]
+    ascii_json: bool,
+    /// Sync the output to disk after every finished turn, when stdout is a file
+    #[arg(long, env = "ASR_DURABLE")]
+    durable: bool,
+    /// Log the length, CRC32 and sample offset of every audio chunk sent to this file
+    #[arg(long, env = "ASR_AUDIO_CHECKSUMS", value_name = "FILE")]
+    audio_checksums: Option<PathBuf>,
+    /// Compare the transcript with this reference, plain text or --format results lines, and report the error rate
+    #[arg(long, env = "ASR_REFERENCE", value_name = "PATH", conflicts_with = "cache")]
+    reference: Option<PathBuf>,
+    /// Also align each turn with its part of the reference
+    #[arg(long, env = "ASR_REFERENCE_DETAIL", requires = "reference")]
+    reference_detail: bool,
+    /// Characters per second below which a final transcript is tagged low_density, 1 for CJK text and 2 otherwise when unset
+    #[arg(long, env = "ASR_MIN_CPS")]
+    min_cps: Option<f64>,
+    /// Characters per second above which a final transcript is tagged high_density, 10 for CJK text and 25 otherwise when unset
+    #[arg(long, env = "ASR_MAX_CPS")]
+    max_cps: Option<f64>,
+    /// Also write finalized text, one plain line per utterance, to this terminal, or to a new one with `auto`
+    #[arg(long, env = "ASR_TTY_OUT", value_name = "PATH", conflicts_with = "cache")]
+    tty_out: Option<PathBuf>,
+    /// Warn with a client.server_lagging event when the server falls this many seconds behind the speech sent to it, like 60
+    #[arg(long, env = "ASR_ACK_LAG_S", value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "files")]
+    ack_lag_s: Option<u64>,
+    /// Exit once the server falls behind by --ack-lag-s, instead of only warning
+    #[arg(long, env = "ASR_ACK_LAG_FATAL", requires = "ack_lag_s")]
+    ack_lag_fatal: bool,
+    /// Fail instead of warning when the input rate contradicts --sample-rate
+    #[arg(long, env = "ASR_STRICT_INPUT")]
+    strict_input: bool,
+    /// Retry a turn whose transcription failed up to this many times, on a separate session
+    #[arg(long, env = "ASR_TURN_RETRIES", default_value_t = 0)]
+    turn_retries: u32,
+    /// Seconds of sent audio kept for turn retries
+    #[arg(long, env = "ASR_RETRY_BUFFER_S", default_value_t = 30)]
+    retry_buffer_s: u32,
+    /// Audible cue on end of speech and on each final transcript
+    #[arg(long, env = "ASR_FEEDBACK", value_enum, default_value_t = Feedback::None)]
+    feedback: Feedback,
+    /// Volume of the feedback cues, from 0.0 to 1.0
+    #[arg(long, env = "ASR_FEEDBACK_VOLUME", default_value_t = 0.3)]
+    feedback_volume: f32,
+    /// Dictate on the controlling terminal: Enter starts a capture, Enter again commits it
+    #[arg(long, env = "ASR_INTERACTIVE")]
+    interactive: bool,
+    /// Copy each interactive transcript to the clipboard through the terminal (OSC 52)
+    #[arg(long, env = "ASR_CLIPBOARD", requires = "interactive")]
+    clipboard: bool,
+    /// Spill audio that does not fit into the send queue to numbered files in this directory
+    #[arg(long, env = "ASR_SPOOL", value_name = "DIR")]
+    spool: Option<PathBuf>,
+    /// Disk cap for the spool in MiB, the oldest audio is dropped beyond it
+    #[arg(long, env = "ASR_SPOOL_MAX_MB", default_value_t = 512, requires = "spool")]
+    spool_max_mb: u64,
+    /// Send the audio an earlier run left in the spool directory first
+    #[arg(long, env = "ASR_SPOOL_RESUME", requires = "spool")]
+    spool_resume: bool,
+    /// Reuse the results of an earlier run over the same files and options from this directory
+    #[arg(long, env = "ASR_CACHE", value_name = "DIR")]
+    cache: Option<PathBuf>,
+    /// Transcribe even on a cache hit, replacing the cached results
+    #[arg(long, env = "ASR_CACHE_BUST", requires = "cache")]
+    cache_bust: bool,
+    /// Finished turns kept in memory for the export and history control commands, 0 disables
+    #[arg(long, env = "ASR_HISTORY_TURNS", default_value_t = 1000)]
+    history_turns: usize,
+    /// Append every finished turn to this NDJSON file, synced as it is written
+    #[arg(long, env = "ASR_JOURNAL", value_name = "PATH")]
+    journal: Option<PathBuf>,
+    /// Continue the --journal of an earlier run, counting its turns in the output and numbering on from them
+    #[arg(long, env = "ASR_JOURNAL_RESUME", requires = "journal")]
+    journal_resume: bool,
+    /// Fail unless systemd's NOTIFY_SOCKET is set; readiness, status and stopping are reported whenever it is
+    #[arg(long, env = "ASR_SD_NOTIFY")]
+    sd_notify: bool,
+    /// Act on a phrase said in a final transcript, as phrase=action with export_last:SECONDS, mark, mute or unmute (repeatable)
+    #[arg(long, env = "ASR_KEYWORD_ACTION", value_name = "PHRASE=ACTION", value_delimiter = ',', value_parser = keyword::parse_rule, conflicts_with = "cache")]
+    keyword_action: Vec<Rule>,
+    /// Where export_last keyword actions create their directories
+    #[arg(long, env = "ASR_KEYWORD_EXPORT_DIR", default_value = ".")]
+    keyword_export_dir: PathBuf,
+    /// Name export_last directories under --keyword-export-dir after the turn that triggered them, like {date}/{session}/{turn:04}-{start_ms}
+    #[arg(long, env = "ASR_NAME_TEMPLATE", value_name = "TEMPLATE", value_parser = template::parse_template, requires = "keyword_action")]
+    name_template: Option<Template>,
+    /// Read newline-delimited JSON control commands from this inherited file descriptor
+    #[arg(long, env = "ASR_CONTROL_FD", value_parser = clap::value_parser!(i32).range(0..))]
+    control_fd: Option<i32>,
+    /// Largest server message accepted, like 16M, so a runaway frame cannot exhaust memory
+    #[arg(long, env = "ASR_MAX_MESSAGE_SIZE", default_value = "16M", value_parser = cache::parse_size)]
+    max_message_size: u64,
+    /// Only check that a session can be set up: connect, configure it, print a client.check event and exit
+    #[arg(long, env = "ASR_CHECK")]
+    check: bool,
+    /// Send a generated tone through a real session, print PASS or FAIL with timings to stderr and exit
+    #[arg(long, env = "ASR_SELF_TEST", conflicts_with = "check")]
+    self_test: bool,
+    /// Try each optional setting on a session of its own, print a client.capabilities event and exit
+    #[arg(long, env = "ASR_PROBE_CAPABILITIES", conflicts_with_all = ["check", "self_test"])]
+    probe_capabilities: bool,
+    /// With ASR_CHAOS=1, drop the connection this many seconds after it opened
+    #[arg(long, env = "ASR_CHAOS_DROP_CONNECTION_AFTER_S", hide = true)]
+    chaos_drop_connection_after_s: Option<u64>,
+    /// With ASR_CHAOS=1, hold every server event back this many milliseconds
+    #[arg(long, env = "ASR_CHAOS_DELAY_EVENTS_MS", hide = true)]
+    chaos_delay_events_ms: Option<u64>,
+    /// With ASR_CHAOS=1, flip a byte in every Nth outgoing audio frame
+    #[arg(long, env = "ASR_CHAOS_CORRUPT_EVERY_N", hide = true)]
+    chaos_corrupt_every_n: Option<u64>,
+    /// With ASR_CHAOS=1, stop reading the audio input this many seconds in
+    #[arg(long, env = "ASR_CHAOS_STALL_STDIN_AFTER_S", hide = true)]
+    chaos_stall_stdin_after_s: Option<u64>,
+    /// Print every resolved option with where its value came from, then exit
+    #[arg(long)]
+    print_config: bool,
+    /// Raw PCM or WAV files to transcribe one after another instead of stdin
+    files: Vec<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct DaemonArgs {
+    /// Address to accept clients on, as unix:PATH
+    #[arg(long, env = "ASR_LISTEN", value_parser = daemon::parse_listen)]
+    listen: PathBuf,
+    /// DashScope API key
+    #[arg(long, env = "DASHSCOPE_API_KEY")]
+    api_key: Option<String>,
+    /// ASR model to use
+    #[arg(long, short, env = "ASR_MODEL", default_value = "qwen3-asr-flash-realtime")]
+    model: String,
+    /// WebSocket endpoint
+    #[arg(long, env = "ASR_BASE_URL", default_value = "wss://dashscope.aliyuncs.com/api-ws/v1/realtime", value_parser = client::parse_base_url)]
+    base_url: Url,
+    /// Extra query parameter for the endpoint, as key=value (repeatable)
+    #[arg(long = "query", env = "ASR_QUERY", value_name = "KEY=VALUE", value_delimiter = ',', value_parser = client::parse_key_value)]
+    query: Vec<(String, String)>,
+    /// Sample rate in Hz of clients that do not send one
+    #[arg(long, short, env = "ASR_SAMPLE_RATE", default_value_t = 16000)]
+    sample_rate: u32,
+    /// Recognition language code of clients that do not send one
+    #[arg(long, short, env = "ASR_LANGUAGE", default_value = "zh")]
+    language: String,
+    /// Voice activity detection threshold
+    #[arg(long, env = "ASR_VAD_THRESHOLD", default_value_t = 0.2)]
+    vad_threshold: f32,
+    /// Silence duration in milliseconds for VAD
+    #[arg(long, env = "ASR_VAD_SILENCE_MS", default_value_t = 800)]
+    vad_silence_ms: u32,
+    /// Maximum concurrent upstream sessions, clients beyond it are turned away
+    #[arg(long, env = "ASR_MAX_CONCURRENT_SESSIONS", value_parser = limiter::parse_sessions)]
+    max_concurrent_sessions: Option<usize>,
+    /// Audio quota, sent audio hours per hour across all clients
+    #[arg(long, env = "ASR_MAX_AUDIO_HOURS_PER_HOUR", value_parser = limiter::parse_rate)]
+    max_audio_hours_per_hour: Option<f64>,
+    /// Maximum outgoing messages per second across all clients
+    #[arg(long, env = "ASR_REQUEST_RATE", value_parser = limiter::parse_rate)]
+    request_rate: Option<f64>,
+    /// Largest server message accepted, like 16M
+    #[arg(long, env = "ASR_MAX_MESSAGE_SIZE", default_value = "16M", value_parser = cache::parse_size)]
+    max_message_size: u64,
+    /// Fail unless systemd's NOTIFY_SOCKET is set; readiness, status and stopping are reported whenever it is
+    #[arg(long, env = "ASR_SD_NOTIFY")]
+    sd_notify: bool,
+}
+
+// Hands the writer a replacement session and where on the sent timeline it starts.
+// Without one, --schedule-disconnect closed the session until the window opens again.
+struct Switch {
+    sink: Option<SplitSink<client::WsStream, Message>>,
+    start_ms: f64,
+}
+
+// Where --schedule-disconnect has the upstream session
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Upstream {
+    Connected,
+    // session.finish went out, the last results are still coming in
+    Finishing,
+    Disconnected,
+}
+
+const REPLAY_CHUNK_BYTES: usize = 8192;
+
+// Audio chunks the B session may lag behind before its audio gets dropped
+const AB_QUEUE_CHUNKS: usize = 128;
+
+// How long --check waits for session.updated
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A sweep across the voice band for --self-test, and how long the server gets to answer its commit
+const SELF_TEST_SIGNAL: Signal = Signal::Chirp { from: 300.0, to: 3400.0 };
+const SELF_TEST_DURATION_S: f64 = 3.0;
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(20);
+
+const UNTRANSLATED_MARKER: &str = "[untranslated]";
+
+// Sessions reopened after frames the stream could not get past without a turn finished in between, before the run
+// gives up on the server
+const MAX_FRAME_RECOVERIES: u32 = 3;
+
+// How long the server gets to answer session.finish with the last results
+const FINISH_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How often the --ack-lag-s watchdog compares sent audio with what the server referred to
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+// How often systemd is told how far the run got, for systemctl status
+const SD_STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+// Longest --active-hours wait before the schedule is looked at again, in case the wall clock jumped
+const SCHEDULE_RECHECK: Duration = Duration::from_secs(60);
+
+// Silence kept in a trimmed gap on top of the VAD silence duration, so turns still end server-side
+const TRIM_BRIDGE_MARGIN_MS: u32 = 200;
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Toggle {
+    On,
+    Off,
+}
+
+impl Toggle {
+    fn enabled(self) -> bool {
+        matches!(self, Toggle::On)
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Events,
+    Results,
+    Srt,
+    Ass,
+}
+
+impl Format {
+    fn subtitles(self) -> Option<SubtitleFormat> {
+        match self {
+            Format::Srt => Some(SubtitleFormat::Srt),
+            Format::Ass => Some(SubtitleFormat::Ass),
+            Format::Events | Format::Results => None,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum ConnectFormat {
+    Events,
+    Results,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum SubtitleFallback {
+    Mark,
+    Skip,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Feedback {
+    Beep,
+    None,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Pattern {
+    Sine,
+    Silence,
+    Noise,
+    Chirp,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[command(about = "Transcribe audio from stdin or files, the default when no subcommand is given")]
+    Transcribe(Box<Args>),
+    #[command(about = "Print the JSON Schema of the client.* events")]
+    Schema,
+    #[command(about = "Print the asr(1) man page in roff format")]
+    Man,
+    #[command(about = "Print a shell completion script")]
+    Completions { shell: Shell },
+    #[command(about = "Manage the --cache directory")]
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+    #[command(about = "Check audio against an --audio-checksums log and report the first chunk that differs")]
+    VerifyChecksums {
+        /// Log written by --audio-checksums
+        #[arg(long, env = "ASR_AUDIO_CHECKSUMS")]
+        log: PathBuf,
+        /// Raw PCM or WAV files as they were sent, stdin if none are given
+        files: Vec<PathBuf>,
+    },
+    #[command(about = "Measure the noise floor of ambient audio on stdin and recommend --trim-silence-db")]
+    Calibrate {
+        /// Seconds of audio to measure
+        #[arg(long, env = "ASR_CALIBRATE_SECONDS", default_value_t = 5)]
+        seconds: u32,
+        /// Audio sample rate in Hz
+        #[arg(long, short, env = "ASR_SAMPLE_RATE", default_value_t = 16000)]
+        sample_rate: u32,
+        /// Print the report as JSON
+        #[arg(long, env = "ASR_CALIBRATE_JSON")]
+        json: bool,
+    },
+    #[command(about = "Serve transcriptions to `asr connect` clients, each on its own upstream session")]
+    Daemon(Box<DaemonArgs>),
+    #[command(about = "Transcribe stdin through a running `asr daemon`")]
+    Connect {
+        /// Socket the daemon listens on, as a path or unix:PATH
+        #[arg(env = "ASR_DAEMON_SOCKET")]
+        socket: String,
+        /// Recognition language code, the daemon's default when unset
+        #[arg(long, short, env = "ASR_LANGUAGE")]
+        language: Option<String>,
+        /// Audio sample rate in Hz, the daemon's default when unset
+        #[arg(long, short, env = "ASR_SAMPLE_RATE")]
+        sample_rate: Option<u32>,
+        /// Output protocol events as they arrive, or one combined line per finished turn
+        #[arg(long, env = "ASR_CONNECT_FORMAT", value_enum, default_value_t = ConnectFormat::Events)]
+        format: ConnectFormat,
+    },
+    #[command(about = "Write a test signal as raw PCM or WAV, no microphone needed")]
+    GenAudio {
+        /// Signal to generate
+        #[arg(long, env = "ASR_GEN_AUDIO_PATTERN", alias = "text-pattern", value_enum, default_value = "sine")]
+        pattern: Pattern,
+        /// Frequency in Hz of the sine, or where the chirp starts
+        #[arg(long, env = "ASR_GEN_AUDIO_FREQ", default_value_t = 440.0)]
+        freq: f64,
+        /// Frequency in Hz the chirp ends at
+        #[arg(long, env = "ASR_GEN_AUDIO_END_FREQ", default_value_t = 4000.0)]
+        end_freq: f64,
+        /// Duration in seconds
+        #[arg(long, env = "ASR_GEN_AUDIO_DURATION_S", default_value_t = 5.0)]
+        duration_s: f64,
+        /// Sample rate in Hz
+        #[arg(long, env = "ASR_GEN_AUDIO_RATE", alias = "sample-rate", default_value_t = 16000)]
+        rate: u32,
+        /// Peak amplitude from 0 to 1
+        #[arg(long, env = "ASR_GEN_AUDIO_AMPLITUDE", default_value_t = 0.5)]
+        amplitude: f64,
+        /// Seed of the noise generator
+        #[arg(long, env = "ASR_GEN_AUDIO_SEED", default_value_t = 0)]
+        seed: u64,
+        /// Write a WAV file instead of raw PCM, the default for a .wav output
+        #[arg(long, env = "ASR_GEN_AUDIO_WAV")]
+        wav: bool,
+        /// Output file, stdout if not given
+        #[arg(short, long, env = "ASR_GEN_AUDIO_OUTPUT")]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheCommand {
+    #[command(about = "Remove the least recently used entries until the cache fits into --max-size")]
+    Gc {
+        /// Cache directory
+        #[arg(long, env = "ASR_CACHE", value_name = "DIR")]
+        cache: PathBuf,
+        /// Size to shrink the cache to, like 500M or 2G
+        #[arg(long, env = "ASR_CACHE_MAX_SIZE", value_parser = cache::parse_size)]
+        max_size: u64,
+    },
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
-    
-    if io::stdin().is_terminal() {
-        Args::command().print_help()?;
-        std::process::exit(0);
+async fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+
+    let (args, matches) = match cli.command {
+        Some(Command::Transcribe(args)) => (*args, matches.subcommand_matches("transcribe").unwrap()),
+        None => (cli.transcribe, &matches),
+        Some(Command::Daemon(args)) => {
+            if let Err(err) = run_daemon(*args).await {
+                eprintln!("asr: {err}");
+                std::process::exit(err.exit_code());
+            }
+            return;
+        }
+        Some(command) => {
+            if let Err(err) = run_command(command) {
+                eprintln!("asr: {err}");
+                std::process::exit(err.exit_code());
+            }
+            return;
+        }
+    };
+
+    if args.print_config {
+        if let Err(err) = print_stdout(resolved_config(matches)) {
+            eprintln!("asr: {err}");
+            std::process::exit(err.exit_code());
+        }
+        return;
     }
-    
-    let url = format!("{}?model={}", args.base_url, args.model);
-
-    let request = tokio_tungstenite::tungstenite::http::Request::builder()
-        .uri(&url)
-        .header("Authorization", format!("Bearer {}", args.api_key))
-        .header("OpenAI-Beta", "realtime=v1")
-        .header("Host", "dashscope.aliyuncs.com")
-        .header("Connection", "Upgrade")
-        .header("Upgrade", "websocket")
-        .header("Sec-WebSocket-Version", "13")
-        .header("Sec-WebSocket-Key", tokio_tungstenite::tungstenite::handshake::client::generate_key())
-        .body(())?;
-
-    let (ws_stream, _) = connect_async(request).await?;
-    let (mut message_tx, mut message_rx) = ws_stream.split();
 
-    let (audio_tx, mut audio_rx) = mpsc::channel::<Vec<u8>>(128);
+    let server_sessions = Arc::new(ServerSessions::new(args.session_id_file.clone()));
 
-    // Send session configuration
-    let session_update = json!({
-        "event_id": Uuid::now_v7().to_string(),
-        "type": "session.update",
-        "session": {
-            "modalities": ["text"],
-            "input_audio_format": "pcm",
-            "sample_rate": args.sample_rate,
-            "input_audio_transcription": {
-                "language": args.language
-            },
-            "turn_detection": {
-                "type": "server_vad",
-                "threshold": args.vad_threshold,
-                "silence_duration_ms": args.vad_silence_ms
+    if let Err(err) = run(args, server_sessions.clone()).await {
+        match server_sessions.current() {
+            Some(id) => eprintln!("asr: {err} (server session {id})"),
+            None => eprintln!("asr: {err}"),
+        }
+        std::process::exit(err.exit_code());
+    }
+}
+
+fn run_command(command: Command) -> Result<()> {
+    match command {
+        Command::Schema => print_stdout(serde_json::to_string_pretty(&event::schema())?)?,
+        Command::Man => man::render(Cli::command(), &mut io::stdout())?,
+        Command::Completions { shell } => clap_complete::generate(shell, &mut Cli::command(), "asr", &mut io::stdout()),
+        Command::Cache { command: CacheCommand::Gc { cache, max_size } } => print_stdout(Cache::new(&cache).gc(max_size)?)?,
+        Command::VerifyChecksums { log, files } => {
+            let mut input: Box<dyn io::Read + Send> = match files.is_empty() {
+                true => Box::new(io::stdin()),
+                false => input::open_files(&files, 0)?.0,
+            };
+
+            let report = checksum::verify(&log, &mut input).map_err(AsrError::AudioInput)?;
+            print_stdout(&report)?;
+
+            if report["ok"] == false {
+                std::process::exit(1);
             }
         }
-    });
+        Command::Calibrate { seconds, sample_rate, json } => {
+            if io::stdin().is_terminal() {
+                Cli::command().error(ErrorKind::InvalidValue, "pipe ambient audio into asr calibrate, e.g. from ffmpeg or arecord").exit();
+            }
 
-    message_tx.send(Message::Text(session_update.to_string().into())).await?;
+            let mut pcm = Vec::new();
+            io::stdin().take(sample_rate as u64 * 2 * seconds as u64).read_to_end(&mut pcm).map_err(AsrError::AudioInput)?;
 
-    tokio::task::spawn_blocking(move || {
-        if let Err(err) = read_audio_data(audio_tx) {
-            error!("Error reading stdin: {err}");
+            let Some(calibration) = audio::calibrate(&pcm, sample_rate) else {
+                return Err(AsrError::AudioInput(io::Error::new(io::ErrorKind::UnexpectedEof, "no audio on stdin")))
+            };
+
+            match json {
+                true => print_stdout(serde_json::to_string(&calibration)?)?,
+                false => print_stdout(format!(
+                    "Noise floor over {:.1} s: median {} dBFS, 95th percentile {} dBFS, peak {} dBFS\nRecommended: --trim-silence-db {}",
+                    calibration.seconds, calibration.p50_dbfs, calibration.p95_dbfs, calibration.peak_dbfs, calibration.recommended_silence_dbfs
+                ))?,
+            }
         }
-    });
+        Command::GenAudio { pattern, freq, end_freq, duration_s, rate, amplitude, seed, wav, output } => {
+            if !duration_s.is_finite() || duration_s < 0.0 {
+                Cli::command().error(ErrorKind::InvalidValue, "--duration-s must be a non-negative number of seconds").exit();
+            }
 
-    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
-    let keep = args.keep;
-    
-    let _task_w_audio = tokio::spawn(async move {
-        while let Some(audio_data) = audio_rx.recv().await {
-            let encoded = base64::engine::general_purpose::STANDARD.encode(&audio_data);
-            let audio_event = json!({
-                "event_id": Uuid::now_v7().to_string(),
-                "type": "input_audio_buffer.append",
-                "audio": encoded
+            if output.is_none() && io::stdout().is_terminal() {
+                Cli::command().error(ErrorKind::InvalidValue, "refusing to write audio to a terminal, pass -o or redirect stdout").exit();
+            }
+
+            let signal = match pattern {
+                Pattern::Sine => Signal::Sine { frequency: freq },
+                Pattern::Silence => Signal::Silence,
+                Pattern::Noise => Signal::Noise { seed },
+                Pattern::Chirp => Signal::Chirp { from: freq, to: end_freq },
+            };
+
+            let samples = generate::synthesize(signal, rate, generate::sample_count(rate, duration_s), amplitude);
+            let wav = wav || output.as_ref().is_some_and(|path| path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("wav")));
+
+            let mut w: Box<dyn Write> = match &output {
+                Some(path) => Box::new(io::BufWriter::new(std::fs::File::create(path)?)),
+                None => Box::new(io::BufWriter::new(io::stdout().lock())),
+            };
+
+            match wav {
+                true => generate::write_wav(&mut w, rate, &samples)?,
+                false => generate::write_raw(&mut w, &samples)?,
+            }
+
+            w.flush()?;
+        }
+        Command::Connect { socket, language, sample_rate, format } => {
+            let path = daemon::parse_listen(&socket).unwrap_or_else(|_| PathBuf::from(&socket));
+            let header = json!({
+                "language": language,
+                "sample_rate": sample_rate,
+                "format": match format {
+                    ConnectFormat::Events => "events",
+                    ConnectFormat::Results => "results",
+                },
             });
 
-            if message_tx.send(Message::Text(audio_event.to_string().into())).await.is_err() {
-                error!("Failed to send audio data");
-                break;
+            let connected = daemon::connect(&path, &header, Box::new(io::stdin()), &mut io::stdout().lock());
+            let code = connected.map_err(|err| io::Error::new(err.kind(), format!("failed to talk to the daemon on {}: {err}", path.display())))?;
+
+            // the session failed on the daemon's side, exiting the way a local transcription would
+            if let Some(code) = code {
+                std::process::exit(code);
             }
         }
-        
-        if !keep {
-            let _ = shutdown_tx.send(());
+        Command::Transcribe(_) | Command::Daemon(_) => unreachable!(),
+    }
+
+    Ok(())
+}
+
+// A line to stdout that a reader closing the pipe early, like `asr schema | head`, does not turn into a panic
+fn print_stdout(text: impl std::fmt::Display) -> Result<()> {
+    match writeln!(io::stdout().lock(), "{text}") {
+        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+async fn run_daemon(args: DaemonArgs) -> Result<()> {
+    let Some(api_key) = args.api_key else {
+        Cli::command()
+            .error(ErrorKind::MissingRequiredArgument, "--api-key or DASHSCOPE_API_KEY is required")
+            .exit();
+    };
+
+    if let Err(err) = client::validate_vad(Some(args.vad_threshold), Some(args.vad_silence_ms)) {
+        Cli::command().error(ErrorKind::InvalidValue, err).exit();
+    }
+
+    let daemon = Arc::new(Daemon {
+        url: client::endpoint_url(&args.base_url, &args.model, &args.query),
+        api_key,
+        model: args.model,
+        session_config: SessionConfig {
+            sample_rate: args.sample_rate,
+            language: args.language,
+            vad_threshold: args.vad_threshold,
+            vad_silence_ms: args.vad_silence_ms,
+            vad_prefix_padding_ms: None,
+            server_vad: true,
+            translate_to: None,
+            itn: None,
+            punctuation: None,
+        },
+        limiter: Arc::new(Limiter::new(args.max_concurrent_sessions, args.request_rate, args.max_audio_hours_per_hour)),
+        max_message_size: args.max_message_size as usize,
+    });
+
+    let notifier = notifier(args.sd_notify);
+
+    daemon.serve(&args.listen, &notifier).await.map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", args.listen.display())))?;
+    Ok(())
+}
+
+async fn run(args: Args, server_sessions: Arc<ServerSessions>) -> Result<()> {
+    if args.check {
+        return check(&args).await
+    }
+
+    if args.self_test {
+        return self_test(&args).await
+    }
+
+    if args.probe_capabilities {
+        return probe_capabilities(&args).await
+    }
+
+    if args.files.is_empty() && io::stdin().is_terminal() {
+        Cli::command().print_help()?;
+        std::process::exit(0);
+    }
+
+    let seek = args.seek.unwrap_or_default();
+    let input: Box<dyn io::Read + Send> = if args.files.is_empty() {
+        Box::new(io::stdin())
+    } else {
+        let (input, layouts) = input::open_files(&args.files, input::frames_at(seek, args.sample_rate))?;
+
+        // WAV files are sent as they are, only mixed down to mono
+        let rate = args.files.iter().zip(&layouts).find_map(|(path, layout)| layout.sample_rate.filter(|&rate| rate != args.sample_rate).map(|rate| (path, rate)));
+
+        if let Some((path, rate)) = rate {
+            Cli::command().error(ErrorKind::InvalidValue, format!("{} is {rate} Hz audio but --sample-rate is {}; pass --sample-rate {rate}", path.display(), args.sample_rate)).exit();
+        }
+
+        if let Err(err) = input::check_seek(seek, args.sample_rate, &layouts) {
+            Cli::command().error(ErrorKind::InvalidValue, err).exit();
+        }
+
+        input
+    };
+    let seek_bytes = input::bytes_at(seek, args.sample_rate);
+
+    let input: Box<dyn io::Read + Send> = match args.duration {
+        Some(duration) => Box::new(input.take(input::bytes_at(duration, args.sample_rate))),
+        None => input,
+    };
+
+    let chaos = chaos(&args).armed();
+    let input: Box<dyn io::Read + Send> = match chaos.stall_stdin_after {
+        Some(after) => Box::new(StallingReader::new(input, after)),
+        None => input,
+    };
+
+    let api_key = require_api_key(&args);
+
+    if !args.subtitle_out.is_empty() && args.format.subtitles().is_none() {
+        Cli::command().error(ErrorKind::ArgumentConflict, "--subtitle-out needs --format srt or ass").exit();
+    }
+
+    if args.subtitle_out.iter().any(|(track, _)| *track == Track::Translation) && args.translate_to.is_none() {
+        Cli::command().error(ErrorKind::MissingRequiredArgument, "the translation subtitle track needs --translate-to").exit();
+    }
+
+    if args.ascii_json && args.format.subtitles().is_some() && args.subtitle_out.is_empty() {
+        Cli::command().error(ErrorKind::ArgumentConflict, "--ascii-json only applies to JSON output, not subtitles on stdout").exit();
+    }
+
+    let terminal = match args.interactive {
+        true => match Terminal::open(args.clipboard) {
+            Ok(terminal) => Some(Arc::new(terminal)),
+            Err(err) => Cli::command()
+                .error(ErrorKind::InvalidValue, format!("--interactive needs a terminal: {err}"))
+                .exit(),
+        },
+        false => None,
+    };
+
+    let reference = match &args.reference {
+        Some(path) => Some(reference::load(path).map_err(|err| io::Error::new(err.kind(), format!("failed to read {}: {err}", path.display())))?),
+        None => None,
+    };
+    let (journal, journaled) = match &args.journal {
+        Some(path) => {
+            let (journal, turns) = Journal::open(path, args.journal_resume, TurnResults::new(TurnResults::sources(&args.files, args.sample_rate)))
+                .map_err(|err| io::Error::new(err.kind(), format!("failed to open the journal {}: {err}", path.display())))?;
+            (Some(Arc::new(Mutex::new(journal))), turns)
+        }
+        None => (None, Vec::new()),
+    };
+    let notifier = Arc::new(notifier(args.sd_notify));
+
+    // only collected for --reference
+    let transcripts = reference.is_some().then(|| Arc::new(Transcripts::default()));
+
+    let tty_out = args.tty_out.as_deref().map(|path| {
+        let opened = match path.as_os_str() == "auto" {
+            true => TtyOut::allocate(),
+            false => TtyOut::open(path),
+        };
+
+        match opened {
+            Ok(tty_out) => Arc::new(tty_out),
+            Err(err) => Cli::command().error(ErrorKind::InvalidValue, format!("--tty-out {}: {err}", path.display())).exit(),
         }
     });
-    
-    let task_r_message = tokio::spawn(async move {
-        while let Some(msg) = message_rx.next().await {
-            match msg {
-                Ok(Message::Text(text)) => {
-                    println!("{text}");
+
+    // live input never repeats, only files are worth caching
+    let cache = args.cache.as_deref().filter(|_| !args.files.is_empty()).map(Cache::new);
+    let printer = match cache {
+        Some(_) => Printer::new(args.timestamps).record(),
+        None => Printer::new(args.timestamps),
+    };
+    let printer = match args.ascii_json {
+        true => printer.ascii_json(),
+        false => printer,
+    };
+    let printer = match args.durable {
+        true => printer.durable()?,
+        false => printer,
+    };
+    let printer = match args.format.subtitles().is_some() && args.subtitle_out.is_empty() {
+        true => printer.without_client_events(),
+        false => printer,
+    };
+    // nothing is printed unless speech starts
+    let printer = match args.once {
+        true => printer.hold(),
+        false => printer,
+    };
+    let printer = Arc::new(printer.server_sessions(server_sessions.clone()));
+    let _finish = FinishGuard::new(printer.clone());
+
+    // printed with the server's id once the session is created, or right away for a replay
+    let session_id = Uuid::now_v7().to_string();
+    let session_info = (!args.no_session_info).then(|| {
+        ClientEvent::SessionInfo(SessionInfo {
+            session_id: session_id.clone(),
+            client_version: env!("CARGO_PKG_VERSION").into(),
+            model: args.model.clone(),
+            host: args.base_url.host_str().unwrap_or_default().into(),
+            sample_rate: args.sample_rate,
+            language: args.language.clone(),
+            vad: VadInfo {
+                threshold: args.vad_threshold,
+                silence_duration_ms: args.vad_silence_ms,
+                prefix_padding_ms: args.vad_prefix_padding_ms,
+            },
+            translate_to: args.translate_to.clone(),
+            itn: args.itn.map(Toggle::enabled),
+            punctuation: args.punctuation.map(Toggle::enabled),
+        })
+    });
+
+    let tty_allocated = match (&tty_out, args.tty_out.as_ref().is_some_and(|path| path.as_os_str() == "auto")) {
+        (Some(tty_out), true) => Some(ClientEvent::TtyAllocated(TtyAllocated { path: tty_out.path().into() })),
+        _ => None,
+    };
+    let mut startup_events: Vec<ClientEvent> = session_info.into_iter().chain(tty_allocated).collect();
+
+    let session_config = session_config(&args);
+    let session_update = session_config.update_event();
+
+    let cache_key = match &cache {
+        Some(_) => Some(Cache::key(&args.files, &cache_params(&args, &session_update))?),
+        None => None,
+    };
+
+    if let (Some(cache), Some(key), false) = (&cache, &cache_key, args.cache_bust) {
+        if replay_cached(cache, key, &printer, &mut startup_events) {
+            return Ok(())
+        }
+    }
+
+    let limiter = Arc::new(Limiter::new(args.max_concurrent_sessions, args.request_rate, args.max_audio_hours_per_hour));
+    let _session_permit = limiter.acquire_session().await;
+
+    let url = client::endpoint_url(&args.base_url, &args.model, &args.query);
+    let ws_stream = client::connect(&url, api_key, args.max_message_size as usize).await?;
+    let (mut message_tx, message_rx) = ws_stream.split();
+    let chaos_drop_at = chaos.drop_connection_after.map(|after| tokio::time::Instant::now() + after);
+
+    let (audio_tx, mut audio_rx) = mpsc::channel::<Vec<u8>>(128);
+
+    // Send session configuration
+    limiter.acquire_request().await;
+    message_tx.send(Message::Text(session_update.to_string().into())).await?;
+
+    let offsets = Arc::new(Mutex::new(OffsetMap::new(args.sample_rate)));
+
+    // what --seek skipped is a gap at the very start, so offsets stay on the timeline of the files
+    if seek_bytes > 0 {
+        offsets.lock().unwrap().skipped(seek_bytes as usize);
+    }
+
+    // the B session gets its own queue and tasks, so it can only ever fall behind on its own
+    let (ab_error_tx, mut ab_error_rx) = oneshot::channel::<AsrError>();
+    let comparison = args.ab_model.as_ref().map(|ab_model| Arc::new(Mutex::new(Comparison::new(&args.model, ab_model))));
+    let task_r_ab = match &args.ab_model {
+        Some(ab_model) => {
+            let ws_stream = client::connect(&client::endpoint_url(&args.base_url, ab_model, &args.query), api_key, args.max_message_size as usize).await?;
+            let (mut sink, mut stream) = ws_stream.split();
+            sink.send(Message::Text(session_update.to_string().into())).await?;
+
+            let (events_tx, mut events_rx) = mpsc::channel::<Value>(AB_QUEUE_CHUNKS);
+            let fanout = Arc::new(Fanout::new(events_tx, args.sample_rate));
+
+            tokio::spawn(async move {
+                while let Some(event) = events_rx.recv().await {
+                    if sink.send(Message::Text(event.to_string().into())).await.is_err() {
+                        error!("Failed to send audio data to the B session");
+                        break;
+                    }
                 }
-                Ok(Message::Close(_)) => {
-                    break;
+            });
+
+            let (ab_model, host) = (ab_model.clone(), args.base_url.host_str().unwrap_or_default().to_string());
+            let (fanout_r, offsets, printer, comparison) = (fanout.clone(), offsets.clone(), printer.clone(), comparison.clone().unwrap());
+            let optional_fields = session_config.optional_fields();
+            let ab_diff = args.ab_diff;
+            let normalize_events = args.normalize_events;
+
+            let task = tokio::spawn(async move {
+                let result = async {
+                    let mut handshake = Handshake::default();
+
+                    while let Some(msg) = stream.next().await {
+                        let received_at = Instant::now();
+
+                        let Message::Text(text) = msg? else {
+                            continue
+                        };
+
+                        let Ok(mut event) = serde_json::from_str::<Value>(&text) else {
+                            continue
+                        };
+
+                        handshake.observe(&event, &ab_model, &optional_fields)?;
+
+                        // first onto the timeline both sessions were fed, then onto the source
+                        fanout_r.correct_event(&mut event);
+                        offsets.lock().unwrap().correct_event(&mut event);
+                        event["variant"] = Variant::B.tag().into();
+                        printer.print_at(normalize::event(&event, normalize_events), received_at);
+
+                        if protocol::ends_turn(&event) {
+                            printer.sync();
+                        }
+
+                        if let (Some(diff), true) = (comparison.lock().unwrap().observe(Variant::B, &event, received_at), ab_diff) {
+                            printer.print(ClientEvent::AbDiff(diff));
+                        }
+
+                        if protocol::event_type(&event) == "session.finished" {
+                            break
+                        }
+                    }
+
+                    if !handshake.is_ready() {
+                        return Err(handshake.error(&ab_model, &host))
+                    }
+
+                    Ok(())
+                };
+
+                if let Err(err) = result.await {
+                    let _ = ab_error_tx.send(err);
                 }
-                Err(err) => {
-                    error!("Error receiving message: {err}");
-                    break;
+            });
+
+            Some((fanout, task))
+        }
+        None => None,
+    };
+    let fanout = task_r_ab.as_ref().map(|(fanout, _)| fanout.clone());
+
+    let spool = match &args.spool {
+        Some(dir) => Some(Arc::new(Spool::open(dir, args.spool_max_mb * 1024 * 1024, args.spool_resume, offsets.clone())?)),
+        None => None,
+    };
+
+    // routing replays what the old session heard past the switch point, so it needs the history as well, as do keyword exports
+    let export_s = keyword::export_window(&args.keyword_action);
+    let history = (args.turn_retries > 0 || !args.language_route.is_empty() || export_s.is_some())
+        .then(|| Arc::new(Mutex::new(AudioHistory::new(args.sample_rate, args.retry_buffer_s.max(export_s.unwrap_or(0))))));
+    let paused = Arc::new(AtomicBool::new(args.interactive));
+    let schedule = (!args.active_hours.is_empty())
+        .then(|| Schedule::new(args.active_hours.clone(), args.active_days.unwrap_or(Days::ALL), args.timezone.clone().unwrap_or_else(TimeZone::system)));
+    let muted = Arc::new(AtomicBool::new(schedule.as_ref().is_some_and(|schedule| !schedule.is_active(jiff::Timestamp::now()))));
+
+    let audio_reader = AudioReader {
+        audio_tx,
+        trimmer: args.trim_silence.then(|| {
+            SilenceTrimmer::new(args.sample_rate, args.trim_silence_db, args.vad_silence_ms + TRIM_BRIDGE_MARGIN_MS, args.trim_threshold_ms, args.preroll_ms, offsets.clone())
+        }),
+        rate_monitor: (args.files.is_empty() && !input::stdin_is_file()).then(|| RateMonitor::new(args.sample_rate)),
+        strict_input: args.strict_input,
+        paused: paused.clone(),
+        muted: muted.clone(),
+        offsets: offsets.clone(),
+        printer: printer.clone(),
+        spool: spool.clone(),
+    };
+
+    // a plain thread, so a read blocked on stdin that is still open does not hold up runtime shutdown
+    let (read_result_tx, task_r_audio) = oneshot::channel();
+    std::thread::spawn(move || read_result_tx.send(audio_reader.run(input)));
+
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<Result<()>>();
+    let keep = args.keep;
+    let (allow_empty, min_audio_ms) = (args.allow_empty, args.min_audio_ms);
+    let short_input = Arc::new(AtomicBool::new(false));
+    let (short_input_w, printer_w) = (short_input.clone(), printer.clone());
+    // the session is set up before an empty input is reported, a failure to set it up wins
+    let session_ready = Arc::new(tokio::sync::Notify::new());
+    let session_ready_w = session_ready.clone();
+    let notifier_w = notifier.clone();
+    let bytes_per_second = args.sample_rate as f64 * 2.0;
+
+    let (control_tx, mut control_rx) = mpsc::channel::<Control>(8);
+    let turn_history = (args.history_turns > 0 && (args.control_fd.is_some() || export_s.is_some()))
+        .then(|| Arc::new(Mutex::new(TurnHistory::new(args.history_turns, TurnResults::sources(&args.files, args.sample_rate)))));
+
+    if let Some(turn_history) = &turn_history {
+        turn_history.lock().unwrap().restore(&journaled);
+    }
+
+    if let Some(fd) = args.control_fd {
+        let (control_tx, paused, force, turn_history) = (control_tx.clone(), paused.clone(), args.force, turn_history.clone());
+
+        // same for a descriptor nobody closes
+        std::thread::spawn(move || {
+            if let Err(err) = control::serve_fd(fd, control_tx, &paused, force, turn_history.as_deref()) {
+                error!("Control descriptor {fd} disabled: {err}");
+            }
+        });
+    }
+
+    // stays pending without a terminal, its sender is dropped right away
+    let (finished_tx, finished_rx) = oneshot::channel::<()>();
+
+    if let Some(terminal) = terminal.clone() {
+        let (control_tx, paused) = (control_tx.clone(), paused.clone());
+
+        std::thread::spawn(move || {
+            if let Err(err) = terminal.run(control_tx, &paused) {
+                error!("Interactive terminal failed: {err}");
+            }
+
+            let _ = finished_tx.send(());
+        });
+    }
+
+    // tells the reader when to close and reopen the session, with --schedule-disconnect
+    let (schedule_tx, schedule_rx) = mpsc::channel::<bool>(4);
+    let control_tx_r = control_tx.clone();
+
+    if let Some(schedule) = schedule {
+        let (muted, printer, disconnect) = (muted.clone(), printer.clone(), args.schedule_disconnect);
+
+        tokio::spawn(async move {
+            let mut was_active = true;
+
+            loop {
+                let now = jiff::Timestamp::now();
+                let active = schedule.is_active(now);
+                let next = schedule.next_transition(now);
+
+                if active != was_active {
+                    // a disconnected session is unmuted by the reader once its replacement is in place
+                    if !(active && disconnect) {
+                        muted.store(!active, Ordering::Relaxed);
+                    }
+
+                    let change = ScheduleChange { until: next.map(|next| next.display_with_offset(schedule.time_zone().to_offset(next)).to_string()) };
+
+                    printer.print(match active {
+                        true => ClientEvent::ScheduleUnmuted(change),
+                        false => ClientEvent::ScheduleMuted(change),
+                    });
+
+                    if disconnect && schedule_tx.send(active).await.is_err() {
+                        break
+                    }
+
+                    was_active = active;
                 }
-                _ => {}
+
+                let wait = next.map_or(SCHEDULE_RECHECK, |next| next.duration_since(now).unsigned_abs().min(SCHEDULE_RECHECK));
+                tokio::time::sleep(wait).await;
             }
+        });
+    }
+
+    tokio::spawn(async move {
+        if let Err(err) = control::forward_commit_signal(control_tx).await {
+            error!("Failed to listen for the commit signal: {err}");
         }
     });
 
-    tokio::select! {
-        _ = task_r_message => {},
-        _ = tokio::signal::ctrl_c() => {},
-        _ = shutdown_rx => {}
-    }
+    let (switch_tx, mut switch_rx) = mpsc::channel::<Switch>(1);
+    // set_vad changes it while running, a session opened later starts from the current settings
+    let live_config = Arc::new(Mutex::new(session_config.clone()));
+    let vad_pending = Arc::new(AtomicUsize::new(0));
+    let (live_config_w, vad_pending_w) = (live_config.clone(), vad_pending.clone());
+    let limiter_w = limiter.clone();
+    let spool_w = spool.clone();
+    let history_w = history.clone();
+    let fanout_w = fanout.clone();
+    let watchdog = args.ack_lag_s.map(|ack_lag_s| Arc::new(Mutex::new(AckWatchdog::new(args.sample_rate, Duration::from_secs(ack_lag_s)))));
+    let watchdog_w = watchdog.clone();
+    let mut checksums_w = args.audio_checksums.as_deref().map(ChecksumLog::create).transpose()?;
+    let mut corruptor_w = chaos.corruptor();
+    let _task_w_audio = tokio::spawn(async move {
+        let mut task_r_audio = Some(task_r_audio);
+        let mut shutdown_tx = Some(shutdown_tx);
+        let mut disconnected = false;
+        let mut finishing = false;
+        let mut sent_bytes = 0u64;
 
-    Ok(())
-}
+        loop {
+            // queued audio goes out before a commit that was issued after it
+            let event = tokio::select! {
+                biased;
+                Some(switch) = switch_rx.recv() => {
+                    let _ = message_tx.close().await;
+
+                    let Some(sink) = switch.sink else {
+                        disconnected = true;
+                        continue
+                    };
+
+                    message_tx = sink;
+                    disconnected = false;
+
+                    // audio the old session got past the switch point is sent again, the new one starts right there
+                    let replay = history_w.as_ref().and_then(|history| history.lock().unwrap().slice(switch.start_ms, f64::MAX));
+
+                    for audio_data in replay.unwrap_or_default().chunks(REPLAY_CHUNK_BYTES) {
+                        limiter_w.acquire_request().await;
+
+                        if let Some(watchdog) = &watchdog_w {
+                            watchdog.lock().unwrap().sent(audio_data, Instant::now());
+                        }
+
+                        if message_tx.send(Message::Text(client::audio_append_event(audio_data).to_string().into())).await.is_err() {
+                            error!("Failed to send audio data");
+                            break;
+                        }
+                    }
+
+                    // the input ended already, the new session has to be told as well
+                    if finishing {
+                        limiter_w.acquire_request().await;
+                        let _ = message_tx.send(Message::Text(client::finish_event().to_string().into())).await;
+                    }
 
-fn read_audio_data(audio_tx: mpsc::Sender<Vec<u8>>) -> Result<()> {
-    let mut stdin = io::stdin();
-    let mut buffer = [0u8; 8192];
+                    continue
+                }
+                audio_data = spool::recv(&mut audio_rx, spool_w.as_deref()), if task_r_audio.is_some() => match audio_data {
+                    Some(mut audio_data) => {
+                        sent_bytes += audio_data.len() as u64;
+
+                        if let Some(history) = &history_w {
+                            history.lock().unwrap().push(&audio_data);
+                        }
+
+                        if let Some(corruptor) = &mut corruptor_w {
+                            corruptor.apply(&mut audio_data);
+                        }
+
+                        let event = client::audio_append_event(&audio_data);
+
+                        if let Some(Err(err)) = checksums_w.as_mut().map(|checksums| checksums.append(&audio_data)) {
+                            error!("Failed to log audio checksums, no longer logging them: {err}");
+                            checksums_w = None;
+                        }
+
+                        if let Some(fanout) = &fanout_w {
+                            fanout.audio(&event, audio_data.len());
+                        }
+
+                        limiter_w.acquire_audio(audio_data.len() as f64 / bytes_per_second).await;
+
+                        if let Some(watchdog) = &watchdog_w {
+                            watchdog.lock().unwrap().sent(&audio_data, Instant::now());
+                        }
+
+                        event
+                    }
+                    None => {
+                        let read_bytes = match task_r_audio.take().unwrap().await.map(|result| result.map(Some)).unwrap_or(Ok(None)) {
+                            Ok(read_bytes) => read_bytes,
+                            Err(err) => {
+                                let _ = shutdown_tx.take().unwrap().send(Err(err));
+                                continue
+                            }
+                        };
+
+                        // spool_resume audio counts, an empty stdin after it is fine
+                        if read_bytes == Some(0) && sent_bytes == 0 {
+                            let _ = tokio::time::timeout(FINISH_TIMEOUT, session_ready_w.notified()).await;
+                            printer_w.print(ClientEvent::NoInput);
+
+                            let _ = shutdown_tx.take().unwrap().send(match allow_empty {
+                                true => Ok(()),
+                                false => Err(AsrError::NoInput),
+                            });
+                            continue
+                        }
+
+                        if let Some(read_ms) = read_bytes.map(|bytes| bytes as f64 / bytes_per_second * 1000.0).filter(|&read_ms| read_ms < min_audio_ms as f64) {
+                            debug!("The input ended after {read_ms:.0} ms of audio, below --min-audio-ms");
+                            short_input_w.store(true, Ordering::Relaxed);
+                        }
+
+                        if keep {
+                            continue
+                        }
+
+                        notifier_w.stopping();
+
+                        // no session left to finish
+                        if disconnected {
+                            let _ = shutdown_tx.take().unwrap().send(Ok(()));
+                            continue
+                        }
+
+                        // the reader ends on session.finished, this only covers a server that never sends it
+                        let shutdown_tx = shutdown_tx.take().unwrap();
+                        tokio::spawn(async move {
+                            tokio::time::sleep(FINISH_TIMEOUT).await;
+                            warn!("No session.finished within {}s, exiting without the last results", FINISH_TIMEOUT.as_secs());
+                            let _ = shutdown_tx.send(Ok(()));
+                        });
 
-    loop {
-        let n = stdin.read(&mut buffer)?;
+                        let event = client::finish_event();
+                        finishing = true;
 
-        if n == 0 {
-            break
+                        if let Some(fanout) = &fanout_w {
+                            fanout.control(&event).await;
+                        }
+
+                        event
+                    }
+                },
+                Some(control) = control_rx.recv() => {
+                    let event = match control {
+                        Control::Commit => client::commit_event(),
+                        Control::Clear => client::clear_event(),
+                        Control::SetVad(change) => {
+                            let mut config = live_config_w.lock().unwrap();
+                            config.apply_vad(change);
+                            vad_pending_w.fetch_add(1, Ordering::Relaxed);
+                            config.update_event()
+                        }
+                        Control::Finish => client::finish_event(),
+                    };
+
+                    if let Some(fanout) = &fanout_w {
+                        fanout.control(&event).await;
+                    }
+
+                    event
+                }
+                else => break,
+            };
+
+            if disconnected {
+                debug!("Not sending {}, the session is closed outside --active-hours", protocol::event_type(&event));
+                continue
+            }
+
+            limiter_w.acquire_request().await;
+
+            if message_tx.send(Message::Text(event.to_string().into())).await.is_err() {
+                error!("Failed to send audio data");
+                break;
+            }
         }
+    });
+    
+    let router = (!args.language_route.is_empty()).then(|| Router::new(args.language_route.clone(), Duration::from_secs(args.route_min_interval_s)));
+    let turn_retry = (args.turn_retries > 0).then(|| {
+        Arc::new(TurnRetry {
+            url: url.clone(),
+            api_key: api_key.to_string(),
+            session_config: session_config.clone(),
+            limiter: limiter.clone(),
+            attempts: args.turn_retries,
+            max_message_size: args.max_message_size as usize,
+        })
+    });
+    let results = (args.format != Format::Events)
+        .then(|| Arc::new(Mutex::new(TurnResults::new(TurnResults::sources(&args.files, args.sample_rate)))));
 
-        audio_tx.blocking_send(buffer[..n].to_vec())?;
+    if let Some(results) = &results {
+        results.lock().unwrap().continue_after(&journaled);
     }
+    let subtitles = match args.format.subtitles() {
+        Some(format) => Some(Arc::new(Mutex::new(open_subtitles(&args, format, &printer)?))),
+        None => None,
+    };
+    let density = Arc::new(DensityCheck::new(args.min_cps, args.max_cps));
 
-    Ok(())
-}
\ No newline at end of file
+    // only timed transcripts were ever checked
+    for turn in journaled.iter().filter(|turn| turn.text.is_some() && turn.start_ms.is_some() && turn.end_ms.is_some()) {
+        density.restore(turn.quality_warning.as_deref());
+    }
+    let once_started = Arc::new(AtomicBool::new(false));
+    let keyword_exports = Arc::new(Mutex::new(JoinSet::new()));
+    let turns_done = Arc::new(AtomicU64::new(journaled.len() as u64));
+    let reader = Reader {
+        message_rx,
+        switch_tx,
+        schedule_rx,
+        control_tx: control_tx_r,
+        chaos_drop_at,
+        chaos_delay: chaos.delay_events,
+        live_config,
+        vad_pending,
+        base_url: args.base_url.clone(),
+        query: args.query.clone(),
+        api_key: api_key.to_string(),
+        max_message_size: args.max_message_size as usize,
+        limiter: limiter.clone(),
+        model: args.model.clone(),
+        host: args.base_url.host_str().unwrap_or_default().to_string(),
+        optional_fields: session_config.optional_fields(),
+        outputs: TurnOutputs {
+            printer: printer.clone(),
+            offsets: offsets.clone(),
+            results,
+            subtitles: subtitles.clone(),
+            turn_history: turn_history.clone(),
+            journal,
+            tty_out,
+            transcripts: transcripts.clone(),
+            density: density.clone(),
+            translate_only: args.translate_only,
+            normalize_events: args.normalize_events,
+        },
+        watchdog: watchdog.clone(),
+        startup_events,
+        server_sessions: server_sessions.clone(),
+        turn_retry,
+        history: history.clone(),
+        short_input,
+        // only a model switch replays audio a session already heard
+        dedup: router.is_some().then(|| TurnDedup::new(args.dedup_similarity)),
+        router,
+        comparison: comparison.clone(),
+        ab_diff: args.ab_diff,
+        keyword_actions: KeywordActions {
+            rules: args.keyword_action.clone(),
+            export_dir: args.keyword_export_dir.clone(),
+            name_template: args.name_template.clone(),
+            session_id,
+            sample_rate: args.sample_rate,
+            printer: printer.clone(),
+            paused: paused.clone(),
+            offsets: offsets.clone(),
+            history,
+            turn_history,
+            exports: keyword_exports.clone(),
+        },
+        terminal,
+        beeper: (args.feedback == Feedback::Beep).then(|| Beeper::start(args.feedback_volume)).flatten(),
+        session_ready,
+        notifier: notifier.clone(),
+        turns_done: turns_done.clone(),
+        once_item: args.once.then_some(None),
+        once_started: once_started.clone(),
+        translate: args.translate_to.is_some(),
+        muted,
+        handshake: Handshake::default(),
+        ready_notified: false,
+        turn_spans: TurnSpans::default(),
+        upstream: Upstream::Connected,
+        frame_recoveries: 0,
+    };
+    let task_r_message = tokio::spawn(reader.run());
+
+    // stays pending without the watchdog, its sender is dropped right away
+    let (lagging_tx, lagging_rx) = oneshot::channel::<f64>();
+
+    if let Some(watchdog) = watchdog {
+        let (printer, fatal) = (printer.clone(), args.ack_lag_fatal);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(WATCHDOG_INTERVAL);
+
+            loop {
+                interval.tick().await;
+
+                let Some(lagging) = watchdog.lock().unwrap().check(Instant::now()) else {
+                    continue
+                };
+
+                warn!("The server is {} s behind the sent audio", lagging.lag_s);
+                let lag_s = lagging.lag_s;
+                printer.print(ClientEvent::ServerLagging(lagging));
+
+                if fatal {
+                    let _ = lagging_tx.send(lag_s);
+                    break
+                }
+            }
+        });
+    }
+
+    if notifier.is_enabled() {
+        let (notifier, offsets) = (notifier.clone(), offsets.clone());
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SD_STATUS_INTERVAL);
+
+            loop {
+                interval.tick().await;
+                notifier.status(&format!("{} turns, {:.0} s of audio sent", turns_done.load(Ordering::Relaxed), offsets.lock().unwrap().sent_ms() / 1000.0));
+            }
+        });
+    }
+
+    let mut finished = false;
+    let once_timeout = Duration::from_millis(args.once_timeout_ms);
+    let no_speech = async {
+        tokio::time::sleep(once_timeout).await;
+
+        if !args.once || once_started.load(Ordering::Relaxed) {
+            std::future::pending::<()>().await;
+        }
+    };
+
+    tokio::select! {
+        result = task_r_message => {
+            if let Ok(result) = result {
+                result?;
+                finished = true;
+            }
+        },
+        _ = tokio::signal::ctrl_c() => notifier.stopping(),
+        _ = no_speech => return Err(AsrError::NoSpeech(args.once_timeout_ms)),
+        Ok(lag_s) = lagging_rx => return Err(AsrError::Timeout(format!("the server stopped keeping up, {lag_s} s behind the sent audio"))),
+        Ok(result) = shutdown_rx => result?,
+        Ok(()) = finished_rx => {}
+        Ok(err) = &mut ab_error_rx => return Err(err),
+    }
+
+    if let (Some((fanout, task)), Some(comparison)) = (task_r_ab, comparison) {
+        // the B session usually finishes a little after A
+        if finished && tokio::time::timeout(FINISH_TIMEOUT, task).await.is_ok() {
+            if let Ok(err) = ab_error_rx.try_recv() {
+                return Err(err)
+            }
+        }
+
+        printer.print(ClientEvent::AbSummary(comparison.lock().unwrap().summary([0, fanout.dropped_bytes()])));
+    }
+
+    if let Some(subtitles) = &subtitles {
+        subtitles.lock().unwrap().finish()?;
+    }
+
+    // keyword exports still being written are announced before the summaries
+    let mut keyword_exports = std::mem::take(&mut *keyword_exports.lock().unwrap());
+    while keyword_exports.join_next().await.is_some() {}
+
+    // model switches and --schedule-disconnect reconnects each add one
+    if let server_session_ids @ [_, _, ..] = server_sessions.all().as_slice() {
+        printer.print(ClientEvent::ServerSessions(ServerSessionList { server_session_ids: server_session_ids.to_vec() }));
+    }
+
+    if let Some(summary) = density.summary() {
+        printer.print(ClientEvent::QualitySummary(summary));
+    }
+
+    if let (Some(reference), Some(transcripts)) = (&reference, &transcripts) {
+        let mut transcripts = transcripts.lock().unwrap().clone();
+
+        // retried turns arrive late, offsets put them back where they were spoken
+        if transcripts.iter().all(|(start_ms, _)| start_ms.is_some()) {
+            transcripts.sort_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        }
+
+        // an earlier run's turns come first, their offsets are on a timeline of their own
+        let journaled = journaled.iter().filter_map(|turn| turn.text.clone());
+        let turns: Vec<String> = journaled.chain(transcripts.into_iter().map(|(_, text)| text)).collect();
+        printer.print(ClientEvent::ReferenceReport(reference::compare(reference, &turns, args.reference_detail)));
+    }
+
+    if let Some(spool) = spool.filter(|spool| spool.chunks() > 0) {
+        printer.print(ClientEvent::SpoolRemaining(SpoolRemaining {
+            directory: spool.dir().display().to_string(),
+            chunks: spool.chunks(),
+            bytes: spool.bytes(),
+        }));
+    }
+
+    if let (Some(cache), Some(key), true) = (&cache, &cache_key, finished) {
+        store_cached(cache, key, &args, &printer);
+    }
+
+    if args.durable {
+        printer.sync();
+        debug!("Synced the output {} times", printer.fsyncs());
+    }
+
+    Ok(())
+}
+
+// (start on the source timeline, text) of every final transcript
+type Transcripts = Mutex<Vec<(Option<f64>, String)>>;
+
+// Where final transcripts go, shared by the reader and the turn retries it spawns
+#[derive(Clone)]
+struct TurnOutputs {
+    printer: Arc<Printer>,
+    offsets: Arc<Mutex<OffsetMap>>,
+    results: Option<Arc<Mutex<TurnResults>>>,
+    subtitles: Option<Arc<Mutex<Subtitles>>>,
+    turn_history: Option<Arc<Mutex<TurnHistory>>>,
+    journal: Option<Arc<Mutex<Journal>>>,
+    tty_out: Option<Arc<TtyOut>>,
+    transcripts: Option<Arc<Transcripts>>,
+    density: Arc<DensityCheck>,
+    translate_only: bool,
+    normalize_events: bool,
+}
+
+impl TurnOutputs {
+    fn next_session(&self) {
+        if let Some(results) = &self.results {
+            results.lock().unwrap().next_session();
+        }
+
+        if let Some(turn_history) = &self.turn_history {
+            turn_history.lock().unwrap().next_session();
+        }
+
+        if let Some(journal) = &self.journal {
+            journal.lock().unwrap().next_session();
+        }
+    }
+
+    // Whatever the output format is, kept for the control descriptor, keyword exports and the journal
+    fn record(&self, event: &Value, received_at: Instant) {
+        if let Some(turn_history) = &self.turn_history {
+            turn_history.lock().unwrap().observe(event, received_at);
+        }
+
+        if let Some(journal) = &self.journal {
+            journal.lock().unwrap().observe(event, received_at);
+        }
+    }
+
+    // A turn the live session failed, transcribed on a session of its own
+    fn retried(&self, completed: &mut Value) {
+        self.density.observe(completed);
+        self.offsets.lock().unwrap().correct_event(completed);
+
+        if let (Some(tty_out), false) = (&self.tty_out, self.translate_only) {
+            tty_out.observe(completed, false);
+        }
+
+        if let Some(transcripts) = &self.transcripts {
+            collect_transcript(transcripts, completed);
+        }
+
+        self.record(completed, Instant::now());
+
+        match &self.results {
+            Some(results) => {
+                if let Some(result) = results.lock().unwrap().observe(completed, Instant::now()) {
+                    emit_result(&self.printer, self.subtitles.as_deref(), result, Instant::now());
+                }
+            }
+            None => self.printer.print_at(normalize::event(completed, self.normalize_events), Instant::now()),
+        }
+    }
+
+    // A failed turn every retry failed as well, reported as the server sent it
+    fn failed(&self, event: &Value, text: &str, received_at: Instant) {
+        self.record(event, received_at);
+
+        match &self.results {
+            Some(results) => {
+                if let Some(result) = results.lock().unwrap().observe(event, received_at) {
+                    emit_result(&self.printer, self.subtitles.as_deref(), result, received_at);
+                }
+            }
+            None if self.normalize_events => self.printer.print_at(normalize::event(event, true), received_at),
+            None => self.printer.print_at(text, received_at),
+        }
+    }
+}
+
+// Reads the server events of the session, and of every session that takes over from it, until the last one finishes
+struct Reader {
+    message_rx: SplitStream<client::WsStream>,
+    switch_tx: mpsc::Sender<Switch>,
+    schedule_rx: mpsc::Receiver<bool>,
+    control_tx: mpsc::Sender<Control>,
+    chaos_drop_at: Option<tokio::time::Instant>,
+    chaos_delay: Option<Duration>,
+    live_config: Arc<Mutex<SessionConfig>>,
+    vad_pending: Arc<AtomicUsize>,
+    base_url: Url,
+    query: Vec<(String, String)>,
+    api_key: String,
+    max_message_size: usize,
+    limiter: Arc<Limiter>,
+    model: String,
+    host: String,
+    optional_fields: Vec<(&'static str, &'static str)>,
+    outputs: TurnOutputs,
+    watchdog: Option<Arc<Mutex<AckWatchdog>>>,
+    // printed once the first server event is in
+    startup_events: Vec<ClientEvent>,
+    server_sessions: Arc<ServerSessions>,
+    turn_retry: Option<Arc<TurnRetry>>,
+    history: Option<Arc<Mutex<AudioHistory>>>,
+    short_input: Arc<AtomicBool>,
+    router: Option<Router>,
+    dedup: Option<TurnDedup>,
+    comparison: Option<Arc<Mutex<Comparison>>>,
+    ab_diff: bool,
+    keyword_actions: KeywordActions,
+    terminal: Option<Arc<Terminal>>,
+    beeper: Option<Beeper>,
+    session_ready: Arc<tokio::sync::Notify>,
+    notifier: Arc<Notifier>,
+    turns_done: Arc<AtomicU64>,
+    // the item of the one utterance --once waits for, once it started
+    once_item: Option<Option<String>>,
+    once_started: Arc<AtomicBool>,
+    translate: bool,
+    muted: Arc<AtomicBool>,
+    handshake: Handshake,
+    ready_notified: bool,
+    turn_spans: TurnSpans,
+    upstream: Upstream,
+    frame_recoveries: u32,
+}
+
+impl Reader {
+    async fn run(mut self) -> Result<()> {
+        loop {
+            let msg = tokio::select! {
+                msg = self.message_rx.next(), if self.upstream != Upstream::Disconnected => match msg {
+                    // the server may close right after session.finished
+                    None | Some(Ok(Message::Close(_))) if self.upstream == Upstream::Finishing => {
+                        self.disconnected().await;
+                        continue
+                    }
+                    Some(msg) => msg,
+                    None => break,
+                },
+                // only the first session is dropped, whatever happens next is what is being tested
+                _ = tokio::time::sleep_until(self.chaos_drop_at.unwrap_or_else(tokio::time::Instant::now)), if self.chaos_drop_at.is_some() => {
+                    self.chaos_drop_at = None;
+                    Err(tungstenite::Error::Io(Chaos::dropped_connection()))
+                }
+                Some(active) = self.schedule_rx.recv() => {
+                    self.schedule(active).await?;
+                    continue
+                }
+                else => break,
+            };
+
+            if let Some(delay) = self.chaos_delay {
+                tokio::time::sleep(delay).await;
+            }
+
+            let received_at = Instant::now();
+
+            // a reset right after the server's in-band error still gets diagnosed
+            let msg = match msg {
+                Err(_) if !self.handshake.is_ready() && self.handshake.has_errors() => break,
+                Err(err) => {
+                    let Some(kind) = protocol::frame_violation(&err) else {
+                        return Err(err.into())
+                    };
+
+                    self.outputs.printer.print(ClientEvent::ProtocolWarning(ProtocolWarning {
+                        kind: kind.into(),
+                        message: err.to_string(),
+                        preview: None,
+                        bytes: None,
+                    }));
+
+                    if !self.handshake.is_ready() || self.upstream != Upstream::Connected || self.frame_recoveries == MAX_FRAME_RECOVERIES {
+                        return Err(err.into())
+                    }
+
+                    self.frame_recoveries += 1;
+                    warn!("Reopening the session after a frame it could not read ({kind})");
+                    self.resume().await?;
+                    continue
+                }
+                Ok(msg) => msg,
+            };
+
+            let flow = match msg {
+                Message::Text(text) => self.event(&text, received_at).await?,
+                Message::Close(_) if !self.handshake.is_ready() => break,
+                Message::Close(Some(frame)) if frame.code != CloseCode::Normal => {
+                    return Err(AsrError::Closed { code: frame.code.into(), reason: frame.reason.to_string() })
+                }
+                Message::Close(_) => {
+                    break;
+                }
+                _ => ControlFlow::Continue(()),
+            };
+
+            if flow.is_break() {
+                break
+            }
+        }
+
+        if !self.handshake.is_ready() {
+            return Err(self.handshake.error(&self.model, &self.host))
+        }
+
+        Ok(())
+    }
+
+    async fn event(&mut self, text: &str, received_at: Instant) -> Result<ControlFlow<()>> {
+        let printer = self.outputs.printer.clone();
+
+        let mut event = match serde_json::from_str::<Value>(text) {
+            Ok(event) => event,
+            Err(err) => {
+                let preview = protocol::preview(text);
+                warn!("Skipping a server message that is not JSON ({err}): {preview}");
+
+                printer.print(ClientEvent::ProtocolWarning(ProtocolWarning {
+                    kind: "invalid_json".into(),
+                    message: err.to_string(),
+                    preview: Some(preview),
+                    bytes: Some(text.len()),
+                }));
+
+                return Ok(ControlFlow::Continue(()))
+            }
+        };
+
+        if let Some(id) = protocol::server_session_id(&event) {
+            self.server_sessions.push(id);
+        }
+
+        if protocol::ends_turn(&event) {
+            self.frame_recoveries = 0;
+        }
+
+        // the first server event, session.created at best, is when its id is known
+        for event in self.startup_events.drain(..) {
+            printer.print(event);
+        }
+
+        self.turn_spans.observe(&event);
+
+        if self.retry(&event, text, received_at) {
+            return Ok(ControlFlow::Continue(()))
+        }
+
+        let server_end_ms = event["audio_end_ms"].as_f64();
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.lock().unwrap().observe(&event);
+        }
+
+        let mut tagged = self.outputs.density.observe(&mut event);
+
+        if self.short_input.load(Ordering::Relaxed) && protocol::event_type(&event) == "conversation.item.input_audio_transcription.completed" {
+            event["short_input"] = true.into();
+            tagged = true;
+        }
+        let mut corrected = self.outputs.offsets.lock().unwrap().correct_event(&mut event) || tagged;
+
+        if let Some(duplicate) = self.dedup.as_mut().and_then(|dedup| dedup.observe(&event)) {
+            debug!("Dropping turn {}, it repeats {} from the replayed audio", duplicate.item_id, duplicate.duplicate_of);
+            printer.print(ClientEvent::TurnDuplicate(duplicate));
+            return Ok(ControlFlow::Continue(()))
+        }
+
+        if let Some(comparison) = &self.comparison {
+            event["variant"] = Variant::A.tag().into();
+            corrected = true;
+
+            if let (Some(diff), true) = (comparison.lock().unwrap().observe(Variant::A, &event, received_at), self.ab_diff) {
+                printer.print(ClientEvent::AbDiff(diff));
+            }
+        }
+
+        self.outputs.record(&event, received_at);
+
+        let normalize_events = self.outputs.normalize_events;
+
+        if let Some(results) = &self.outputs.results {
+            // turns are reported once finished, the events leading up to them are folded in
+            let result = results.lock().unwrap().observe(&event, received_at);
+
+            match (result, &self.outputs.subtitles) {
+                (Some(result), subtitles) => emit_result(&printer, subtitles.as_deref(), result, received_at),
+                (None, Some(subtitles)) => subtitles.lock().unwrap().translation(&event)?,
+                (None, None) => {}
+            }
+        } else if protocol::is_translation(&event) {
+            event["kind"] = "translation".into();
+            printer.print_at(normalize::event(&event, normalize_events), received_at);
+        } else if self.outputs.translate_only && protocol::is_transcription(&event) {
+            // dropped, only translations are wanted
+        } else if corrected || normalize_events {
+            printer.print_at(normalize::event(&event, normalize_events), received_at);
+        } else {
+            printer.print_at(text, received_at);
+        }
+
+        self.keyword_actions.observe(&event);
+
+        if protocol::ends_turn(&event) {
+            printer.sync();
+        }
+
+        if let Some(terminal) = &self.terminal {
+            terminal.observe(&event);
+        }
+
+        if let Some(tty_out) = &self.outputs.tty_out {
+            tty_out.observe(&event, self.outputs.translate_only);
+        }
+
+        if let Some(transcripts) = &self.outputs.transcripts {
+            collect_transcript(transcripts, &event);
+        }
+
+        if let (Some(beeper), Some(cue)) = (&self.beeper, Cue::for_event(&event)) {
+            beeper.play(cue);
+        }
+
+        // the first session.updated answers the opening session.update, even with a set_vad already sent after it
+        let opening_ack = !self.handshake.is_updated();
+        self.handshake.observe(&event, &self.model, &self.optional_fields)?;
+
+        if self.handshake.is_ready() {
+            self.session_ready.notify_one();
+
+            if !self.ready_notified {
+                self.notifier.ready();
+                self.ready_notified = true;
+            }
+        }
+
+        if protocol::event_type(&event) == "conversation.item.input_audio_transcription.completed" {
+            self.turns_done.fetch_add(1, Ordering::Relaxed);
+        }
+
+        if !opening_ack {
+            self.vad_confirmed(&event);
+        }
+
+        if let (Some((language, to)), Some(server_end_ms)) = (self.router.as_mut().and_then(|router| router.target(&event, &self.model)), server_end_ms) {
+            self.switch_model(language, to, server_end_ms).await;
+        }
+
+        if self.once_done(&event) {
+            return Ok(ControlFlow::Break(()))
+        }
+
+        if protocol::is_throttling_error(&event) {
+            let delay = self.limiter.throttle();
+            let throttled_event = ClientEvent::Throttled(Throttled {
+                retry_after_ms: delay.as_millis() as u64,
+                attempt: self.limiter.backoff_attempts(),
+            });
+
+            printer.print(throttled_event);
+        }
+
+        if protocol::event_type(&event) == "session.finished" {
+            if self.upstream == Upstream::Finishing {
+                self.disconnected().await;
+                return Ok(ControlFlow::Continue(()))
+            }
+
+            return Ok(ControlFlow::Break(()))
+        }
+
+        Ok(ControlFlow::Continue(()))
+    }
+
+    // A failed turn is transcribed again on a session of its own, from the audio kept of it. Returns whether it is.
+    fn retry(&mut self, event: &Value, text: &str, received_at: Instant) -> bool {
+        let (Some(turn_retry), Some(history), Some(item_id)) = (&self.turn_retry, &self.history, protocol::failed_turn(event)) else {
+            return false
+        };
+
+        let span = self.turn_spans.take(item_id);
+        let session_start_ms = self.outputs.offsets.lock().unwrap().session_start_ms();
+        let audio = span.and_then(|(start_ms, end_ms)| history.lock().unwrap().slice(session_start_ms + start_ms, session_start_ms + end_ms));
+
+        let (Some((start_ms, end_ms)), Some(audio)) = (span, audio) else {
+            return false
+        };
+
+        let (turn_retry, item_id, outputs) = (turn_retry.clone(), item_id.to_string(), self.outputs.clone());
+        let (event, text) = (event.clone(), text.to_string());
+
+        tokio::spawn(async move {
+            match turn_retry.run(&item_id, &audio).await {
+                Ok(mut completed) => {
+                    // report the retried turn where it was in the live session
+                    completed["item_id"] = item_id.into();
+                    completed["audio_start_ms"] = (start_ms.round() as u64).into();
+                    completed["audio_end_ms"] = (end_ms.round() as u64).into();
+                    outputs.retried(&mut completed);
+                }
+                Err(err) => {
+                    error!("Giving up on turn {item_id}: {err}");
+                    outputs.failed(&event, &text, received_at);
+                }
+            }
+
+            outputs.printer.sync();
+        });
+
+        true
+    }
+
+    // Reported as the server confirmed a set_vad change, falling back to what was asked for
+    fn vad_confirmed(&self, event: &Value) {
+        let confirmed = protocol::event_type(event) == "session.updated"
+            && self.vad_pending.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |pending| pending.checked_sub(1)).is_ok();
+
+        if !confirmed {
+            return
+        }
+
+        let turn_detection = &event["session"]["turn_detection"];
+        let config = self.live_config.lock().unwrap();
+
+        self.outputs.printer.print(ClientEvent::VadUpdated(VadInfo {
+            threshold: turn_detection["threshold"].as_f64().map_or(config.vad_threshold, |threshold| threshold as f32),
+            silence_duration_ms: turn_detection["silence_duration_ms"].as_u64().map_or(config.vad_silence_ms, |ms| ms as u32),
+            prefix_padding_ms: turn_detection["prefix_padding_ms"].as_u64().map(|ms| ms as u32).or(config.vad_prefix_padding_ms),
+        }));
+    }
+
+    // With --once, whether the one utterance is done. Nothing is printed before it starts.
+    fn once_done(&mut self, event: &Value) -> bool {
+        match &mut self.once_item {
+            Some(item) if item.is_none() && protocol::event_type(event) == "input_audio_buffer.speech_started" => {
+                *item = event["item_id"].as_str().map(Into::into);
+                self.once_started.store(true, Ordering::Relaxed);
+                self.outputs.printer.release();
+                false
+            }
+            // with translation the utterance is done once it is translated as well
+            Some(Some(item)) if event["item_id"].as_str() == Some(item) => match self.translate {
+                true => protocol::translation_text(event).is_some() || protocol::failed_turn(event).is_some(),
+                false => protocol::ends_turn(event),
+            },
+            _ => false,
+        }
+    }
+
+    // The language the server detected has a model of its own, which takes over from the end of the turn
+    async fn switch_model(&mut self, language: String, to: String, server_end_ms: f64) {
+        let (switch_ms, at_ms, replay_end_ms) = {
+            let offsets = self.outputs.offsets.lock().unwrap();
+            (offsets.session_start_ms() + server_end_ms, offsets.to_source_ms(server_end_ms), offsets.to_source_ms(offsets.sent_ms() - offsets.session_start_ms()))
+        };
+
+        if let Err(err) = self.take_over(&to, switch_ms).await {
+            error!("Failed to switch to model {to}: {err}");
+            return
+        }
+
+        if let Some(dedup) = &mut self.dedup {
+            dedup.replayed(at_ms, replay_end_ms);
+        }
+
+        self.outputs.printer.print(ClientEvent::ModelSwitched(ModelSwitched {
+            from: std::mem::replace(&mut self.model, to.clone()),
+            to,
+            language,
+            at_ms: at_ms.round() as u64,
+        }));
+    }
+
+    // An --active-hours window closed or opened, with --schedule-disconnect
+    async fn schedule(&mut self, active: bool) -> Result<()> {
+        match (active, self.upstream) {
+            (false, Upstream::Connected) => {
+                // the turn in progress still gets its results before the session closes
+                let _ = self.control_tx.send(Control::Finish).await;
+                self.upstream = Upstream::Finishing;
+            }
+            (true, Upstream::Finishing | Upstream::Disconnected) => {
+                // still muted, so nothing is sent past this point before the writer has the new session
+                let start_ms = self.outputs.offsets.lock().unwrap().sent_ms();
+                let model = self.model.clone();
+                self.take_over(&model, start_ms).await?;
+                self.upstream = Upstream::Connected;
+                self.muted.store(false, Ordering::Relaxed);
+
+                debug!("Opened a new session for the --active-hours window");
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    // Opens a session on the model and hands it to the writer, which sends it the audio from start_ms on the sent timeline
+    async fn take_over(&mut self, model: &str, start_ms: f64) -> Result<()> {
+        let session_update = self.live_config.lock().unwrap().update_event();
+        let (sink, stream) = open_upstream(&client::endpoint_url(&self.base_url, model, &self.query), &self.api_key, self.max_message_size, &self.limiter, session_update).await?;
+
+        let _ = self.switch_tx.send(Switch { sink: Some(sink), start_ms }).await;
+        self.message_rx = stream;
+
+        // a replacement session counts its offsets and turns from zero again
+        self.outputs.offsets.lock().unwrap().start_session(start_ms);
+
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.lock().unwrap().restart();
+        }
+
+        self.outputs.next_session();
+        self.turn_spans = TurnSpans::default();
+        Ok(())
+    }
+
+    // tungstenite ends the stream after a frame it rejected, a new session picks up with the oldest turn still open.
+    // Without the audio kept for it, the turn in progress is lost and the new session starts where the old one stopped.
+    async fn resume(&mut self) -> Result<()> {
+        let (start_ms, at_ms, replay_end_ms) = {
+            let offsets = self.outputs.offsets.lock().unwrap();
+            let start_ms = match &self.history {
+                Some(history) => (offsets.session_start_ms() + self.turn_spans.pending_from_ms()).max(history.lock().unwrap().kept_from_ms()).min(offsets.sent_ms()),
+                None => offsets.sent_ms(),
+            };
+            (start_ms, offsets.sent_to_source_ms(start_ms), offsets.sent_to_source_ms(offsets.sent_ms()))
+        };
+
+        let model = self.model.clone();
+        self.take_over(&model, start_ms).await?;
+
+        if let Some(dedup) = &mut self.dedup {
+            dedup.replayed(at_ms, replay_end_ms);
+        }
+
+        Ok(())
+    }
+
+    // The session closed on the schedule, the writer drops audio until the next one
+    async fn disconnected(&mut self) {
+        let _ = self.switch_tx.send(Switch { sink: None, start_ms: 0.0 }).await;
+        self.upstream = Upstream::Disconnected;
+    }
+}
+
+// Prints the results of an earlier run of the same files and settings, if there are any. Returns whether there were.
+fn replay_cached(cache: &Cache, key: &str, printer: &Printer, startup_events: &mut Vec<ClientEvent>) -> bool {
+    match cache.load(key) {
+        Ok(Some(lines)) => {
+            debug!("Replaying cached results {key}");
+
+            for event in startup_events.drain(..) {
+                printer.print(event);
+            }
+
+            for (kind, line) in lines {
+                printer.print_recorded(kind, line);
+            }
+
+            printer.sync();
+            true
+        }
+        Ok(None) => {
+            debug!("No cached results for {key}");
+            false
+        }
+        Err(err) => {
+            warn!("Ignoring the cache entry {key}: {err}");
+            false
+        }
+    }
+}
+
+// What a finished run printed, for the next run of the same files and settings
+fn store_cached(cache: &Cache, key: &str, args: &Args, printer: &Printer) {
+    let recorded = printer.recorded().unwrap_or_default();
+
+    // a failure would be replayed forever, the next run gets another chance instead
+    if cache::failed(&recorded) {
+        debug!("Not caching {key}, the session reported errors");
+    } else if let Err(err) = cache.store(key, json!({ "model": args.model, "files": args.files }), &recorded) {
+        warn!("Failed to write the cache entry {key}: {err}");
+    }
+}
+
+fn chaos(args: &Args) -> Chaos {
+    Chaos {
+        drop_connection_after: args.chaos_drop_connection_after_s.map(Duration::from_secs),
+        delay_events: args.chaos_delay_events_ms.map(Duration::from_millis),
+        corrupt_every_n: args.chaos_corrupt_every_n,
+        stall_stdin_after: args.chaos_stall_stdin_after_s.map(Duration::from_secs),
+    }
+}
+
+fn notifier(sd_notify: bool) -> Notifier {
+    let notifier = Notifier::from_env();
+
+    if sd_notify && !notifier.is_enabled() {
+        let message = match cfg!(target_os = "linux") {
+            true => format!("--sd-notify needs {} set, run it as a Type=notify systemd service", systemd::NOTIFY_SOCKET),
+            false => "--sd-notify is only supported on Linux".into(),
+        };
+        Cli::command().error(ErrorKind::InvalidValue, message).exit();
+    }
+
+    notifier
+}
+
+// Connects and configures a session that takes over from the current one
+async fn open_upstream(url: &Url, api_key: &str, max_message_size: usize, limiter: &Limiter, session_update: Value) -> Result<(SplitSink<client::WsStream, Message>, SplitStream<client::WsStream>)> {
+    let mut ws_stream = client::connect(url, api_key, max_message_size).await?;
+    limiter.acquire_request().await;
+    ws_stream.send(Message::Text(session_update.to_string().into())).await?;
+    Ok(ws_stream.split())
+}
+
+fn collect_transcript(transcripts: &Transcripts, event: &Value) {
+    if protocol::event_type(event) == "conversation.item.input_audio_transcription.completed" {
+        let transcript = event["transcript"].as_str().unwrap_or_default().to_string();
+        transcripts.lock().unwrap().push((event["audio_start_ms"].as_f64(), transcript));
+    }
+}
+
+// A finished turn as --format results, srt or ass wants it
+fn emit_result(printer: &Printer, subtitles: Option<&Mutex<Subtitles>>, result: TurnResult, received_at: Instant) {
+    match subtitles {
+        Some(subtitles) => {
+            if let Err(err) = subtitles.lock().unwrap().turn(result) {
+                error!("Failed to write a subtitle cue: {err}");
+            }
+        }
+        None => printer.print_turn(result, received_at),
+    }
+}
+
+fn open_subtitles(args: &Args, format: SubtitleFormat, printer: &Arc<Printer>) -> io::Result<Subtitles> {
+    let fallback = (args.subtitle_fallback == SubtitleFallback::Mark).then(|| UNTRANSLATED_MARKER.to_string());
+
+    if args.subtitle_out.is_empty() {
+        return Ok(Subtitles::new(Some(CueWriter::stdout(printer.clone(), format)?), None, fallback))
+    }
+
+    let open = |track| {
+        args.subtitle_out
+            .iter()
+            .find(|(output, _)| *output == track)
+            .map(|(_, path)| CueWriter::create(path, format, args.durable))
+            .transpose()
+    };
+
+    Ok(Subtitles::new(open(Track::Transcript)?, open(Track::Translation)?, fallback))
+}
+
+// Everything besides the audio that changes what the server returns or what gets printed
+fn cache_params(args: &Args, session_update: &Value) -> Value {
+    json!({
+        "endpoint": client::endpoint_url(&args.base_url, &args.model, &args.query).as_str(),
+        "session": session_update["session"],
+        "language_route": args.language_route,
+        "route_min_interval_s": (!args.language_route.is_empty()).then_some(args.route_min_interval_s),
+        "dedup_similarity": (!args.language_route.is_empty()).then_some(args.dedup_similarity),
+        "translate_only": args.translate_only,
+        "normalize_events": args.normalize_events,
+        "format": format!("{:?}", args.format),
+        "ab_model": args.ab_model,
+        "ab_diff": args.ab_diff,
+        "trim_threshold_ms": args.trim_silence.then_some(args.trim_threshold_ms),
+        "trim_silence_db": args.trim_silence.then_some(args.trim_silence_db),
+        "preroll_ms": args.trim_silence.then_some(args.preroll_ms),
+        "min_audio_ms": args.min_audio_ms,
+        "seek_bytes": args.seek.map(|seek| input::bytes_at(seek, args.sample_rate)),
+        "duration_bytes": args.duration.map(|duration| input::bytes_at(duration, args.sample_rate)),
+        "min_cps": args.min_cps,
+        "max_cps": args.max_cps,
+        "active_hours": format!("{:?}", args.active_hours),
+        "active_days": args.active_days.map(|days| format!("{days:?}")),
+        "timezone": args.timezone.as_ref().map(|timezone| timezone.iana_name().map_or_else(|| format!("{timezone:?}"), str::to_string)),
+        "reference": args.reference,
+        "reference_detail": args.reference_detail,
+    })
+}
+
+fn require_api_key(args: &Args) -> &str {
+    let Some(api_key) = &args.api_key else {
+        Cli::command()
+            .error(ErrorKind::MissingRequiredArgument, "--api-key or DASHSCOPE_API_KEY is required")
+            .exit();
+    };
+
+    api_key
+}
+
+fn session_config(args: &Args) -> SessionConfig {
+    if let (Err(err), false) = (client::validate_vad(Some(args.vad_threshold), Some(args.vad_silence_ms)), args.force) {
+        Cli::command().error(ErrorKind::InvalidValue, format!("{err}, pass --force to send it anyway")).exit();
+    }
+
+    let config = SessionConfig {
+        sample_rate: args.sample_rate,
+        language: args.language.clone(),
+        vad_threshold: args.vad_threshold,
+        vad_silence_ms: args.vad_silence_ms,
+        vad_prefix_padding_ms: args.vad_prefix_padding_ms,
+        // captures are committed from the terminal instead
+        server_vad: !args.interactive,
+        translate_to: args.translate_to.clone(),
+        itn: args.itn.map(Toggle::enabled),
+        punctuation: args.punctuation.map(Toggle::enabled),
+    };
+
+    // every model the run may switch to gets the same settings
+    let host = args.base_url.host_str().unwrap_or_default();
+    let models = std::iter::once(&args.model).chain(&args.ab_model).chain(args.language_route.iter().map(|(_, model)| model));
+
+    for model in models.filter(|_| !args.force && !args.probe_capabilities) {
+        if let Err(err) = capability::check(host, model, &config) {
+            Cli::command().error(ErrorKind::InvalidValue, format!("{err} (--force sends it anyway)")).exit();
+        }
+    }
+
+    config
+}
+
+// What the --keyword-action rules act on
+struct KeywordActions {
+    rules: Vec<Rule>,
+    export_dir: PathBuf,
+    name_template: Option<Template>,
+    // the client.session_info id, named in templates
+    session_id: String,
+    sample_rate: u32,
+    printer: Arc<Printer>,
+    paused: Arc<AtomicBool>,
+    offsets: Arc<Mutex<OffsetMap>>,
+    history: Option<Arc<Mutex<AudioHistory>>>,
+    turn_history: Option<Arc<Mutex<TurnHistory>>>,
+    exports: Arc<Mutex<JoinSet<()>>>,
+}
+
+impl KeywordActions {
+    // Final transcripts only, a phrase in a partial hypothesis may still be revised away
+    fn observe(&self, event: &Value) {
+        if self.rules.is_empty() || protocol::event_type(event) != "conversation.item.input_audio_transcription.completed" {
+            return
+        }
+
+        let tokens = keyword::tokens(event["transcript"].as_str().unwrap_or_default());
+        let item_id = event["item_id"].as_str().unwrap_or_default();
+
+        for rule in self.rules.iter().filter(|rule| rule.matches(&tokens)) {
+            debug!("Heard \"{}\", running {}", rule.phrase, rule.action);
+
+            match rule.action {
+                Action::ExportLast(seconds) => {
+                    // snapshotted here, written where the disk holds nobody up, and announced once it is
+                    let (audio, turns) = self.last(seconds as f64 * 1000.0);
+                    let (export_dir, sample_rate, printer) = (self.export_dir.clone(), self.sample_rate, self.printer.clone());
+                    let path = export_dir.join(self.export_name(event));
+                    let triggered = KeywordTriggered { rule: rule.phrase.clone(), action: rule.action.to_string(), item_id: item_id.into(), path: None };
+
+                    self.exports.lock().unwrap().spawn_blocking(move || match keyword::export(&path, sample_rate, &audio, &turns) {
+                        Ok(path) => printer.print(ClientEvent::KeywordTriggered(KeywordTriggered { path: Some(path.display().to_string()), ..triggered })),
+                        Err(err) => error!("Failed to export the last {seconds} s to {}: {err}", export_dir.display()),
+                    });
+
+                    continue
+                }
+                Action::Mark => self.printer.print(ClientEvent::Marker(Marker {
+                    rule: rule.phrase.clone(),
+                    item_id: item_id.into(),
+                    start_ms: event["audio_start_ms"].as_u64(),
+                    end_ms: event["audio_end_ms"].as_u64(),
+                })),
+                Action::Mute => self.paused.store(true, Ordering::Relaxed),
+                Action::Unmute => self.paused.store(false, Ordering::Relaxed),
+            }
+
+            self.printer.print(ClientEvent::KeywordTriggered(KeywordTriggered { rule: rule.phrase.clone(), action: rule.action.to_string(), item_id: item_id.into(), path: None }));
+        }
+    }
+
+    // The trigger turn is the last one recorded, unless the turn history is off and the event is all there is
+    fn export_name(&self, event: &Value) -> PathBuf {
+        let Some(template) = &self.name_template else {
+            return keyword::default_name()
+        };
+
+        let trigger = self.turn_history.as_ref().and_then(|turn_history| turn_history.lock().unwrap().last(1).pop()).filter(|turn| event["item_id"].as_str() == Some(turn.item_id.as_str()));
+
+        let fields = match trigger {
+            Some(turn) => Fields { stem: Fields::stem_of(&turn.source), turn: Some(turn.turn), start_ms: turn.start_ms, end_ms: turn.end_ms, language: turn.language.clone(), ..Fields::default() },
+            None => Fields {
+                stem: "unknown".into(),
+                start_ms: event["audio_start_ms"].as_u64(),
+                end_ms: event["audio_end_ms"].as_u64(),
+                language: event["language"].as_str().map(Into::into),
+                ..Fields::default()
+            },
+        };
+
+        template.render(&Fields { session: self.session_id.clone(), ..fields }.now())
+    }
+
+    // The last ms of sent audio and the turns heard in it, both from the same point of the sent audio. The turns are on
+    // the source timeline, where trimmed silences and --seek put that point further along.
+    fn last(&self, ms: f64) -> (Vec<u8>, Vec<TurnResult>) {
+        let (start_ms, source_start_ms) = {
+            let offsets = self.offsets.lock().unwrap();
+            let start_ms = (offsets.sent_ms() - ms).max(0.0);
+            (start_ms, offsets.sent_to_source_ms(start_ms))
+        };
+
+        let audio = self.history.as_ref().map(|history| history.lock().unwrap().since(start_ms)).unwrap_or_default();
+        let turns = self.turn_history.as_ref().map(|turn_history| turn_history.lock().unwrap().since(source_start_ms.round() as u64)).unwrap_or_default();
+
+        (audio, turns)
+    }
+}
+
+// A configured session, as far as --check and --self-test get before any audio
+struct OpenedSession {
+    ws_stream: client::WsStream,
+    updated: Value,
+    connect_ms: u64,
+    rtt: Duration,
+}
+
+// Sets up a session exactly like a transcription would, without reading any input
+async fn open_session(args: &Args, session_config: &SessionConfig) -> Result<OpenedSession> {
+    let api_key = require_api_key(args);
+    let host = args.base_url.host_str().unwrap_or_default();
+    let optional_fields = session_config.optional_fields();
+
+    let started = Instant::now();
+    let mut ws_stream = client::connect(&client::endpoint_url(&args.base_url, &args.model, &args.query), api_key, args.max_message_size as usize).await?;
+    let connect_ms = started.elapsed().as_millis() as u64;
+
+    let sent = Instant::now();
+    ws_stream.send(Message::Text(session_config.update_event().to_string().into())).await?;
+
+    let mut handshake = Handshake::default();
+
+    let updated = tokio::time::timeout(CHECK_TIMEOUT, async {
+        while let Some(msg) = ws_stream.next().await {
+            let msg = match msg {
+                Err(_) if handshake.has_errors() => break,
+                msg => msg?,
+            };
+
+            let Message::Text(text) = msg else {
+                continue
+            };
+
+            let Ok(event) = serde_json::from_str::<Value>(&text) else {
+                continue
+            };
+
+            handshake.observe(&event, &args.model, &optional_fields)?;
+
+            if handshake.is_updated() {
+                return Ok(Some((event, sent.elapsed())))
+            }
+
+            // a session that already reported an error is not worth waiting for
+            if handshake.has_errors() {
+                break
+            }
+        }
+
+        Ok::<_, AsrError>(None)
+    })
+    .await
+    .map_err(|_| AsrError::Timeout("waiting for session.updated".into()))??;
+
+    let Some((updated, rtt)) = updated else {
+        return Err(handshake.error(&args.model, host))
+    };
+
+    Ok(OpenedSession { ws_stream, updated, connect_ms, rtt })
+}
+
+async fn check(args: &Args) -> Result<()> {
+    let OpenedSession { mut ws_stream, updated, connect_ms, rtt } = open_session(args, &session_config(args)).await?;
+    let _ = ws_stream.close(None).await;
+
+    let printer = match args.ascii_json {
+        true => Printer::new(args.timestamps).ascii_json(),
+        false => Printer::new(args.timestamps),
+    };
+
+    printer.print(ClientEvent::Check(Check {
+        model: args.model.clone(),
+        host: args.base_url.host_str().unwrap_or_default().into(),
+        server_session_id: updated["session"]["id"].as_str().map(Into::into),
+        session: updated["session"].clone(),
+        connect_ms,
+        rtt_ms: rtt.as_millis() as u64,
+    }));
+
+    Ok(())
+}
+
+// A plain session first, so a bad key or model fails as usual, then one more per optional setting
+async fn probe_capabilities(args: &Args) -> Result<()> {
+    let mut plain = session_config(args);
+    (plain.translate_to, plain.itn, plain.punctuation) = (None, None, None);
+
+    let OpenedSession { mut ws_stream, .. } = open_session(args, &plain).await?;
+    let _ = ws_stream.close(None).await;
+
+    let host = args.base_url.host_str().unwrap_or_default();
+    let known = capability::lookup(host, &args.model);
+    let mut features = Vec::new();
+
+    for feature in capability::FEATURES {
+        let mut session_config = plain.clone();
+        feature.enable(&mut session_config);
+
+        let (supported, message) = match open_session(args, &session_config).await {
+            Ok(mut opened) => {
+                let _ = opened.ws_stream.close(None).await;
+                (true, None)
+            }
+            Err(AsrError::Protocol { message, .. }) => (false, Some(message)),
+            Err(err) => return Err(err),
+        };
+
+        features.push(FeatureProbe {
+            flag: feature.flag().into(),
+            supported,
+            expected: known.map(|entry| entry.features.contains(&feature)),
+            message,
+        });
+    }
+
+    let printer = match args.ascii_json {
+        true => Printer::new(args.timestamps).ascii_json(),
+        false => Printer::new(args.timestamps),
+    };
+
+    printer.print(ClientEvent::Capabilities(Capabilities { model: args.model.clone(), host: host.into(), features }));
+    Ok(())
+}
+
+// Sends a generated tone with turn detection off, commits it and waits for any transcription event.
+// The tone is no speech, an empty or failed transcript still shows the whole pipeline works.
+async fn self_test(args: &Args) -> Result<()> {
+    let mut session_config = session_config(args);
+    session_config.server_vad = false;
+
+    let started = Instant::now();
+
+    let (opened, round_trip) = match open_session(args, &session_config).await {
+        Ok(mut opened) => {
+            let round_trip = self_test_round_trip(&mut opened.ws_stream, args.sample_rate).await;
+
+            // the session ends on the server even when nothing came back in time
+            let _ = opened.ws_stream.send(Message::Text(client::finish_event().to_string().into())).await;
+            let _ = opened.ws_stream.close(None).await;
+            (Some(opened), round_trip)
+        }
+        Err(err) => (None, Err(err)),
+    };
+
+    let setup = opened.map(|opened| format!("connect {} ms, session.updated {} ms, ", opened.connect_ms, opened.rtt.as_millis())).unwrap_or_default();
+
+    match round_trip {
+        Ok((kind, latency)) => {
+            eprintln!("asr: self-test PASS: {setup}{kind} {} ms after commit", latency.as_millis());
+            Ok(())
+        }
+        Err(err) => {
+            eprintln!("asr: self-test FAIL after {} ms: {setup}{err}", started.elapsed().as_millis());
+            std::process::exit(err.exit_code());
+        }
+    }
+}
+
+// Returns the type of the first transcription event and how long after the commit it came
+async fn self_test_round_trip(ws_stream: &mut client::WsStream, sample_rate: u32) -> Result<(String, Duration)> {
+    let samples = generate::synthesize(SELF_TEST_SIGNAL, sample_rate, generate::sample_count(sample_rate, SELF_TEST_DURATION_S), 0.5);
+    let audio: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+
+    // 100 ms per append, like a live capture
+    for chunk in audio.chunks(sample_rate as usize / 5) {
+        ws_stream.send(Message::Text(client::audio_append_event(chunk).to_string().into())).await?;
+    }
+
+    ws_stream.send(Message::Text(client::commit_event().to_string().into())).await?;
+    let committed = Instant::now();
+
+    tokio::time::timeout(SELF_TEST_TIMEOUT, async {
+        while let Some(msg) = ws_stream.next().await {
+            let Message::Text(text) = msg? else {
+                continue
+            };
+
+            let Ok(event) = serde_json::from_str::<Value>(&text) else {
+                continue
+            };
+
+            if let Some((code, message)) = protocol::error_detail(&event) {
+                return Err(AsrError::Protocol { code, message })
+            }
+
+            if protocol::is_transcription(&event) {
+                return Ok((protocol::event_type(&event).to_string(), committed.elapsed()))
+            }
+        }
+
+        Err(AsrError::Closed { code: 1006, reason: "server closed the connection before any transcription event".into() })
+    })
+    .await
+    .map_err(|_| AsrError::Timeout(format!("no transcription event within {}s of the commit", SELF_TEST_TIMEOUT.as_secs())))?
+}
+
+fn resolved_config(matches: &ArgMatches) -> Value {
+    let mut config = serde_json::Map::new();
+
+    for arg in Cli::command().get_arguments() {
+        let id = arg.get_id().as_str();
+
+        if matches!(id, "help" | "version" | "print_config") {
+            continue
+        }
+
+        let source = match matches.value_source(id) {
+            Some(ValueSource::CommandLine) => "cli",
+            Some(ValueSource::EnvVariable) => "env",
+            Some(ValueSource::DefaultValue) => "default",
+            _ => "unset",
+        };
+
+        let value = match matches.get_raw(id) {
+            Some(_) if id == "api_key" => "<redacted>".into(),
+            Some(mut values) if values.len() == 1 => values.next().unwrap().to_string_lossy().into(),
+            Some(values) => values.map(|value| value.to_string_lossy()).collect::<Vec<_>>().into(),
+            None => Value::Null,
+        };
+
+        config.insert(id.into(), json!({ "value": value, "source": source }));
+    }
+
+    config.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_option_has_an_environment_variable() {
+        let mut commands = vec![Cli::command()];
+
+        while let Some(command) = commands.pop() {
+            for arg in command.get_arguments().filter(|arg| arg.get_long().is_some()) {
+                let id = arg.get_id().as_str();
+
+                if matches!(id, "help" | "version" | "print_config") {
+                    continue
+                }
+
+                let env = arg.get_env().map(|env| env.to_string_lossy().into_owned());
+                assert!(env.as_deref().is_some_and(|env| env.starts_with("ASR_") || env == "DASHSCOPE_API_KEY"), "{} --{} has no ASR_ variable", command.get_name(), arg.get_long().unwrap());
+            }
+
+            commands.extend(command.get_subcommands().cloned());
+        }
+    }
+
+    // The only test setting variables, the others in this binary never read them
+    #[test]
+    fn resolves_options_from_the_environment() {
+        let vars = [
+            ("ASR_MODEL", "qwen3-asr-flash-realtime-2025-10-27"),
+            ("ASR_BASE_URL", "ws://127.0.0.1:9999"),
+            ("ASR_QUERY", "region=cn,debug=1"),
+            ("ASR_SAMPLE_RATE", "8000"),
+            ("ASR_LANGUAGE", "en"),
+            ("ASR_VAD_THRESHOLD", "0.5"),
+            ("ASR_VAD_SILENCE_MS", "1200"),
+            ("ASR_FORMAT", "results"),
+            ("ASR_KEEP", "true"),
+            ("ASR_TRIM_SILENCE", "false"),
+            ("ASR_MIN_AUDIO_MS", "250"),
+            ("ASR_KEYWORD_ACTION", "note that=mark,stop listening=mute"),
+        ];
+        for (name, value) in vars {
+            std::env::set_var(name, value);
+        }
+
+        let matches = Cli::command().try_get_matches_from(["asr", "--language", "ja", "--vad-silence-ms", "600"]);
+        let cli = matches.as_ref().map_err(ToString::to_string).and_then(|matches| Cli::from_arg_matches(matches).map_err(|err| err.to_string()));
+
+        for (name, _) in vars {
+            std::env::remove_var(name);
+        }
+
+        let (matches, args) = (matches.unwrap(), cli.unwrap().transcribe);
+        assert_eq!(args.model, "qwen3-asr-flash-realtime-2025-10-27");
+        assert_eq!(args.base_url.as_str(), "ws://127.0.0.1:9999/");
+        assert_eq!(args.query, [("region".into(), "cn".into()), ("debug".into(), "1".into())]);
+        assert_eq!(args.sample_rate, 8000);
+        assert_eq!(args.vad_threshold, 0.5);
+        assert_eq!(args.format, Format::Results);
+        assert!(args.keep && !args.trim_silence);
+        assert_eq!(args.keyword_action.iter().map(|rule| (rule.phrase.as_str(), rule.action)).collect::<Vec<_>>(), [("note that", Action::Mark), ("stop listening", Action::Mute)]);
+        assert_eq!(args.min_audio_ms, 250);
+
+        // the command line wins
+        assert_eq!(args.language, "ja");
+        assert_eq!(args.vad_silence_ms, 600);
+
+        let config = resolved_config(&matches);
+        assert_eq!(config["language"], json!({ "value": "ja", "source": "cli" }));
+        assert_eq!(config["sample_rate"], json!({ "value": "8000", "source": "env" }));
+        assert_eq!(config["keep"], json!({ "value": "true", "source": "env" }));
+        assert_eq!(config["query"], json!({ "value": ["region=cn", "debug=1"], "source": "env" }));
+        assert_eq!(config["route_min_interval_s"], json!({ "value": "30", "source": "default" }));
+        assert_eq!(config["ab_model"]["source"], "unset");
+    }
+}
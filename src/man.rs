@@ -0,0 +1,44 @@
+use crate::error::EXIT_CODES;
+use clap_mangen::Man;
+use roff::{bold, roman, Roff};
+use std::io::{self, Write};
+
+pub fn render(cmd: clap::Command, w: &mut dyn Write) -> io::Result<()> {
+    let man = Man::new(cmd.clone());
+
+    man.render_title(w)?;
+    man.render_name_section(w)?;
+    man.render_synopsis_section(w)?;
+    man.render_description_section(w)?;
+    man.render_options_section(w)?;
+
+    if cmd.has_subcommands() {
+        man.render_subcommands_section(w)?;
+    }
+
+    let mut roff = Roff::new();
+
+    roff.control("SH", ["ENVIRONMENT"]);
+    for arg in cmd.get_arguments() {
+        if let Some(env) = arg.get_env() {
+            let long = arg.get_long().unwrap_or_default();
+            let help = arg.get_help().map_or_else(|| format!("Same as --{long}"), |help| format!("{help} (--{long})"));
+
+            roff.control("TP", []);
+            roff.text([bold(env.to_string_lossy())]);
+            roff.text([roman(help)]);
+        }
+    }
+
+    roff.control("SH", ["EXIT STATUS"]);
+    for (code, meaning) in EXIT_CODES {
+        roff.control("TP", []);
+        roff.text([bold(code.to_string())]);
+        roff.text([roman(*meaning)]);
+    }
+
+    roff.to_writer(w)?;
+
+    man.render_version_section(w)?;
+    man.render_authors_section(w)
+}
@@ -0,0 +1,158 @@
+use crate::protocol;
+use log::debug;
+use serde_json::{json, Value};
+use std::borrow::Cow;
+
+// The event types --normalize-events emits, whichever dialect the provider speaks
+pub const CANONICAL_TYPES: &[&str] = &[
+    "session.opened",
+    "session.ready",
+    "session.finished",
+    "vad.start",
+    "vad.end",
+    "buffer.committed",
+    "buffer.cleared",
+    "transcript.partial",
+    "transcript.final",
+    "transcript.failed",
+    "translation.partial",
+    "translation.final",
+    "error",
+];
+
+// DashScope realtime
+const DASHSCOPE: &[(&str, &str)] = &[
+    ("session.created", "session.opened"),
+    ("session.updated", "session.ready"),
+    ("session.finished", "session.finished"),
+    ("input_audio_buffer.speech_started", "vad.start"),
+    ("input_audio_buffer.speech_stopped", "vad.end"),
+    ("input_audio_buffer.committed", "buffer.committed"),
+    ("input_audio_buffer.cleared", "buffer.cleared"),
+    ("conversation.item.input_audio_transcription.text", "transcript.partial"),
+    ("conversation.item.input_audio_transcription.completed", "transcript.final"),
+    ("conversation.item.input_audio_transcription.failed", "transcript.failed"),
+    ("error", "error"),
+];
+
+// OpenAI-compatible gateways, in transcription sessions
+const OPENAI: &[(&str, &str)] = &[
+    ("transcription_session.created", "session.opened"),
+    ("transcription_session.updated", "session.ready"),
+    ("conversation.item.input_audio_transcription.delta", "transcript.partial"),
+];
+
+pub fn canonical_type(event: &Value) -> Option<&'static str> {
+    let kind = protocol::event_type(event);
+
+    if let Some((_, canonical)) = DASHSCOPE.iter().chain(OPENAI).find(|(provider, _)| *provider == kind) {
+        return Some(canonical)
+    }
+
+    // translation types vary the most, only partial or final matters
+    if protocol::is_translation(event) {
+        return Some(match protocol::translation_text(event) {
+            Some(_) => "translation.final",
+            None => "translation.partial",
+        })
+    }
+
+    None
+}
+
+// The event renamed onto the canonical set, with its provider type kept as original_type
+pub fn event(event: &Value, enabled: bool) -> Cow<'_, Value> {
+    if !enabled {
+        return Cow::Borrowed(event)
+    }
+
+    let Some(canonical) = canonical_type(event) else {
+        debug!("No canonical type for {}, passing it through", protocol::event_type(event));
+        return Cow::Borrowed(event)
+    };
+
+    let mut normalized = event.clone();
+    normalized["original_type"] = normalized["type"].take();
+    normalized["type"] = canonical.into();
+    Cow::Owned(normalized)
+}
+
+// For the JSON Schema export, the fields every normalized event has
+pub fn schema() -> Value {
+    json!({
+        "description": "A server event as printed with --normalize-events, all other fields as the provider sent them",
+        "type": "object",
+        "properties": {
+            "type": { "enum": CANONICAL_TYPES },
+            "original_type": { "type": "string" }
+        },
+        "required": ["type", "original_type"]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Events as each dialect sends them, with the canonical type they map to
+    const DASHSCOPE_FIXTURES: &[(&str, &str)] = &[
+        (r#"{"event_id":"event_1","type":"session.created","session":{"id":"sess_1","object":"realtime.session","model":"qwen3-asr-flash-realtime","modalities":["text"],"input_audio_format":"pcm16"}}"#, "session.opened"),
+        (r#"{"event_id":"event_2","type":"session.updated","session":{"id":"sess_1","input_audio_transcription":{"language":"zh"},"turn_detection":{"type":"server_vad","threshold":0.2,"silence_duration_ms":800}}}"#, "session.ready"),
+        (r#"{"event_id":"event_3","type":"input_audio_buffer.speech_started","audio_start_ms":1040,"item_id":"item_1"}"#, "vad.start"),
+        (r#"{"event_id":"event_4","type":"input_audio_buffer.speech_stopped","audio_end_ms":2880,"item_id":"item_1"}"#, "vad.end"),
+        (r#"{"event_id":"event_5","type":"input_audio_buffer.committed","previous_item_id":null,"item_id":"item_1"}"#, "buffer.committed"),
+        (r#"{"event_id":"event_6","type":"conversation.item.input_audio_transcription.text","item_id":"item_1","content_index":0,"text":"今天","stash":"天气"}"#, "transcript.partial"),
+        (r#"{"event_id":"event_7","type":"conversation.item.input_audio_transcription.completed","item_id":"item_1","content_index":0,"transcript":"今天天气不错。","language":"zh","emotion":"neutral"}"#, "transcript.final"),
+        (r#"{"event_id":"event_8","type":"conversation.item.input_audio_transcription.failed","item_id":"item_2","content_index":0,"error":{"code":"InternalError","message":"failed"}}"#, "transcript.failed"),
+        (r#"{"event_id":"event_9","type":"error","error":{"type":"invalid_request_error","code":"invalid_value","message":"Invalid value","param":"session.turn_detection.threshold"}}"#, "error"),
+        (r#"{"event_id":"event_10","type":"session.finished"}"#, "session.finished"),
+    ];
+
+    const OPENAI_FIXTURES: &[(&str, &str)] = &[
+        (r#"{"event_id":"event_A1","type":"transcription_session.created","session":{"id":"sess_A1","object":"realtime.transcription_session","input_audio_format":"pcm16","input_audio_transcription":{"model":"gpt-4o-transcribe"}}}"#, "session.opened"),
+        (r#"{"event_id":"event_A2","type":"transcription_session.updated","session":{"id":"sess_A1","turn_detection":{"type":"server_vad","threshold":0.5,"prefix_padding_ms":300,"silence_duration_ms":500}}}"#, "session.ready"),
+        (r#"{"event_id":"event_A3","type":"input_audio_buffer.speech_started","audio_start_ms":512,"item_id":"item_A1"}"#, "vad.start"),
+        (r#"{"event_id":"event_A4","type":"input_audio_buffer.speech_stopped","audio_end_ms":1984,"item_id":"item_A1"}"#, "vad.end"),
+        (r#"{"event_id":"event_A5","type":"input_audio_buffer.committed","previous_item_id":null,"item_id":"item_A1"}"#, "buffer.committed"),
+        (r#"{"event_id":"event_A6","type":"input_audio_buffer.cleared"}"#, "buffer.cleared"),
+        (r#"{"event_id":"event_A7","type":"conversation.item.input_audio_transcription.delta","item_id":"item_A1","content_index":0,"delta":"Hello"}"#, "transcript.partial"),
+        (r#"{"event_id":"event_A8","type":"conversation.item.input_audio_transcription.completed","item_id":"item_A1","content_index":0,"transcript":"Hello world."}"#, "transcript.final"),
+        (r#"{"event_id":"event_A9","type":"response.text.delta","response_id":"resp_A1","delta":"你好"}"#, "translation.partial"),
+        (r#"{"event_id":"event_A10","type":"response.text.done","response_id":"resp_A1","text":"你好，世界。"}"#, "translation.final"),
+    ];
+
+    #[test]
+    fn round_trips_both_dialects() {
+        for &(line, canonical) in DASHSCOPE_FIXTURES.iter().chain(OPENAI_FIXTURES) {
+            let original: Value = serde_json::from_str(line).unwrap();
+            let normalized = event(&original, true).into_owned();
+
+            assert_eq!(normalized["type"], canonical, "{line}");
+            assert_eq!(normalized["original_type"], original["type"], "{line}");
+            assert!(CANONICAL_TYPES.contains(&canonical));
+
+            // every other field as the provider sent it
+            let mut restored = normalized.clone();
+            restored["type"] = restored["original_type"].clone();
+            restored.as_object_mut().unwrap().remove("original_type");
+            assert_eq!(restored, original, "{line}");
+        }
+    }
+
+    #[test]
+    fn covers_every_canonical_type() {
+        let mapped: Vec<&str> = DASHSCOPE_FIXTURES.iter().chain(OPENAI_FIXTURES).map(|&(_, canonical)| canonical).collect();
+        assert!(CANONICAL_TYPES.iter().all(|canonical| mapped.contains(canonical)));
+    }
+
+    #[test]
+    fn passes_unknown_types_through() {
+        let unknown: Value = serde_json::from_str(r#"{"type":"rate_limits.updated","rate_limits":[]}"#).unwrap();
+        assert!(canonical_type(&unknown).is_none());
+        assert!(matches!(event(&unknown, true), Cow::Borrowed(borrowed) if *borrowed == unknown));
+
+        // nothing changes unless asked for
+        let completed: Value = serde_json::from_str(DASHSCOPE_FIXTURES[6].0).unwrap();
+        assert!(matches!(event(&completed, false), Cow::Borrowed(borrowed) if *borrowed == completed));
+    }
+}
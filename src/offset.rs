@@ -0,0 +1,296 @@
+use crate::words;
+use serde_json::Value;
+
+const OFFSET_FIELDS: &[&str] = &["audio_start_ms", "audio_end_ms"];
+const WORD_OFFSET_FIELDS: &[&str] = &["start_ms", "end_ms", "begin_time", "end_time"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Gap {
+    at_ms: f64,
+    skipped_ms: f64,
+}
+
+// Maps offsets on the timeline the server saw back to the original source timeline
+#[derive(Debug)]
+pub struct OffsetMap {
+    bytes_per_ms: f64,
+    sent_ms: f64,
+    session_start_ms: f64,
+    gaps: Vec<Gap>,
+    // sent-audio spans that waited in the spool, their turns arrive late
+    backfilled: Vec<(f64, f64)>,
+}
+
+impl OffsetMap {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            bytes_per_ms: sample_rate as f64 * 2.0 / 1000.0,
+            sent_ms: 0.0,
+            session_start_ms: 0.0,
+            gaps: Vec::new(),
+            backfilled: Vec::new(),
+        }
+    }
+
+    pub fn sent(&mut self, bytes: usize) {
+        self.sent_ms += bytes as f64 / self.bytes_per_ms;
+    }
+
+    // Audio dropped at the current sent position extends the gap already open there
+    pub fn skipped(&mut self, bytes: usize) {
+        let skipped_ms = bytes as f64 / self.bytes_per_ms;
+
+        match self.gaps.last_mut() {
+            Some(gap) if gap.at_ms == self.sent_ms => gap.skipped_ms += skipped_ms,
+            _ => self.gaps.push(Gap { at_ms: self.sent_ms, skipped_ms }),
+        }
+    }
+
+    // The last bytes sent went through the spool rather than straight out
+    pub fn backfill(&mut self, bytes: usize) {
+        let start_ms = self.sent_ms - bytes as f64 / self.bytes_per_ms;
+
+        match self.backfilled.last_mut() {
+            Some(span) if span.1 >= start_ms => span.1 = self.sent_ms,
+            _ => self.backfilled.push((start_ms, self.sent_ms)),
+        }
+    }
+
+    // Audio already counted as sent, starting tail_bytes before the current position, never reached the server.
+    // What came after it moves up.
+    pub fn dropped(&mut self, tail_bytes: usize, bytes: usize) {
+        let at_ms = self.sent_ms - tail_bytes as f64 / self.bytes_per_ms;
+        let dropped_ms = bytes as f64 / self.bytes_per_ms;
+        let move_up = |ms: &mut f64| {
+            if *ms > at_ms {
+                *ms = (*ms - dropped_ms).max(at_ms);
+            }
+        };
+
+        self.sent_ms -= dropped_ms;
+        self.gaps.iter_mut().for_each(|gap| move_up(&mut gap.at_ms));
+        self.backfilled.iter_mut().for_each(|(start_ms, end_ms)| {
+            move_up(start_ms);
+            move_up(end_ms);
+        });
+
+        let index = self.gaps.partition_point(|gap| gap.at_ms <= at_ms);
+        self.gaps.insert(index, Gap { at_ms, skipped_ms: dropped_ms });
+    }
+
+    // A replacement session counts its offsets from zero again, starting at this point of the sent audio
+    pub fn start_session(&mut self, start_ms: f64) {
+        self.session_start_ms = start_ms;
+    }
+
+    pub fn sent_ms(&self) -> f64 {
+        self.sent_ms
+    }
+
+    pub fn session_start_ms(&self) -> f64 {
+        self.session_start_ms
+    }
+
+    pub fn to_source_ms(&self, server_ms: f64) -> f64 {
+        self.sent_to_source_ms(self.session_start_ms + server_ms)
+    }
+
+    // A point of the sent audio, counted across every session, on the source timeline
+    pub fn sent_to_source_ms(&self, sent_ms: f64) -> f64 {
+        let skipped: f64 = self.gaps.iter().take_while(|gap| gap.at_ms <= sent_ms).map(|gap| gap.skipped_ms).sum();
+
+        sent_ms + skipped
+    }
+
+    pub fn correct_event(&self, event: &mut Value) -> bool {
+        if self.gaps.is_empty() && self.session_start_ms == 0.0 && self.backfilled.is_empty() {
+            return false
+        }
+
+        let mut corrected = self.mark_backfilled(event);
+        corrected |= self.correct_words(event);
+
+        for field in OFFSET_FIELDS {
+            if let Some(server_ms) = event[field].as_f64() {
+                event[field] = (self.to_source_ms(server_ms).round() as u64).into();
+                corrected = true;
+            }
+        }
+
+        corrected
+    }
+
+    // Word offsets on the session timeline move with the turn, those counted from its start stay as they are.
+    // Without offsets of the turn in the same event there is no telling, and they are left alone.
+    fn correct_words(&self, event: &mut Value) -> bool {
+        let (Some(words), Some(start_ms), Some(end_ms)) = (words::raw(event), event["audio_start_ms"].as_f64(), event["audio_end_ms"].as_f64()) else {
+            return false
+        };
+
+        if words::is_relative(&words, start_ms, end_ms) {
+            return false
+        }
+
+        for word in event["words"].as_array_mut().into_iter().flatten() {
+            for field in WORD_OFFSET_FIELDS {
+                if let Some(server_ms) = word[field].as_f64() {
+                    word[field] = (self.to_source_ms(server_ms).round() as u64).into();
+                }
+            }
+        }
+
+        true
+    }
+
+    fn mark_backfilled(&self, event: &mut Value) -> bool {
+        let mut span = OFFSET_FIELDS.iter().filter_map(|field| event[field].as_f64()).map(|server_ms| self.session_start_ms + server_ms);
+
+        let Some(start_ms) = span.next() else {
+            return false
+        };
+
+        let end_ms = span.next().unwrap_or(start_ms);
+
+        if !self.backfilled.iter().any(|&(from_ms, to_ms)| start_ms < to_ms && end_ms >= from_ms) {
+            return false
+        }
+
+        event["backfilled"] = true.into();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // 16 kHz mono s16, 32 bytes a millisecond
+    const BYTES_PER_MS: usize = 32;
+
+    fn map() -> OffsetMap {
+        OffsetMap::new(16000)
+    }
+
+    #[test]
+    fn untouched_timeline() {
+        let mut offsets = map();
+        offsets.sent(10_000 * BYTES_PER_MS);
+
+        assert_eq!(offsets.to_source_ms(7500.0), 7500.0);
+        assert!(!offsets.correct_event(&mut json!({ "audio_start_ms": 100 })));
+    }
+
+    #[test]
+    fn trimmed_gaps() {
+        let mut offsets = map();
+
+        // 1 s of speech, 4 s of silence skipped, 2 s of speech, 3 s skipped in two parts, 1 s of speech
+        offsets.sent(1000 * BYTES_PER_MS);
+        offsets.skipped(4000 * BYTES_PER_MS);
+        offsets.sent(2000 * BYTES_PER_MS);
+        offsets.skipped(1000 * BYTES_PER_MS);
+        offsets.skipped(2000 * BYTES_PER_MS);
+        offsets.sent(1000 * BYTES_PER_MS);
+
+        assert_eq!(offsets.sent_ms(), 4000.0);
+        assert_eq!(offsets.to_source_ms(500.0), 500.0);
+        // the gap counts from the point it was skipped at
+        assert_eq!(offsets.to_source_ms(1000.0), 5000.0);
+        assert_eq!(offsets.to_source_ms(2500.0), 6500.0);
+        assert_eq!(offsets.to_source_ms(3000.0), 10_000.0);
+        assert_eq!(offsets.to_source_ms(3500.0), 10_500.0);
+        assert_eq!(offsets.gaps.len(), 2);
+
+        let mut event = json!({ "audio_start_ms": 1200, "audio_end_ms": 3400.4 });
+        assert!(offsets.correct_event(&mut event));
+        assert_eq!(event, json!({ "audio_start_ms": 5200, "audio_end_ms": 10_400 }));
+    }
+
+    #[test]
+    fn seek() {
+        let mut offsets = map();
+
+        // --seek 12:30 is a gap before anything was sent
+        offsets.skipped(750_000 * BYTES_PER_MS);
+        offsets.sent(60_000 * BYTES_PER_MS);
+
+        assert_eq!(offsets.to_source_ms(0.0), 750_000.0);
+        assert_eq!(offsets.to_source_ms(1000.0), 751_000.0);
+        assert_eq!(offsets.sent_to_source_ms(30_000.0), 780_000.0);
+    }
+
+    #[test]
+    fn session_restarts() {
+        let mut offsets = map();
+
+        offsets.sent(3000 * BYTES_PER_MS);
+        offsets.skipped(2000 * BYTES_PER_MS);
+        offsets.sent(3000 * BYTES_PER_MS);
+        offsets.start_session(4000.0);
+
+        // the new session's zero is 4 s into the sent audio, past the gap
+        assert_eq!(offsets.session_start_ms(), 4000.0);
+        assert_eq!(offsets.to_source_ms(0.0), 6000.0);
+        assert_eq!(offsets.to_source_ms(1500.0), 7500.0);
+
+        let mut event = json!({ "audio_start_ms": 0, "audio_end_ms": 1000 });
+        assert!(offsets.correct_event(&mut event));
+        assert_eq!(event, json!({ "audio_start_ms": 6000, "audio_end_ms": 7000 }));
+
+        // a restart before the gap puts it back between the offsets
+        offsets.start_session(2000.0);
+        assert_eq!(offsets.to_source_ms(500.0), 2500.0);
+        assert_eq!(offsets.to_source_ms(1500.0), 5500.0);
+    }
+
+    #[test]
+    fn dropped_audio() {
+        let mut offsets = map();
+
+        offsets.sent(2000 * BYTES_PER_MS);
+        offsets.skipped(1000 * BYTES_PER_MS);
+        offsets.sent(2000 * BYTES_PER_MS);
+        // the 500 ms that were sent 1 s before the end never arrived
+        offsets.dropped(1000 * BYTES_PER_MS, 500 * BYTES_PER_MS);
+
+        assert_eq!(offsets.sent_ms(), 3500.0);
+        assert_eq!(offsets.to_source_ms(2000.0), 3000.0);
+        assert_eq!(offsets.to_source_ms(3000.0), 4500.0);
+    }
+
+    #[test]
+    fn backfilled_turns() {
+        let mut offsets = map();
+
+        offsets.sent(1000 * BYTES_PER_MS);
+        offsets.sent(1000 * BYTES_PER_MS);
+        offsets.backfill(1000 * BYTES_PER_MS);
+
+        let mut live = json!({ "audio_start_ms": 200, "audio_end_ms": 900 });
+        let mut spooled = json!({ "audio_start_ms": 800, "audio_end_ms": 1500 });
+        offsets.correct_event(&mut live);
+        offsets.correct_event(&mut spooled);
+
+        assert_eq!(live.get("backfilled"), None);
+        assert_eq!(spooled["backfilled"], true);
+    }
+
+    #[test]
+    fn absolute_word_offsets() {
+        let mut offsets = map();
+
+        offsets.sent(1000 * BYTES_PER_MS);
+        offsets.skipped(2000 * BYTES_PER_MS);
+        offsets.sent(3000 * BYTES_PER_MS);
+
+        let mut absolute = json!({ "audio_start_ms": 1500, "audio_end_ms": 2500, "words": [{ "text": "hi", "start_ms": 1500, "end_ms": 2000 }] });
+        let mut relative = json!({ "audio_start_ms": 1500, "audio_end_ms": 2500, "words": [{ "text": "hi", "begin_time": 0, "end_time": 500 }] });
+        offsets.correct_event(&mut absolute);
+        offsets.correct_event(&mut relative);
+
+        assert_eq!(absolute["words"][0], json!({ "text": "hi", "start_ms": 3500, "end_ms": 4000 }));
+        assert_eq!(relative["words"][0], json!({ "text": "hi", "begin_time": 0, "end_time": 500 }));
+        assert_eq!(relative["audio_start_ms"], 3500);
+    }
+}
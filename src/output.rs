@@ -0,0 +1,323 @@
+use crate::event::ClientEvent;
+use crate::protocol::ServerSessions;
+use crate::sink::{Dispatcher, EventSink, Filter, Kind, OutputEvent, StdoutSink};
+use log::warn;
+use std::borrow::Cow;
+use std::fmt::{Display, Write as _};
+use std::fs::File;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Instant, SystemTime};
+
+// Wall time derived from a monotonic clock anchored once at startup, so it never jumps under NTP slews
+#[derive(Debug, Clone, Copy)]
+pub struct WallClock {
+    wall: SystemTime,
+    mono: Instant,
+}
+
+impl WallClock {
+    pub fn new() -> Self {
+        Self { wall: SystemTime::now(), mono: Instant::now() }
+    }
+
+    pub fn wall_time(&self, at: Instant) -> SystemTime {
+        self.wall + at.saturating_duration_since(self.mono)
+    }
+}
+
+impl Default for WallClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Formats output and hands it to the sinks. Stdout is the only one so far; it gets configured here
+// and the dispatcher starts with the first line.
+pub struct Printer {
+    stdout: Mutex<Option<StdoutSink>>,
+    dispatcher: OnceLock<Dispatcher>,
+    fsyncs: Arc<AtomicU64>,
+    server_sessions: Arc<ServerSessions>,
+    recorded: Option<Mutex<Vec<(Kind, String)>>>,
+    // lines kept back until release, dropped if it never comes
+    held: Mutex<Option<Vec<OutputEvent>>>,
+}
+
+impl Printer {
+    pub fn new(timestamps: bool) -> Self {
+        let fsyncs = Arc::new(AtomicU64::new(0));
+        let stdout = StdoutSink { clock: timestamps.then(WallClock::new), fsyncs: fsyncs.clone(), ..Default::default() };
+
+        Self { stdout: Mutex::new(Some(stdout)), dispatcher: OnceLock::new(), fsyncs, server_sessions: Arc::default(), recorded: None, held: Mutex::new(None) }
+    }
+
+    // Client events carry the id of the upstream session current when they are printed
+    pub fn server_sessions(mut self, server_sessions: Arc<ServerSessions>) -> Self {
+        self.server_sessions = server_sessions;
+        self
+    }
+
+    // For output that is not a JSON stream, like subtitles on stdout
+    pub fn without_client_events(self) -> Self {
+        self.configure(|stdout| stdout.filter = Filter::Turns)
+    }
+
+    // For consumers that choke on raw UTF-8
+    pub fn ascii_json(self) -> Self {
+        self.configure(|stdout| stdout.ascii_json = true)
+    }
+
+    // Nothing reaches stdout until release
+    pub fn hold(self) -> Self {
+        *self.held.lock().unwrap() = Some(Vec::new());
+        self
+    }
+
+    pub fn release(&self) {
+        let held = self.held.lock().unwrap().take();
+
+        for event in held.unwrap_or_default() {
+            self.dispatcher().send(event);
+        }
+    }
+
+    // Syncs stdout to disk at every finished turn
+    pub fn durable(self) -> io::Result<Self> {
+        let file = stdout_file()?;
+
+        if !file.metadata()?.is_file() {
+            warn!("--durable only has an effect when stdout is redirected to a file");
+            return Ok(self)
+        }
+
+        Ok(self.configure(|stdout| stdout.durable = Some(file)))
+    }
+
+    pub fn fsyncs(&self) -> u64 {
+        self.fsyncs.load(Ordering::Relaxed)
+    }
+
+    // Called at turn boundaries only, a sync per partial result would slow everything down for no gain
+    pub fn sync(&self) {
+        self.dispatcher().flush();
+    }
+
+    // Waits for the sinks to take everything printed so far, on every way out of a run
+    pub fn finish(&self) {
+        if let Some(dispatcher) = self.dispatcher.get() {
+            dispatcher.finish();
+        }
+    }
+
+    // Keeps every server event printed from now on, for the response cache
+    pub fn record(mut self) -> Self {
+        self.recorded = Some(Mutex::default());
+        self
+    }
+
+    pub fn recorded(&self) -> Option<Vec<(Kind, String)>> {
+        self.recorded.as_ref().map(|recorded| recorded.lock().unwrap().clone())
+    }
+
+    pub fn print(&self, event: ClientEvent) {
+        self.write(Kind::Client, event.line(self.server_sessions.current()), Instant::now());
+    }
+
+    // Lines recorded by an earlier run, replayed from the cache as what they were printed as
+    pub fn print_recorded(&self, kind: Kind, line: String) {
+        self.write(kind, line, Instant::now());
+    }
+
+    // Server events, as opposed to the client.* ones print emits
+    pub fn print_at(&self, line: impl Display, received_at: Instant) {
+        self.write_recorded(Kind::Server, line.to_string(), received_at);
+    }
+
+    // A finished turn, folded from the server events that led up to it
+    pub fn print_turn(&self, line: impl Display, received_at: Instant) {
+        self.write_recorded(Kind::Turn, line.to_string(), received_at);
+    }
+
+    fn write_recorded(&self, kind: Kind, line: String, received_at: Instant) {
+        if let Some(recorded) = &self.recorded {
+            recorded.lock().unwrap().push((kind, line.clone()));
+        }
+
+        self.write(kind, line, received_at);
+    }
+
+    fn write(&self, kind: Kind, line: String, received_at: Instant) {
+        let event = OutputEvent { kind, line, received_at };
+
+        match self.held.lock().unwrap().as_mut() {
+            Some(held) => held.push(event),
+            None => self.dispatcher().send(event),
+        }
+    }
+
+    fn configure(self, f: impl FnOnce(&mut StdoutSink)) -> Self {
+        if let Some(stdout) = self.stdout.lock().unwrap().as_mut() {
+            f(stdout);
+        }
+
+        self
+    }
+
+    fn dispatcher(&self) -> &Dispatcher {
+        self.dispatcher.get_or_init(|| {
+            let sinks: Vec<Box<dyn EventSink>> = self.stdout.lock().unwrap().take().into_iter().map(|stdout| Box::new(stdout) as _).collect();
+            Dispatcher::start(sinks)
+        })
+    }
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+// Finishes a shared printer however the scope holding it is left, tasks still holding it do not keep lines back
+pub struct FinishGuard(Arc<Printer>);
+
+impl FinishGuard {
+    pub fn new(printer: Arc<Printer>) -> Self {
+        Self(printer)
+    }
+}
+
+impl Drop for FinishGuard {
+    fn drop(&mut self) {
+        self.0.finish();
+    }
+}
+
+// Every non-ASCII character as a \u escape, beyond the BMP as a UTF-16 surrogate pair.
+// Only correct for JSON, where such characters can only occur inside strings.
+pub fn escape_non_ascii(json: &str) -> Cow<'_, str> {
+    if json.is_ascii() {
+        return Cow::Borrowed(json)
+    }
+
+    let mut escaped = String::with_capacity(json.len() * 2);
+
+    for c in json.chars() {
+        if c.is_ascii() {
+            escaped.push(c);
+            continue
+        }
+
+        for unit in c.encode_utf16(&mut [0; 2]) {
+            let _ = write!(escaped, "\\u{unit:04x}");
+        }
+    }
+
+    Cow::Owned(escaped)
+}
+
+#[cfg(unix)]
+fn stdout_file() -> io::Result<File> {
+    use std::os::fd::AsFd;
+    Ok(io::stdout().as_fd().try_clone_to_owned()?.into())
+}
+
+#[cfg(windows)]
+fn stdout_file() -> io::Result<File> {
+    use std::os::windows::io::AsHandle;
+    Ok(io::stdout().as_handle().try_clone_to_owned()?.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::Cache;
+    use serde_json::json;
+
+    type Printed = Arc<Mutex<Vec<(Kind, String)>>>;
+
+    // Keeps what reaches it, behind the filter stdout has for the given output
+    struct Recording {
+        filter: Filter,
+        events: Printed,
+    }
+
+    impl EventSink for Recording {
+        fn name(&self) -> &str {
+            "recording"
+        }
+
+        fn filter(&self) -> Filter {
+            self.filter
+        }
+
+        fn accept(&mut self, event: &OutputEvent) -> io::Result<()> {
+            self.events.lock().unwrap().push((event.kind, event.line.clone()));
+            Ok(())
+        }
+    }
+
+    fn recording(filter: Filter) -> (Printer, Printed) {
+        let events = Arc::new(Mutex::default());
+        let mut printer = Printer::new(false);
+
+        printer.stdout = Mutex::new(None);
+        printer.dispatcher = OnceLock::from(Dispatcher::start(vec![Box::new(Recording { filter, events: events.clone() })]));
+
+        (printer, events)
+    }
+
+    #[test]
+    fn cached_subtitles_replay_as_turns() {
+        let dir = std::env::temp_dir().join(format!("qasr-test-cache-{}", std::process::id()));
+        let cache = Cache::new(&dir);
+        let cue = "1\n00:00:01,000 --> 00:00:02,500\n你好\n";
+
+        // a --format srt run, stdout only takes the cues
+        let (printer, printed) = recording(Filter::Turns);
+        let printer = printer.record();
+        printer.print_at(json!({ "type": "input_audio_buffer.speech_started", "audio_start_ms": 1000 }), Instant::now());
+        printer.print_turn(cue, Instant::now());
+        printer.finish();
+
+        let recorded = printer.recorded().unwrap();
+        assert_eq!(recorded.len(), 2);
+        assert_eq!(recorded[0].0, Kind::Server);
+        assert_eq!(*printed.lock().unwrap(), [(Kind::Turn, cue.to_string())]);
+
+        cache.store("key", json!({}), &recorded).unwrap();
+        let lines = cache.load("key").unwrap().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(lines, recorded);
+
+        let (replay, replayed) = recording(Filter::Turns);
+        for (kind, line) in lines {
+            replay.print_recorded(kind, line);
+        }
+        replay.finish();
+
+        assert_eq!(*replayed.lock().unwrap(), *printed.lock().unwrap());
+    }
+
+    #[test]
+    fn escapes_beyond_the_bmp_as_surrogate_pairs() {
+        assert!(matches!(escape_non_ascii(r#"{"text":"a"}"#), Cow::Borrowed(r#"{"text":"a"}"#)));
+        assert_eq!(escape_non_ascii(r#""中é""#), r#""\u4e2d\u00e9""#);
+        assert_eq!(escape_non_ascii(r#""😀""#), r#""\ud83d\ude00""#);
+        assert_eq!(escape_non_ascii(r#""𠀀""#), r#""\ud840\udc00""#);
+    }
+
+    #[test]
+    fn escaped_json_round_trips() {
+        let text = "你好😀 𠀀𪚥 café \"quoted\"\n\u{10ffff}\u{ffff}";
+        let line = json!({ "type": "conversation.item.input_audio_transcription.completed", "transcript": text }).to_string();
+
+        let escaped = escape_non_ascii(&line);
+        assert!(escaped.is_ascii());
+
+        let unescaped: serde_json::Value = serde_json::from_str(&escaped).unwrap();
+        assert_eq!(unescaped["transcript"].as_str().unwrap().as_bytes(), text.as_bytes());
+        assert_eq!(unescaped.to_string(), line);
+    }
+}
@@ -0,0 +1,304 @@
+use crate::error::AsrError;
+use log::warn;
+use serde_json::Value;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tokio_tungstenite::tungstenite;
+
+const PREVIEW_CHARS: usize = 120;
+
+pub fn event_type(event: &Value) -> &str {
+    event["type"].as_str().unwrap_or_default()
+}
+
+pub fn is_transcription(event: &Value) -> bool {
+    event_type(event).starts_with("conversation.item.input_audio_transcription.")
+}
+
+// Translation output arrives either as dedicated translation events or as response text
+pub fn is_translation(event: &Value) -> bool {
+    let kind = event_type(event);
+
+    kind.contains("translation") || kind.starts_with("response.text.")
+}
+
+// Text of a finished translation, None for partial ones
+pub fn translation_text(event: &Value) -> Option<&str> {
+    let kind = event_type(event);
+
+    if !is_translation(event) || !(kind.ends_with(".done") || kind.ends_with(".completed")) {
+        return None
+    }
+
+    ["transcript", "text", "translation"].into_iter().find_map(|field| event[field].as_str())
+}
+
+pub fn error_detail(event: &Value) -> Option<(String, String)> {
+    if event_type(event) != "error" {
+        return None
+    }
+
+    let code = event["error"]["code"].as_str().unwrap_or("unknown");
+    let message = event["error"]["message"].as_str().unwrap_or_default();
+
+    Some((code.to_string(), message.to_string()))
+}
+
+// The id the server gave a new session, what its support asks for
+pub fn server_session_id(event: &Value) -> Option<&str> {
+    match event_type(event) {
+        "session.created" | "transcription_session.created" => event["session"]["id"].as_str(),
+        _ => None,
+    }
+}
+
+// Item id of a turn whose transcription failed while the session itself stays usable
+pub fn failed_turn(event: &Value) -> Option<&str> {
+    match event_type(event) {
+        "conversation.item.input_audio_transcription.failed" | "error" => event["item_id"].as_str(),
+        _ => None,
+    }
+}
+
+// Completed or failed, either way nothing more arrives for the turn
+pub fn ends_turn(event: &Value) -> bool {
+    event_type(event) == "conversation.item.input_audio_transcription.completed" || failed_turn(event).is_some()
+}
+
+// Errors caused by a single bad message rather than the connection. The stream still ends after them,
+// tungstenite cannot skip past a frame it rejected, so the session is reopened instead.
+pub fn frame_violation(err: &tungstenite::Error) -> Option<&'static str> {
+    match err {
+        tungstenite::Error::Utf8(_) => Some("invalid_utf8"),
+        tungstenite::Error::Capacity(tungstenite::error::CapacityError::MessageTooLong { .. }) => Some("message_too_large"),
+        _ => None,
+    }
+}
+
+// The start of a malformed payload, enough to recognize it without flooding the logs
+pub fn preview(text: &str) -> String {
+    match text.char_indices().nth(PREVIEW_CHARS) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.into(),
+    }
+}
+
+pub fn is_throttling_error(event: &Value) -> bool {
+    let Some((code, _)) = error_detail(event) else {
+        return false
+    };
+
+    let code = code.to_ascii_lowercase();
+
+    code.contains("throttl") || code.contains("rate_limit") || code.contains("ratequota")
+}
+
+pub fn rejected_flag(code: &str, message: &str, optional_fields: &[(&'static str, &'static str)]) -> Option<&'static str> {
+    let code = code.to_ascii_lowercase();
+    let message = message.to_ascii_lowercase();
+
+    if let Some((flag, _)) = optional_fields.iter().find(|(_, keyword)| message.contains(keyword)) {
+        return Some(flag)
+    }
+
+    match optional_fields {
+        [(flag, _)] if code.contains("parameter") || code.contains("unsupported") => Some(flag),
+        _ => None,
+    }
+}
+
+fn is_auth_error(code: &str, message: &str) -> bool {
+    let text = format!("{code} {message}").to_ascii_lowercase();
+
+    ["apikey", "api-key", "api_key", "unauthorized", "accessdenied", "access denied", "auth"].iter().any(|word| text.contains(word))
+}
+
+// Turns a connection that closed before the session became ready into an error naming the likely causes
+fn not_ready_error(errors: &[(String, String)], model: &str, host: &str) -> AsrError {
+    let summary = match errors {
+        [] => "no error event received".to_string(),
+        errors => errors.iter().map(|(code, message)| format!("{code}: {message}")).collect::<Vec<_>>().join("; "),
+    };
+
+    let auth = errors.iter().any(|(code, message)| is_auth_error(code, message));
+    let mut causes = [
+        "the API key is wrong, expired or not activated (--api-key, DASHSCOPE_API_KEY)".to_string(),
+        format!("the model `{model}` does not exist or is not enabled for this key (--model)"),
+        format!("the key belongs to another region than {host} (--base-url)"),
+    ];
+
+    if !auth && errors.iter().any(|(code, message)| format!("{code} {message}").to_ascii_lowercase().contains("model")) {
+        causes.swap(0, 1);
+    }
+
+    let message = format!(
+        "server closed the connection before the session was ready ({summary})\nlikely causes:\n{}",
+        causes.iter().map(|cause| format!("  - {cause}")).collect::<Vec<_>>().join("\n")
+    );
+
+    match errors.first() {
+        Some(_) if auth => AsrError::Auth(message),
+        Some((code, _)) => AsrError::Protocol { code: code.clone(), message },
+        None => AsrError::Protocol { code: "closed_before_ready".into(), message },
+    }
+}
+
+// The server's answers up to session.updated, checked the same way by a transcription and by --check
+#[derive(Debug, Default)]
+pub struct Handshake {
+    created: bool,
+    updated: bool,
+    errors: Vec<(String, String)>,
+}
+
+impl Handshake {
+    // Fails right away when the model rejects one of the optional settings
+    pub fn observe(&mut self, event: &Value, model: &str, optional_fields: &[(&'static str, &'static str)]) -> Result<(), AsrError> {
+        match event_type(event) {
+            "session.created" => self.created = true,
+            "session.updated" => self.updated = true,
+            _ => {}
+        }
+
+        let Some((code, message)) = error_detail(event).filter(|_| !self.updated) else {
+            return Ok(())
+        };
+
+        if let Some(flag) = rejected_flag(&code, &message, optional_fields) {
+            return Err(AsrError::Protocol {
+                code,
+                message: format!("model {model} rejected {flag}: {message}"),
+            })
+        }
+
+        self.errors.push((code, message));
+        Ok(())
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.created || self.updated
+    }
+
+    pub fn is_updated(&self) -> bool {
+        self.updated
+    }
+
+    pub fn has_errors(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
+    // Why the session never got configured
+    pub fn error(&self, model: &str, host: &str) -> AsrError {
+        if !self.is_ready() {
+            return not_ready_error(&self.errors, model, host)
+        }
+
+        match self.errors.first() {
+            Some((code, message)) if is_auth_error(code, message) => AsrError::Auth(format!("{code}: {message}")),
+            Some((code, message)) => AsrError::Protocol { code: code.clone(), message: message.clone() },
+            None => AsrError::Protocol {
+                code: "closed_before_update".into(),
+                message: "server closed the connection before confirming the session configuration".into(),
+            },
+        }
+    }
+}
+
+// Every upstream session of a run, in the order they were created. Reconnects and model switches add more.
+#[derive(Debug, Default)]
+pub struct ServerSessions {
+    ids: Mutex<Vec<String>>,
+    // rewritten with all ids whenever one is added, for --session-id-file
+    file: Option<PathBuf>,
+}
+
+impl ServerSessions {
+    pub fn new(file: Option<PathBuf>) -> Self {
+        Self { ids: Mutex::default(), file }
+    }
+
+    pub fn push(&self, id: &str) {
+        let ids = {
+            let mut ids = self.ids.lock().unwrap();
+            ids.push(id.to_string());
+            ids.clone()
+        };
+
+        let Some(path) = &self.file else {
+            return
+        };
+
+        // renamed into place, a reader never sees half a list
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+
+        if let Err(err) = fs::write(&partial, ids.join("\n") + "\n").and_then(|()| fs::rename(&partial, path)) {
+            warn!("Failed to write the session ids to {}: {err}", path.display());
+        }
+    }
+
+    pub fn current(&self) -> Option<String> {
+        self.ids.lock().unwrap().last().cloned()
+    }
+
+    pub fn all(&self) -> Vec<String> {
+        self.ids.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_tungstenite::tungstenite::error::{CapacityError, ProtocolError};
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::{Data, OpCode};
+    use tokio_tungstenite::tungstenite::protocol::frame::Frame;
+    use std::io::Cursor;
+    use tokio_tungstenite::tungstenite::protocol::{Role, WebSocket, WebSocketConfig};
+
+    // What a client reading these bytes off the wire gets
+    fn read(frames: Vec<Frame>, max_message_size: usize) -> tungstenite::Result<tungstenite::Message> {
+        let mut wire = Vec::new();
+
+        for frame in frames {
+            frame.format(&mut wire).unwrap();
+        }
+
+        let config = WebSocketConfig::default().max_message_size(Some(max_message_size));
+        WebSocket::from_raw_socket(Cursor::new(wire), Role::Client, Some(config)).read()
+    }
+
+    fn text(payload: &[u8]) -> Frame {
+        Frame::message(payload.to_vec(), OpCode::Data(Data::Text), true)
+    }
+
+    #[test]
+    fn recognizes_frames_the_stream_cannot_get_past() {
+        let oversized = read(vec![text(&[b'x'; 2048])], 1024).unwrap_err();
+        assert_eq!(frame_violation(&oversized), Some("message_too_large"));
+
+        let invalid = read(vec![text(&[0xff, 0xfe, 0x41])], 1024).unwrap_err();
+        assert_eq!(frame_violation(&invalid), Some("invalid_utf8"));
+
+        assert!(read(vec![text("{}".as_bytes())], 1024).is_ok());
+    }
+
+    #[test]
+    fn leaves_connection_errors_alone() {
+        let errors = [
+            tungstenite::Error::ConnectionClosed,
+            tungstenite::Error::Io(std::io::ErrorKind::ConnectionReset.into()),
+            tungstenite::Error::Protocol(ProtocolError::ResetWithoutClosingHandshake),
+            tungstenite::Error::Capacity(CapacityError::TooManyHeaders),
+        ];
+
+        assert!(errors.iter().all(|err| frame_violation(err).is_none()));
+    }
+
+    #[test]
+    fn previews_the_start_of_a_payload() {
+        assert_eq!(preview("not json"), "not json");
+        assert_eq!(preview(&"好".repeat(PREVIEW_CHARS)), "好".repeat(PREVIEW_CHARS));
+        assert_eq!(preview(&"好".repeat(PREVIEW_CHARS + 1)), format!("{}...", "好".repeat(PREVIEW_CHARS)));
+    }
+}
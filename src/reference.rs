@@ -0,0 +1,247 @@
+use crate::event::{ReferenceReport, TurnAlignment};
+use crate::text::{self, Folding};
+use serde_json::Value;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Edit {
+    Match,
+    Substitution,
+    Insertion,
+    Deletion,
+}
+
+// A reference transcript, plain text or the lines of an earlier `--format results` run
+pub fn load(path: &Path) -> io::Result<String> {
+    let contents = fs::read_to_string(path)?;
+
+    let lines: Option<Vec<Value>> = contents.lines().filter(|line| !line.trim().is_empty()).map(|line| serde_json::from_str(line).ok()).collect();
+
+    // client.* lines carry no text and are skipped
+    Ok(match lines {
+        Some(lines) => lines.iter().filter_map(|line| line["text"].as_str()).collect::<Vec<_>>().join(" "),
+        None => contents,
+    })
+}
+
+// CJK characters are tokens of their own, everything else splits into words. Case and punctuation do not count.
+pub fn tokenize(text: &str) -> Vec<String> {
+    text::tokens(text, Folding::Case)
+}
+
+// Aligns the turns, in the order they were spoken, against the reference as a whole
+pub fn compare(reference: &str, turns: &[String], detail: bool) -> ReferenceReport {
+    let reference = tokenize(reference);
+    let mut hypothesis = Vec::new();
+    let mut owners = Vec::new();
+
+    for (turn, text) in turns.iter().enumerate() {
+        let tokens = tokenize(text);
+        owners.extend(std::iter::repeat_n(turn, tokens.len()));
+        hypothesis.extend(tokens);
+    }
+
+    let edits = align(&reference, &hypothesis);
+    let count = |edit: Edit| edits.iter().filter(|(kind, _, _)| *kind == edit).count();
+    let errors = count(Edit::Substitution) + count(Edit::Insertion) + count(Edit::Deletion);
+
+    ReferenceReport {
+        reference_tokens: reference.len(),
+        hypothesis_tokens: hypothesis.len(),
+        substitutions: count(Edit::Substitution),
+        insertions: count(Edit::Insertion),
+        deletions: count(Edit::Deletion),
+        error_rate: errors as f64 / reference.len().max(1) as f64,
+        turns: detail.then(|| turn_alignments(&edits, &reference, &hypothesis, &owners, turns.len())),
+    }
+}
+
+// Levenshtein over tokens, returning (edit, reference index, hypothesis index) from start to end
+fn align(reference: &[String], hypothesis: &[String]) -> Vec<(Edit, usize, usize)> {
+    let (n, m) = (reference.len(), hypothesis.len());
+    let mut cost: Vec<Vec<usize>> = (0..=n).map(|i| (0..=m).map(|j| if i == 0 { j } else { i }).collect()).collect();
+
+    for i in 1..=n {
+        for j in 1..=m {
+            let substitution = cost[i - 1][j - 1] + usize::from(reference[i - 1] != hypothesis[j - 1]);
+            cost[i][j] = substitution.min(cost[i - 1][j] + 1).min(cost[i][j - 1] + 1);
+        }
+    }
+
+    let (mut i, mut j) = (n, m);
+    let mut edits = Vec::with_capacity(n.max(m));
+
+    while i > 0 || j > 0 {
+        if i > 0 && j > 0 && cost[i][j] == cost[i - 1][j - 1] + usize::from(reference[i - 1] != hypothesis[j - 1]) {
+            let edit = if reference[i - 1] == hypothesis[j - 1] { Edit::Match } else { Edit::Substitution };
+            edits.push((edit, i - 1, j - 1));
+            (i, j) = (i - 1, j - 1);
+        } else if j > 0 && cost[i][j] == cost[i][j - 1] + 1 {
+            edits.push((Edit::Insertion, i, j - 1));
+            j -= 1;
+        } else {
+            edits.push((Edit::Deletion, i - 1, j));
+            i -= 1;
+        }
+    }
+
+    edits.reverse();
+    edits
+}
+
+// Deleted reference tokens go to the turn of the next hypothesis token, or the last turn at the end
+fn turn_alignments(edits: &[(Edit, usize, usize)], reference: &[String], hypothesis: &[String], owners: &[usize], turns: usize) -> Vec<TurnAlignment> {
+    let mut alignments: Vec<TurnAlignment> = (0..turns).map(|turn| TurnAlignment { turn, ..Default::default() }).collect();
+    let mut references: Vec<Vec<&str>> = vec![Vec::new(); turns];
+    let mut hypotheses: Vec<Vec<&str>> = vec![Vec::new(); turns];
+
+    for &(edit, i, j) in edits {
+        let Some(&turn) = owners.get(j).or(owners.last()) else {
+            continue
+        };
+
+        let alignment = &mut alignments[turn];
+
+        match edit {
+            Edit::Match | Edit::Substitution => {
+                references[turn].push(&reference[i]);
+                hypotheses[turn].push(&hypothesis[j]);
+                alignment.substitutions += usize::from(edit == Edit::Substitution);
+            }
+            Edit::Insertion => {
+                hypotheses[turn].push(&hypothesis[j]);
+                alignment.insertions += 1;
+            }
+            Edit::Deletion => {
+                references[turn].push(&reference[i]);
+                alignment.deletions += 1;
+            }
+        }
+    }
+
+    for (turn, alignment) in alignments.iter_mut().enumerate() {
+        alignment.reference = join(&references[turn]);
+        alignment.hypothesis = join(&hypotheses[turn]);
+    }
+
+    alignments
+}
+
+// CJK tokens run together, words keep their spaces
+fn join(tokens: &[&str]) -> String {
+    let mut text = String::new();
+
+    for token in tokens {
+        let previous_cjk = text.chars().last().is_some_and(text::is_cjk);
+        let next_cjk = token.chars().next().is_some_and(text::is_cjk);
+
+        if !(text.is_empty() || previous_cjk && next_cjk) {
+            text.push(' ');
+        }
+
+        text.push_str(token);
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edits(reference: &str, hypothesis: &str) -> Vec<Edit> {
+        align(&tokenize(reference), &tokenize(hypothesis)).into_iter().map(|(edit, _, _)| edit).collect()
+    }
+
+    #[test]
+    fn aligns_tokenize() {
+        use Edit::*;
+
+        assert_eq!(edits("the cat sat", "the cat sat"), [Match, Match, Match]);
+        assert_eq!(edits("the cat sat", "the bat sat"), [Match, Substitution, Match]);
+        assert_eq!(edits("the cat sat", "the cat sat down"), [Match, Match, Match, Insertion]);
+        assert_eq!(edits("the cat sat", "cat sat"), [Deletion, Match, Match]);
+        assert_eq!(edits("", "hello"), [Insertion]);
+        assert_eq!(edits("hello", ""), [Deletion]);
+        assert!(edits("", "").is_empty());
+    }
+
+    #[test]
+    fn indexes_edits() {
+        let (reference, hypothesis) = (tokenize("a b c d"), tokenize("a x c"));
+
+        assert_eq!(
+            align(&reference, &hypothesis),
+            [(Edit::Match, 0, 0), (Edit::Substitution, 1, 1), (Edit::Match, 2, 2), (Edit::Deletion, 3, 3)],
+        );
+    }
+
+    #[test]
+    fn mixed_chinese_and_english() {
+        assert_eq!(tokenize("我用Qwen3 ASR转写"), ["我", "用", "qwen3", "asr", "转", "写"]);
+
+        // a Chinese character and an English word weigh the same
+        let report = compare("我用 Qwen3 ASR 转写会议", &["我用qwen3 asr转写回忆".into()], false);
+        assert_eq!((report.reference_tokens, report.hypothesis_tokens), (8, 8));
+        assert_eq!((report.substitutions, report.insertions, report.deletions), (2, 0, 0));
+        assert_eq!(report.error_rate, 0.25);
+        assert!(report.turns.is_none());
+    }
+
+    #[test]
+    fn case_and_punctuation_do_not_count() {
+        let report = compare("你好，世界！Hello, World.", &["你好世界 hello world".into()], false);
+        assert_eq!(report.error_rate, 0.0);
+    }
+
+    #[test]
+    fn splits_detail_by_turn() {
+        let turns = ["今天天气".to_string(), "very good".into()];
+        let report = compare("今天天气很好 very good", &turns, true);
+
+        // 很好 is missing between the turns, it goes to the one that follows
+        assert_eq!(report.deletions, 2);
+        let alignments = report.turns.unwrap();
+        assert_eq!(alignments[0].reference, "今天天气");
+        assert_eq!(alignments[0].deletions, 0);
+        assert_eq!(alignments[1].reference, "很好 very good");
+        assert_eq!(alignments[1].hypothesis, "very good");
+        assert_eq!(alignments[1].deletions, 2);
+    }
+
+    #[test]
+    fn deletion_at_the_end_goes_to_the_last_turn() {
+        let turns = ["hello there".to_string(), "你好".into()];
+        let report = compare("hello there 你好世界", &turns, true);
+        let alignments = report.turns.unwrap();
+
+        assert_eq!(report.deletions, 2);
+        assert_eq!((alignments[0].deletions, alignments[1].deletions), (0, 2));
+        assert_eq!(alignments[1].reference, "你好世界");
+        assert_eq!(alignments[1].hypothesis, "你好");
+    }
+
+    #[test]
+    fn no_turns() {
+        let report = compare("hello world", &[], true);
+
+        assert_eq!((report.deletions, report.error_rate), (2, 1.0));
+        assert!(report.turns.unwrap().is_empty());
+        assert_eq!(compare("", &[], false).error_rate, 0.0);
+    }
+
+    #[test]
+    fn loads_results_lines() {
+        let path = std::env::temp_dir().join(format!("qasr-test-reference-{}.jsonl", std::process::id()));
+        fs::write(&path, "{\"type\":\"client.session_info\"}\n{\"turn\":0,\"text\":\"你好\"}\n\n{\"turn\":1,\"text\":\"world\"}\n").unwrap();
+        let loaded = load(&path).unwrap();
+        fs::write(&path, "plain {text}\n").unwrap();
+        let plain = load(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(loaded, "你好 world");
+        assert_eq!(plain, "plain {text}\n");
+    }
+}
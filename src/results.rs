@@ -0,0 +1,350 @@
+use crate::input;
+use crate::protocol;
+use crate::words::{self, Word};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+// Everything known about one finished turn, the unit `--format results` and other sinks emit
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TurnResult {
+    #[serde(skip)]
+    pub item_id: String,
+    pub turn: u64,
+    pub start_ms: Option<u64>,
+    pub end_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    // only where the server sent word timing
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub words: Vec<Word>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f64>,
+    // from the end of speech (or the commit) to the final transcript
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    pub session_index: u32,
+    pub source: String,
+    // sent late from the spool, so it arrives after turns that come later in the audio
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub backfilled: bool,
+    // low_density or high_density, the transcript is implausible for the length of the turn
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality_warning: Option<String>,
+    // the whole input was shorter than --min-audio-ms
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub short_input: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<Value>,
+}
+
+impl fmt::Display for TurnResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&serde_json::to_string(self).map_err(|_| fmt::Error)?)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    start_ms: Option<u64>,
+    end_ms: Option<u64>,
+    stopped_at: Option<Instant>,
+    backfilled: bool,
+}
+
+// Folds the protocol events of each turn into a TurnResult, numbering turns across sessions
+#[derive(Debug)]
+pub struct TurnResults {
+    next_turn: u64,
+    session_index: u32,
+    // (end on the source timeline, name) of every input
+    sources: Vec<(f64, String)>,
+    pending: HashMap<String, Pending>,
+}
+
+impl TurnResults {
+    pub fn new(sources: Vec<(f64, String)>) -> Self {
+        Self { next_turn: 0, session_index: 0, sources, pending: HashMap::new() }
+    }
+
+    // Files are sent back to back, so their lengths place a turn in one of them
+    pub fn sources(files: &[PathBuf], sample_rate: u32) -> Vec<(f64, String)> {
+        if files.is_empty() {
+            return vec![(f64::MAX, "stdin".into())]
+        }
+
+        let bytes_per_ms = sample_rate as f64 * 2.0 / 1000.0;
+        let mut end_ms = 0.0;
+
+        files
+            .iter()
+            .map(|path| {
+                end_ms += input::layout(path).map_or(0, |layout| layout.frames() * 2) as f64 / bytes_per_ms;
+                (end_ms, path.display().to_string())
+            })
+            .collect()
+    }
+
+    pub fn next_session(&mut self) {
+        self.session_index += 1;
+        self.pending.clear();
+    }
+
+    // Numbers turns and sessions on from those of an earlier run
+    pub fn continue_after(&mut self, turns: &[TurnResult]) {
+        if let Some(last) = turns.last() {
+            self.next_turn = last.turn + 1;
+            self.session_index = last.session_index + 1;
+        }
+    }
+
+    // Offsets are expected on the source timeline already
+    pub fn observe(&mut self, event: &Value, received_at: Instant) -> Option<TurnResult> {
+        let item_id = event["item_id"].as_str()?;
+        let offset = |field: &str| event[field].as_f64().map(|ms| ms.round() as u64);
+        let backfilled = event["backfilled"] == true;
+
+        match protocol::event_type(event) {
+            "input_audio_buffer.speech_started" => {
+                let pending = self.pending.entry(item_id.into()).or_default();
+                pending.start_ms = offset("audio_start_ms");
+                pending.backfilled |= backfilled;
+                None
+            }
+            "input_audio_buffer.speech_stopped" | "input_audio_buffer.committed" => {
+                let pending = self.pending.entry(item_id.into()).or_default();
+                pending.end_ms = offset("audio_end_ms").or(pending.end_ms);
+                pending.stopped_at.get_or_insert(received_at);
+                pending.backfilled |= backfilled;
+                None
+            }
+            "conversation.item.input_audio_transcription.completed" => {
+                let mut result = self.finish(item_id, received_at, offset("audio_start_ms"), offset("audio_end_ms"));
+                result.text = Some(event["transcript"].as_str().unwrap_or_default().into());
+                result.words = words::raw(event).map(|words| words::place(&words, result.start_ms.unwrap_or(0), result.end_ms)).unwrap_or_default();
+                result.language = event["language"].as_str().map(Into::into);
+                result.confidence = event["confidence"].as_f64();
+                result.backfilled |= backfilled;
+                result.quality_warning = event["quality_warning"].as_str().map(Into::into);
+                result.short_input = event["short_input"] == true;
+                Some(result)
+            }
+            _ if protocol::failed_turn(event).is_some() => {
+                let mut result = self.finish(item_id, received_at, None, None);
+                result.error = Some(event["error"].clone());
+                Some(result)
+            }
+            _ => None,
+        }
+    }
+
+    fn finish(&mut self, item_id: &str, received_at: Instant, start_ms: Option<u64>, end_ms: Option<u64>) -> TurnResult {
+        let pending = self.pending.remove(item_id).unwrap_or_default();
+        let (start_ms, end_ms) = (start_ms.or(pending.start_ms), end_ms.or(pending.end_ms));
+        let turn = self.next_turn;
+        self.next_turn += 1;
+
+        // the end of the turn decides, a turn spanning two files belongs to the one it was finished in
+        let at_ms = end_ms.or(start_ms).unwrap_or(0) as f64;
+        let source = self.sources.iter().find(|(end_ms, _)| at_ms <= *end_ms).or(self.sources.last());
+
+        TurnResult {
+            item_id: item_id.into(),
+            turn,
+            start_ms,
+            end_ms,
+            text: None,
+            words: Vec::new(),
+            language: None,
+            confidence: None,
+            latency_ms: pending.stopped_at.map(|stopped_at| received_at.saturating_duration_since(stopped_at).as_millis() as u64),
+            session_index: self.session_index,
+            source: source.map(|(_, name)| name.clone()).unwrap_or_default(),
+            backfilled: pending.backfilled,
+            quality_warning: None,
+            short_input: false,
+            error: None,
+        }
+    }
+}
+
+// The last finished turns, the oldest dropped first, so what a session produced so far can still be saved
+#[derive(Debug)]
+pub struct TurnHistory {
+    // its own, the output may not be made of turns at all
+    results: TurnResults,
+    capacity: usize,
+    turns: VecDeque<TurnResult>,
+    evicted: u64,
+}
+
+impl TurnHistory {
+    pub fn new(capacity: usize, sources: Vec<(f64, String)>) -> Self {
+        Self { results: TurnResults::new(sources), capacity, turns: VecDeque::with_capacity(capacity.min(1024)), evicted: 0 }
+    }
+
+    pub fn observe(&mut self, event: &Value, received_at: Instant) {
+        if let Some(result) = self.results.observe(event, received_at) {
+            self.push(result);
+        }
+    }
+
+    pub fn next_session(&mut self) {
+        self.results.next_session();
+    }
+
+    pub fn restore(&mut self, turns: &[TurnResult]) {
+        self.results.continue_after(turns);
+        turns.iter().cloned().for_each(|turn| self.push(turn));
+    }
+
+    fn push(&mut self, result: TurnResult) {
+        if self.turns.len() == self.capacity {
+            self.turns.pop_front();
+            self.evicted += 1;
+        }
+
+        self.turns.push_back(result);
+    }
+
+    pub fn last(&self, n: usize) -> Vec<TurnResult> {
+        self.turns.iter().skip(self.turns.len().saturating_sub(n)).cloned().collect()
+    }
+
+    // The turns that ended at or after a point of the source timeline
+    pub fn since(&self, source_ms: u64) -> Vec<TurnResult> {
+        self.turns.iter().filter(|turn| turn.end_ms.or(turn.start_ms).is_some_and(|end_ms| end_ms >= source_ms)).cloned().collect()
+    }
+
+    // Turns that no longer fit and are missing from an export
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+}
+
+// A JSON array of --format results lines, written aside and renamed so nobody reads half of it
+pub fn export(turns: &[TurnResult], path: &Path) -> io::Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+
+    let mut file = io::BufWriter::new(File::create(&partial)?);
+    serde_json::to_writer(&mut file, turns)?;
+    writeln!(file)?;
+
+    file.into_inner().map_err(io::IntoInnerError::into_error)?.sync_all()?;
+    fs::rename(partial, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn completed(item_id: &str, start_ms: u64, end_ms: u64) -> Value {
+        json!({
+            "type": "conversation.item.input_audio_transcription.completed",
+            "item_id": item_id,
+            "audio_start_ms": start_ms,
+            "audio_end_ms": end_ms,
+            "transcript": item_id,
+        })
+    }
+
+    fn texts(turns: &[TurnResult]) -> Vec<&str> {
+        turns.iter().filter_map(|turn| turn.text.as_deref()).collect()
+    }
+
+    #[test]
+    fn keeps_the_last_turns() {
+        let mut history = TurnHistory::new(3, vec![(f64::MAX, "stdin".into())]);
+        let now = Instant::now();
+
+        for (i, item_id) in ["a", "b", "c", "d", "e"].into_iter().enumerate() {
+            history.observe(&completed(item_id, i as u64 * 1000, i as u64 * 1000 + 800), now);
+        }
+
+        assert_eq!(history.evicted(), 2);
+        assert_eq!(texts(&history.last(usize::MAX)), ["c", "d", "e"]);
+        assert_eq!(texts(&history.last(2)), ["d", "e"]);
+        assert!(history.last(0).is_empty());
+        // turns keep their numbers past the ones evicted
+        assert_eq!(history.last(1)[0].turn, 4);
+    }
+
+    #[test]
+    fn selects_turns_by_their_end() {
+        let mut history = TurnHistory::new(8, vec![(f64::MAX, "stdin".into())]);
+        let now = Instant::now();
+
+        history.observe(&completed("a", 0, 800), now);
+        history.observe(&completed("b", 1000, 1800), now);
+        history.observe(&completed("c", 2000, 2800), now);
+        // a failed turn without offsets is never within a window
+        history.observe(&json!({ "type": "conversation.item.input_audio_transcription.failed", "item_id": "d", "error": { "message": "no" } }), now);
+
+        assert_eq!(history.last(usize::MAX).len(), 4);
+        assert_eq!(history.since(0).len(), 3);
+        assert_eq!(texts(&history.since(0)), ["a", "b", "c"]);
+        // a turn still running into the window counts
+        assert_eq!(texts(&history.since(1500)), ["b", "c"]);
+        assert_eq!(texts(&history.since(1800)), ["b", "c"]);
+        assert_eq!(texts(&history.since(1801)), ["c"]);
+        assert!(history.since(3000).is_empty());
+    }
+
+    #[test]
+    fn evicts_nothing_within_capacity() {
+        let mut history = TurnHistory::new(2, vec![(f64::MAX, "stdin".into())]);
+        let now = Instant::now();
+
+        history.observe(&json!({ "type": "input_audio_buffer.speech_started", "item_id": "a", "audio_start_ms": 0 }), now);
+        history.observe(&completed("a", 0, 800), now);
+        history.observe(&completed("b", 1000, 1800), now);
+
+        assert_eq!(history.evicted(), 0);
+        assert_eq!(texts(&history.last(usize::MAX)), ["a", "b"]);
+    }
+
+    #[test]
+    fn restored_turns_count_towards_capacity() {
+        let mut earlier = TurnHistory::new(8, vec![(f64::MAX, "stdin".into())]);
+        let now = Instant::now();
+
+        for (i, item_id) in ["a", "b", "c"].into_iter().enumerate() {
+            earlier.observe(&completed(item_id, i as u64 * 1000, i as u64 * 1000 + 800), now);
+        }
+
+        let mut history = TurnHistory::new(2, vec![(f64::MAX, "stdin".into())]);
+        history.restore(&earlier.last(usize::MAX));
+        history.observe(&completed("d", 3000, 3800), now);
+
+        assert_eq!(history.evicted(), 2);
+        assert_eq!(texts(&history.last(usize::MAX)), ["c", "d"]);
+        // numbered on from the earlier run, in a session of its own
+        let last = &history.last(1)[0];
+        assert_eq!((last.turn, last.session_index), (3, 1));
+    }
+
+    #[test]
+    fn exports_replace_the_file_whole() {
+        let path = std::env::temp_dir().join(format!("qasr-test-export-{}.json", std::process::id()));
+        let mut history = TurnHistory::new(8, vec![(f64::MAX, "stdin".into())]);
+
+        std::fs::write(&path, "an earlier export").unwrap();
+        history.observe(&completed("a", 0, 800), Instant::now());
+        export(&history.last(usize::MAX), &path).unwrap();
+
+        let exported: Vec<TurnResult> = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(texts(&exported), ["a"]);
+        assert!(!path.with_extension("json.partial").exists());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
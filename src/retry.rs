@@ -0,0 +1,179 @@
+use crate::client::{self, SessionConfig};
+use crate::error::{AsrError, Result};
+use crate::limiter::Limiter;
+use crate::protocol;
+use futures_util::{SinkExt, StreamExt};
+use log::error;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::{self, Message};
+use url::Url;
+
+const RETRY_TIMEOUT: Duration = Duration::from_secs(30);
+const RETRY_CHUNK_MS: usize = 100;
+
+// Keeps the most recently sent audio, addressed on the timeline the server saw
+pub struct AudioHistory {
+    bytes_per_ms: f64,
+    capacity: usize,
+    start: usize,
+    audio: VecDeque<u8>,
+}
+
+impl AudioHistory {
+    pub fn new(sample_rate: u32, seconds: u32) -> Self {
+        Self {
+            bytes_per_ms: sample_rate as f64 * 2.0 / 1000.0,
+            capacity: sample_rate as usize * 2 * seconds as usize,
+            start: 0,
+            audio: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, audio: &[u8]) {
+        self.audio.extend(audio);
+
+        let excess = self.audio.len().saturating_sub(self.capacity);
+        self.audio.drain(..excess);
+        self.start += excess;
+    }
+
+    // Everything from start_ms on, as much of it as is kept
+    pub fn since(&self, start_ms: f64) -> Vec<u8> {
+        let start = ((start_ms * self.bytes_per_ms) as usize & !1).clamp(self.start, self.start + self.audio.len());
+        self.audio.range(start - self.start..).copied().collect()
+    }
+
+    // Where the kept audio starts, older audio is gone
+    pub fn kept_from_ms(&self) -> f64 {
+        self.start as f64 / self.bytes_per_ms
+    }
+
+    pub fn slice(&self, start_ms: f64, end_ms: f64) -> Option<Vec<u8>> {
+        let start = (start_ms * self.bytes_per_ms) as usize & !1;
+        let end = ((end_ms * self.bytes_per_ms) as usize & !1).min(self.start + self.audio.len());
+
+        if start < self.start || start >= end {
+            return None
+        }
+
+        Some(self.audio.range(start - self.start..end - self.start).copied().collect())
+    }
+}
+
+// Server-timeline span of every turn that has not reached a final result yet
+#[derive(Default)]
+pub struct TurnSpans {
+    spans: HashMap<String, (Option<f64>, Option<f64>)>,
+    // where the last turn the server heard the end of stopped
+    stopped_ms: f64,
+}
+
+impl TurnSpans {
+    pub fn observe(&mut self, event: &Value) {
+        let Some(item_id) = event["item_id"].as_str() else {
+            return
+        };
+
+        match protocol::event_type(event) {
+            "input_audio_buffer.speech_started" => {
+                self.spans.entry(item_id.into()).or_default().0 = event["audio_start_ms"].as_f64();
+            }
+            "input_audio_buffer.speech_stopped" => {
+                self.spans.entry(item_id.into()).or_default().1 = event["audio_end_ms"].as_f64();
+                self.stopped_ms = self.stopped_ms.max(event["audio_end_ms"].as_f64().unwrap_or_default());
+            }
+            "conversation.item.input_audio_transcription.completed" => {
+                self.spans.remove(item_id);
+            }
+            _ => {}
+        }
+    }
+
+    // The earliest audio a replacement session needs again: where the oldest open turn started, or past the last
+    // turn if every one is done
+    pub fn pending_from_ms(&self) -> f64 {
+        self.spans.values().filter_map(|span| span.0).reduce(f64::min).unwrap_or(self.stopped_ms)
+    }
+
+    pub fn take(&mut self, item_id: &str) -> Option<(f64, f64)> {
+        match self.spans.remove(item_id)? {
+            (Some(start_ms), Some(end_ms)) => Some((start_ms, end_ms)),
+            _ => None,
+        }
+    }
+}
+
+// Re-transcribes a failed turn on a short-lived session of its own, so it never mixes with live audio
+pub struct TurnRetry {
+    pub url: Url,
+    pub api_key: String,
+    pub session_config: SessionConfig,
+    pub limiter: Arc<Limiter>,
+    pub attempts: u32,
+    pub max_message_size: usize,
+}
+
+impl TurnRetry {
+    pub async fn run(&self, item_id: &str, audio: &[u8]) -> Result<Value> {
+        let mut attempt = 1;
+
+        loop {
+            match self.transcribe(audio).await {
+                Err(err) if attempt < self.attempts => {
+                    error!("Retry {attempt} of turn {item_id} failed: {err}");
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn transcribe(&self, audio: &[u8]) -> Result<Value> {
+        let _session_permit = self.limiter.acquire_session().await;
+        let ws_stream = client::connect(&self.url, &self.api_key, self.max_message_size).await?;
+        let (mut message_tx, mut message_rx) = ws_stream.split();
+
+        let session_update = SessionConfig { server_vad: false, ..self.session_config.clone() }.update_event();
+
+        let chunk_bytes = self.session_config.sample_rate as usize * 2 * RETRY_CHUNK_MS / 1000;
+        let events = std::iter::once(session_update)
+            .chain(audio.chunks(chunk_bytes).map(client::audio_append_event))
+            .chain(std::iter::once(client::commit_event()));
+
+        for event in events {
+            self.limiter.acquire_request().await;
+            message_tx.send(Message::Text(event.to_string().into())).await?;
+        }
+
+        let completed = tokio::time::timeout(RETRY_TIMEOUT, async {
+            while let Some(msg) = message_rx.next().await {
+                let Message::Text(text) = msg? else {
+                    continue
+                };
+
+                let event: Value = serde_json::from_str(&text)?;
+
+                match protocol::event_type(&event) {
+                    "conversation.item.input_audio_transcription.completed" => return Ok(event),
+                    "error" | "conversation.item.input_audio_transcription.failed" => {
+                        return Err(AsrError::Protocol {
+                            code: event["error"]["code"].as_str().unwrap_or("unknown").into(),
+                            message: event["error"]["message"].as_str().unwrap_or_default().into(),
+                        })
+                    }
+                    _ => {}
+                }
+            }
+
+            Err(tungstenite::Error::ConnectionClosed.into())
+        })
+        .await
+        .map_err(|_| AsrError::Timeout("waiting for the retried turn".into()))?;
+
+        let _ = message_tx.close().await;
+        completed
+    }
+}
@@ -0,0 +1,33 @@
+use crate::protocol;
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+// Picks the model for the language the server detected, switching at most once per `min_interval`
+pub struct Router {
+    routes: Vec<(String, String)>,
+    min_interval: Duration,
+    last_switch: Option<Instant>,
+}
+
+impl Router {
+    pub fn new(routes: Vec<(String, String)>, min_interval: Duration) -> Self {
+        Self { routes, min_interval, last_switch: None }
+    }
+
+    // (language, model) to switch to after this event, if it is a completed turn in a routed language
+    pub fn target(&mut self, event: &Value, current_model: &str) -> Option<(String, String)> {
+        if protocol::event_type(event) != "conversation.item.input_audio_transcription.completed" {
+            return None
+        }
+
+        let language = event["language"].as_str()?;
+        let (_, model) = self.routes.iter().find(|(routed, _)| routed.eq_ignore_ascii_case(language))?;
+
+        if model == current_model || self.last_switch.is_some_and(|at| at.elapsed() < self.min_interval) {
+            return None
+        }
+
+        self.last_switch = Some(Instant::now());
+        Some((language.to_string(), model.clone()))
+    }
+}
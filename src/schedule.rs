@@ -0,0 +1,241 @@
+use jiff::civil::{Date, Weekday};
+use jiff::tz::TimeZone;
+use jiff::Timestamp;
+
+// Days ahead the next transition is looked for, a week covers every --active-days pattern
+const LOOKAHEAD_DAYS: i32 = 8;
+
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+const FULL_DAY_NAMES: [&str; 7] = ["monday", "tuesday", "wednesday", "thursday", "friday", "saturday", "sunday"];
+
+// HH:MM-HH:MM in local time, an end at or before the start runs past midnight
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Window {
+    start: (i8, i8),
+    end: (i8, i8),
+}
+
+pub fn parse_window(value: &str) -> Result<Window, String> {
+    let (start, end) = value.split_once('-').ok_or_else(|| format!("`{value}` is not a HH:MM-HH:MM window"))?;
+
+    Ok(Window { start: parse_time(start)?, end: parse_time(end)? })
+}
+
+fn parse_time(value: &str) -> Result<(i8, i8), String> {
+    let parsed = value.trim().split_once(':').and_then(|(hour, minute)| Some((hour.parse::<i8>().ok()?, minute.parse::<i8>().ok()?)));
+
+    match parsed {
+        Some((hour, minute)) if (0..24).contains(&hour) && (0..60).contains(&minute) => Ok((hour, minute)),
+        _ => Err(format!("`{value}` is not a HH:MM time of day")),
+    }
+}
+
+// Weekdays as a bit set, Monday first
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Days(u8);
+
+impl Days {
+    pub const ALL: Days = Days(0x7f);
+
+    fn contains(self, weekday: Weekday) -> bool {
+        self.0 & 1 << weekday.to_monday_zero_offset() != 0
+    }
+}
+
+// Comma-separated days or ranges like mon-fri, a range may wrap around the week
+pub fn parse_days(value: &str) -> Result<Days, String> {
+    let day = |name: &str| {
+        let lowercase = name.trim().to_lowercase();
+
+        DAY_NAMES
+            .iter()
+            .zip(FULL_DAY_NAMES)
+            .position(|(&short, full)| lowercase == short || lowercase == full)
+            .ok_or_else(|| format!("`{name}` is not a day, expected one of {} or the full name", DAY_NAMES.join(", ")))
+    };
+
+    let mut days = 0u8;
+
+    for part in value.split(',') {
+        let (from, to) = match part.split_once('-') {
+            Some((from, to)) => (day(from)?, day(to)?),
+            None => (day(part)?, day(part)?),
+        };
+
+        let mut current = from;
+        loop {
+            days |= 1 << current;
+
+            if current == to {
+                break
+            }
+            current = (current + 1) % 7;
+        }
+    }
+
+    Ok(Days(days))
+}
+
+pub fn parse_timezone(value: &str) -> Result<TimeZone, String> {
+    TimeZone::get(value).map_err(|err| format!("unknown time zone `{value}`: {err}"))
+}
+
+// When transcription is wanted. Intervals are worked out from the calendar every time, so a DST change
+// only moves the instants, it never leaves the scheduler waiting for a wall-clock time that got skipped.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    windows: Vec<Window>,
+    days: Days,
+    tz: TimeZone,
+}
+
+impl Schedule {
+    pub fn new(windows: Vec<Window>, days: Days, tz: TimeZone) -> Self {
+        Self { windows, days, tz }
+    }
+
+    pub fn is_active(&self, now: Timestamp) -> bool {
+        let today = now.to_zoned(self.tz.clone()).date();
+
+        // a window is at most a day long, so only those starting yesterday or today can cover now
+        [today.yesterday().ok(), Some(today)].into_iter().flatten().flat_map(|date| self.intervals(date)).any(|(start, end)| start <= now && now < end)
+    }
+
+    // None when the schedule stays as it is for the whole week
+    pub fn next_transition(&self, now: Timestamp) -> Option<Timestamp> {
+        let today = now.to_zoned(self.tz.clone()).date();
+        let active = self.is_active(now);
+
+        let mut instants: Vec<Timestamp> = (-1..LOOKAHEAD_DAYS)
+            .filter_map(|days| today.checked_add(jiff::Span::new().days(days)).ok())
+            .flat_map(|date| self.intervals(date))
+            .flat_map(|(start, end)| [start, end])
+            .filter(|&instant| instant > now)
+            .collect();
+        instants.sort();
+
+        // adjoining or overlapping windows share instants that change nothing
+        instants.into_iter().find(|&instant| self.is_active(instant) != active)
+    }
+
+    pub fn time_zone(&self) -> &TimeZone {
+        &self.tz
+    }
+
+    fn intervals(&self, date: Date) -> Vec<(Timestamp, Timestamp)> {
+        if !self.days.contains(date.weekday()) {
+            return Vec::new()
+        }
+
+        let at = |date: Date, (hour, minute): (i8, i8)| date.at(hour, minute, 0, 0).to_zoned(self.tz.clone()).ok().map(|zoned| zoned.timestamp());
+
+        self.windows
+            .iter()
+            .filter_map(|window| {
+                let end_date = match window.end <= window.start {
+                    true => date.tomorrow().ok()?,
+                    false => date,
+                };
+
+                Some((at(date, window.start)?, at(end_date, window.end)?))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(instant: &str) -> Timestamp {
+        instant.parse().unwrap()
+    }
+
+    fn schedule(windows: &str, days: &str, tz: &str) -> Schedule {
+        Schedule::new(windows.split(',').map(|window| parse_window(window).unwrap()).collect(), parse_days(days).unwrap(), parse_timezone(tz).unwrap())
+    }
+
+    fn hours((start, end): (Timestamp, Timestamp)) -> f64 {
+        (end.as_second() - start.as_second()) as f64 / 3600.0
+    }
+
+    #[test]
+    fn parses_windows() {
+        assert_eq!(parse_window("08:00-12:30"), Ok(Window { start: (8, 0), end: (12, 30) }));
+        assert_eq!(parse_window(" 22:00 - 06:00"), Ok(Window { start: (22, 0), end: (6, 0) }));
+
+        for value in ["08:00", "8-12", "24:00-01:00", "08:60-09:00", "-08:00-09:00"] {
+            assert!(parse_window(value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn parses_whole_day_names_only() {
+        assert_eq!(parse_days("mon-fri"), Ok(Days(0x1f)));
+        assert_eq!(parse_days("Saturday,SUN"), Ok(Days(0x60)));
+        assert_eq!(parse_days("fri-mon"), Ok(Days(0x71)));
+        assert_eq!(parse_days("wed"), Ok(Days(0x04)));
+
+        for value in ["monkey", "mo", "thurs", "sundays", "mon-", ""] {
+            assert!(parse_days(value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn runs_a_window_past_midnight() {
+        let schedule = schedule("22:00-06:00", "mon", "UTC");
+
+        // 2026-10-12 is a Monday
+        assert!(!schedule.is_active(at("2026-10-12T21:59:59Z")));
+        assert!(schedule.is_active(at("2026-10-12T22:00:00Z")));
+        assert!(schedule.is_active(at("2026-10-13T05:59:59Z")), "the Monday night carries over into Tuesday");
+        assert!(!schedule.is_active(at("2026-10-13T06:00:00Z")));
+        assert!(!schedule.is_active(at("2026-10-13T23:00:00Z")), "no window starts on Tuesday");
+    }
+
+    #[test]
+    fn finds_the_next_transition() {
+        let schedule = schedule("09:00-17:00", "mon-fri", "UTC");
+
+        assert_eq!(schedule.next_transition(at("2026-10-12T08:00:00Z")), Some(at("2026-10-12T09:00:00Z")));
+        assert_eq!(schedule.next_transition(at("2026-10-12T09:00:00Z")), Some(at("2026-10-12T17:00:00Z")));
+        // over the weekend
+        assert_eq!(schedule.next_transition(at("2026-10-16T17:00:00Z")), Some(at("2026-10-19T09:00:00Z")));
+
+        // adjoining and overlapping windows change nothing where they meet
+        let schedule = self::schedule("08:00-12:00,12:00-13:00,12:30-18:00", "mon-sun", "UTC");
+        assert_eq!(schedule.next_transition(at("2026-10-12T10:00:00Z")), Some(at("2026-10-12T18:00:00Z")));
+
+        let always = self::schedule("00:00-12:00,12:00-00:00", "mon-sun", "UTC");
+        assert!(always.is_active(at("2026-10-12T12:00:00Z")));
+        assert_eq!(always.next_transition(at("2026-10-12T12:00:00Z")), None);
+    }
+
+    // 2026-03-08 02:00 does not exist in New York, 2026-11-01 01:00 to 02:00 happens twice
+    #[test]
+    fn works_out_intervals_across_dst_changes() {
+        let overnight = schedule("22:00-06:00", "mon-sun", "America/New_York");
+        assert_eq!(overnight.intervals(Date::constant(2026, 3, 7)), [(at("2026-03-08T03:00:00Z"), at("2026-03-08T10:00:00Z"))]);
+        assert_eq!(hours(overnight.intervals(Date::constant(2026, 3, 7))[0]), 7.0);
+        assert_eq!(hours(overnight.intervals(Date::constant(2026, 10, 31))[0]), 9.0);
+
+        // a start in the gap moves on to when the clocks say it again, an ambiguous one is the first of the two
+        let gap = schedule("02:30-04:00", "mon-sun", "America/New_York");
+        assert_eq!(gap.intervals(Date::constant(2026, 3, 8)), [(at("2026-03-08T07:30:00Z"), at("2026-03-08T08:00:00Z"))]);
+        let overlap = schedule("01:30-02:30", "mon-sun", "America/New_York");
+        assert_eq!(overlap.intervals(Date::constant(2026, 11, 1)), [(at("2026-11-01T05:30:00Z"), at("2026-11-01T07:30:00Z"))]);
+
+        // the wall clock skips ahead, the transition is still an instant that exists
+        assert_eq!(gap.next_transition(at("2026-03-08T06:00:00Z")), Some(at("2026-03-08T07:30:00Z")));
+        assert!(!gap.is_active(at("2026-03-08T07:29:59Z")) && gap.is_active(at("2026-03-08T07:30:00Z")));
+    }
+
+    #[test]
+    fn skips_days_not_scheduled() {
+        let schedule = schedule("09:00-17:00", "sat,sun", "UTC");
+
+        assert!(schedule.intervals(Date::constant(2026, 10, 12)).is_empty());
+        assert_eq!(schedule.intervals(Date::constant(2026, 10, 17)), [(at("2026-10-17T09:00:00Z"), at("2026-10-17T17:00:00Z"))]);
+    }
+}
@@ -0,0 +1,326 @@
+use crate::output::{self, WallClock};
+use crate::protocol;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    // client.* events
+    Client,
+    // server events, as received or corrected
+    Server,
+    // a finished turn, a --format results line or a subtitle cue
+    Turn,
+}
+
+#[derive(Debug, Clone)]
+pub struct OutputEvent {
+    pub kind: Kind,
+    pub line: String,
+    pub received_at: Instant,
+}
+
+// What a sink wants to see
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Filter {
+    #[default]
+    All,
+    // final transcripts and translations, and finished turns
+    Finals,
+    Turns,
+}
+
+impl Filter {
+    pub fn accepts(self, event: &OutputEvent) -> bool {
+        match (self, event.kind) {
+            (Filter::All, _) | (_, Kind::Turn) => true,
+            (Filter::Turns, _) | (Filter::Finals, Kind::Client) => false,
+            // only parsed for sinks that ask, on the dispatcher thread
+            (Filter::Finals, Kind::Server) => serde_json::from_str::<Value>(&event.line).is_ok_and(|event| {
+                protocol::event_type(&event) == "conversation.item.input_audio_transcription.completed" || protocol::translation_text(&event).is_some()
+            }),
+        }
+    }
+}
+
+// One output destination. A sink that fails is disabled, the others carry on.
+pub trait EventSink: Send {
+    fn name(&self) -> &str;
+
+    fn filter(&self) -> Filter {
+        Filter::All
+    }
+
+    fn accept(&mut self, event: &OutputEvent) -> io::Result<()>;
+
+    // At turn boundaries and once more before shutdown
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Message {
+    Event(OutputEvent),
+    Flush,
+}
+
+// Owns every sink on a thread of its own, so a slow one never holds up the read loop.
+// Each sink sees the events in the order they were sent.
+pub struct Dispatcher {
+    tx: Mutex<Option<mpsc::Sender<Message>>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Dispatcher {
+    pub fn start(mut sinks: Vec<Box<dyn EventSink>>) -> Self {
+        let (tx, rx) = mpsc::channel();
+
+        let thread = std::thread::spawn(move || {
+            for message in rx {
+                match message {
+                    Message::Event(event) => deliver(&mut sinks, |sink| match sink.filter().accepts(&event) {
+                        true => sink.accept(&event),
+                        false => Ok(()),
+                    }),
+                    Message::Flush => deliver(&mut sinks, |sink| sink.flush()),
+                }
+            }
+
+            // the channel closes on shutdown, whatever is still buffered goes out now
+            deliver(&mut sinks, |sink| sink.flush());
+        });
+
+        Self { tx: Mutex::new(Some(tx)), thread: Mutex::new(Some(thread)) }
+    }
+
+    pub fn send(&self, event: OutputEvent) {
+        self.message(Message::Event(event));
+    }
+
+    pub fn flush(&self) {
+        self.message(Message::Flush);
+    }
+
+    // Waits until every event sent so far has reached its sinks, nothing is taken after it
+    pub fn finish(&self) {
+        drop(self.tx.lock().unwrap().take());
+
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
+
+    fn message(&self, message: Message) {
+        match self.tx.lock().unwrap().as_ref() {
+            Some(tx) => {
+                let _ = tx.send(message);
+            }
+            None => debug!("Dropping output after shutdown"),
+        }
+    }
+}
+
+fn deliver(sinks: &mut Vec<Box<dyn EventSink>>, mut f: impl FnMut(&mut dyn EventSink) -> io::Result<()>) {
+    sinks.retain_mut(|sink| match f(sink.as_mut()) {
+        Ok(()) => true,
+        Err(err) => {
+            error!("Output to {} failed, disabling it: {err}", sink.name());
+            false
+        }
+    });
+}
+
+// The JSON lines (or SRT cues) on stdout
+#[derive(Debug, Default)]
+pub struct StdoutSink {
+    pub clock: Option<WallClock>,
+    pub ascii_json: bool,
+    pub filter: Filter,
+    // synced at every flush, for --durable
+    pub durable: Option<File>,
+    pub fsyncs: Arc<AtomicU64>,
+}
+
+impl EventSink for StdoutSink {
+    fn name(&self) -> &str {
+        "stdout"
+    }
+
+    fn filter(&self) -> Filter {
+        self.filter
+    }
+
+    fn accept(&mut self, event: &OutputEvent) -> io::Result<()> {
+        let line = match self.ascii_json {
+            true => output::escape_non_ascii(&event.line),
+            false => event.line.as_str().into(),
+        };
+
+        let mut stdout = io::stdout().lock();
+
+        match &self.clock {
+            Some(clock) => writeln!(stdout, "{}\t{line}", humantime::format_rfc3339_millis(clock.wall_time(event.received_at))),
+            None => writeln!(stdout, "{line}"),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()?;
+
+        let Some(file) = &self.durable else {
+            return Ok(())
+        };
+
+        // a disk that cannot sync still takes the output
+        match file.sync_data() {
+            Ok(()) => {
+                self.fsyncs.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(err) => error!("Failed to sync the output to disk: {err}"),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    // Writes what it is given to a shared log, and fails once it has taken fail_after events
+    struct Recording {
+        name: &'static str,
+        filter: Filter,
+        fail_after: Option<usize>,
+        log: Log,
+    }
+
+    impl EventSink for Recording {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn filter(&self) -> Filter {
+            self.filter
+        }
+
+        fn accept(&mut self, event: &OutputEvent) -> io::Result<()> {
+            let mut log = self.log.lock().unwrap();
+
+            if self.fail_after.is_some_and(|n| log.iter().filter(|entry| entry.starts_with(self.name)).count() >= n) {
+                return Err(io::ErrorKind::BrokenPipe.into())
+            }
+
+            log.push(format!("{} {}", self.name, event.line));
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.log.lock().unwrap().push(format!("{} flush", self.name));
+            Ok(())
+        }
+    }
+
+    fn sink(name: &'static str, filter: Filter, fail_after: Option<usize>, log: &Log) -> Box<dyn EventSink> {
+        Box::new(Recording { name, filter, fail_after, log: log.clone() })
+    }
+
+    fn event(kind: Kind, line: &str) -> OutputEvent {
+        OutputEvent { kind, line: line.into(), received_at: Instant::now() }
+    }
+
+    fn entries(log: &Log, name: &str) -> Vec<String> {
+        log.lock().unwrap().iter().filter_map(|entry| entry.strip_prefix(name)?.strip_prefix(' ').map(Into::into)).collect()
+    }
+
+    #[test]
+    fn delivers_in_order_and_flushes_on_finish() {
+        let log = Log::default();
+        let dispatcher = Dispatcher::start(vec![sink("a", Filter::All, None, &log), sink("b", Filter::All, None, &log)]);
+
+        for i in 0..100 {
+            dispatcher.send(event(Kind::Server, &i.to_string()));
+        }
+        dispatcher.flush();
+        dispatcher.send(event(Kind::Turn, "last"));
+        dispatcher.finish();
+
+        let mut expected: Vec<String> = (0..100).map(|i| i.to_string()).collect();
+        expected.extend(["flush".into(), "last".into(), "flush".into()]);
+        assert_eq!(entries(&log, "a"), expected);
+        assert_eq!(entries(&log, "b"), expected);
+
+        // nothing is taken after finishing
+        dispatcher.send(event(Kind::Server, "late"));
+        dispatcher.finish();
+        assert_eq!(entries(&log, "a").len(), expected.len());
+    }
+
+    #[test]
+    fn disables_a_failing_sink() {
+        let log = Log::default();
+        let dispatcher = Dispatcher::start(vec![sink("bad", Filter::All, Some(2), &log), sink("good", Filter::All, None, &log)]);
+
+        for i in 0..5 {
+            dispatcher.send(event(Kind::Server, &i.to_string()));
+        }
+        dispatcher.finish();
+
+        // neither flushed nor given anything after it failed
+        assert_eq!(entries(&log, "bad"), ["0", "1"]);
+        assert_eq!(entries(&log, "good"), ["0", "1", "2", "3", "4", "flush"]);
+    }
+
+    #[test]
+    fn filters_per_sink() {
+        let log = Log::default();
+        let dispatcher = Dispatcher::start(vec![sink("all", Filter::All, None, &log), sink("finals", Filter::Finals, None, &log), sink("turns", Filter::Turns, None, &log)]);
+
+        let lines = [
+            (Kind::Client, r#"{"type":"client.connected"}"#),
+            (Kind::Server, r#"{"type":"conversation.item.input_audio_transcription.text","text":"partial"}"#),
+            (Kind::Server, r#"{"type":"conversation.item.input_audio_transcription.completed","transcript":"final"}"#),
+            (Kind::Server, r#"{"type":"response.text.delta","delta":"trans"}"#),
+            (Kind::Server, r#"{"type":"response.text.done","text":"translated"}"#),
+            (Kind::Server, "not json"),
+            (Kind::Turn, "1 00:00:00,000 --> 00:00:01,000"),
+        ];
+        for (kind, line) in lines {
+            dispatcher.send(event(kind, line));
+        }
+        dispatcher.finish();
+
+        assert_eq!(entries(&log, "all").len(), lines.len() + 1);
+        assert_eq!(entries(&log, "finals"), [lines[2].1, lines[4].1, lines[6].1, "flush"]);
+        assert_eq!(entries(&log, "turns"), [lines[6].1, "flush"]);
+    }
+
+    #[test]
+    fn durable_stdout_syncs_at_every_flush() {
+        let path = std::env::temp_dir().join(format!("qasr-test-durable-{}", std::process::id()));
+        let fsyncs = Arc::new(AtomicU64::new(0));
+        let mut stdout = StdoutSink { durable: Some(File::create(&path).unwrap()), fsyncs: fsyncs.clone(), ..Default::default() };
+
+        stdout.flush().unwrap();
+        stdout.flush().unwrap();
+        assert_eq!(fsyncs.load(Ordering::Relaxed), 2);
+
+        // without --durable flushing never syncs
+        let mut stdout = StdoutSink { fsyncs: fsyncs.clone(), ..Default::default() };
+        stdout.flush().unwrap();
+        assert_eq!(fsyncs.load(Ordering::Relaxed), 2);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
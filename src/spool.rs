@@ -0,0 +1,167 @@
+use crate::offset::OffsetMap;
+use log::{error, warn};
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+const CHUNK_EXTENSION: &str = "pcm";
+
+// Audio that did not fit into the send queue, kept in numbered files until the writer catches up
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    state: Mutex<State>,
+    offsets: Arc<Mutex<OffsetMap>>,
+}
+
+struct State {
+    chunks: VecDeque<(u64, u64)>,
+    bytes: u64,
+    next_seq: u64,
+}
+
+impl Spool {
+    pub fn open(dir: &Path, max_bytes: u64, resume: bool, offsets: Arc<Mutex<OffsetMap>>) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let mut chunks = Vec::new();
+
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+
+            if path.extension().is_some_and(|extension| extension == CHUNK_EXTENSION) {
+                if let Some(seq) = path.file_stem().and_then(|stem| stem.to_str()?.parse::<u64>().ok()) {
+                    chunks.push((seq, entry.metadata()?.len()));
+                }
+            }
+        }
+
+        if !chunks.is_empty() && !resume {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} holds {} spooled chunks from an earlier run, pass --spool-resume to send them or remove them", dir.display(), chunks.len()),
+            ))
+        }
+
+        chunks.sort_unstable();
+
+        let state = State {
+            bytes: chunks.iter().map(|(_, len)| len).sum(),
+            next_seq: chunks.last().map_or(0, |(seq, _)| seq + 1),
+            chunks: chunks.into(),
+        };
+
+        // resumed audio goes out ahead of the live input, on the same timeline
+        if state.bytes > 0 {
+            let mut offsets = offsets.lock().unwrap();
+            offsets.sent(state.bytes as usize);
+            offsets.backfill(state.bytes as usize);
+        }
+
+        Ok(Self { dir: dir.to_path_buf(), max_bytes, state: Mutex::new(state), offsets })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn chunks(&self) -> usize {
+        self.state.lock().unwrap().chunks.len()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.state.lock().unwrap().bytes
+    }
+
+    // Live audio only takes the channel while nothing is spooled, so replay always comes first.
+    // Returns false once the channel is closed.
+    pub fn send(&self, audio_tx: &mpsc::Sender<Vec<u8>>, audio: Vec<u8>) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        let audio = if state.chunks.is_empty() {
+            match audio_tx.try_send(audio) {
+                Ok(()) => return true,
+                Err(TrySendError::Closed(_)) => return false,
+                Err(TrySendError::Full(audio)) => audio,
+            }
+        } else {
+            audio
+        };
+
+        match self.spill(&mut state, &audio) {
+            Ok(()) => true,
+            Err(err) => {
+                drop(state);
+                error!("Failed to spool audio to {}: {err}", self.dir.display());
+                audio_tx.blocking_send(audio).is_ok()
+            }
+        }
+    }
+
+    // The audio was counted as sent already, and whatever is spooled is always the tail of what was sent
+    fn spill(&self, state: &mut State, audio: &[u8]) -> io::Result<()> {
+        while state.bytes + audio.len() as u64 > self.max_bytes {
+            let Some((seq, len)) = state.chunks.pop_front() else {
+                break
+            };
+
+            warn!("Spool exceeds its cap, dropping {len} bytes of the oldest audio");
+            self.offsets.lock().unwrap().dropped((state.bytes + audio.len() as u64) as usize, len as usize);
+            state.bytes -= len;
+            fs::remove_file(self.chunk_path(seq))?;
+        }
+
+        fs::write(self.chunk_path(state.next_seq), audio)?;
+        self.offsets.lock().unwrap().backfill(audio.len());
+        state.chunks.push_back((state.next_seq, audio.len() as u64));
+        state.bytes += audio.len() as u64;
+        state.next_seq += 1;
+
+        Ok(())
+    }
+
+    fn pop(&self) -> io::Result<Option<Vec<u8>>> {
+        let mut state = self.state.lock().unwrap();
+
+        let Some((seq, len)) = state.chunks.pop_front() else {
+            return Ok(None)
+        };
+
+        state.bytes -= len;
+
+        let path = self.chunk_path(seq);
+        let audio = fs::read(&path)?;
+        fs::remove_file(path)?;
+
+        Ok(Some(audio))
+    }
+
+    fn chunk_path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{seq:010}.{CHUNK_EXTENSION}"))
+    }
+}
+
+// Next chunk to send: whatever is queued in memory first, it was captured before anything spooled
+pub async fn recv(audio_rx: &mut mpsc::Receiver<Vec<u8>>, spool: Option<&Spool>) -> Option<Vec<u8>> {
+    let Some(spool) = spool else {
+        return audio_rx.recv().await
+    };
+
+    if let Ok(audio) = audio_rx.try_recv() {
+        return Some(audio)
+    }
+
+    loop {
+        match spool.pop() {
+            Ok(Some(audio)) => return Some(audio),
+            Ok(None) => break,
+            Err(err) => error!("Failed to read spooled audio from {}: {err}", spool.dir.display()),
+        }
+    }
+
+    audio_rx.recv().await
+}
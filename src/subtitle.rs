@@ -0,0 +1,301 @@
+use crate::output::Printer;
+use crate::protocol;
+use crate::results::TurnResult;
+use crate::words::Word;
+use log::debug;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+// Finished turns still waiting for their translation, older ones get the fallback cue
+const MAX_WAITING: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Track {
+    Transcript,
+    Translation,
+}
+
+// track=path, as given to --subtitle-out
+pub fn parse_track(value: &str) -> Result<(Track, PathBuf), String> {
+    let (track, path) = value.split_once('=').ok_or_else(|| format!("`{value}` is not a track=path pair"))?;
+
+    let track = match track.trim() {
+        "transcript" => Track::Transcript,
+        "translation" => Track::Translation,
+        other => return Err(format!("`{other}` is not a subtitle track, expected transcript or translation")),
+    };
+
+    match path.trim() {
+        "" => Err(format!("`{value}` has no path")),
+        path => Ok((track, PathBuf::from(path))),
+    }
+}
+
+// One style for every line. Karaoke words turn from the secondary colour (grey) to the primary one (white) as they are spoken.
+const ASS_HEADER: &str = "[Script Info]
+ScriptType: v4.00+
+PlayResX: 384
+PlayResY: 288
+WrapStyle: 0
+ScaledBorderAndShadow: yes
+
+[V4+ Styles]
+Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
+Style: Default,Arial,20,&H00FFFFFF,&H00A0A0A0,&H00000000,&H80000000,0,0,0,0,100,100,0,0,1,2,1,2,10,10,12,1
+
+[Events]
+Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubtitleFormat {
+    Srt,
+    // Advanced SubStation Alpha, with per-word karaoke timing where the turn has it
+    Ass,
+}
+
+enum Sink {
+    Stdout(Arc<Printer>),
+    File { file: BufWriter<File>, durable: bool },
+}
+
+// One subtitle file, SRT cue indices count per file
+pub struct CueWriter {
+    sink: Sink,
+    format: SubtitleFormat,
+    next_index: u64,
+}
+
+impl CueWriter {
+    pub fn stdout(printer: Arc<Printer>, format: SubtitleFormat) -> io::Result<Self> {
+        Self { sink: Sink::Stdout(printer), format, next_index: 1 }.started()
+    }
+
+    pub fn create(path: &Path, format: SubtitleFormat, durable: bool) -> io::Result<Self> {
+        Self { sink: Sink::File { file: BufWriter::new(File::create(path)?), durable }, format, next_index: 1 }.started()
+    }
+
+    fn started(mut self) -> io::Result<Self> {
+        if self.format == SubtitleFormat::Ass {
+            self.write(ASS_HEADER.to_string())?;
+        }
+
+        Ok(self)
+    }
+
+    // Flushed per cue, a player following the file never sees half of one
+    pub fn cue(&mut self, start_ms: u64, end_ms: u64, text: &str, words: &[Word]) -> io::Result<()> {
+        let cue = match self.format {
+            // the blank line that ends the cue comes with the write
+            SubtitleFormat::Srt => format!("{}\n{} --> {}\n{}\n", self.next_index, timestamp(start_ms), timestamp(end_ms), text.trim()),
+            SubtitleFormat::Ass => {
+                let text = match words.is_empty() {
+                    true => ass_text(text.trim()),
+                    false => karaoke(start_ms, text, words),
+                };
+                format!("Dialogue: 0,{},{},Default,,0,0,0,,{text}", ass_timestamp(start_ms), ass_timestamp(end_ms))
+            }
+        };
+        self.next_index += 1;
+
+        self.write(cue)
+    }
+
+    fn write(&mut self, lines: String) -> io::Result<()> {
+        match &mut self.sink {
+            // println adds the line end
+            Sink::Stdout(printer) => printer.print_turn(lines, Instant::now()),
+            Sink::File { file, durable } => {
+                writeln!(file, "{lines}")?;
+                file.flush()?;
+
+                if *durable {
+                    file.get_ref().sync_data()?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Writes the cues of the transcript and translation tracks, both timed by the turn they belong to
+pub struct Subtitles {
+    transcript: Option<CueWriter>,
+    translation: Option<CueWriter>,
+    // None drops cues whose translation never arrived
+    fallback: Option<String>,
+    waiting: VecDeque<TurnResult>,
+    early: VecDeque<(Option<String>, String)>,
+}
+
+impl Subtitles {
+    pub fn new(transcript: Option<CueWriter>, translation: Option<CueWriter>, fallback: Option<String>) -> Self {
+        Self { transcript, translation, fallback, waiting: VecDeque::new(), early: VecDeque::new() }
+    }
+
+    pub fn turn(&mut self, result: TurnResult) -> io::Result<()> {
+        let (Some(start_ms), Some(text)) = (result.start_ms, &result.text) else {
+            debug!("No subtitle cue for turn {}, it has no text or timing", result.turn);
+            return Ok(())
+        };
+
+        let end_ms = result.end_ms.unwrap_or(start_ms).max(start_ms);
+
+        if let Some(transcript) = &mut self.transcript {
+            transcript.cue(start_ms, end_ms, text, &result.words)?;
+        }
+
+        if self.translation.is_none() {
+            return Ok(())
+        }
+
+        // the translation may have come first
+        let early = match self.early.iter().position(|(item_id, _)| item_id.as_deref() == Some(&result.item_id)) {
+            Some(position) => self.early.remove(position),
+            None => self.early.pop_front(),
+        };
+
+        if let Some((_, translation)) = early {
+            return self.translated(&result, Some(&translation))
+        }
+
+        if self.waiting.len() == MAX_WAITING {
+            let oldest = self.waiting.pop_front().unwrap();
+            self.translated(&oldest, None)?;
+        }
+
+        self.waiting.push_back(result);
+        Ok(())
+    }
+
+    pub fn translation(&mut self, event: &Value) -> io::Result<()> {
+        let Some(text) = protocol::translation_text(event) else {
+            return Ok(())
+        };
+
+        let item_id = event["item_id"].as_str();
+
+        // matched by item id where the server repeats it, in order otherwise
+        let Some(position) = self.waiting.iter().position(|result| Some(result.item_id.as_str()) == item_id).or((!self.waiting.is_empty()).then_some(0)) else {
+            self.early.push_back((item_id.map(Into::into), text.into()));
+            self.early.truncate(MAX_WAITING);
+            return Ok(())
+        };
+
+        // everything before it was skipped by the server
+        for result in self.waiting.drain(..position).collect::<Vec<_>>() {
+            self.translated(&result, None)?;
+        }
+
+        let result = self.waiting.pop_front().unwrap();
+        self.translated(&result, Some(text))
+    }
+
+    // Turns whose translation never arrived
+    pub fn finish(&mut self) -> io::Result<()> {
+        while let Some(result) = self.waiting.pop_front() {
+            self.translated(&result, None)?;
+        }
+
+        Ok(())
+    }
+
+    fn translated(&mut self, result: &TurnResult, translation: Option<&str>) -> io::Result<()> {
+        let (Some(writer), Some(start_ms)) = (&mut self.translation, result.start_ms) else {
+            return Ok(())
+        };
+
+        let text = match (translation, &self.fallback) {
+            (Some(translation), _) => translation.to_string(),
+            (None, Some(marker)) => format!("{} {marker}", result.text.as_deref().unwrap_or_default()),
+            (None, None) => return Ok(()),
+        };
+
+        writer.cue(start_ms, result.end_ms.unwrap_or(start_ms).max(start_ms), &text, &[])
+    }
+}
+
+// HH:MM:SS,mmm
+fn timestamp(ms: u64) -> String {
+    format!("{:02}:{:02}:{:02},{:03}", ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60, ms % 1000)
+}
+
+// H:MM:SS.cc, ASS counts in centiseconds
+fn ass_timestamp(ms: u64) -> String {
+    let cs = (ms + 5) / 10;
+    format!("{}:{:02}:{:02}.{:02}", cs / 360_000, cs / 6000 % 60, cs / 100 % 60, cs % 100)
+}
+
+// Override blocks are the only markup in a line, braces in a transcript would open one
+fn ass_text(text: &str) -> String {
+    text.replace('{', "(").replace('}', ")").replace('\n', "\\N")
+}
+
+// Each word gets a \k of its length in centiseconds, pauses between words an empty one. Whatever the transcript has
+// between two words, spaces and punctuation, stays with the word before it.
+fn karaoke(start_ms: u64, transcript: &str, words: &[Word]) -> String {
+    let mut rest = transcript.trim();
+    let mut texts: Vec<String> = Vec::new();
+
+    for word in words {
+        let Some(at) = rest.find(&word.text) else {
+            texts.push(word.text.clone());
+            continue
+        };
+
+        match texts.last_mut() {
+            Some(last) => {
+                last.push_str(&rest[..at]);
+                texts.push(word.text.clone());
+            }
+            None => texts.push(rest[..at + word.text.len()].into()),
+        }
+        rest = &rest[at + word.text.len()..];
+    }
+
+    if let Some(last) = texts.last_mut() {
+        last.push_str(rest);
+    }
+
+    let cs = |ms: u64| (ms + 5) / 10;
+    let mut line = String::new();
+    let mut cursor = cs(start_ms);
+
+    for (word, text) in words.iter().zip(texts) {
+        if cs(word.start_ms) > cursor {
+            line.push_str(&format!("{{\\k{}}}", cs(word.start_ms) - cursor));
+            cursor = cs(word.start_ms);
+        }
+
+        let end = cs(word.end_ms).max(cursor);
+        line.push_str(&format!("{{\\k{}}}{}", end - cursor, ass_text(&text)));
+        cursor = end;
+    }
+
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_cue_reaches_the_file_whole() {
+        let path = std::env::temp_dir().join(format!("qasr-test-cues-{}.srt", std::process::id()));
+        let mut writer = CueWriter::create(&path, SubtitleFormat::Srt, true).unwrap();
+
+        writer.cue(0, 1500, " 今天天气不错。 ", &[]).unwrap();
+        // nothing is left in the buffer for a crash to lose
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "1\n00:00:00,000 --> 00:00:01,500\n今天天气不错。\n\n");
+
+        writer.cue(3_723_004, 3_723_900, "Hello.", &[]).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "1\n00:00:00,000 --> 00:00:01,500\n今天天气不错。\n\n2\n01:02:03,004 --> 01:02:03,900\nHello.\n\n");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+#[cfg(target_os = "linux")]
+use log::debug;
+#[cfg(unix)]
+use log::warn;
+
+// Where systemd takes notifications from a Type=notify service
+pub const NOTIFY_SOCKET: &str = "NOTIFY_SOCKET";
+
+// The first file descriptor systemd passes to a socket-activated service
+#[cfg(unix)]
+const LISTEN_FDS_START: std::os::fd::RawFd = 3;
+
+// sd_notify(3) without libsystemd, a datagram per state change. Does nothing unless systemd set NOTIFY_SOCKET.
+#[derive(Debug, Default)]
+pub struct Notifier {
+    #[cfg(target_os = "linux")]
+    socket: Option<(std::os::unix::net::UnixDatagram, std::os::unix::net::SocketAddr)>,
+}
+
+impl Notifier {
+    #[cfg(target_os = "linux")]
+    pub fn from_env() -> Self {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+        let Some(path) = std::env::var_os(NOTIFY_SOCKET) else {
+            return Self::default()
+        };
+
+        // notifications are for the service alone, not for what it starts
+        std::env::remove_var(NOTIFY_SOCKET);
+
+        // a leading @ names a socket in the abstract namespace
+        let addr = match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name),
+            None => SocketAddr::from_pathname(&path),
+        };
+
+        match addr.and_then(|addr| Ok((UnixDatagram::unbound()?, addr))) {
+            Ok(socket) => Self { socket: Some(socket) },
+            Err(err) => {
+                warn!("Not notifying systemd, {NOTIFY_SOCKET}={}: {err}", path.to_string_lossy());
+                Self::default()
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn from_env() -> Self {
+        Self::default()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        #[cfg(target_os = "linux")]
+        return self.socket.is_some();
+
+        #[cfg(not(target_os = "linux"))]
+        false
+    }
+
+    pub fn ready(&self) {
+        self.notify("READY=1");
+    }
+
+    // A line for systemctl status
+    pub fn status(&self, status: &str) {
+        self.notify(&format!("STATUS={status}"));
+    }
+
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    // systemd not listening is no reason to stop transcribing
+    #[cfg(target_os = "linux")]
+    fn notify(&self, state: &str) {
+        let Some((socket, addr)) = &self.socket else {
+            return
+        };
+
+        if let Err(err) = socket.send_to_addr(state.as_bytes(), addr) {
+            debug!("Failed to notify systemd of {state}: {err}");
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn notify(&self, _state: &str) {}
+}
+
+// The listening socket systemd passed in, when the service was socket-activated. Only the first one is used.
+#[cfg(unix)]
+pub fn listen_fd() -> Option<std::os::fd::RawFd> {
+    let (pid, fds) = (std::env::var("LISTEN_PID"), std::env::var("LISTEN_FDS"));
+
+    // like sd_listen_fds(3) with unset_environment, the variables are only meant for the service itself
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+
+    let pid = pid.ok()?.parse::<u32>().ok()?;
+    let fds = fds.ok()?.parse::<u32>().ok()?;
+
+    // set for another process, one that started this one without unsetting them
+    if pid != std::process::id() || fds == 0 {
+        return None
+    }
+
+    if fds > 1 {
+        warn!("Using the first of the {fds} sockets systemd passed in");
+    }
+
+    Some(LISTEN_FDS_START)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn notifies_the_socket_from_the_environment() {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+        let path = std::env::temp_dir().join(format!("qasr-test-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+
+        std::env::set_var(NOTIFY_SOCKET, &path);
+        let notifier = Notifier::from_env();
+        assert!(notifier.is_enabled());
+        assert!(std::env::var_os(NOTIFY_SOCKET).is_none());
+
+        notifier.ready();
+        notifier.status("3 turns, 2.0 MB sent");
+        notifier.stopping();
+
+        let recv = |socket: &UnixDatagram| {
+            let mut buf = [0; 256];
+            let len = socket.recv(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..len]).into_owned()
+        };
+        let received: Vec<String> = (0..3).map(|_| recv(&systemd)).collect();
+        assert_eq!(received, ["READY=1", "STATUS=3 turns, 2.0 MB sent", "STOPPING=1"]);
+
+        // nothing is sent without the variable
+        assert!(!Notifier::from_env().is_enabled());
+        std::fs::remove_file(&path).unwrap();
+
+        // and a socket in the abstract namespace by its name
+        let name = format!("qasr-test-notify-{}", std::process::id());
+        let systemd = UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+
+        std::env::set_var(NOTIFY_SOCKET, format!("@{name}"));
+        Notifier::from_env().ready();
+        assert_eq!(recv(&systemd), "READY=1");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn takes_the_first_socket_passed_to_this_process() {
+        let set = |pid: u32, fds: &str| {
+            std::env::set_var("LISTEN_PID", pid.to_string());
+            std::env::set_var("LISTEN_FDS", fds);
+            std::env::set_var("LISTEN_FDNAMES", "asr.socket");
+        };
+
+        set(std::process::id(), "2");
+        assert_eq!(listen_fd(), Some(LISTEN_FDS_START));
+        assert!(["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"].iter().all(|name| std::env::var_os(name).is_none()));
+        // only once, nothing is left for a child to take
+        assert_eq!(listen_fd(), None);
+
+        set(std::process::id() + 1, "1");
+        assert_eq!(listen_fd(), None);
+        assert!(std::env::var_os("LISTEN_FDS").is_none());
+
+        set(std::process::id(), "0");
+        assert_eq!(listen_fd(), None);
+    }
+}
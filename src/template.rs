@@ -0,0 +1,247 @@
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+// Every field a template can name, the numbers among them take a width like {turn:04}
+const FIELDS: [&str; 8] = ["session", "stem", "turn", "start_ms", "end_ms", "language", "date", "time"];
+
+const NUMERIC: [&str; 3] = ["turn", "start_ms", "end_ms"];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    // the width pads with zeros when it starts with one, with spaces otherwise
+    Field { name: &'static str, width: usize, zeros: bool },
+}
+
+// A relative path with {field} placeholders, like {date}/{session}/{turn:04}-{start_ms}
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+// What one output is named after. Values missing from a turn render as `unknown`.
+#[derive(Debug, Clone, Default)]
+pub struct Fields {
+    pub session: String,
+    pub stem: String,
+    pub turn: Option<u64>,
+    pub start_ms: Option<u64>,
+    pub end_ms: Option<u64>,
+    pub language: Option<String>,
+    // local YYYY-MM-DD and HHMMSS
+    pub date: String,
+    pub time: String,
+}
+
+impl Fields {
+    // the source's file name without its extension, stdin as it is
+    pub fn stem_of(source: &str) -> String {
+        Path::new(source).file_stem().map_or_else(|| source.into(), |stem| stem.to_string_lossy().into_owned())
+    }
+
+    pub fn now(mut self) -> Self {
+        let now = jiff::Zoned::now();
+        self.date = now.strftime("%Y-%m-%d").to_string();
+        self.time = now.strftime("%H%M%S").to_string();
+        self
+    }
+}
+
+pub fn parse_template(value: &str) -> Result<Template, String> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut rest = value;
+
+    while let Some(at) = rest.find(['{', '}']) {
+        literal.push_str(&rest[..at]);
+
+        if rest[at..].starts_with('}') {
+            return Err(format!("`{value}` has a `}}` without a `{{` before it"))
+        }
+
+        let Some(len) = rest[at + 1..].find('}') else {
+            return Err(format!("`{value}` has a `{{` that is never closed"))
+        };
+
+        if !literal.is_empty() {
+            parts.push(Part::Literal(std::mem::take(&mut literal)));
+        }
+        parts.push(parse_field(&rest[at + 1..at + 1 + len])?);
+        rest = &rest[at + len + 2..];
+    }
+
+    literal.push_str(rest);
+    if !literal.is_empty() {
+        parts.push(Part::Literal(literal));
+    }
+
+    if parts.is_empty() {
+        return Err("the template is empty".into())
+    }
+
+    // only the literal parts can add components, a field's value never leaves its own
+    let literals: String = parts.iter().map(|part| if let Part::Literal(text) = part { text.as_str() } else { "x" }).collect();
+    if Path::new(&literals).components().any(|component| !matches!(component, Component::Normal(_) | Component::CurDir)) {
+        return Err(format!("`{value}` has to stay inside the directory it is written to"))
+    }
+
+    Ok(Template { parts })
+}
+
+fn parse_field(field: &str) -> Result<Part, String> {
+    let (name, width) = match field.split_once(':') {
+        Some((name, width)) => (name, Some(width)),
+        None => (field, None),
+    };
+
+    let Some(&name) = FIELDS.iter().find(|&&known| known == name) else {
+        return Err(format!("`{{{field}}}` is not a template field, expected one of {}", FIELDS.join(", ")))
+    };
+
+    match width {
+        None => Ok(Part::Field { name, width: 0, zeros: false }),
+        Some(_) if !NUMERIC.contains(&name) => Err(format!("`{{{field}}}` takes no width, only {} do", NUMERIC.join(", "))),
+        Some(width) => match width.parse::<usize>() {
+            Ok(len) if width.bytes().all(|byte| byte.is_ascii_digit()) => Ok(Part::Field { name, width: len, zeros: width.starts_with('0') }),
+            _ => Err(format!("`{{{field}}}` has a width that is not a number")),
+        },
+    }
+}
+
+impl Template {
+    pub fn render(&self, fields: &Fields) -> PathBuf {
+        let number = |value: Option<u64>| value.map_or_else(|| "unknown".into(), |value| value.to_string());
+
+        self.parts
+            .iter()
+            .map(|part| match *part {
+                Part::Literal(ref text) => text.clone(),
+                Part::Field { name, width, zeros } => {
+                    let value = match name {
+                        "session" => fields.session.clone(),
+                        "stem" => fields.stem.clone(),
+                        "turn" => number(fields.turn),
+                        "start_ms" => number(fields.start_ms),
+                        "end_ms" => number(fields.end_ms),
+                        "language" => fields.language.clone().unwrap_or_else(|| "unknown".into()),
+                        "date" => fields.date.clone(),
+                        "time" => fields.time.clone(),
+                        _ => unreachable!("{name} is not in FIELDS"),
+                    };
+
+                    sanitize(match (zeros, value.parse::<u64>()) {
+                        (true, Ok(_)) => format!("{value:0>width$}"),
+                        _ => format!("{value:>width$}"),
+                    })
+                }
+            })
+            .collect::<String>()
+            .into()
+    }
+}
+
+// A value is one path component at most, whatever the server or the file name put in it
+fn sanitize(value: String) -> String {
+    match value.as_str() {
+        "" | "." | ".." => "_".into(),
+        _ => value.replace(['/', '\\', '\0'], "_"),
+    }
+}
+
+// Creates the directory and any missing parents. One that already exists gets a suffix, like name-2, instead.
+pub fn create_dir_unique(path: &Path) -> io::Result<PathBuf> {
+    let mut unique = path.to_path_buf();
+    let mut suffix = 1;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    while let Err(err) = fs::create_dir(&unique) {
+        if err.kind() != io::ErrorKind::AlreadyExists {
+            return Err(err)
+        }
+
+        suffix += 1;
+        let mut name = path.file_name().unwrap_or_default().to_owned();
+        name.push(format!("-{suffix}"));
+        unique = path.with_file_name(name);
+    }
+
+    Ok(unique)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> Fields {
+        Fields {
+            session: "01a13a67".into(),
+            stem: "meeting".into(),
+            turn: Some(7),
+            start_ms: Some(1500),
+            end_ms: Some(4200),
+            language: Some("en".into()),
+            date: "2026-10-14".into(),
+            time: "093000".into(),
+        }
+    }
+
+    fn render(template: &str, fields: &Fields) -> String {
+        parse_template(template).unwrap().render(fields).display().to_string()
+    }
+
+    #[test]
+    fn renders_every_field() {
+        assert_eq!(render("{date}/{session}/{turn:04}-{start_ms}", &fields()), "2026-10-14/01a13a67/0007-1500");
+        assert_eq!(render("{stem}_{language}_{end_ms}_{time}", &fields()), "meeting_en_4200_093000");
+        assert_eq!(render("turn{turn:3}", &fields()), "turn  7");
+        assert_eq!(render("{turn:02}", &Fields { turn: Some(123), ..fields() }), "123");
+        assert_eq!(render("plain", &fields()), "plain");
+    }
+
+    #[test]
+    fn fills_in_what_a_turn_lacks() {
+        let fields = Fields { turn: None, start_ms: None, language: None, ..fields() };
+
+        assert_eq!(render("{turn:04}-{start_ms}-{language}", &fields), "unknown-unknown-unknown");
+    }
+
+    // a value with a separator in it cannot add directories, or climb out of the one it is in
+    #[test]
+    fn keeps_each_value_in_one_component() {
+        let fields = Fields { language: Some("../../etc".into()), session: "..".into(), stem: "".into(), ..fields() };
+
+        assert_eq!(render("{language}/{session}/{stem}", &fields), ".._.._etc/_/_");
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        for template in ["", "{nope}", "{Turn}", "{language:04}", "{turn:x}", "{turn:-4}", "{turn", "turn}", "{}", "/exports/{turn}", "../{turn}", "{date}/../{turn}"] {
+            assert!(parse_template(template).is_err(), "{template}");
+        }
+
+        assert!(parse_template("{nope}").unwrap_err().contains("expected one of session, stem"));
+        assert!(parse_template("./{date}/{turn}").is_ok());
+    }
+
+    #[test]
+    fn names_the_stem_of_a_source() {
+        assert_eq!(Fields::stem_of("/recordings/meeting.wav"), "meeting");
+        assert_eq!(Fields::stem_of("archive.tar.gz"), "archive.tar");
+        assert_eq!(Fields::stem_of("stdin"), "stdin");
+    }
+
+    #[test]
+    fn suffixes_taken_directories() {
+        let root = std::env::temp_dir().join(format!("qasr-test-template-unique-{}", std::process::id()));
+        let path = root.join("2026-10-14/01a13a67/0007-1500");
+
+        let created: Vec<_> = (0..3).map(|_| create_dir_unique(&path).unwrap()).collect();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(created, [path.clone(), root.join("2026-10-14/01a13a67/0007-1500-2"), root.join("2026-10-14/01a13a67/0007-1500-3")]);
+    }
+}
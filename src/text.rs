@@ -0,0 +1,125 @@
+// How far text is folded before it is compared, beyond case and full-width letters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Folding {
+    // accents still count, café and cafe are different words to an error rate
+    Case,
+    // Latin letters without their diacritics as well, for matching what was meant
+    Accents,
+}
+
+// Text as it is compared: lowercase words, and every CJK character a token of its own, so Chinese and Japanese compare
+// by character. Full-width letters and digits are folded to ASCII; punctuation of either width only separates tokens,
+// an apostrophe inside a word is part of it.
+pub fn tokens(text: &str, folding: Folding) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+
+    for c in text.chars().flat_map(char::to_lowercase).map(half_width) {
+        if is_cjk(c) {
+            tokens.extend((!word.is_empty()).then(|| std::mem::take(&mut word)));
+            tokens.push(c.into());
+        } else if c.is_alphanumeric() || c == '\'' && !word.is_empty() {
+            match unaccented(c).filter(|_| folding == Folding::Accents) {
+                Some(folded) => word.push_str(folded),
+                None => word.push(c),
+            }
+        } else if !word.is_empty() {
+            tokens.push(std::mem::take(&mut word));
+        }
+    }
+
+    tokens.extend((!word.is_empty()).then_some(word));
+
+    // a closing quote is not part of the word it follows
+    for token in &mut tokens {
+        token.truncate(token.trim_end_matches('\'').len());
+    }
+
+    tokens
+}
+
+// Han ideographs, Extension B and later included
+pub fn is_han(c: char) -> bool {
+    matches!(c, '\u{3400}'..='\u{4dbf}' | '\u{4e00}'..='\u{9fff}' | '\u{f900}'..='\u{faff}' | '\u{20000}'..='\u{2ebef}' | '\u{2f800}'..='\u{2fa1f}' | '\u{30000}'..='\u{3134f}')
+}
+
+// Han, kana and Hangul syllables, a character each carries about a syllable
+pub fn is_cjk(c: char) -> bool {
+    is_han(c) || matches!(c, '\u{3040}'..='\u{30ff}' | '\u{ac00}'..='\u{d7af}')
+}
+
+// U+FF01..U+FF5E mirror ASCII, the ideographic space is a space
+fn half_width(c: char) -> char {
+    match c {
+        '\u{ff01}'..='\u{ff5e}' => char::from_u32(c as u32 - 0xfee0).unwrap_or(c),
+        '\u{3000}' => ' ',
+        c => c,
+    }
+}
+
+// Latin-1 and Latin Extended-A letters, lowercase, without their diacritics
+fn unaccented(c: char) -> Option<&'static str> {
+    let folded = match c {
+        'à'..='å' | 'ā' | 'ă' | 'ą' => "a",
+        'æ' => "ae",
+        'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => "c",
+        'ď' | 'đ' => "d",
+        'è'..='ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => "e",
+        'ĝ' | 'ğ' | 'ġ' | 'ģ' => "g",
+        'ĥ' | 'ħ' => "h",
+        'ì'..='ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' | 'ı' => "i",
+        'ĵ' => "j",
+        'ķ' => "k",
+        'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => "l",
+        'ñ' | 'ń' | 'ņ' | 'ň' => "n",
+        'ò'..='ö' | 'ø' | 'ō' | 'ŏ' | 'ő' => "o",
+        'œ' => "oe",
+        'ŕ' | 'ŗ' | 'ř' => "r",
+        'ś' | 'ŝ' | 'ş' | 'š' => "s",
+        'ß' => "ss",
+        'ţ' | 'ť' | 'ŧ' => "t",
+        'ù'..='ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => "u",
+        'ŵ' => "w",
+        'ý' | 'ÿ' | 'ŷ' => "y",
+        'ź' | 'ż' | 'ž' => "z",
+        _ => return None,
+    };
+
+    Some(folded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_cjk_by_character() {
+        assert_eq!(tokens("你好，World！", Folding::Case), ["你", "好", "world"]);
+        assert_eq!(tokens("Qwen3模型ASR", Folding::Case), ["qwen3", "模", "型", "asr"]);
+        assert_eq!(tokens("こんにちは", Folding::Case).len(), 5);
+        assert_eq!(tokens("안녕 세계", Folding::Case), ["안", "녕", "세", "계"]);
+        // Extension B and the compatibility supplement
+        assert_eq!(tokens("𠀀丽", Folding::Case), ["𠀀", "丽"]);
+    }
+
+    #[test]
+    fn folds() {
+        assert_eq!(tokens("ＡＢＣ　１２３", Folding::Case), ["abc", "123"]);
+        assert_eq!(tokens("Café Noël", Folding::Case), ["café", "noël"]);
+        assert_eq!(tokens("Café Noël", Folding::Accents), ["cafe", "noel"]);
+        assert_eq!(tokens("Straße", Folding::Accents), ["strasse"]);
+    }
+
+    #[test]
+    fn keeps_apostrophes_inside_words() {
+        assert_eq!(tokens("Don't say 'no'", Folding::Case), ["don't", "say", "no"]);
+        assert_eq!(tokens("rock 'n' roll", Folding::Case), ["rock", "n", "roll"]);
+    }
+
+    #[test]
+    fn classifies_scripts() {
+        assert!(['中', '\u{20000}', '\u{2a6d6}', '\u{3134a}'].into_iter().all(is_han));
+        assert!(!is_han('か') && ['か', 'カ', '한'].into_iter().all(is_cjk));
+        assert!(!['a', '，', '1', '\u{31350}'].into_iter().any(is_cjk));
+    }
+}
@@ -0,0 +1,161 @@
+use crate::protocol;
+use log::warn;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+// Lines not yet taken by a slow reader, full means dropping rather than waiting
+const MAX_PENDING_BYTES: usize = 64 * 1024;
+
+// Finalized text only, one utterance per line, for screen readers following a terminal
+pub struct TtyOut {
+    path: String,
+    state: Mutex<State>,
+}
+
+struct State {
+    file: File,
+    pending: VecDeque<u8>,
+    dropped: u64,
+}
+
+impl TtyOut {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self::new(path.display().to_string(), open_device(path)?))
+    }
+
+    // A new pseudo-terminal, its path is what a screen reader is pointed at
+    pub fn allocate() -> io::Result<Self> {
+        let (path, file) = allocate_pty()?;
+        Ok(Self::new(path, file))
+    }
+
+    fn new(path: String, file: File) -> Self {
+        Self { path, state: Mutex::new(State { file, pending: VecDeque::new(), dropped: 0 }) }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    // Transcripts, or translations when those are all that is wanted
+    pub fn observe(&self, event: &Value, translations: bool) {
+        let text = match translations {
+            true => protocol::translation_text(event),
+            false if protocol::event_type(event) == "conversation.item.input_audio_transcription.completed" => event["transcript"].as_str(),
+            false => None,
+        };
+
+        if let Some(text) = text {
+            self.say(text);
+        }
+    }
+
+    fn say(&self, text: &str) {
+        // no escape sequences or embedded line breaks reach the reader
+        let line: String = text.split_whitespace().collect::<Vec<_>>().join(" ").chars().filter(|c| !c.is_control()).collect();
+
+        if line.is_empty() {
+            return
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        if state.pending.len() + line.len() + 1 > MAX_PENDING_BYTES {
+            if state.dropped == 0 {
+                warn!("Nobody reads {}, dropping text until it catches up", self.path);
+            }
+
+            state.dropped += 1;
+        } else {
+            state.pending.extend(line.bytes().chain(std::iter::once(b'\n')));
+        }
+
+        state.flush();
+    }
+}
+
+impl State {
+    fn flush(&mut self) {
+        while !self.pending.is_empty() {
+            let (written, _) = self.pending.as_slices();
+
+            match self.file.write(written) {
+                Ok(0) => break,
+                Ok(n) => {
+                    self.pending.drain(..n);
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                // non-blocking, whatever did not fit goes out with the next line
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    warn!("Failed to write to the --tty-out terminal: {err}");
+                    self.pending.clear();
+                    break
+                }
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn open_device(path: &Path) -> io::Result<File> {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+
+    if !path.metadata()?.file_type().is_char_device() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a terminal or other character device", path.display())))
+    }
+
+    // never becomes the controlling terminal, and a stalled reader never blocks a write
+    OpenOptions::new().write(true).custom_flags(libc::O_NONBLOCK | libc::O_NOCTTY).open(path)
+}
+
+#[cfg(unix)]
+fn allocate_pty() -> io::Result<(String, File)> {
+    use std::ffi::CStr;
+    use std::os::fd::FromRawFd;
+
+    unsafe {
+        let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK);
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error())
+        }
+
+        let master = File::from_raw_fd(fd);
+
+        if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+            return Err(io::Error::last_os_error())
+        }
+
+        // text is written to the master side, so it shows up as input on the terminal, without echoing back
+        let mut termios = std::mem::zeroed::<libc::termios>();
+
+        if libc::tcgetattr(fd, &mut termios) == 0 {
+            termios.c_lflag &= !libc::ECHO;
+            libc::tcsetattr(fd, libc::TCSANOW, &termios);
+        }
+
+        let name = libc::ptsname(fd);
+
+        if name.is_null() {
+            return Err(io::Error::last_os_error())
+        }
+
+        Ok((CStr::from_ptr(name).to_string_lossy().into_owned(), master))
+    }
+}
+
+#[cfg(not(unix))]
+fn open_device(_path: &Path) -> io::Result<File> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "--tty-out is only supported on Unix"))
+}
+
+#[cfg(not(unix))]
+fn allocate_pty() -> io::Result<(String, File)> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "--tty-out is only supported on Unix"))
+}
@@ -0,0 +1,190 @@
+use crate::audio;
+use crate::event::ServerLagging;
+use crate::protocol;
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+// Audio quieter than this holds no speech for server VAD to refer to, it is not expected to be acknowledged
+const VOICED_DBFS: f32 = -50.0;
+
+// Notices a server that takes audio but silently stopped processing it, there is no error to go by
+#[derive(Debug)]
+pub struct AckWatchdog {
+    bytes_per_second: f64,
+    max_lag: Duration,
+    sent_bytes: u64,
+    // how far the sent audio reaches up to its last voiced chunk, only that much needs acknowledging
+    voiced_bytes: u64,
+    // the first voiced chunk since the last transcription event, for providers without offsets
+    voiced_since: Option<Instant>,
+    // furthest offset any server event referred to, on the session timeline
+    acknowledged_ms: Option<f64>,
+    lagging: bool,
+}
+
+impl AckWatchdog {
+    pub fn new(sample_rate: u32, max_lag: Duration) -> Self {
+        Self {
+            bytes_per_second: sample_rate as f64 * 2.0,
+            max_lag,
+            sent_bytes: 0,
+            voiced_bytes: 0,
+            voiced_since: None,
+            acknowledged_ms: None,
+            lagging: false,
+        }
+    }
+
+    pub fn sent(&mut self, pcm: &[u8], now: Instant) {
+        self.sent_bytes += pcm.len() as u64;
+
+        if audio::rms_dbfs(pcm) > VOICED_DBFS {
+            self.voiced_bytes = self.sent_bytes;
+            self.voiced_since.get_or_insert(now);
+        }
+    }
+
+    // Offsets are expected as the server sent them, before mapping onto the source
+    pub fn observe(&mut self, event: &Value) {
+        let furthest = ["audio_start_ms", "audio_end_ms"].iter().filter_map(|field| event[*field].as_f64()).reduce(f64::max);
+
+        if let Some(furthest) = furthest {
+            self.acknowledged_ms = Some(self.acknowledged_ms.map_or(furthest, |acknowledged| acknowledged.max(furthest)));
+        }
+
+        if protocol::is_transcription(event) {
+            self.voiced_since = None;
+        }
+    }
+
+    // A new session starts a new timeline
+    pub fn restart(&mut self) {
+        self.sent_bytes = 0;
+        self.voiced_bytes = 0;
+        self.voiced_since = None;
+        self.acknowledged_ms = None;
+        self.lagging = false;
+    }
+
+    // Reports once when the lag grows past the limit, and again only after it recovered. Silence after the last voiced
+    // chunk never counts, server VAD says nothing about it however long it lasts.
+    pub fn check(&mut self, now: Instant) -> Option<ServerLagging> {
+        let sent_s = self.sent_bytes as f64 / self.bytes_per_second;
+        let voiced_s = self.voiced_bytes as f64 / self.bytes_per_second;
+
+        // without offsets from the provider, the time voiced audio went without a transcription event stands in
+        let lag_s = match (self.acknowledged_ms, self.voiced_since) {
+            (Some(acknowledged_ms), _) => (voiced_s - acknowledged_ms / 1000.0).max(0.0),
+            (None, Some(voiced_since)) => now.saturating_duration_since(voiced_since).as_secs_f64().min(voiced_s),
+            (None, None) => 0.0,
+        };
+
+        let lagging = lag_s > self.max_lag.as_secs_f64();
+        let report = (lagging && !self.lagging).then(|| ServerLagging {
+            lag_s: (lag_s * 10.0).round() / 10.0,
+            sent_s: (sent_s * 10.0).round() / 10.0,
+            acknowledged_s: self.acknowledged_ms.map(|ms| (ms / 100.0).round() / 10.0),
+        });
+
+        self.lagging = lagging;
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const SAMPLE_RATE: u32 = 1000;
+
+    fn silence(seconds: usize) -> Vec<u8> {
+        vec![0; SAMPLE_RATE as usize * 2 * seconds]
+    }
+
+    fn speech(seconds: usize) -> Vec<u8> {
+        (0..SAMPLE_RATE as usize * seconds).flat_map(|i| [0i16, 8000, 0, -8000][i % 4].to_le_bytes()).collect()
+    }
+
+    fn stopped(end_ms: u64) -> Value {
+        json!({ "type": "input_audio_buffer.speech_stopped", "item_id": "a", "audio_end_ms": end_ms })
+    }
+
+    fn watchdog() -> AckWatchdog {
+        AckWatchdog::new(SAMPLE_RATE, Duration::from_secs(10))
+    }
+
+    #[test]
+    fn reports_voiced_audio_left_unacknowledged() {
+        let (mut watchdog, now) = (watchdog(), Instant::now());
+
+        watchdog.sent(&speech(5), now);
+        watchdog.observe(&stopped(5000));
+        watchdog.sent(&speech(12), now);
+
+        let lagging = watchdog.check(now).unwrap();
+        assert_eq!((lagging.lag_s, lagging.sent_s, lagging.acknowledged_s), (12.0, 17.0, Some(5.0)));
+    }
+
+    #[test]
+    fn reports_once_per_episode() {
+        let (mut watchdog, now) = (watchdog(), Instant::now());
+
+        watchdog.sent(&speech(20), now);
+        watchdog.observe(&stopped(1000));
+
+        assert!(watchdog.check(now).is_some());
+        assert!(watchdog.check(now).is_none());
+
+        // caught up, then behind again
+        watchdog.observe(&stopped(20_000));
+        assert!(watchdog.check(now).is_none());
+        watchdog.sent(&speech(11), now);
+        assert!(watchdog.check(now).is_some());
+    }
+
+    #[test]
+    fn silence_after_the_last_speech_is_no_lag() {
+        let (mut watchdog, now) = (watchdog(), Instant::now());
+
+        watchdog.sent(&speech(3), now);
+        watchdog.observe(&stopped(3000));
+        watchdog.sent(&silence(600), now);
+        assert!(watchdog.check(now).is_none());
+
+        // nothing heard at all, however long
+        let mut watchdog = self::watchdog();
+        watchdog.sent(&silence(600), now);
+        assert!(watchdog.check(now + Duration::from_secs(600)).is_none());
+    }
+
+    #[test]
+    fn falls_back_to_the_time_since_the_last_transcription() {
+        let (mut watchdog, now) = (watchdog(), Instant::now());
+        let partial = json!({ "type": "conversation.item.input_audio_transcription.text", "item_id": "a", "text": "你好" });
+
+        watchdog.sent(&speech(30), now);
+        assert!(watchdog.check(now + Duration::from_secs(5)).is_none());
+
+        watchdog.observe(&partial);
+        watchdog.sent(&speech(1), now + Duration::from_secs(6));
+        assert!(watchdog.check(now + Duration::from_secs(15)).is_none());
+
+        let lagging = watchdog.check(now + Duration::from_secs(17)).unwrap();
+        assert_eq!((lagging.lag_s, lagging.acknowledged_s), (11.0, None));
+    }
+
+    #[test]
+    fn starts_over_with_a_new_session() {
+        let (mut watchdog, now) = (watchdog(), Instant::now());
+
+        watchdog.sent(&speech(20), now);
+        watchdog.observe(&stopped(1000));
+        assert!(watchdog.check(now).is_some());
+
+        watchdog.restart();
+        assert!(watchdog.check(now).is_none());
+        watchdog.sent(&speech(5), now);
+        assert!(watchdog.check(now).is_none());
+    }
+}
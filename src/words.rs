@@ -0,0 +1,176 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// How far word offsets may stray past the turn they belong to and still count as inside it
+const SLACK_MS: f64 = 250.0;
+
+// One word of a transcript with its place on the source timeline, as --format results lists them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Word {
+    pub text: String,
+    pub start_ms: u64,
+    pub end_ms: u64,
+}
+
+// The word timing a transcription result carries, if any: {text, start_ms, end_ms} as some providers name it,
+// {text, begin_time, end_time, punctuation} as others do. Entries without text or both offsets are dropped.
+pub fn raw(event: &Value) -> Option<Vec<(String, f64, f64)>> {
+    let words: Vec<_> = event["words"]
+        .as_array()?
+        .iter()
+        .filter_map(|word| {
+            let text = word["text"].as_str().or(word["word"].as_str())?;
+            let start_ms = word["start_ms"].as_f64().or(word["begin_time"].as_f64())?;
+            let end_ms = word["end_ms"].as_f64().or(word["end_time"].as_f64())?;
+            let punctuation = word["punctuation"].as_str().unwrap_or_default();
+
+            Some((format!("{text}{punctuation}"), start_ms, end_ms.max(start_ms)))
+        })
+        .collect();
+
+    (!words.is_empty()).then_some(words)
+}
+
+// Providers give word offsets either on the session timeline, like audio_start_ms, or counted from the start of the turn.
+// Offsets that begin before the turn does but fit within its length are relative; anything else is taken as absolute.
+// A turn that starts within SLACK_MS of zero cannot tell the two apart, and there it makes no real difference.
+pub fn is_relative(words: &[(String, f64, f64)], turn_start_ms: f64, turn_end_ms: f64) -> bool {
+    let (Some(first), Some(last)) = (words.first(), words.last()) else {
+        return false
+    };
+
+    first.1 < turn_start_ms - SLACK_MS && last.2 <= turn_end_ms - turn_start_ms + SLACK_MS
+}
+
+// Places the words on the turn's timeline, clamped to it
+pub fn place(words: &[(String, f64, f64)], turn_start_ms: u64, turn_end_ms: Option<u64>) -> Vec<Word> {
+    let turn_end_ms = turn_end_ms.unwrap_or(u64::MAX).max(turn_start_ms);
+    let shift = match is_relative(words, turn_start_ms as f64, turn_end_ms as f64) {
+        true => turn_start_ms as f64,
+        false => 0.0,
+    };
+    let at = |ms: f64| ((ms + shift).round().max(0.0) as u64).clamp(turn_start_ms, turn_end_ms);
+
+    words
+        .iter()
+        .map(|(text, start_ms, end_ms)| Word { text: text.clone(), start_ms: at(*start_ms), end_ms: at(*end_ms) })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::offset::OffsetMap;
+    use serde_json::json;
+
+    fn placed(words: &[Word]) -> Vec<(&str, u64, u64)> {
+        words.iter().map(|word| (word.text.as_str(), word.start_ms, word.end_ms)).collect()
+    }
+
+    // The same three words both ways, for a turn from 10 s to 12 s on the session timeline
+    fn relative() -> Value {
+        json!({
+            "audio_start_ms": 10_000,
+            "audio_end_ms": 12_000,
+            "words": [
+                { "text": "hello", "begin_time": 0, "end_time": 600, "punctuation": "," },
+                { "text": "big", "begin_time": 700, "end_time": 1200 },
+                { "text": "world", "begin_time": 1300, "end_time": 2000 },
+            ],
+        })
+    }
+
+    fn absolute() -> Value {
+        json!({
+            "audio_start_ms": 10_000,
+            "audio_end_ms": 12_000,
+            "words": [
+                { "text": "hello,", "start_ms": 10_000, "end_ms": 10_600 },
+                { "text": "big", "start_ms": 10_700, "end_ms": 11_200 },
+                { "text": "world", "start_ms": 11_300, "end_ms": 12_000 },
+            ],
+        })
+    }
+
+    fn place_event(event: &Value) -> Vec<Word> {
+        let turn_start_ms = event["audio_start_ms"].as_u64().unwrap();
+        place(&raw(event).unwrap(), turn_start_ms, event["audio_end_ms"].as_u64())
+    }
+
+    const EXPECTED: [(&str, u64, u64); 3] = [("hello,", 10_000, 10_600), ("big", 10_700, 11_200), ("world", 11_300, 12_000)];
+
+    #[test]
+    fn reads_both_shapes() {
+        assert_eq!(raw(&relative()).unwrap()[0], ("hello,".into(), 0.0, 600.0));
+        assert_eq!(raw(&absolute()).unwrap()[2], ("world".into(), 11_300.0, 12_000.0));
+
+        // entries missing text or an offset are dropped, and none at all is no word timing
+        let partial = json!({ "words": [{ "text": "a", "start_ms": 1 }, { "start_ms": 0, "end_ms": 1 }, { "word": "b", "start_ms": 2, "end_ms": 1 }] });
+        assert_eq!(raw(&partial).unwrap(), [("b".into(), 2.0, 2.0)]);
+        assert_eq!(raw(&json!({ "words": [] })), None);
+        assert_eq!(raw(&json!({ "transcript": "hello" })), None);
+    }
+
+    #[test]
+    fn places_relative_offsets_from_the_turn_start() {
+        assert!(is_relative(&raw(&relative()).unwrap(), 10_000.0, 12_000.0));
+        assert_eq!(placed(&place_event(&relative())), EXPECTED);
+    }
+
+    #[test]
+    fn keeps_absolute_offsets() {
+        assert!(!is_relative(&raw(&absolute()).unwrap(), 10_000.0, 12_000.0));
+        assert_eq!(placed(&place_event(&absolute())), EXPECTED);
+
+        // a little before the turn, as providers round, is still absolute
+        let words = [("a".into(), 9_900.0, 10_500.0)];
+        assert!(!is_relative(&words, 10_000.0, 12_000.0));
+    }
+
+    #[test]
+    fn offsets_past_the_turn_length_are_absolute() {
+        // starts before the turn but runs on past its length, so it cannot be counted from the turn start
+        let words = [("a".into(), 0.0, 500.0), ("b".into(), 500.0, 3000.0)];
+        assert!(!is_relative(&words, 10_000.0, 12_000.0));
+    }
+
+    #[test]
+    fn a_turn_near_zero_errs_by_less_than_the_slack() {
+        // relative offsets on a turn starting 100 ms in look absolute, and land at most that far off
+        let words = [("a".into(), 0.0, 300.0), ("b".into(), 300.0, 800.0)];
+        assert!(!is_relative(&words, 100.0, 900.0));
+
+        let truth = [(100, 400), (400, 900)];
+
+        for (word, (start_ms, end_ms)) in place(&words, 100, Some(900)).iter().zip(truth) {
+            assert!(word.start_ms.abs_diff(start_ms) as f64 <= SLACK_MS && word.end_ms.abs_diff(end_ms) as f64 <= SLACK_MS);
+        }
+    }
+
+    #[test]
+    fn clamps_to_the_turn() {
+        let words = [("early".into(), 9_500.0, 10_200.0), ("late".into(), 11_800.0, 12_600.0)];
+        assert_eq!(placed(&place(&words, 10_000, Some(12_000))), [("early", 10_000, 10_200), ("late", 11_800, 12_000)]);
+
+        // without an end the turn is open ended, and an end before its start is no end
+        let inside = [("late".into(), 11_800.0, 12_600.0)];
+        assert_eq!(placed(&place(&inside, 10_000, None)), [("late", 11_800, 12_600)]);
+        assert_eq!(placed(&place(&words, 10_000, Some(5_000))), [("early", 10_000, 10_000), ("late", 10_000, 10_000)]);
+    }
+
+    #[test]
+    fn follows_the_turn_past_a_seek() {
+        // --seek 12:30, the session timeline starts 750 s into the file
+        let mut offsets = OffsetMap::new(16000);
+        offsets.skipped(750_000 * 32);
+        offsets.sent(60_000 * 32);
+
+        let expected: Vec<_> = EXPECTED.iter().map(|&(text, start_ms, end_ms)| (text, start_ms + 750_000, end_ms + 750_000)).collect();
+
+        for mut event in [relative(), absolute()] {
+            offsets.correct_event(&mut event);
+            assert_eq!(event["audio_start_ms"], 760_000);
+            assert_eq!(placed(&place_event(&event)), expected);
+        }
+    }
+}
@@ -0,0 +1,67 @@
+mod common;
+
+use common::*;
+
+const CHAOS: &[(&str, &str)] = &[("ASR_CHAOS", "1")];
+
+fn appends(server: &MockServer) -> Vec<(u64, u64)> {
+    server.received().iter().filter(|(_, event)| event["type"] == "input_audio_buffer.append").map(|(_, event)| (event["bytes"].as_u64().unwrap(), event["nonzero"].as_u64().unwrap())).collect()
+}
+
+fn transcripts(output: &std::process::Output) -> Vec<String> {
+    of_type(&events(output), "conversation.item.input_audio_transcription.completed").iter().map(|turn| turn["transcript"].as_str().unwrap().to_owned()).collect()
+}
+
+#[tokio::test]
+async fn does_nothing_without_asr_chaos() {
+    let server = MockServer::plain().await;
+
+    let output = qasr(&server, &["--chaos-corrupt-every-n", "1", "--chaos-drop-connection-after-s", "1", "--chaos-delay-events-ms", "100"], &audio(4000)).await;
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("ASR_CHAOS=1 is not set"));
+
+    assert_eq!(transcripts(&output), ["turn 0 of session 0", "turn 1 of session 0"]);
+    assert_eq!(server.connections(), 1);
+    assert!(appends(&server).iter().all(|&(_, nonzero)| nonzero == 0));
+}
+
+// Every other audio frame arrives with one byte flipped, and the session carries on regardless
+#[tokio::test]
+async fn corrupts_every_nth_audio_frame() {
+    let server = MockServer::plain().await;
+
+    let output = qasr_with_env(&server, CHAOS, &["--chaos-corrupt-every-n", "2"], &audio(4000)).await;
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(transcripts(&output).len(), 2);
+
+    let appends = appends(&server);
+    assert_eq!(appends.iter().map(|&(bytes, _)| bytes).sum::<u64>(), audio(4000).len() as u64);
+    assert!(appends.iter().enumerate().all(|(index, &(_, nonzero))| nonzero == (index % 2) as u64), "{appends:?}");
+}
+
+// Events held back until long after the last audio went out are still all waited for before exiting
+#[tokio::test]
+async fn drains_delayed_events() {
+    let server = MockServer::plain().await;
+
+    let output = qasr_with_env(&server, CHAOS, &["--chaos-delay-events-ms", "50"], &audio(10_000)).await;
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(transcripts(&output), (0..5).map(|turn| format!("turn {turn} of session 0")).collect::<Vec<_>>());
+}
+
+// A reset is not reconnected from, the run fails with the connection error after printing what finished before it
+#[tokio::test]
+async fn fails_on_a_dropped_connection_with_the_turns_so_far() {
+    let server = MockServer::plain().await;
+
+    // the events of 20 s take over 3 s to get through, the drop comes in the middle of them
+    let args = ["--chaos-drop-connection-after-s", "1", "--chaos-delay-events-ms", "100"];
+    let output = qasr_with_env(&server, CHAOS, &args, &audio(20_000)).await;
+    assert_eq!(output.status.code(), Some(8));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("connection dropped by --chaos-drop-connection-after-s (server session sess_0)"));
+
+    let transcripts = transcripts(&output);
+    assert!((1..10).contains(&transcripts.len()), "{transcripts:?}");
+    assert_eq!(transcripts[0], "turn 0 of session 0");
+    assert_eq!(server.connections(), 1);
+}
@@ -0,0 +1,250 @@
+// A realtime server on a local port, scripted per test, and a way to run qasr against it
+#![allow(dead_code)]
+
+use base64::Engine;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::process::{Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::Message;
+
+pub const SAMPLE_RATE: usize = 16000;
+
+const RUN_TIMEOUT: Duration = Duration::from_secs(60);
+
+type Handler = dyn Fn(&mut Session, &Value) -> Vec<Message> + Send + Sync;
+
+// One connection to the server, and how far its turns got
+pub struct Session {
+    // connections accepted before this one
+    pub index: usize,
+    pub model: String,
+    pub sent_ms: usize,
+    pub turns: usize,
+    // what completed turns report as detected
+    pub language: String,
+    sample_rate: usize,
+    speech_started_ms: Option<usize>,
+}
+
+impl Session {
+    // What a plain server answers: session.updated, a turn with speech from 300 to 1800 ms of every 2 s of audio on
+    // its own timeline, and session.finished
+    pub fn reply(&mut self, event: &Value) -> Vec<Message> {
+        match event["type"].as_str().unwrap_or_default() {
+            "session.update" => {
+                self.sample_rate = event["session"]["sample_rate"].as_u64().map_or(SAMPLE_RATE, |rate| rate as usize);
+                vec![text(json!({ "type": "session.updated", "session": event["session"] }))]
+            }
+            "input_audio_buffer.append" => {
+                self.sent_ms += event["bytes"].as_u64().unwrap_or_default() as usize * 1000 / (self.sample_rate * 2);
+                let phase_ms = self.sent_ms % 2000;
+                let item_id = format!("item_{}_{}", self.index, self.turns);
+
+                match self.speech_started_ms {
+                    None if (300..1800).contains(&phase_ms) => {
+                        self.speech_started_ms = Some(self.sent_ms);
+                        vec![text(json!({ "type": "input_audio_buffer.speech_started", "item_id": item_id, "audio_start_ms": self.sent_ms }))]
+                    }
+                    Some(start_ms) if self.sent_ms >= start_ms - start_ms % 2000 + 1800 => {
+                        self.speech_started_ms = None;
+                        self.turns += 1;
+
+                        vec![
+                            text(json!({ "type": "input_audio_buffer.speech_stopped", "item_id": item_id, "audio_end_ms": self.sent_ms })),
+                            text(json!({
+                                "type": "conversation.item.input_audio_transcription.completed",
+                                "item_id": item_id,
+                                "transcript": format!("turn {} of session {}", self.turns - 1, self.index),
+                                "language": self.language,
+                                "audio_start_ms": start_ms,
+                                "audio_end_ms": self.sent_ms,
+                            })),
+                        ]
+                    }
+                    _ => Vec::new(),
+                }
+            }
+            "session.finish" => vec![text(json!({ "type": "session.finished" }))],
+            _ => Vec::new(),
+        }
+    }
+}
+
+pub fn text(event: Value) -> Message {
+    Message::Text(event.to_string().into())
+}
+
+pub struct MockServer {
+    pub url: String,
+    // every client event with the connection it came on, audio as its length in bytes and how many of them are not zero
+    received: Arc<Mutex<Vec<(usize, Value)>>>,
+}
+
+impl MockServer {
+    pub async fn plain() -> Self {
+        Self::start(Session::reply).await
+    }
+
+    pub async fn start(handler: impl Fn(&mut Session, &Value) -> Vec<Message> + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let handler: Arc<Handler> = Arc::new(handler);
+
+        tokio::spawn({
+            let received = received.clone();
+
+            async move {
+                for index in 0.. {
+                    let Ok((stream, _)) = listener.accept().await else {
+                        break
+                    };
+
+                    tokio::spawn(serve(stream, index, handler.clone(), received.clone()));
+                }
+            }
+        });
+
+        Self { url, received }
+    }
+
+    pub fn received(&self) -> Vec<(usize, Value)> {
+        self.received.lock().unwrap().clone()
+    }
+
+    pub fn connections(&self) -> usize {
+        self.received().last().map_or(0, |(index, _)| index + 1)
+    }
+
+    // Polls until the server has received this many events of the kind
+    pub async fn wait_for(&self, kind: &str, count: usize) {
+        let received = || self.received().iter().filter(|(_, event)| event["type"] == kind).count();
+
+        tokio::time::timeout(RUN_TIMEOUT, async {
+            while received() < count {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("no {kind} from qasr"));
+    }
+}
+
+// the handshake callback's rejection is a whole HTTP response
+#[allow(clippy::result_large_err)]
+async fn serve(stream: tokio::net::TcpStream, index: usize, handler: Arc<Handler>, received: Arc<Mutex<Vec<(usize, Value)>>>) {
+    let model = Arc::new(Mutex::new(String::new()));
+    let callback = {
+        let model = model.clone();
+
+        move |request: &tokio_tungstenite::tungstenite::handshake::server::Request, response| {
+            let query = request.uri().query().unwrap_or_default();
+            *model.lock().unwrap() = query.split('&').find_map(|pair| pair.strip_prefix("model=")).unwrap_or_default().into();
+            Ok(response)
+        }
+    };
+
+    let Ok(mut ws) = tokio_tungstenite::accept_hdr_async(stream, callback).await else {
+        return
+    };
+
+    let mut session = Session {
+        index,
+        model: model.lock().unwrap().clone(),
+        sent_ms: 0,
+        turns: 0,
+        language: "zh".into(),
+        sample_rate: SAMPLE_RATE,
+        speech_started_ms: None,
+    };
+
+    let created = json!({ "type": "session.created", "session": { "id": format!("sess_{index}") } });
+
+    if ws.send(text(created)).await.is_err() {
+        return
+    }
+
+    while let Some(Ok(message)) = ws.next().await {
+        let Message::Text(message) = message else {
+            continue
+        };
+
+        let mut event: Value = serde_json::from_str(&message).unwrap();
+
+        if let Some(audio) = event.as_object_mut().unwrap().remove("audio") {
+            let audio = base64::engine::general_purpose::STANDARD.decode(audio.as_str().unwrap()).unwrap();
+            event["bytes"] = audio.len().into();
+            event["nonzero"] = audio.iter().filter(|&&byte| byte != 0).count().into();
+        }
+
+        received.lock().unwrap().push((index, event.clone()));
+
+        for reply in handler(&mut session, &event) {
+            if ws.send(reply).await.is_err() {
+                return
+            }
+        }
+
+        if event["type"] == "session.finish" {
+            break
+        }
+    }
+
+    let _ = ws.close(None).await;
+}
+
+// Runs qasr against the server with nothing from the environment but the API key, stdin written and closed
+pub async fn qasr(server: &MockServer, args: &[&str], stdin: &[u8]) -> Output {
+    qasr_with_env(server, &[], args, stdin).await
+}
+
+pub async fn qasr_with_env(server: &MockServer, vars: &[(&str, &str)], args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = command(server, vars).args(args).spawn().unwrap();
+
+    let mut input = child.stdin.take().unwrap();
+    let _ = input.write_all(stdin).await;
+    drop(input);
+
+    finish(child).await
+}
+
+// qasr set up to run against the server, for the tests that drive it as it goes
+pub fn command(server: &MockServer, vars: &[(&str, &str)]) -> tokio::process::Command {
+    let mut command = tokio::process::Command::new(env!("CARGO_BIN_EXE_qasr"));
+
+    for (name, _) in std::env::vars().filter(|(name, _)| name.starts_with("ASR_") || name.starts_with("LISTEN_") || name == "NOTIFY_SOCKET") {
+        command.env_remove(name);
+    }
+
+    command
+        .env("DASHSCOPE_API_KEY", "test")
+        .env("ASR_BASE_URL", &server.url)
+        .envs(vars.iter().copied())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    command
+}
+
+pub async fn finish(child: tokio::process::Child) -> Output {
+    tokio::time::timeout(RUN_TIMEOUT, child.wait_with_output()).await.expect("qasr did not exit").unwrap()
+}
+
+// The JSON lines qasr printed
+pub fn events(output: &Output) -> Vec<Value> {
+    String::from_utf8_lossy(&output.stdout).lines().filter_map(|line| serde_json::from_str(line).ok()).collect()
+}
+
+pub fn of_type<'a>(events: &'a [Value], kind: &str) -> Vec<&'a Value> {
+    events.iter().filter(|event| event["type"] == kind).collect()
+}
+
+// Silent 16 kHz PCM, the server above makes its turns up from the length alone
+pub fn audio(ms: usize) -> Vec<u8> {
+    vec![0; SAMPLE_RATE * 2 * ms / 1000]
+}
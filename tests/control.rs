@@ -0,0 +1,67 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::*;
+use serde_json::Value;
+use std::os::fd::AsRawFd;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+// set_vad on --control-fd sends a new session.update with the change, and client.vad_updated follows the server's
+// session.updated for it
+#[tokio::test]
+async fn updates_the_vad_of_the_live_session() {
+    let server = MockServer::plain().await;
+    let (ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+
+    let mut command = command(&server, &[]);
+    let fd = theirs.as_raw_fd();
+    command.args(["--control-fd", "3", "--vad-threshold", "0.2", "--vad-silence-ms", "800"]);
+    // SAFETY: dup2 is async-signal-safe, and the copy it makes is not closed on exec
+    unsafe {
+        command.pre_exec(move || match libc::dup2(fd, 3) {
+            -1 => Err(std::io::Error::last_os_error()),
+            _ => Ok(()),
+        });
+    }
+
+    let mut child = command.spawn().unwrap();
+    drop(theirs);
+
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(&audio(2000)).await.unwrap();
+    server.wait_for("session.update", 1).await;
+
+    ours.set_nonblocking(true).unwrap();
+    let (control_rx, mut control_tx) = tokio::net::UnixStream::from_std(ours).unwrap().into_split();
+    control_tx.write_all(b"{\"cmd\":\"set_vad\",\"threshold\":0.3,\"silence_ms\":500}\n").await.unwrap();
+
+    let response: Value = serde_json::from_str(&BufReader::new(control_rx).lines().next_line().await.unwrap().unwrap()).unwrap();
+    assert_eq!((response["ok"].as_bool(), response["cmd"].as_str()), (Some(true), Some("set_vad")));
+
+    server.wait_for("session.update", 2).await;
+    stdin.write_all(&audio(2000)).await.unwrap();
+    drop((stdin, control_tx));
+
+    let output = finish(child).await;
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let updates: Vec<_> = server.received().into_iter().filter(|(_, event)| event["type"] == "session.update").map(|(index, event)| (index, event["session"]["turn_detection"].clone())).collect();
+    assert_eq!(updates.len(), 2);
+    assert_eq!(updates[1].0, 0, "the update goes to the same session");
+    // the threshold goes through an f32 on the way
+    let vad = |vad: &Value| (vad["threshold"].as_f64().map(|threshold| threshold as f32), vad["silence_duration_ms"].as_u64());
+    assert_eq!(vad(&updates[0].1), (Some(0.2), Some(800)));
+    assert_eq!(vad(&updates[1].1), (Some(0.3), Some(500)));
+
+    let events = events(&output);
+    let confirmed = of_type(&events, "client.vad_updated");
+    assert_eq!(confirmed.len(), 1);
+    assert_eq!(vad(confirmed[0]), (Some(0.3), Some(500)));
+
+    // confirmed once the server answered, not when the command was taken
+    let updated: Vec<_> = events.iter().enumerate().filter(|(_, event)| event["type"] == "session.updated").map(|(at, _)| at).collect();
+    assert_eq!(updated.len(), 2);
+    assert!(events.iter().position(|event| event["type"] == "client.vad_updated").unwrap() > updated[1]);
+    assert_eq!(of_type(&events, "conversation.item.input_audio_transcription.completed").len(), 2);
+}
@@ -0,0 +1,63 @@
+mod common;
+
+use common::*;
+use serde_json::json;
+
+// An input that ends before any audio, as from /dev/null, is reported instead of exiting 0 without a word
+#[tokio::test]
+async fn reports_an_input_without_audio() {
+    let server = MockServer::plain().await;
+
+    let output = qasr(&server, &[], &[]).await;
+    assert_eq!(output.status.code(), Some(10), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(of_type(&events(&output), "client.no_input").len(), 1);
+
+    // the session came up, but there was nothing to commit
+    let received: Vec<_> = server.received().into_iter().map(|(_, event)| event["type"].as_str().unwrap().to_owned()).collect();
+    assert_eq!(received.first().map(String::as_str), Some("session.update"));
+    assert!(!received.iter().any(|kind| kind.starts_with("input_audio_buffer.")), "{received:?}");
+}
+
+#[tokio::test]
+async fn allows_an_empty_input_when_asked() {
+    let server = MockServer::plain().await;
+
+    for args in [&["--allow-empty"][..], &["--allow-empty", "/dev/null"]] {
+        let output = qasr(&server, args, &[]).await;
+        assert!(output.status.success(), "{args:?}: {}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(of_type(&events(&output), "client.no_input").len(), 1, "{args:?}");
+    }
+}
+
+// 10 ms of audio is still committed and transcribed, the result tagged as coming from a short input
+#[tokio::test]
+async fn tags_the_results_of_a_short_input() {
+    // transcribes whatever is left in the buffer when the session finishes
+    let server = MockServer::start(|session, event| {
+        let mut replies = session.reply(event);
+
+        if event["type"] == "session.finish" && session.sent_ms > 0 {
+            let completed = json!({ "type": "conversation.item.input_audio_transcription.completed", "item_id": "item_0_0", "transcript": "嗯", "audio_start_ms": 0, "audio_end_ms": session.sent_ms });
+            replies.insert(0, text(completed));
+        }
+
+        replies
+    })
+    .await;
+
+    let output = qasr(&server, &[], &audio(10)).await;
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let printed = events(&output);
+    assert!(of_type(&printed, "client.no_input").is_empty());
+    let turns = of_type(&printed, "conversation.item.input_audio_transcription.completed");
+    assert_eq!(turns.len(), 1);
+    assert_eq!((turns[0]["transcript"].as_str(), turns[0]["short_input"].as_bool()), (Some("嗯"), Some(true)));
+
+    let appended: u64 = server.received().iter().filter(|(_, event)| event["type"] == "input_audio_buffer.append").map(|(_, event)| event["bytes"].as_u64().unwrap()).sum();
+    assert_eq!(appended, audio(10).len() as u64);
+
+    // above --min-audio-ms the same turn goes untagged
+    let printed = events(&qasr(&server, &["--min-audio-ms", "5"], &audio(10)).await);
+    assert_eq!(of_type(&printed, "conversation.item.input_audio_transcription.completed")[0].get("short_input"), None);
+}
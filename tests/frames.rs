@@ -0,0 +1,74 @@
+mod common;
+
+use common::*;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::{Data, OpCode};
+use tokio_tungstenite::tungstenite::protocol::frame::Frame;
+use tokio_tungstenite::tungstenite::Message;
+
+// The first session sends a message over --max-message-size after its first turn, the second one a text frame that
+// is not UTF-8. Each time a new session picks up after the last finished turn.
+#[tokio::test]
+async fn survives_oversized_and_invalid_frames() {
+    let server = MockServer::start(|session, event| {
+        let turns = session.turns;
+        let mut replies = session.reply(event);
+
+        if session.turns == 1 && turns == 0 {
+            match session.index {
+                0 => replies.push(Message::Text("x".repeat(100 << 10).into())),
+                1 => replies.push(Message::Frame(Frame::message(vec![0xff, 0xfe, 0x41], OpCode::Data(Data::Text), true))),
+                _ => {}
+            }
+        }
+
+        replies
+    })
+    .await;
+
+    let output = qasr(&server, &["--max-message-size", "64K", "--turn-retries", "1"], &audio(10_000)).await;
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let events = events(&output);
+    let warnings: Vec<_> = of_type(&events, "client.protocol_warning").iter().map(|warning| warning["kind"].clone()).collect();
+    assert_eq!(warnings, ["message_too_large", "invalid_utf8"]);
+
+    // every session after a bad frame transcribes on from the end of the last finished turn
+    let turns: Vec<_> = of_type(&events, "conversation.item.input_audio_transcription.completed")
+        .iter()
+        .map(|turn| (turn["transcript"].as_str().unwrap(), turn["audio_start_ms"].as_u64().unwrap(), turn["audio_end_ms"].as_u64().unwrap()))
+        .collect();
+    assert_eq!(turns.iter().map(|turn| turn.0).take(3).collect::<Vec<_>>(), ["turn 0 of session 0", "turn 0 of session 1", "turn 0 of session 2"]);
+    assert!(turns[3..].iter().all(|turn| turn.0.ends_with("of session 2")));
+    assert!(turns.windows(2).all(|pair| pair[0].2 <= pair[1].1));
+    assert!(turns.last().unwrap().2 > 8000);
+
+    assert_eq!(server.connections(), 3);
+    assert!(server.received().iter().any(|(index, event)| *index == 2 && event["type"] == "session.finish"));
+}
+
+// A server that sends nothing but bad frames once the first session is up is given up on
+#[tokio::test]
+async fn gives_up_on_a_server_that_keeps_sending_bad_frames() {
+    let bad_frame = || Message::Frame(Frame::message(vec![0xc0], OpCode::Data(Data::Text), true));
+
+    let server = MockServer::start(move |session, event| {
+        let turns = session.turns;
+        let mut replies = session.reply(event);
+
+        if session.index > 0 && event["type"] == "session.update" {
+            replies.push(bad_frame());
+        } else if session.turns == 1 && turns == 0 {
+            // cut before the turn completes, a finished turn would count as recovered
+            replies.truncate(1);
+            replies.push(bad_frame());
+        }
+
+        replies
+    })
+    .await;
+
+    let output = qasr(&server, &["--turn-retries", "1"], &audio(10_000)).await;
+    assert_eq!(output.status.code(), Some(8));
+    assert_eq!(of_type(&events(&output), "client.protocol_warning").len(), 4);
+    assert_eq!(server.connections(), 4);
+}
@@ -0,0 +1,51 @@
+mod common;
+
+use common::*;
+
+// (number, start ms, end ms, text) of every cue
+fn cues(srt: &[u8]) -> Vec<(u64, u64, u64, String)> {
+    let ms = |time: &str| {
+        let (clock, millis) = time.trim().split_once(',').unwrap();
+        clock.split(':').fold(0, |total, part| total * 60 + part.parse::<u64>().unwrap()) * 1000 + millis.parse::<u64>().unwrap()
+    };
+
+    String::from_utf8_lossy(srt)
+        .split("\n\n")
+        .filter(|cue| !cue.trim().is_empty())
+        .map(|cue| {
+            let mut lines = cue.trim().lines();
+            let number = lines.next().unwrap().parse().unwrap();
+            let (start, end) = lines.next().unwrap().split_once("-->").unwrap();
+            (number, ms(start), ms(end), lines.collect::<Vec<_>>().join("\n"))
+        })
+        .collect()
+}
+
+// A --language-route switch after the first turn moves the rest of the input to a new session, which counts its
+// offsets from zero again. The cues carry on numbered and timed on the input's timeline, as they would without it.
+#[tokio::test]
+async fn continues_cues_across_a_session_switch() {
+    let english = || async {
+        MockServer::start(|session, event| {
+            session.language = "en".into();
+            session.reply(event)
+        })
+        .await
+    };
+
+    let (server, routed_server) = (english().await, english().await);
+    let plain = qasr(&server, &["--format", "srt"], &audio(10_000)).await;
+    let routed = qasr(&routed_server, &["--format", "srt", "--language-route", "en=qwen3-asr-flash-realtime-en"], &audio(10_000)).await;
+    assert!(plain.status.success() && routed.status.success(), "{}", String::from_utf8_lossy(&routed.stderr));
+    assert_eq!((server.connections(), routed_server.connections()), (1, 2));
+
+    let (plain, routed) = (cues(&plain.stdout), cues(&routed.stdout));
+    assert_eq!(routed.iter().map(|cue| cue.0).collect::<Vec<_>>(), (1..=plain.len() as u64).collect::<Vec<_>>());
+    assert!(routed[0].3.ends_with("of session 0") && routed[1..].iter().all(|cue| cue.3.ends_with("of session 1")), "{routed:?}");
+    assert!(routed.windows(2).all(|pair| pair[0].2 <= pair[1].1), "{routed:?}");
+
+    // the server's turns follow its own timeline, which starts where the switch happened
+    for (plain, routed) in plain.iter().zip(&routed) {
+        assert!(plain.1.abs_diff(routed.1) <= 300 && plain.2.abs_diff(routed.2) <= 300, "{plain:?} {routed:?}");
+    }
+}